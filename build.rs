@@ -0,0 +1,22 @@
+// Generate the C header for the interface in src/ffi.rs
+extern crate cbindgen;
+
+use std::env;
+use std::path::Path;
+
+fn main() {
+    // cbindgen reads the whole crate to resolve the types the interface uses
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=CADMIUM_UPDATE_HEADER");
+
+    let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let bindings = cbindgen::generate(&crate_dir).expect("Unable to generate the C header");
+    // the header is generated in the build directory, which the tests compare with the committed one
+    let out_dir = env::var("OUT_DIR").unwrap();
+    bindings.write_to_file(Path::new(&out_dir).join("cadmium.h"));
+    // the committed header in include/ is only rewritten on request, so building does not change the source tree
+    if env::var_os("CADMIUM_UPDATE_HEADER").is_some() {
+        bindings.write_to_file(Path::new(&crate_dir).join("include/cadmium.h"));
+    }
+}
@@ -0,0 +1,166 @@
+/* C interface for embedding cadmium. */
+
+#ifndef CADMIUM_H
+#define CADMIUM_H
+
+/* This file is generated by build.rs from src/ffi.rs, do not edit it by hand. Run `CADMIUM_UPDATE_HEADER=1 cargo build` to update it. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+// Status codes returned by the C interface.
+typedef enum CadmiumStatus {
+  // The call succeeded. For `cadmium_query_next`, a solution was found.
+  CADMIUM_STATUS_OK = 0,
+  // The query has no (more) solutions.
+  CADMIUM_STATUS_NO_SOLUTION = 1,
+  // The source text or the goal could not be parsed.
+  CADMIUM_STATUS_PARSE_ERROR = 2,
+  // A null pointer or a string that is not valid UTF-8 was passed.
+  CADMIUM_STATUS_INVALID_ARGUMENT = 3,
+  // Another query on the same engine is still open.
+  CADMIUM_STATUS_BUSY = 4,
+  // The variable or argument that was asked for does not exist.
+  CADMIUM_STATUS_NOT_FOUND = 5,
+  // The term is not of the kind the call expects.
+  CADMIUM_STATUS_TYPE_ERROR = 6,
+  // The query raised an exception that it did not catch. The message of `cadmium_engine_error` describes the exception.
+  CADMIUM_STATUS_EXCEPTION = 7,
+  // The engine failed with an internal error, which `cadmium_engine_error` describes. The engine should not be used anymore, except to free it.
+  CADMIUM_STATUS_INTERNAL_ERROR = 8,
+} CadmiumStatus;
+
+// The kinds of terms.
+typedef enum CadmiumTermKind {
+  CADMIUM_TERM_KIND_VARIABLE = 0,
+  CADMIUM_TERM_KIND_ATOM = 1,
+  CADMIUM_TERM_KIND_INTEGER = 2,
+  CADMIUM_TERM_KIND_COMPOUND = 3,
+  CADMIUM_TERM_KIND_STRING = 4,
+  // No term was given.
+  CADMIUM_TERM_KIND_INVALID = -1,
+} CadmiumTermKind;
+
+// An engine holding a consulted program.
+typedef struct CadmiumEngine CadmiumEngine;
+
+// An open query and its current solution.
+typedef struct CadmiumQuery CadmiumQuery;
+
+// A term read back from a solution.
+typedef struct CadmiumTerm CadmiumTerm;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Create a new engine with an empty program. Free it with `cadmium_engine_free`.
+struct CadmiumEngine *cadmium_engine_new(void);
+
+// Destroy an engine. Returns `CADMIUM_STATUS_BUSY` and leaves the engine alive if a query is still open on it.
+//
+// # Safety
+// `engine` must be null or a pointer returned by `cadmium_engine_new` that was not freed yet.
+enum CadmiumStatus cadmium_engine_free(struct CadmiumEngine *engine);
+
+// The message describing the last error on given engine, or null if there was none. The string is owned by the engine and valid until the next call on it.
+//
+// # Safety
+// `engine` must be null or a live engine.
+const char *cadmium_engine_error(const struct CadmiumEngine *engine);
+
+// Add the clauses in given source text to the program of an engine. This is refused with `CADMIUM_STATUS_BUSY` while a query is open on the engine.
+//
+// # Safety
+// `engine` must be null or a live engine, `source` must be null or a NUL-terminated string.
+enum CadmiumStatus cadmium_consult_string(struct CadmiumEngine *engine, const char *source);
+
+// Open a query for given goal and store it in `*query`. Only one query can be open on an engine at a time. Close it with `cadmium_query_close`.
+//
+// # Safety
+// `engine` must be null or a live engine, `goal` must be null or a NUL-terminated string, and `query` must be null or writable.
+enum CadmiumStatus cadmium_query_open(struct CadmiumEngine *engine, const char *goal, struct CadmiumQuery **query);
+
+// Find the next solution of a query. Returns `CADMIUM_STATUS_OK` if there is one, `CADMIUM_STATUS_NO_SOLUTION` once the solutions are exhausted and `CADMIUM_STATUS_EXCEPTION` if the query raised an uncaught exception.
+//
+// # Safety
+// `query` must be null or a live query.
+enum CadmiumStatus cadmium_query_next(struct CadmiumQuery *query);
+
+// The number of named variables in a query.
+//
+// # Safety
+// `query` must be null or a live query.
+size_t cadmium_query_variable_count(const struct CadmiumQuery *query);
+
+// The name of the variable at given index of a query, or null if the index is out of range. The string is owned by the query.
+//
+// # Safety
+// `query` must be null or a live query.
+const char *cadmium_query_variable_name(const struct CadmiumQuery *query, size_t index);
+
+// Store the binding of given variable in the current solution in `*term`. Free the term with `cadmium_term_free`.
+//
+// # Safety
+// `query` must be null or a live query, `name` must be null or a NUL-terminated string, and `term` must be null or writable.
+enum CadmiumStatus cadmium_query_binding(const struct CadmiumQuery *query, const char *name, struct CadmiumTerm **term);
+
+// Close a query and release the engine for the next one.
+//
+// # Safety
+// `query` must be null or a live query, its engine must still be alive.
+void cadmium_query_close(struct CadmiumQuery *query);
+
+// The kind of a term, `CADMIUM_TERM_KIND_INVALID` if `term` is null.
+//
+// # Safety
+// `term` must be null or a live term.
+enum CadmiumTermKind cadmium_term_kind(const struct CadmiumTerm *term);
+
+// Store the value of an integer term in `*value`.
+//
+// # Safety
+// `term` must be null or a live term, and `value` must be null or writable.
+enum CadmiumStatus cadmium_term_integer(const struct CadmiumTerm *term, int64_t *value);
+
+// The name of an atom, the functor name of a compound term or the text of a string, null for other terms. The string is owned by the term.
+//
+// # Safety
+// `term` must be null or a live term.
+const char *cadmium_term_name(const struct CadmiumTerm *term);
+
+// The number of arguments of a compound term, 0 for other terms.
+//
+// # Safety
+// `term` must be null or a live term.
+size_t cadmium_term_arity(const struct CadmiumTerm *term);
+
+// Store the argument at given zero-based index of a compound term in `*arg`. Free the argument with `cadmium_term_free`.
+//
+// # Safety
+// `term` must be null or a live term, and `arg` must be null or writable.
+enum CadmiumStatus cadmium_term_arg(const struct CadmiumTerm *term, size_t index, struct CadmiumTerm **arg);
+
+// Render a term as text. Free the string with `cadmium_string_free`.
+//
+// # Safety
+// `term` must be null or a live term.
+char *cadmium_term_to_string(const struct CadmiumTerm *term);
+
+// Free a term.
+//
+// # Safety
+// `term` must be null or a live term.
+void cadmium_term_free(struct CadmiumTerm *term);
+
+// Free a string returned by the C interface.
+//
+// # Safety
+// `s` must be null or a string returned by `cadmium_term_to_string`.
+void cadmium_string_free(char *s);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* CADMIUM_H */
@@ -1,9 +1,12 @@
 // AST for the front-end
+pub mod check;
 pub mod transform;
 
 use std::fmt;
 use crate::ast_common;
 use ast_common::*;
+use crate::ops::OpType;
+use crate::writer::quote;
 
 // String-based variable representation, the wrapper is there to enforce stricter
 // type checking.
@@ -17,41 +20,188 @@ pub enum Var {
 // names for a more efficient representation in later phases.
 pub type Program<V> = Vec<PredDef<V>>;
 
+// The name of the definitions the parser reads grammar rules, `Head --> Body`, into. The head and the body are the parameters, until the rule is translated to the clause it stands for.
+pub const GRAMMAR_RULE: &str = "-->";
+
 // Predicate definition. `name` should always be a user predicate. TODO: Enforce
 // this.
-#[derive(PartialEq,Eq,Hash)]
+#[derive(Clone,PartialEq,Eq,Hash,Debug)]
 pub struct PredDef<V> {
     pub name: Pred,
     pub params: Vec<Expr<V>>,
-    pub body: Stmt<V>
+    pub body: Stmt<V>,
+    // position of the head of the clause
+    pub span: Span,
 }
 
 impl<V> PredDef<V> {
     pub fn new(name: &str, params: Vec<Expr<V>>, body: Stmt<V>) -> Self {
         PredDef {
             name: Pred::User(name.to_string()),
-            params: params,
-            body: body,
+            params,
+            body,
+            span: Span::default(),
         }
     }
 
     pub fn sig(&self) -> PredSig {
         PredSig(self.name.clone(), self.params.len())
     }
+
+    pub fn is_grammar_rule(&self) -> bool {
+        self.name == Pred::User(GRAMMAR_RULE.to_string()) && self.params.len() == 2
+    }
+}
+
+// The clause in source form, with each goal of the body on its own line
+impl<V> fmt::Display for PredDef<V> where V: fmt::Display {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_call(f, self.name.name(), &self.params)?;
+        match self.body {
+            Stmt::True => write!(f, "."),
+            ref body => {
+                writeln!(f, " :-")?;
+                write_body(f, body, 4, false)?;
+                write!(f, ".")
+            }
+        }
+    }
+}
+
+fn write_call<V: fmt::Display>(f: &mut fmt::Formatter, name: &str, args: &[Expr<V>]) -> fmt::Result {
+    write!(f, "{}", name)?;
+    if let Some((first, rest)) = args.split_first() {
+        write!(f, "({}", first)?;
+        for arg in rest.iter() {
+            write!(f, ", {}", arg)?;
+        }
+        write!(f, ")")?;
+    }
+    Ok(())
+}
+
+// Write a body indented by given number of spaces, the first line is not indented if it continues a line
+fn write_body<V: fmt::Display>(f: &mut fmt::Formatter, s: &Stmt<V>, indent: usize, continued: bool) -> fmt::Result {
+    let pad = if continued { 0 } else { indent };
+    match s {
+        Stmt::And(s1, s2) => {
+            write_body(f, s1, indent, continued)?;
+            writeln!(f, ",")?;
+            write_body(f, s2, indent, false)
+        }
+        Stmt::Or(_, _) | Stmt::If(_, _, _) => {
+            write!(f, "{:pad$}(   ", "", pad = pad)?;
+            write_branches(f, s, indent)?;
+            write!(f, "\n{:indent$})", "", indent = indent)
+        }
+        Stmt::Call(Pred::Sys(p, _), args, _) => {
+            write!(f, "{:pad$}sys:", "", pad = pad)?;
+            write_call(f, p, args)
+        }
+        goal => write!(f, "{:pad$}{}", "", goal, pad = pad)
+    }
+}
+
+// Write the branches of a disjunction or an if-then-else that starts at given indentation
+fn write_branches<V: fmt::Display>(f: &mut fmt::Formatter, s: &Stmt<V>, indent: usize) -> fmt::Result {
+    match s {
+        // an if-then-else on the left of a disjunction needs its own parentheses to keep its meaning
+        Stmt::Or(s1, s2) if !matches!(**s1, Stmt::If(_, _, _)) => {
+            write_branches(f, s1, indent)?;
+            write!(f, "\n{:indent$};   ", "", indent = indent)?;
+            write_branches(f, s2, indent)
+        }
+        Stmt::Or(s1, s2) => {
+            write_body(f, s1, indent + 4, true)?;
+            write!(f, "\n{:indent$};   ", "", indent = indent)?;
+            write_branches(f, s2, indent)
+        }
+        Stmt::If(s1, s2, s3) => {
+            write_body(f, s1, indent + 4, true)?;
+            write!(f, "\n{:indent$}->  ", "", indent = indent)?;
+            write_body(f, s2, indent + 4, true)?;
+            match **s3 {
+                Stmt::Fail => Ok(()),
+                ref s3 => {
+                    write!(f, "\n{:indent$};   ", "", indent = indent)?;
+                    write_branches(f, s3, indent)
+                }
+            }
+        }
+        s => write_body(f, s, indent + 4, true)
+    }
+}
+
+// Directives in a source file, which instruct the loader rather than define predicates
+#[derive(Clone,PartialEq,Eq,Hash,Debug)]
+pub enum Directive<V> {
+    Dynamic(Vec<PredSig>),
+    Discontiguous(Vec<PredSig>),
+    // a goal to run once the file is loaded
+    Initialization(Stmt<V>),
+    // the goal that runs the program, when it is run rather than consulted
+    Main(Stmt<V>),
+    Op(u32, OpType, Vec<Atom>),
+    // a flag that takes effect right away, e.g. double_quotes for the rest of the file
+    SetFlag(Atom, Expr<V>),
+    EnsureLoaded(String),
+    Include(String),
+    // the module the file defines, with the predicates it exports
+    Module(Atom, Vec<PredSig>),
+    // the module to import, with the predicates to import from it if not all of its exports
+    UseModule(Source, Option<Vec<PredSig>>),
+    // the meta-predicates, with whether each of their arguments is a goal or a closure called in the module of the caller
+    MetaPredicate(Vec<(PredSig, Vec<bool>)>),
+    // a directive the loader does not know, which is reported and ignored
+    Unknown(Expr<V>),
 }
 
-#[derive(PartialEq,Eq,Hash,Debug)]
+// Where a module that a program uses comes from
+#[derive(Clone,PartialEq,Eq,Hash,Debug)]
+pub enum Source {
+    File(String),
+    // a module of the prelude, written library(Name)
+    Library(Atom),
+}
+
+// The top-level items of a source file
+#[derive(Clone,PartialEq,Eq,Hash,Debug)]
+pub enum Item<V> {
+    Clause(PredDef<V>),
+    Directive(Directive<V>, Span),
+}
+
+impl<V> Item<V> {
+    pub fn into_clause(self) -> Option<PredDef<V>> {
+        match self {
+            Item::Clause(c) => Some(c),
+            Item::Directive(_, _) => None
+        }
+    }
+}
+
+#[derive(Clone,PartialEq,Eq,Hash,Debug)]
 pub enum Stmt<V> {
     And(Box<Stmt<V>>, Box<Stmt<V>>),
     Or(Box<Stmt<V>>, Box<Stmt<V>>),
     If(Box<Stmt<V>>, Box<Stmt<V>>, Box<Stmt<V>>),
     Unify(Expr<V>, Expr<V>),
-    Call(Pred, Vec<Expr<V>>),
+    Call(Pred, Vec<Expr<V>>, Span), // the span is the position of the goal
+    Catch(Box<Stmt<V>>, Expr<V>, Box<Stmt<V>>), // catch(Goal, Catcher, Recovery)
+    Not(Box<Stmt<V>>, Span), // negation as failure, the span is the position of the negation
+    Cut, // commit to the clause and the choices made since it was entered
     Fail, // For convenience
     True // For convenience
 }
 
 impl<V> Stmt<V> {
+    // Whether there is a cut anywhere in this statement
+    pub fn has_cut(&self) -> bool {
+        let mut found = false;
+        self.traverse(&mut |s| found |= matches!(s, Stmt::Cut));
+        found
+    }
+
     // Recursively apply given function to the AST structure. This is here to remove some boilerplate when recursing. It is quite similar to the visitor pattern
     pub fn traverse<F: FnMut(&Stmt<V>)>(&self, f: &mut F) {
         use Stmt::*;
@@ -70,6 +220,11 @@ impl<V> Stmt<V> {
                 s2.traverse(f);
                 s3.traverse(f);
             }
+            Catch(s1, _, s2) => {
+                s1.traverse(f);
+                s2.traverse(f);
+            }
+            Not(s, _) => s.traverse(f),
             _ => {}
         }
         
@@ -94,6 +249,11 @@ impl<V> Stmt<V> {
                 s2.traverse_mut(f);
                 s3.traverse_mut(f);
             }
+            Catch(s1, _, s2) => {
+                s1.traverse_mut(f);
+                s2.traverse_mut(f);
+            }
+            Not(s, _) => s.traverse_mut(f),
             _ => {}
         }
         
@@ -114,7 +274,8 @@ impl<V: Clone> Stmt<V> {
                 pvs.append(&mut e1.collect_pvs());
                 pvs.append(&mut e2.collect_pvs());
             }
-            Call(_, es) => pvs.extend(es.iter().flat_map(|e| e.collect_pvs())),
+            Call(_, es, _) => pvs.extend(es.iter().flat_map(|e| e.collect_pvs())),
+            Catch(_, e, _) => pvs.append(&mut e.collect_pvs()),
             _ => {}
         });
 
@@ -131,7 +292,7 @@ impl<V> fmt::Display for Stmt<V> where V: fmt::Display {
             Or(ref s1, ref s2) => write!(f, "({}); ({})", *s1, *s2),
             If(ref s1, ref s2, ref s3) => write!(f, "({}->{});({})", *s1, *s2, *s3),
             Unify(ref e1, ref e2) => write!(f, "{}={}", e1, e2),
-            Call(ref p, ref args) =>
+            Call(ref p, ref args, _) =>
                 match args.len() {
                     0 => write!(f, "{}", p),
                     _ => {
@@ -142,6 +303,9 @@ impl<V> fmt::Display for Stmt<V> where V: fmt::Display {
                         write!(f, ")")
                     }
                 }
+            Catch(ref s1, ref e, ref s2) => write!(f, "catch(({}), {}, ({}))", s1, e, s2),
+            Not(ref s, _) => write!(f, "\\+ ({})", s),
+            Cut => write!(f, "!"),
             True => write!(f, "true"),
             Fail => write!(f, "fail")
         }
@@ -153,6 +317,7 @@ pub enum Expr<V> {
     Atom(Atom),
     PV(V),
     Num(i64),
+    Str(String),
     Ctor(Atom, Vec<Expr<V>>)
 }
 
@@ -179,6 +344,7 @@ impl<V> fmt::Display for Expr<V> where V: fmt::Display {
             Atom(ref a) => write!(f, "{}", a),
             PV(ref x) => write!(f, "{}", x),
             Num(ref n) => write!(f, "{}", n),
+            Str(ref s) => write!(f, "{}", quote(s, '"')),
             Ctor(ref p, ref args) => {
                 match args.len() {
                     0 => write!(f, "{}", p),
@@ -0,0 +1,252 @@
+// Static checks over a lowered program, which find calls that are bound to fail at runtime
+use crate::ast::*;
+use crate::ast_common::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+// What a diagnostic is about
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Finding {
+    // a call to a user predicate that is not defined with any arity
+    UndefinedPredicate(PredSig),
+    // a call to a user predicate that is only defined with the listed arities
+    ArityMismatch(PredSig, Vec<usize>),
+    // a reference to a system predicate that is not a built-in
+    UnknownBuiltin(PredSig),
+    // a negation whose goal shares variables with the goals after it, while they are not bound before it
+    Floundering,
+}
+
+impl Finding {
+    pub fn severity(&self) -> Severity {
+        match self {
+            // the predicate may be defined by a later consult
+            Finding::UndefinedPredicate(_) | Finding::ArityMismatch(_, _) => Severity::Warning,
+            // the variables may be bound by the callers
+            Finding::Floundering => Severity::Warning,
+            Finding::UnknownBuiltin(_) => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Finding::UndefinedPredicate(sig) => write!(f, "call to undefined predicate {}", sig),
+            Finding::ArityMismatch(PredSig(p, n), arities) => {
+                write!(f, "call to {}/{}, but {} is only defined with arity ", p, n, p)?;
+                for (i, arity) in arities.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arity)?;
+                }
+                Ok(())
+            }
+            // the system predicates show their arity already
+            Finding::UnknownBuiltin(PredSig(p, _)) => write!(f, "unknown built-in predicate {}", p),
+            Finding::Floundering => write!(f, "negated goal has variables that are unbound here but used later, it may flounder"),
+        }
+    }
+}
+
+// Where a diagnostic was found: the offending call and the predicate whose body contains it
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Location {
+    pub predicate: PredSig,
+    pub span: Span,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Diagnostic {
+    pub finding: Finding,
+    pub location: Location,
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        self.finding.severity()
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.location.span.is_known() {
+            write!(f, "{}: ", self.location.span)?;
+        }
+        write!(f, "{}: in {}: {}", self.severity(), self.location.predicate, self.finding)
+    }
+}
+
+// Check the calls in a program against its definitions and the known built-ins. The program should be consolidated, so that there is a single definition per predicate.
+pub struct StaticCheck {
+    builtins: HashSet<(String, usize)>
+}
+
+impl StaticCheck {
+    pub fn new<'a, I: IntoIterator<Item=&'a (String, usize)>>(builtins: I) -> Self {
+        StaticCheck { builtins: builtins.into_iter().cloned().collect() }
+    }
+
+    pub fn check<V: Clone + Eq + Hash>(&self, program: &Program<V>) -> Vec<Diagnostic> {
+        self.check_part(program, program)
+    }
+
+    // Check the calls in a part of a program, such as the clauses just loaded, against the definitions of the whole program
+    pub fn check_part<V: Clone + Eq + Hash, W>(&self, part: &Program<V>, program: &Program<W>) -> Vec<Diagnostic> {
+        // the arities each user predicate is defined with, by module and name
+        let mut arities: HashMap<(Option<&str>, &str), Vec<usize>> = HashMap::new();
+        for pred_def in program.iter() {
+            arities.entry((pred_def.name.module(), pred_def.name.name())).or_default().push(pred_def.params.len());
+        }
+        for defined in arities.values_mut() {
+            defined.sort_unstable();
+            defined.dedup();
+        }
+
+        let mut diagnostics = Vec::new();
+        for pred_def in part.iter() {
+            pred_def.body.traverse(&mut |s| {
+                let (finding, span) = match s {
+                    Stmt::Call(p @ (Pred::User(_) | Pred::Qualified(_, _)), args, span) => (match arities.get(&(p.module(), p.name())) {
+                        None => Some(Finding::UndefinedPredicate(PredSig(p.clone(), args.len()))),
+                        Some(defined) if !defined.contains(&args.len()) =>
+                            Some(Finding::ArityMismatch(PredSig(p.clone(), args.len()), defined.clone())),
+                        Some(_) => None
+                    }, span),
+                    Stmt::Call(p @ Pred::Sys(name, arity), args, span) => {
+                        if *arity != args.len() || !self.builtins.contains(&(name.clone(), *arity)) {
+                            (Some(Finding::UnknownBuiltin(PredSig(p.clone(), args.len()))), span)
+                        } else {
+                            (None, span)
+                        }
+                    }
+                    _ => return
+                };
+                if let Some(finding) = finding {
+                    let location = Location { predicate: pred_def.sig(), span: span.clone() };
+                    diagnostics.push(Diagnostic { finding, location });
+                }
+            });
+
+            let params = pred_def.params.iter().flat_map(|e| e.collect_pvs()).collect();
+            let mut negations = Vec::new();
+            floundering(&pred_def.body, &params, &HashSet::new(), &mut negations);
+            for span in negations.into_iter() {
+                let location = Location { predicate: pred_def.sig(), span: span.clone() };
+                diagnostics.push(Diagnostic { finding: Finding::Floundering, location });
+            }
+        }
+        diagnostics
+    }
+}
+
+// Find the negations in given statement whose goal has variables that are not bound before the negation, but are used after it. `bound` are the variables that occur before the statement and `later` are the ones that occur after it.
+fn floundering<'a, V: Clone + Eq + Hash>(s: &'a Stmt<V>, bound: &HashSet<V>, later: &HashSet<V>, found: &mut Vec<&'a Span>) {
+    use Stmt::*;
+
+    let with = |vars: &HashSet<V>, s: &Stmt<V>| -> HashSet<V> { vars.iter().cloned().chain(s.collect_pvs()).collect() };
+    match s {
+        And(s1, s2) => {
+            floundering(s1, bound, &with(later, s2), found);
+            floundering(s2, &with(bound, s1), later, found);
+        }
+        Or(s1, s2) => {
+            floundering(s1, bound, later, found);
+            floundering(s2, bound, later, found);
+        }
+        If(s1, s2, s3) => {
+            floundering(s1, bound, &with(later, s2), found);
+            floundering(s2, &with(bound, s1), later, found);
+            floundering(s3, bound, later, found);
+        }
+        Catch(s1, _, s2) => {
+            floundering(s1, bound, later, found);
+            floundering(s2, bound, later, found);
+        }
+        Not(s1, span) => {
+            if s1.collect_pvs().iter().any(|x| !bound.contains(x) && later.contains(x)) {
+                found.push(span);
+            } else {
+                floundering(s1, bound, later, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtins::BuiltIns;
+    use crate::database::Database;
+    use crate::engine::lower;
+    use crate::parser;
+    use crate::ops::Ops;
+
+    fn check(source: &str) -> Vec<String> {
+        let builtins = BuiltIns::new();
+        let (items, errors) = parser::parse_program(None, source, &mut Ops::default());
+        assert!(errors.is_empty());
+        let program = items.into_iter().filter_map(Item::into_clause).collect();
+        StaticCheck::new(builtins.signatures())
+            .check(&lower(program, &Database::new(builtins.signatures().cloned().collect())))
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_program() {
+        assert!(check("
+            len(nil, 0).
+            len(cons(X, T), N) :- len(T, M), N is M + 1.
+        ").is_empty());
+    }
+
+    #[test]
+    fn test_findings() {
+        assert_eq!(check("
+            p(X) :- q(X), r(X, X), s.
+            q(a) :- sys:nope(a), sys:print(a, b).
+            r(a).
+            r(b, c, d).
+        "), vec![
+            "2:27: warning: in p/1: call to r/2, but r is only defined with arity 1, 3".to_string(),
+            "2:36: warning: in p/1: call to undefined predicate s/0".to_string(),
+            "3:21: error: in q/1: unknown built-in predicate sys:nope/1".to_string(),
+            "3:34: error: in q/1: unknown built-in predicate sys:print/2".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_floundering() {
+        assert_eq!(check("
+            person(ann).
+            married(ann, bob).
+            single(X) :- person(X), \\+ married(X, _).
+            odd(X) :- \\+ married(X, Y), person(Y).
+            lonely :- \\+ married(_, _), \\+ (person(X), \\+ married(X, _)).
+            both(X) :- ( \\+ person(Y) -> married(X, Y) ; true ), person(X).
+        "), vec![
+            "5:23: warning: in odd/1: negated goal has variables that are unbound here but used later, it may flounder".to_string(),
+            "7:26: warning: in both/1: negated goal has variables that are unbound here but used later, it may flounder".to_string(),
+        ]);
+    }
+}
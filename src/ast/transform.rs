@@ -1,6 +1,8 @@
 // The overall structure of the AST transformers
 
-use crate::ast_common::PredSig;
+use crate::ast_common::{Pred, PredSig, Span, USER};
+use crate::database::Database;
+use crate::parser;
 use std::collections::HashMap;
 use std::iter::FromIterator;
 use std::marker::PhantomData;
@@ -25,24 +27,36 @@ impl<V, T> Transformer<V, V> for T where T: InplaceTransformer<V> + Sized {
     }
 }
 
-// Transformer that consolidates all definitions of a predicate
+// A single clause of a predicate: its parameters, its body and the position of its head
+type Clause = (Vec<Expr<String>>, Stmt<String>, Span);
+
+// Transformer that consolidates all definitions of a predicate. The predicates are kept in the order of their first clause.
 pub struct ConsolidateDefs {
-    defs: HashMap<(Pred, usize), Vec<(Vec<Expr<String>>, Stmt<String>)>>,
+    defs: HashMap<(Pred, usize), Vec<Clause>>,
+    order: Vec<(Pred, usize)>,
+}
+
+impl Default for ConsolidateDefs {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ConsolidateDefs {
     pub fn new() -> Self {
         ConsolidateDefs {
             defs: HashMap::new(),
+            order: Vec::new(),
         }
     }
 
     fn add_pred_def(&mut self, pred_def: PredDef<String>) {
         let name_with_arity = (pred_def.name, pred_def.params.len());
-        let clause_def = (pred_def.params, pred_def.body);
+        let clause_def = (pred_def.params, pred_def.body, pred_def.span);
         if let Some(ref mut clause_defs) = self.defs.get_mut(&name_with_arity) {
             clause_defs.push(clause_def);
         } else {
+            self.order.push(name_with_arity.clone());
             self.defs.insert(name_with_arity, vec![clause_def]);
         }
     }
@@ -56,20 +70,25 @@ impl Transformer<String, String> for ConsolidateDefs {
             self.add_pred_def(pred_def);
         }
 
-        Vec::from_iter(self.defs.into_iter().map(|((pred, n_args), bodies)| {
-            let params: Vec<Expr<String>> = Vec::from_iter((1..n_args).map(|i| Expr::PV(format!("_P{}", i))));
+        let mut defs = self.defs;
+        Vec::from_iter(self.order.into_iter().map(|(pred, n_args)| {
+            let bodies = defs.remove(&(pred.clone(), n_args)).unwrap_or_default();
+            let params: Vec<Expr<String>> = Vec::from_iter((0..n_args).map(|i| Expr::PV(format!("_P{}", i))));
+            // the consolidated definition is where the first clause is
+            let span = bodies.first().map(|(_, _, span)| span.clone()).unwrap_or_default();
 
-            let new_body = bodies.into_iter().fold(Fail, |acc, (clause_params, body)| {
+            let new_body = bodies.into_iter().fold(Fail, |acc, (clause_params, body, _)| {
                 // create a statement that will assign each clause parameter to the corresponded generated predicate parameter
-                let param_assignment = params.iter().zip(clause_params.into_iter()).fold(True, |acc, (p, cp)| { And(Box::new(acc), Box::new(Unify(p.clone(), cp))) });
+                let param_assignment = params.iter().zip(clause_params).fold(True, |acc, (p, cp)| { And(Box::new(acc), Box::new(Unify(p.clone(), cp))) });
 
                 Or(Box::new(acc), Box::new(And(Box::new(param_assignment), Box::new(body))))
             });
 
             PredDef {
                 name: pred,
-                params: params,
-                body: new_body
+                params,
+                body: new_body,
+                span
             }
         }))
     }
@@ -80,6 +99,12 @@ pub struct UnderscoreElim {
     underscore_counter: usize
 }
 
+impl Default for UnderscoreElim {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl UnderscoreElim {
     pub fn new() -> Self { UnderscoreElim { underscore_counter: 0 } }
 
@@ -116,12 +141,18 @@ impl UnderscoreElim {
                 self.transform_expr(e1);
                 self.transform_expr(e2);
             }
-            Call(_, args) => {
+            Call(_, args, _) => {
                 for arg in args.iter_mut() {
                     self.transform_expr(arg);
                 }
             }
-            Fail | True => {}
+            Catch(ref mut s1, ref mut e, ref mut s2) => {
+                self.transform_stmt(s1);
+                self.transform_expr(e);
+                self.transform_stmt(s2);
+            }
+            Not(ref mut s, _) => self.transform_stmt(s),
+            Fail | True | Cut => {}
         }
     }
 
@@ -137,7 +168,7 @@ impl UnderscoreElim {
                     self.transform_expr(arg);
                 }
             }
-            PV(_) | Atom(_) | Num(_) => {}
+            PV(_) | Atom(_) | Num(_) | Str(_) => {}
         }
     }
 }
@@ -155,6 +186,12 @@ pub struct IdempotentElim<V> {
     phantom_data: PhantomData<V>
 }
 
+impl<V: PartialEq> Default for IdempotentElim<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // we need the PartialEq on V to derive it for statement equality
 impl<V: PartialEq> IdempotentElim<V> {
     pub fn new() -> Self {
@@ -177,6 +214,8 @@ impl<V: PartialEq> IdempotentElim<V> {
                 Or(ref mut s1, ref mut s2) if **s2 == Fail => { mem::swap(s, s1.as_mut()); }
                 If(ref mut s1, _, ref mut s3) if **s1 == Fail => { mem::swap(s, s3.as_mut()); }
                 If(ref mut s1, ref mut s2, _) if **s1 == True => { mem::swap(s, s2.as_mut()); }
+                Not(ref s1, _) if **s1 == True => *s = Fail,
+                Not(ref s1, _) if **s1 == Fail => *s = True,
                 _ => mem::swap(s, &mut temp) // put the value back
             };
         });
@@ -196,6 +235,12 @@ pub struct EnumerateVariables {
     enums: HashMap<PredSig, HashMap<String, usize>>,
 }
 
+impl Default for EnumerateVariables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EnumerateVariables {
     pub fn new() -> Self {
         EnumerateVariables { enums: HashMap::new() }
@@ -208,7 +253,8 @@ impl EnumerateVariables {
         PredDef {
             name: pred.name,
             params: pred.params.into_iter().map(|e| self.transform_expr(&sig, e)).collect(),
-            body: self.transform_stmt(&sig, pred.body)
+            body: self.transform_stmt(&sig, pred.body),
+            span: pred.span
         }
     }
 
@@ -228,7 +274,8 @@ impl EnumerateVariables {
                 Ctor(p, args.into_iter().map(|e| self.transform_expr(sig, e)).collect())
             }
             Atom(a) => Atom(a),
-            Num(n) => Num(n)
+            Num(n) => Num(n),
+            Str(s) => Str(s)
         }
     }
 
@@ -242,16 +289,298 @@ impl EnumerateVariables {
                 Box::new(self.transform_stmt(sig, *s1)),
                 Box::new(self.transform_stmt(sig, *s2)),
                 Box::new(self.transform_stmt(sig, *s3))),
-            Call(p, args) => Call(p, args.into_iter().map(|e| self.transform_expr(sig, e)).collect()),
+            Call(p, args, span) => Call(p, args.into_iter().map(|e| self.transform_expr(sig, e)).collect(), span),
+            Catch(s1, e, s2) => Catch(
+                Box::new(self.transform_stmt(sig, *s1)),
+                self.transform_expr(sig, e),
+                Box::new(self.transform_stmt(sig, *s2))),
+            Not(s, span) => Not(Box::new(self.transform_stmt(sig, *s)), span),
             Unify(e1, e2) => Unify(self.transform_expr(sig, e1), self.transform_expr(sig, e2)),
             Fail => Fail,
-            True => True
+            True => True,
+            Cut => Cut
         }
     }
 }
 
+impl EnumerateVariables {
+    // Transform given program while keeping the enumeration tables around
+    pub fn enumerate(&mut self, input: Program<String>) -> Program<usize> {
+        input.into_iter().map(|p| self.transform_pred(p)).collect()
+    }
+
+    // The numbers assigned to the variables of given predicate
+    pub fn variables(&self, sig: &PredSig) -> Option<&HashMap<String, usize>> {
+        self.enums.get(sig)
+    }
+}
+
 impl Transformer<String, usize> for EnumerateVariables {
     fn transform(mut self, input: Program<String>) -> Program<usize> {
-        input.into_iter().map(|p| self.transform_pred(p)).collect()
+        self.enumerate(input)
+    }
+}
+
+// Resolve the calls of a program to the predicates they refer to in the module of their clause, or in the module they are qualified with. Calls to built-in predicates written as user predicates, e.g. `X is 1 + 2`, become system predicate calls. The clauses of predicates that are not qualified yet belong to user.
+pub struct ResolveModules<'a> {
+    database: &'a Database
+}
+
+impl<'a> ResolveModules<'a> {
+    pub fn new(database: &'a Database) -> Self {
+        ResolveModules { database }
+    }
+
+    fn transform_stmt<V>(&self, module: &str, stmt: &mut Stmt<V>) {
+        stmt.traverse_mut(&mut |s| {
+            if let Stmt::Call(p, args, _) = s {
+                let context = match &*p {
+                    Pred::User(_) => module,
+                    Pred::Qualified(m, _) => m,
+                    Pred::Sys(_, _) => return
+                };
+                let callee = self.database.resolve(context, p.name(), args.len());
+                // the goals given to a meta-predicate of another module are called in the module of the caller
+                if let Some(meta) = callee.module().filter(|m| *m != context).and_then(|_| self.database.modules().meta(&PredSig(callee.clone(), args.len()))) {
+                    for (arg, _) in args.iter_mut().zip(meta.iter()).filter(|(_, is_meta)| **is_meta) {
+                        if !matches!(arg, Expr::Ctor(f, qualified) if f == ":" && qualified.len() == 2) {
+                            let goal = mem::replace(arg, Expr::Atom(String::new()));
+                            *arg = Expr::Ctor(":".to_string(), vec![Expr::Atom(context.to_string()), goal]);
+                        }
+                    }
+                }
+                *p = callee;
+            }
+        });
+    }
+}
+
+impl<'a, V> InplaceTransformer<V> for ResolveModules<'a> {
+    fn transform_inplace(self, input: &mut Program<V>) {
+        for pred_def in input.iter_mut() {
+            pred_def.name = mem::replace(&mut pred_def.name, Pred::User(String::new())).in_module(USER);
+            let module = pred_def.name.module().unwrap_or(USER).to_string();
+            self.transform_stmt(&module, &mut pred_def.body);
+        }
+    }
+}
+
+// Transformer that translates grammar rules, `Head --> Body`, to the clauses they stand for. A non-terminal is called with two more arguments than it is written with: the list to parse, and the rest of the list after the part it covers.
+pub struct DcgTranslation {
+    var_counter: usize,
+    // the position of the rule being translated, which its goals are reported at
+    span: Span,
+}
+
+impl Default for DcgTranslation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DcgTranslation {
+    pub fn new() -> Self {
+        DcgTranslation { var_counter: 0, span: Span::default() }
     }
-}
\ No newline at end of file
+
+    // The variables of the lists are named $S<n>, which source text cannot name, so that they do not capture the variables of the rule
+    fn gen_var(&mut self) -> Expr<String> {
+        self.var_counter += 1;
+        Expr::PV(format!("$S{}", self.var_counter))
+    }
+
+    fn call(&self, name: &str, args: Vec<Expr<String>>) -> Stmt<String> {
+        Stmt::Call(Pred::User(name.to_string()), args, self.span.clone())
+    }
+
+    // A goal that raises a type error for a part of a body that cannot be called
+    fn not_callable(&mut self, culprit: Expr<String>) -> Stmt<String> {
+        let formal = Expr::Ctor("type_error".to_string(), vec![Expr::Atom("callable".to_string()), culprit]);
+        let ball = Expr::Ctor("error".to_string(), vec![formal, self.gen_var()]);
+        self.call("throw", vec![ball])
+    }
+
+    fn transform_rule(&mut self, rule: PredDef<String>) -> PredDef<String> {
+        self.span = rule.span.clone();
+        let mut params = rule.params;
+        let body = params.pop().unwrap();
+        let head = params.pop().unwrap();
+        let (s0, s) = (self.gen_var(), self.gen_var());
+        let (head, body) = match head {
+            // the pushback list is put in front of the rest of the list once the body is parsed
+            Expr::Ctor(f, mut args) if f == "," && args.len() == 2 => {
+                let pushback = args.pop().unwrap();
+                let mid = self.gen_var();
+                let body = self.body(body, s0.clone(), mid.clone());
+                let pushback = self.terminals(pushback, s.clone(), mid);
+                (args.pop().unwrap(), Stmt::And(Box::new(body), Box::new(pushback)))
+            }
+            head => {
+                let body = self.body(body, s0.clone(), s.clone());
+                (head, body)
+            }
+        };
+        let (name, mut params) = match head {
+            Expr::Atom(a) => (a, Vec::new()),
+            Expr::Ctor(f, args) => (f, args),
+            head => unreachable!("the parser only accepts callable heads in grammar rules, not {}", head)
+        };
+        params.push(s0);
+        params.push(s);
+        PredDef { name: Pred::User(name), params, body, span: rule.span }
+    }
+
+    // The goal that parses given terminals from the list s0, leaving the list s. Terminals are a list or a string, which stands for the list of its codes.
+    fn terminals(&mut self, terminals: Expr<String>, s0: Expr<String>, s: Expr<String>) -> Stmt<String> {
+        fn append(list: Expr<String>, tail: Expr<String>) -> Option<Expr<String>> {
+            match list {
+                Expr::Atom(ref a) if a == "[]" => Some(tail),
+                Expr::Ctor(f, mut args) if f == "." && args.len() == 2 => {
+                    let rest = append(args.pop().unwrap(), tail)?;
+                    Some(Expr::Ctor(f, vec![args.pop().unwrap(), rest]))
+                }
+                Expr::Str(text) => Some(text.chars().rev().fold(tail, |rest, c| Expr::Ctor(".".to_string(), vec![Expr::Num(c as i64), rest]))),
+                _ => None
+            }
+        }
+        match append(terminals.clone(), s) {
+            Some(list) => Stmt::Unify(s0, list),
+            None => self.not_callable(terminals)
+        }
+    }
+
+    // Translate a grammar body to the goal that parses it from the list s0, leaving the list s
+    pub fn body(&mut self, body: Expr<String>, s0: Expr<String>, s: Expr<String>) -> Stmt<String> {
+        use Stmt::*;
+
+        let (name, mut args) = match body {
+            // a variable body is parsed with the body it is bound to at runtime
+            Expr::PV(x) => return self.call("phrase", vec![Expr::PV(x), s0, s]),
+            Expr::Num(_) => return self.not_callable(body),
+            Expr::Str(_) => return self.terminals(body, s0, s),
+            Expr::Atom(a) => (a, Vec::new()),
+            Expr::Ctor(f, args) => (f, args),
+        };
+        match (name.as_str(), args.len()) {
+            ("[]", 0) => Unify(s0, s),
+            (".", 2) => self.terminals(Expr::Ctor(name, args), s0, s),
+            ("!", 0) => And(Box::new(Cut), Box::new(Unify(s0, s))),
+            ("{}", 1) => {
+                let goal = args.pop().unwrap();
+                let goal = match parser::goal(parser::expr_term(goal.clone(), &self.span)) {
+                    Ok(goal) => goal,
+                    Err(_) => self.not_callable(goal)
+                };
+                And(Box::new(goal), Box::new(Unify(s0, s)))
+            }
+            (",", 2) => {
+                let b2 = args.pop().unwrap();
+                let b1 = args.pop().unwrap();
+                let mid = self.gen_var();
+                And(Box::new(self.body(b1, s0, mid.clone())), Box::new(self.body(b2, mid, s)))
+            }
+            (";", 2) => {
+                let b2 = args.pop().unwrap();
+                match args.pop().unwrap() {
+                    Expr::Ctor(f, mut ct) if f == "->" && ct.len() == 2 => {
+                        let t = ct.pop().unwrap();
+                        let mid = self.gen_var();
+                        let c = self.body(ct.pop().unwrap(), s0.clone(), mid.clone());
+                        If(Box::new(c), Box::new(self.body(t, mid, s.clone())), Box::new(self.body(b2, s0, s)))
+                    }
+                    b1 => Or(Box::new(self.body(b1, s0.clone(), s.clone())), Box::new(self.body(b2, s0, s)))
+                }
+            }
+            ("->", 2) => {
+                let t = args.pop().unwrap();
+                let mid = self.gen_var();
+                let c = self.body(args.pop().unwrap(), s0, mid.clone());
+                If(Box::new(c), Box::new(self.body(t, mid, s)), Box::new(Fail))
+            }
+            // a negation parses nothing
+            ("\\+", 1) => {
+                let rest = self.gen_var();
+                let negated = self.body(args.pop().unwrap(), s0.clone(), rest);
+                And(Box::new(Not(Box::new(negated), self.span.clone())), Box::new(Unify(s0, s)))
+            }
+            // the non-terminals of a body qualified with a module are called in the module
+            (":", 2) if matches!(args[0], Expr::Atom(_)) => {
+                let b = args.pop().unwrap();
+                let module = args.pop().unwrap().to_string();
+                parser::qualify(&module, self.body(b, s0, s))
+            }
+            // the other bodies are non-terminals, including call//N which calls a closure with the lists as its last two arguments
+            _ => {
+                args.push(s0);
+                args.push(s);
+                self.call(&name, args)
+            }
+        }
+    }
+}
+
+impl InplaceTransformer<String> for DcgTranslation {
+    fn transform_inplace(mut self, input: &mut Program<String>) {
+        for pred_def in input.iter_mut() {
+            if pred_def.is_grammar_rule() {
+                let rule = mem::replace(pred_def, PredDef::new(GRAMMAR_RULE, Vec::new(), Stmt::True));
+                *pred_def = self.transform_rule(rule);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::Ops;
+
+    fn clauses(source: &str) -> Program<String> {
+        let (items, errors) = parser::parse_program(None, source, &mut Ops::default());
+        assert!(errors.is_empty(), "unexpected errors {:?}", errors);
+        items.into_iter().filter_map(Item::into_clause).collect()
+    }
+
+    // The clauses of given source, with the variables _S<n> renamed to the $S<n> the translation generates
+    fn translated(source: &str) -> Program<String> {
+        fn rename(e: &mut Expr<String>) {
+            match e {
+                Expr::PV(x) if x.starts_with("_S") => *x = format!("${}", &x[1..]),
+                Expr::Ctor(_, args) => args.iter_mut().for_each(rename),
+                _ => {}
+            }
+        }
+        let mut defs = clauses(source);
+        for def in defs.iter_mut() {
+            def.params.iter_mut().for_each(rename);
+            def.body.traverse_mut(&mut |s| match s {
+                Stmt::Call(_, args, _) => args.iter_mut().for_each(rename),
+                Stmt::Unify(e1, e2) => {
+                    rename(e1);
+                    rename(e2);
+                }
+                Stmt::Catch(_, e, _) => rename(e),
+                _ => {}
+            });
+        }
+        defs
+    }
+
+    #[test]
+    fn test_dcg_translation() {
+        let cases = [
+            ("a --> [x], !, {b}.", "a(_S1, _S2) :- _S1 = [x|_S3], ((!, _S3 = _S4), (b, _S4 = _S2))."),
+            ("e --> [].", "e(_S1, _S2) :- _S1 = _S2."),
+            // the pushback is put back in front of the rest of the list
+            ("p, [a] --> [b].", "p(_S1, _S2) :- _S1 = [b|_S3], _S2 = [a|_S3]."),
+            ("q(G) --> ( G -> call(G, x) ; \\+ [c] ).", "q(G, _S1, _S2) :- ( phrase(G, _S1, _S3) -> call(G, x, _S3, _S2) ; \\+ _S1 = [c|_S4], _S1 = _S2 )."),
+            ("r --> m:s, [d].", "r(_S1, _S2) :- m:s(_S1, _S3), _S3 = [d|_S2]."),
+            ("t --> 1.", "t(_S1, _S2) :- throw(error(type_error(callable, 1), _S3))."),
+            // the other clauses are left as they are
+            ("u(X) :- v(X).", "u(X) :- v(X)."),
+        ];
+        for (rule, clause) in cases.iter() {
+            assert_eq!(DcgTranslation::new().transform(clauses(rule)), translated(clause), "{}", rule);
+        }
+    }
+}
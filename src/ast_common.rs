@@ -1,18 +1,52 @@
 // Data structures that are common between different program representations.
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
 
-// Predicate names are tagged with whether they are system predicates or not.
+// The module of the programs that do not declare one, which every module sees
+pub const USER: &str = "user";
+
+// The module of the library predicates written in Prolog, which every module sees after user
+pub const SYSTEM: &str = "system";
+
+// Predicate names are tagged with whether they are system predicates or not. The user predicates are qualified with the module they belong to once the calls are resolved.
 #[derive(Hash,PartialOrd,Ord,PartialEq,Eq,Debug,Clone)]
 pub enum Pred {
     Sys(String, usize),
-    User(String)
+    User(String),
+    Qualified(Atom, String)
+}
+
+impl Pred {
+    pub fn name(&self) -> &str {
+        match self {
+            Pred::Sys(ref p, _) | Pred::User(ref p) | Pred::Qualified(_, ref p) => p
+        }
+    }
+
+    // The module of a qualified predicate
+    pub fn module(&self) -> Option<&str> {
+        match self {
+            Pred::Qualified(ref m, _) => Some(m),
+            _ => None
+        }
+    }
+
+    // This predicate qualified with given module if it is not qualified yet
+    pub fn in_module(self, module: &str) -> Pred {
+        match self {
+            Pred::User(p) => Pred::Qualified(module.to_string(), p),
+            p => p
+        }
+    }
 }
 
 impl fmt::Display for Pred {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Pred::Sys(ref p, ref arity) => write!(f, "sys:{}/{}", p, arity),
-            Pred::User(ref p) => write!(f, "{}", p)
+            Pred::Qualified(ref m, ref p) if m != USER => write!(f, "{}:{}", m, p),
+            Pred::User(ref p) | Pred::Qualified(_, ref p) => write!(f, "{}", p)
         }
     }
 }
@@ -29,3 +63,46 @@ impl fmt::Display for PredSig {
 
 // TODO: Implement string interning for atoms
 pub type Atom = String;
+
+// A position in the source code. Spans that were not recorded by the parser have line 0.
+#[derive(Clone,Debug,Default)]
+pub struct Span {
+    pub file: Option<Rc<str>>,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    pub fn new(file: Option<Rc<str>>, line: usize, col: usize) -> Self {
+        Span { file, line, col }
+    }
+
+    pub fn is_known(&self) -> bool {
+        self.line > 0
+    }
+}
+
+// Spans do not take part in comparing or hashing the nodes they are attached to, so that the same program at different positions is still the same program
+impl PartialEq for Span {
+    fn eq(&self, _: &Span) -> bool {
+        true
+    }
+}
+
+impl Eq for Span {}
+
+impl Hash for Span {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.is_known() {
+            return write!(f, "<unknown>");
+        }
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file)?;
+        }
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
@@ -1,33 +1,1324 @@
 // Implementations of built-in functions exposed as system predicates.
 
+use crate::ast_common::{Atom, Pred, PredSig};
+use crate::database::{clause_def, clause_terms, grammar_goal, head_predicate, read_term, split_clause, strip_module, ReadTerm};
 use crate::domains::*;
+use crate::exceptions::*;
 use crate::ir::*;
-use std::collections::HashMap;
+use crate::flags::{DoubleQuotes, Flags};
+use crate::format;
+use crate::ops::Ops;
+use crate::parser;
+use crate::streams::{self, Mode};
+use crate::unification::Unification;
+use crate::writer::Writer;
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
 use std::ops::Index;
 
+// The outcome of a built-in: whether it succeeded, or the exception it raised
+pub type BuiltInResult = Result<bool, Exception>;
+
 // An alias for a boxed function pointer to a built-in function implementation. With this definition, built-in functions are semi-deterministic.
 // TODO: allow for nondeterministic built-ins in an efficient way
-pub type BuiltInFn = Box<dyn Fn(Vec<Value>, &mut State) -> bool>;
+pub type BuiltInFn = Box<dyn Fn(Vec<Value>, &mut State) -> BuiltInResult>;
+
+// A nondeterministic built-in, which computes the bindings of all of its solutions at once. They are tried in order on backtracking.
+pub type NondetFn = Box<dyn Fn(Vec<Value>, &mut State) -> Result<VecDeque<Unification>, Exception>>;
+
+// An arithmetic comparison
+type Comparison = fn(&i64, &i64) -> bool;
+
+// A test of the standard order of two terms
+type OrderCheck = fn(Ordering) -> bool;
+
+// A test of the type of a term
+type TypeCheck = fn(&Unification, &Value) -> bool;
+
+// The representation of a character as a list element, and the character a list element stands for
+type CharOf = (fn(char) -> Value, fn(&Value) -> Result<char, Exception>);
 
 // A struct containing mappings to all built-in functions to make calling them easy.
 pub struct BuiltIns {
-    impls: HashMap<(String, usize), BuiltInFn>
+    impls: HashMap<(String, usize), BuiltInFn>,
+    nondet: HashMap<(String, usize), NondetFn>,
+    // the built-ins that the VM implements with their own instructions
+    control: Vec<(String, usize)>,
+}
+
+impl Default for BuiltIns {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Evaluate an arithmetic expression
+pub fn eval(bindings: &Unification, e: &Value) -> Result<i64, Exception> {
+    match bindings.find(e) {
+        Value::Num(n) => Ok(*n),
+        Value::LV(_) => Err(instantiation_error()),
+        Value::Atom(a) => Err(type_error("evaluable", predicate_indicator(a, 0))),
+        culprit @ Value::Str(_) => Err(type_error("evaluable", culprit.clone())),
+        Value::Ctor(f, args) => {
+            let overflow = || evaluation_error("int_overflow");
+            match (f.as_str(), args.as_slice()) {
+                ("-", [x]) => eval(bindings, x)?.checked_neg().ok_or_else(overflow),
+                ("+", [x]) => eval(bindings, x),
+                ("abs", [x]) => eval(bindings, x)?.checked_abs().ok_or_else(overflow),
+                ("sign", [x]) => Ok(eval(bindings, x)?.signum()),
+                ("\\", [x]) => Ok(!eval(bindings, x)?),
+                (op, [x, y]) => {
+                    let (x, y) = (eval(bindings, x)?, eval(bindings, y)?);
+                    let nonzero = |y: i64| if y == 0 { Err(evaluation_error("zero_divisor")) } else { Ok(y) };
+                    match op {
+                        "+" => x.checked_add(y).ok_or_else(overflow),
+                        "-" => x.checked_sub(y).ok_or_else(overflow),
+                        "*" => x.checked_mul(y).ok_or_else(overflow),
+                        "//" => x.checked_div(nonzero(y)?).ok_or_else(overflow),
+                        "rem" => x.checked_rem(nonzero(y)?).ok_or_else(overflow),
+                        "mod" => x.checked_rem_euclid(nonzero(y)?).map(|r| if y < 0 && r != 0 { r + y } else { r }).ok_or_else(overflow),
+                        "min" => Ok(x.min(y)),
+                        "max" => Ok(x.max(y)),
+                        "^" if y < 0 => Err(type_error("float", Value::Num(x))),
+                        "^" => u32::try_from(y).ok().and_then(|y| x.checked_pow(y)).ok_or_else(overflow),
+                        ">>" => Ok(x >> y.clamp(0, 63)),
+                        "<<" => Ok(x << y.clamp(0, 63)),
+                        "/\\" => Ok(x & y),
+                        "\\/" => Ok(x | y),
+                        "xor" => Ok(x ^ y),
+                        _ => Err(type_error("evaluable", predicate_indicator(op, 2)))
+                    }
+                }
+                (f, args) => Err(type_error("evaluable", predicate_indicator(f, args.len())))
+            }
+        }
+    }
+}
+
+// The module a goal, a head or a clause is qualified with, or else the module of the caller, and the term without the qualification
+fn qualified(state: &State, term: &Value) -> Result<(Atom, Value), Exception> {
+    strip_module(&state.bindings, term, state.local_state.module())
+}
+
+// The predicate given predicate indicator refers to, in given module unless the indicator is qualified with another one
+fn indicated_predicate(bindings: &Unification, pi: &Value, module: &str) -> Result<PredSig, Exception> {
+    let (module, pi) = strip_module(bindings, pi, module)?;
+    match bindings.resolve(&pi) {
+        Value::LV(_) => Err(instantiation_error()),
+        Value::Ctor(f, args) if f == "/" && args.len() == 2 => {
+            // Module:Name/Arity reads as (Module:Name)/Arity
+            let name = match strip_module(bindings, &args[0], &module)? {
+                (_, Value::LV(_)) => return Err(instantiation_error()),
+                (module, Value::Atom(name)) => Pred::Qualified(module, name),
+                (_, culprit) => return Err(type_error("atom", culprit))
+            };
+            match &args[1] {
+                Value::LV(_) => Err(instantiation_error()),
+                Value::Num(n) if *n < 0 => Err(domain_error("not_less_than_zero", args[1].clone())),
+                Value::Num(n) => Ok(PredSig(name, *n as usize)),
+                culprit => Err(type_error("integer", culprit.clone()))
+            }
+        }
+        culprit => Err(type_error("predicate_indicator", culprit))
+    }
+}
+
+fn unify(state: &mut State, x: &Value, y: &Value) -> bool {
+    match state.bindings.union(x, y) {
+        Some(bindings) => {
+            state.bindings = bindings;
+            true
+        }
+        None => false
+    }
+}
+
+// Whether two values are the same up to the names of their variables. The values should be resolved first.
+fn variant(x: &Value, y: &Value) -> bool {
+    fn walk(x: &Value, y: &Value, renaming: &mut Vec<(LV, LV)>) -> bool {
+        match (x, y) {
+            (Value::LV(a), Value::LV(b)) => match renaming.iter().find(|(c, d)| c == a || d == b) {
+                Some((c, d)) => c == a && d == b,
+                None => {
+                    renaming.push((*a, *b));
+                    true
+                }
+            },
+            (Value::Ctor(f, xs), Value::Ctor(g, ys)) =>
+                f == g && xs.len() == ys.len() && xs.iter().zip(ys.iter()).all(|(x, y)| walk(x, y, renaming)),
+            (x, y) => x == y
+        }
+    }
+    walk(x, y, &mut Vec::new())
+}
+
+// The elements of given list
+fn list(bindings: &Unification, value: &Value) -> Result<Vec<Value>, Exception> {
+    let value = bindings.resolve(value);
+    match value.list_items() {
+        Some(items) => Ok(items.into_iter().cloned().collect()),
+        None if is_partial_list(&value) => Err(instantiation_error()),
+        None => Err(type_error("list", value.clone()))
+    }
+}
+
+// The text of an atom, a number or a string, None if the value is a variable
+fn text(bindings: &Unification, value: &Value) -> Result<Option<String>, Exception> {
+    match bindings.find(value) {
+        Value::LV(_) => Ok(None),
+        Value::Atom(a) => Ok(Some(a.clone())),
+        Value::Num(n) => Ok(Some(n.to_string())),
+        Value::Str(s) => Ok(Some(s.clone())),
+        culprit => Err(type_error("atomic", culprit.clone()))
+    }
+}
+
+// The kinds of text: atoms, and strings which are not interned
+#[derive(Clone,Copy)]
+enum Text {
+    Atom,
+    Str,
+}
+
+impl Text {
+    fn name(self) -> &'static str {
+        match self {
+            Text::Atom => "atom",
+            Text::Str => "string",
+        }
+    }
+
+    fn value(self, text: String) -> Value {
+        match self {
+            Text::Atom => Value::Atom(text),
+            Text::Str => Value::Str(text),
+        }
+    }
+
+    // The text of an argument, None if it is a variable. The string built-ins also take lists of codes or characters.
+    fn of(self, bindings: &Unification, value: &Value) -> Result<Option<String>, Exception> {
+        match (self, bindings.find(value)) {
+            (Text::Str, Value::Ctor(_, _)) => {
+                let items = list(bindings, value)?;
+                if items.iter().all(|item| matches!(item, Value::Num(_))) {
+                    items.iter().map(code_of).collect::<Result<String, _>>().map(Some)
+                } else {
+                    items.iter().map(char_of).collect::<Result<String, _>>().map(Some)
+                }
+            }
+            _ => text(bindings, value)
+        }
+    }
+}
+
+fn concat(kind: Text, args: &[Value], state: &mut State) -> Result<VecDeque<Unification>, Exception> {
+    let mut solutions = VecDeque::new();
+    if let (Some(t1), Some(t2)) = (kind.of(&state.bindings, &args[0])?, kind.of(&state.bindings, &args[1])?) {
+        solutions.extend(state.bindings.union(&args[2], &kind.value(t1 + &t2)));
+        return Ok(solutions);
+    }
+    let whole = kind.of(&state.bindings, &args[2])?.ok_or_else(instantiation_error)?;
+    let splits = whole.char_indices().map(|(i, _)| i).chain(std::iter::once(whole.len()));
+    for i in splits {
+        let (t1, t2) = whole.split_at(i);
+        let bindings = state.bindings.union(&args[0], &kind.value(t1.to_string()))
+            .and_then(|bindings| bindings.union(&args[1], &kind.value(t2.to_string())));
+        solutions.extend(bindings);
+    }
+    Ok(solutions)
+}
+
+fn sub_text(kind: Text, args: &[Value], state: &mut State) -> Result<VecDeque<Unification>, Exception> {
+    let chars: Vec<char> = kind.of(&state.bindings, &args[0])?.ok_or_else(instantiation_error)?.chars().collect();
+    let before = integer(&state.bindings, &args[1])?;
+    let length = integer(&state.bindings, &args[2])?;
+    let after = integer(&state.bindings, &args[3])?;
+    let sub: Option<Vec<char>> = match (kind, state.bindings.find(&args[4])) {
+        (_, Value::LV(_)) => None,
+        (Text::Atom, Value::Atom(a)) => Some(a.chars().collect()),
+        (Text::Atom, culprit) => return Err(type_error("atom", culprit.clone())),
+        (Text::Str, _) => kind.of(&state.bindings, &args[4])?.map(|s| s.chars().collect())
+    };
+    let n = chars.len();
+    let mut solutions = VecDeque::new();
+    for b in 0..=n {
+        for l in 0..=n - b {
+            let a = n - b - l;
+            let matches = |given: Option<i64>, k: usize| given.is_none_or(|given| given == k as i64);
+            if !matches(before, b) || !matches(length, l) || !matches(after, a) || sub.as_ref().is_some_and(|s| s[..] != chars[b..b + l]) {
+                continue;
+            }
+            let bindings = state.bindings.union(&args[1], &Value::Num(b as i64))
+                .and_then(|bindings| bindings.union(&args[2], &Value::Num(l as i64)))
+                .and_then(|bindings| bindings.union(&args[3], &Value::Num(a as i64)))
+                .and_then(|bindings| bindings.union(&args[4], &kind.value(chars[b..b + l].iter().collect())));
+            solutions.extend(bindings);
+        }
+    }
+    Ok(solutions)
+}
+
+// An integer argument that may be unbound, such as a length or a position
+fn integer(bindings: &Unification, value: &Value) -> Result<Option<i64>, Exception> {
+    match bindings.find(value) {
+        Value::LV(_) => Ok(None),
+        Value::Num(n) => Ok(Some(*n)),
+        culprit => Err(type_error("integer", culprit.clone()))
+    }
+}
+
+fn code(c: char) -> Value {
+    Value::Num(c as i64)
+}
+
+fn code_of(value: &Value) -> Result<char, Exception> {
+    match value {
+        Value::LV(_) => Err(instantiation_error()),
+        Value::Num(n) => u32::try_from(*n).ok().and_then(std::char::from_u32).ok_or_else(|| representation_error("character_code")),
+        culprit => Err(type_error("integer", culprit.clone()))
+    }
+}
+
+fn char_atom(c: char) -> Value {
+    Value::Atom(c.to_string())
+}
+
+fn char_of(value: &Value) -> Result<char, Exception> {
+    match value {
+        Value::LV(_) => Err(instantiation_error()),
+        Value::Atom(a) if a.chars().count() == 1 => Ok(a.chars().next().unwrap()),
+        culprit => Err(type_error("character", culprit.clone()))
+    }
+}
+
+// The text of a list of characters or codes
+fn list_text(bindings: &Unification, value: &Value, of: fn(&Value) -> Result<char, Exception>) -> Result<String, Exception> {
+    list(bindings, value)?.iter().map(of).collect()
+}
+
+// The number given text reads as, if any
+fn parse_number(ops: &Ops, text: &str) -> Option<i64> {
+    match parser::parse_term(text, ops, DoubleQuotes::Codes) {
+        Ok(parser::Term { kind: parser::TermKind::Num(n), .. }) => Some(n),
+        _ => None
+    }
+}
+
+// Whether a resolved value is a list that ends with a variable
+fn is_partial_list(value: &Value) -> bool {
+    match value {
+        Value::LV(_) => true,
+        Value::Ctor(f, args) if f == "." && args.len() == 2 => is_partial_list(&args[1]),
+        _ => false
+    }
+}
+
+fn ctor(f: &str, args: Vec<Value>) -> Value {
+    Value::Ctor(f.to_string(), args)
+}
+
+// Build the goal of bagof/3 or setof/3, which collects the solutions of the inner goal with the values of its free variables, then groups them by those values
+fn bag_goal(kind: &str, args: &[Value], state: &mut State) -> BuiltInResult {
+    let template = state.bindings.resolve(&args[0]);
+    let mut goal = state.bindings.resolve(&args[1]);
+    // the variables of the template and the ones before ^ are not free
+    let mut bound = template.variables();
+    while let Value::Ctor(f, mut ctor_args) = goal {
+        if f == "^" && ctor_args.len() == 2 {
+            goal = ctor_args.pop().unwrap();
+            bound.extend(ctor_args[0].variables());
+        } else {
+            goal = Value::Ctor(f, ctor_args);
+            break;
+        }
+    }
+    let witness = Value::list(goal.variables().into_iter().filter(|x| !bound.contains(x)).map(Value::LV).collect::<Vec<_>>());
+    let pairs = state.fresh_lv();
+    let findall = ctor("findall", vec![ctor("-", vec![witness.clone(), template]), goal, pairs.clone()]);
+    let bags = ctor("$bags", vec![Value::Atom(kind.to_string()), witness, pairs, args[2].clone()]);
+    Ok(unify(state, &args[3], &ctor(",", vec![findall, bags])))
+}
+
+// Write given text to the stream given by a term, or to the current output
+fn put(state: &mut State, stream: Option<&Value>, text: &str) -> BuiltInResult {
+    let mut streams = state.streams.borrow_mut();
+    match stream {
+        Some(s) => streams.output(&state.bindings, s)?.put(text)?,
+        None => streams.current_output().put(text)?,
+    }
+    Ok(true)
+}
+
+// Read the next character of the stream given by a term, or of the current input, and unify it with the last argument.
+// The character is end_of_file at the end of the stream.
+fn get(args: &[Value], state: &mut State, peek: bool) -> BuiltInResult {
+    let (stream, c) = match args {
+        [c] => (None, c),
+        [s, c] => (Some(s), c),
+        _ => unreachable!("get_char and peek_char have one or two arguments")
+    };
+    match state.bindings.find(c) {
+        Value::LV(_) => {}
+        Value::Atom(a) if a == "end_of_file" || a.chars().count() == 1 => {}
+        culprit => return Err(type_error("in_character", culprit.clone()))
+    }
+    let next = {
+        let mut streams = state.streams.borrow_mut();
+        let input = match stream {
+            Some(s) => streams.input(&state.bindings, s)?,
+            None => streams.current_input(),
+        };
+        if peek { input.peek()? } else { input.get()? }
+    };
+    let value = next.map_or_else(|| Value::Atom("end_of_file".to_string()), char_atom);
+    Ok(unify(state, c, &value))
+}
+
+// Read a term from given text with the current operators and double_quotes flag, the full stop after the term is optional
+fn read_text(state: &mut State, text: &str) -> Result<ReadTerm, Exception> {
+    let double_quotes = state.flags.borrow().double_quotes;
+    let term = parser::parse_term(text, &state.ops.borrow(), double_quotes).map_err(|e| syntax_error(&e.description()))?;
+    Ok(read_term(term, || state.fresh_lv()))
+}
+
+// Read the next clause of the stream given by a term, or of the current input, and unify it with given term.
+// The term is end_of_file at the end of the stream. The options of read_term/2,3 give the variables of the term.
+fn read(state: &mut State, stream: Option<&Value>, term: &Value, options: Option<&Value>) -> BuiltInResult {
+    let mut wanted = Vec::new();
+    if let Some(options) = options {
+        for option in list(&state.bindings, options)? {
+            match state.bindings.find(&option) {
+                Value::LV(_) => return Err(instantiation_error()),
+                Value::Ctor(f, args) if args.len() == 1 && ["variable_names", "singletons", "variables"].contains(&f.as_str()) =>
+                    wanted.push((f.clone(), args[0].clone())),
+                culprit => return Err(domain_error("read_option", state.bindings.resolve(culprit)))
+            }
+        }
+    }
+    let text = {
+        let mut streams = state.streams.borrow_mut();
+        match stream {
+            Some(s) => streams.input(&state.bindings, s)?,
+            None => streams.current_input(),
+        }.clause_text()?
+    };
+    let clause = match text {
+        Some(text) => {
+            let mut reader = parser::Reader::new(None, &text);
+            reader.set_double_quotes(state.flags.borrow().double_quotes);
+            let clause = reader.read_clause(&state.ops.borrow());
+            clause.map_err(|e| syntax_error(&e.description()))?
+        }
+        None => None
+    };
+    let read = match clause {
+        Some(term) => read_term(term, || state.fresh_lv()),
+        None => ReadTerm { value: Value::Atom("end_of_file".to_string()), variable_names: Vec::new(), singletons: Vec::new() }
+    };
+    let names = |vars: &[(String, Value)]| Value::list(vars.iter().map(|(x, v)| ctor("=", vec![Value::Atom(x.clone()), v.clone()])).collect::<Vec<_>>());
+    if !unify(state, term, &read.value) {
+        return Ok(false);
+    }
+    for (option, value) in wanted {
+        let list = match option.as_str() {
+            "variable_names" => names(&read.variable_names),
+            "singletons" => names(&read.singletons),
+            _ => Value::list(read.value.variables().into_iter().map(Value::LV).collect::<Vec<_>>()),
+        };
+        if !unify(state, &value, &list) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// The options of write_term/2,3
+#[derive(Debug,Clone,Copy,Default)]
+struct WriteOptions {
+    quoted: bool,
+    ignore_ops: bool,
+    number_vars: bool,
+    portray: bool,
+    max_depth: usize,
+}
+
+impl WriteOptions {
+    fn parse(bindings: &Unification, options: &Value) -> Result<Self, Exception> {
+        let mut parsed = WriteOptions::default();
+        for option in list(bindings, options)? {
+            let option = bindings.resolve(&option);
+            let invalid = || domain_error("write_option", option.clone());
+            let (name, value) = match &option {
+                Value::LV(_) => return Err(instantiation_error()),
+                Value::Ctor(f, args) if args.len() == 1 => (f.as_str(), &args[0]),
+                _ => return Err(invalid())
+            };
+            if let Value::LV(_) = value {
+                return Err(instantiation_error());
+            }
+            let flag = match value {
+                Value::Atom(b) if b == "true" => Some(true),
+                Value::Atom(b) if b == "false" => Some(false),
+                _ => None
+            };
+            match (name, flag, value) {
+                ("quoted", Some(b), _) => parsed.quoted = b,
+                ("ignore_ops", Some(b), _) => parsed.ignore_ops = b,
+                ("numbervars", Some(b), _) => parsed.number_vars = b,
+                ("portray", Some(b), _) => parsed.portray = b,
+                ("max_depth", _, Value::Num(n)) => parsed.max_depth = usize::try_from(*n).map_err(|_| invalid())?,
+                _ => return Err(invalid())
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn writer<'a>(&self, ops: &'a Ops) -> Writer<'a> {
+        Writer::new(ops, self.quoted)
+            .ignore_ops(self.ignore_ops)
+            .number_vars(self.number_vars)
+            .portray(self.portray)
+            .max_depth(self.max_depth)
+    }
+}
+
+// Write a term with given options to the stream given by a term, or to the current output
+fn write_term(state: &mut State, stream: Option<&Value>, term: &Value, options: &WriteOptions) -> BuiltInResult {
+    let text = options.writer(&state.ops.borrow()).write(&state.bindings.resolve(term));
+    put(state, stream, &text)
+}
+
+// The arguments of format/2,3, which are a list or a single term that is not a list
+fn format_args(bindings: &Unification, value: &Value) -> Result<Vec<Value>, Exception> {
+    let value = bindings.resolve(value);
+    match value.list_items() {
+        Some(items) => Ok(items.into_iter().cloned().collect()),
+        None if is_partial_list(&value) => Err(instantiation_error()),
+        None => Ok(vec![value])
+    }
+}
+
+// Format the arguments of format/2,3 to the stream given by a term.
+// The arguments are the format text, the arguments and the portrayed arguments of the ~p directives.
+fn format_to(state: &mut State, stream: &Value, args: &[Value]) -> BuiltInResult {
+    let text = Text::Str.of(&state.bindings, &args[0])?.ok_or_else(instantiation_error)?;
+    let values = format_args(&state.bindings, &args[1])?;
+    let printed = list(&state.bindings, &args[2])?;
+    let column = state.streams.borrow_mut().output(&state.bindings, stream)?.column();
+    let out = format::format(&state.ops.borrow(), &text, &values, &printed, column)?;
+    put(state, Some(stream), &out)
+}
+
+// The alias in the options of open/4, if any
+fn open_options(bindings: &Unification, options: &Value) -> Result<Option<String>, Exception> {
+    let mut alias = None;
+    for option in list(bindings, options)? {
+        match bindings.find(&option) {
+            Value::LV(_) => return Err(instantiation_error()),
+            Value::Ctor(f, args) if f == "alias" && args.len() == 1 => match bindings.find(&args[0]) {
+                Value::LV(_) => return Err(instantiation_error()),
+                Value::Atom(a) => alias = Some(a.clone()),
+                _ => return Err(domain_error("stream_option", bindings.resolve(&option)))
+            },
+            culprit => return Err(domain_error("stream_option", bindings.resolve(culprit)))
+        }
+    }
+    Ok(alias)
+}
+
+// The kind of a sink of with_output_to/2, which is atom(A), string(S), codes(Cs) or chars(Cs), and the term to unify with the text
+fn output_sink(bindings: &Unification, sink: &Value) -> Result<(String, Value), Exception> {
+    match bindings.find(sink) {
+        Value::LV(_) => Err(instantiation_error()),
+        Value::Ctor(f, args) if args.len() == 1 && ["atom", "string", "codes", "chars"].contains(&f.as_str()) => Ok((f.clone(), args[0].clone())),
+        culprit => Err(domain_error("output_sink", bindings.resolve(culprit)))
+    }
 }
 
 impl BuiltIns {
     pub fn new() -> Self {
         let mut impls: HashMap<(String, usize), BuiltInFn> = HashMap::new();
         // create the mapping for each built-in
-        impls.insert(("print".to_string(), 1), Box::new(|args: Vec<Value>, _state| {
-            print!("{}", args[0]);
-            true
+        impls.insert(("throw".to_string(), 1), Box::new(|args: Vec<Value>, state| {
+            match state.bindings.find(&args[0]) {
+                Value::LV(_) => Err(instantiation_error()),
+                ball => Err(Exception::Throw(ball.clone()))
+            }
+        }));
+
+        impls.insert(("is".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let result = Value::Num(eval(&state.bindings, &args[1])?);
+            Ok(match state.bindings.union(&args[0], &result) {
+                Some(bindings) => {
+                    state.bindings = bindings;
+                    true
+                }
+                None => false
+            })
         }));
 
-        BuiltIns { impls: impls }
+        impls.insert(("set_prolog_flag".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            match state.bindings.find(&args[0]) {
+                Value::LV(_) => Err(instantiation_error()),
+                Value::Atom(name) => {
+                    let value = state.bindings.resolve(&args[1]);
+                    state.flags.borrow_mut().set(name, &value)?;
+                    Ok(true)
+                }
+                culprit => Err(type_error("atom", culprit.clone()))
+            }
+        }));
+
+        for (name, at_start) in [("asserta", true), ("assertz", false), ("assert", false)].iter() {
+            let at_start = *at_start;
+            impls.insert((name.to_string(), 1), Box::new(move |args: Vec<Value>, state| {
+                let def = clause_def(&state.bindings, &args[0], state.local_state.module())?;
+                state.database.borrow_mut().add(def, at_start)?;
+                Ok(true)
+            }));
+        }
+
+        // '$retract'(Clause, Head, Body) splits the clause given to retract/1 into its head, qualified with the module that defines it, and its body
+        impls.insert(("$retract".to_string(), 3), Box::new(|args: Vec<Value>, state| {
+            let (module, clause) = qualified(state, &args[0])?;
+            let (head, body) = split_clause(state.bindings.resolve(&clause));
+            let (module, head) = strip_module(&state.bindings, &head, &module)?;
+            let PredSig(p, arity) = head_predicate(&head, &module)?;
+            // the clauses of a predicate the module imports are those of the module that defines it, as for clause/2
+            let sig = PredSig(state.database.borrow().resolve(&module, p.name(), arity), arity);
+            state.database.borrow().modifiable(&sig)?;
+            let module = sig.0.module().unwrap_or(&module).to_string();
+            let head = Value::Ctor(":".to_string(), vec![Value::Atom(module), head]);
+            Ok(unify(state, &args[1], &head) && unify(state, &args[2], &body))
+        }));
+
+        // '$erase'(Head, Body) removes the first clause that unifies with Head :- Body, which retract/1 found with clause/2
+        impls.insert(("$erase".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let (module, head) = qualified(state, &args[0])?;
+            let head = state.bindings.resolve(&head);
+            let sig = head_predicate(&head, &module)?;
+            let database = state.database.clone();
+            let clauses = database.borrow().clauses(&sig).unwrap_or_default();
+            for clause in clauses.iter() {
+                let (h, b) = clause.terms(|| state.fresh_lv());
+                if state.bindings.union(&head, &h).and_then(|bindings| bindings.union(&args[1], &b)).is_some() {
+                    database.borrow_mut().remove(&sig, clause);
+                    break;
+                }
+            }
+            // a clause that was removed since it was found is retracted already
+            Ok(true)
+        }));
+
+        impls.insert(("retractall".to_string(), 1), Box::new(|args: Vec<Value>, state| {
+            let (module, head) = qualified(state, &args[0])?;
+            let head = state.bindings.resolve(&head);
+            let sig = head_predicate(&head, &module)?;
+            let database = state.database.clone();
+            // an undefined predicate becomes dynamic, as if it had its clauses retracted
+            database.borrow_mut().declare(sig.clone())?;
+            let clauses = database.borrow().clauses(&sig).unwrap_or_default();
+            for clause in clauses.iter() {
+                let (h, _) = clause.terms(|| state.fresh_lv());
+                if state.bindings.union(&head, &h).is_some() {
+                    database.borrow_mut().remove(&sig, clause);
+                }
+            }
+            Ok(true)
+        }));
+
+        impls.insert(("listing".to_string(), 1), Box::new(|args: Vec<Value>, state| {
+            let ops = state.ops.clone();
+            let text = match qualified(state, &args[0])? {
+                (module, Value::Atom(name)) => state.database.borrow().listing(&ops.borrow(), &module, &name, None),
+                (module, spec) => {
+                    let PredSig(p, arity) = indicated_predicate(&state.bindings, &spec, &module)?;
+                    state.database.borrow().listing(&ops.borrow(), p.module().unwrap_or(&module), p.name(), Some(arity))
+                }
+            };
+            put(state, None, &text)
+        }));
+
+        // '$phrase'(Body, List, Rest, Goal) gives the goal that parses List with a grammar body, leaving Rest
+        impls.insert(("$phrase".to_string(), 4), Box::new(|args: Vec<Value>, state| {
+            let body = match qualified(state, &args[0])? {
+                (_, Value::LV(_)) => return Err(instantiation_error()),
+                (_, body @ (Value::Num(_) | Value::Str(_))) => return Err(type_error("callable", body)),
+                (module, body) => Value::Ctor(":".to_string(), vec![Value::Atom(module), body])
+            };
+            let (body, s0, s) = (state.bindings.resolve(&body), state.bindings.resolve(&args[1]), state.bindings.resolve(&args[2]));
+            for list in [&s0, &s].iter() {
+                if list.list_items().is_none() && !is_partial_list(list) {
+                    return Err(type_error("list", (*list).clone()));
+                }
+            }
+            let goal = grammar_goal(&body, &s0, &s, || state.fresh_lv());
+            Ok(unify(state, &args[3], &goal))
+        }));
+
+        impls.insert(("abolish".to_string(), 1), Box::new(|args: Vec<Value>, state| {
+            let sig = indicated_predicate(&state.bindings, &args[0], state.local_state.module())?;
+            state.database.borrow_mut().abolish(&sig)?;
+            Ok(true)
+        }));
+
+        let comparisons: [(&str, Comparison); 6] = [
+            ("=:=", i64::eq),
+            ("=\\=", i64::ne),
+            ("<", i64::lt),
+            (">", i64::gt),
+            ("=<", i64::le),
+            (">=", i64::ge),
+        ];
+        for (name, cmp) in comparisons.iter() {
+            let cmp = *cmp;
+            impls.insert((name.to_string(), 2), Box::new(move |args: Vec<Value>, state| {
+                Ok(cmp(&eval(&state.bindings, &args[0])?, &eval(&state.bindings, &args[1])?))
+            }));
+        }
+
+        let type_checks: [(&str, TypeCheck); 11] = [
+            ("var", |b, x| matches!(b.find(x), Value::LV(_))),
+            ("nonvar", |b, x| !matches!(b.find(x), Value::LV(_))),
+            ("atom", |b, x| matches!(b.find(x), Value::Atom(_))),
+            ("number", |b, x| matches!(b.find(x), Value::Num(_))),
+            ("integer", |b, x| matches!(b.find(x), Value::Num(_))),
+            ("string", |b, x| matches!(b.find(x), Value::Str(_))),
+            ("atomic", |b, x| matches!(b.find(x), Value::Atom(_) | Value::Num(_) | Value::Str(_))),
+            ("compound", |b, x| matches!(b.find(x), Value::Ctor(_, _))),
+            ("callable", |b, x| matches!(b.find(x), Value::Atom(_) | Value::Ctor(_, _))),
+            ("is_list", |b, x| b.resolve(x).list_items().is_some()),
+            ("ground", |b, x| b.resolve(x).variables().is_empty()),
+        ];
+        for (name, check) in type_checks.iter() {
+            let check = *check;
+            impls.insert((name.to_string(), 1), Box::new(move |args: Vec<Value>, state| Ok(check(&state.bindings, &args[0]))));
+        }
+
+        impls.insert(("functor".to_string(), 3), Box::new(|args: Vec<Value>, state| {
+            let (name, arity) = match state.bindings.find(&args[0]).clone() {
+                Value::Ctor(f, f_args) => (Value::Atom(f), Value::Num(f_args.len() as i64)),
+                Value::LV(_) => {
+                    let name = state.bindings.find(&args[1]).clone();
+                    let arity = match state.bindings.find(&args[2]) {
+                        Value::LV(_) => return Err(instantiation_error()),
+                        Value::Num(n) if *n < 0 => return Err(domain_error("not_less_than_zero", Value::Num(*n))),
+                        Value::Num(n) => *n as usize,
+                        culprit => return Err(type_error("integer", culprit.clone()))
+                    };
+                    let term = match name {
+                        Value::LV(_) => return Err(instantiation_error()),
+                        Value::Ctor(_, _) => return Err(type_error("atomic", name)),
+                        name if arity == 0 => name,
+                        Value::Atom(f) => Value::Ctor(f, (0..arity).map(|_| state.fresh_lv()).collect()),
+                        name => return Err(type_error("atom", name))
+                    };
+                    return Ok(unify(state, &args[0], &term));
+                }
+                atomic => (atomic, Value::Num(0))
+            };
+            Ok(unify(state, &args[1], &name) && unify(state, &args[2], &arity))
+        }));
+
+        impls.insert(("arg".to_string(), 3), Box::new(|args: Vec<Value>, state| {
+            let n = match state.bindings.find(&args[0]) {
+                Value::LV(_) => return Err(instantiation_error()),
+                Value::Num(n) => *n,
+                culprit => return Err(type_error("integer", culprit.clone()))
+            };
+            let arg = match state.bindings.find(&args[1]) {
+                Value::LV(_) => return Err(instantiation_error()),
+                Value::Ctor(_, t_args) if n >= 1 && n as usize <= t_args.len() => t_args[n as usize - 1].clone(),
+                Value::Ctor(_, _) => return Ok(false),
+                culprit => return Err(type_error("compound", culprit.clone()))
+            };
+            Ok(unify(state, &args[2], &arg))
+        }));
+
+        impls.insert(("=..".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let list = match state.bindings.find(&args[0]).clone() {
+                Value::Ctor(f, t_args) => Value::list(std::iter::once(Value::Atom(f)).chain(t_args).collect::<Vec<_>>()),
+                Value::LV(_) => {
+                    let list = state.bindings.resolve(&args[1]);
+                    let items = match list.list_items() {
+                        Some(items) => items,
+                        None if is_partial_list(&list) => return Err(instantiation_error()),
+                        None => return Err(type_error("list", list.clone()))
+                    };
+                    let term = match items.split_first() {
+                        None => return Err(domain_error("non_empty_list", list.clone())),
+                        Some((Value::LV(_), _)) => return Err(instantiation_error()),
+                        Some((head, [])) if !matches!(head, Value::Ctor(_, _)) => (*head).clone(),
+                        Some((Value::Atom(f), rest)) => Value::Ctor(f.clone(), rest.iter().map(|&x| x.clone()).collect()),
+                        Some((head @ Value::Ctor(_, _), _)) => return Err(type_error("atomic", (*head).clone())),
+                        Some((head, _)) => return Err(type_error("atom", (*head).clone()))
+                    };
+                    return Ok(unify(state, &args[0], &term));
+                }
+                atomic => Value::list(vec![atomic])
+            };
+            Ok(unify(state, &args[1], &list))
+        }));
+
+        impls.insert(("copy_term".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let copy = state.copy(&args[0]);
+            Ok(unify(state, &args[1], &copy))
+        }));
+
+        let order_checks: [(&str, OrderCheck); 6] = [
+            ("==", Ordering::is_eq),
+            ("\\==", Ordering::is_ne),
+            ("@<", Ordering::is_lt),
+            ("@>", Ordering::is_gt),
+            ("@=<", Ordering::is_le),
+            ("@>=", Ordering::is_ge),
+        ];
+        for (name, check) in order_checks.iter() {
+            let check = *check;
+            impls.insert((name.to_string(), 2), Box::new(move |args: Vec<Value>, state| {
+                Ok(check(state.bindings.resolve(&args[0]).cmp(&state.bindings.resolve(&args[1]))))
+            }));
+        }
+
+        impls.insert(("compare".to_string(), 3), Box::new(|args: Vec<Value>, state| {
+            match state.bindings.find(&args[0]) {
+                Value::LV(_) => {}
+                Value::Atom(o) if ["<", "=", ">"].contains(&o.as_str()) => {}
+                Value::Atom(o) => return Err(domain_error("order", Value::Atom(o.clone()))),
+                culprit => return Err(type_error("atom", culprit.clone()))
+            }
+            let order = match state.bindings.resolve(&args[1]).cmp(&state.bindings.resolve(&args[2])) {
+                Ordering::Less => "<",
+                Ordering::Equal => "=",
+                Ordering::Greater => ">"
+            };
+            Ok(unify(state, &args[0], &Value::Atom(order.to_string())))
+        }));
+
+        impls.insert(("msort".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let mut items = list(&state.bindings, &args[0])?;
+            items.sort();
+            Ok(unify(state, &args[1], &Value::list(items)))
+        }));
+
+        impls.insert(("sort".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let mut items = list(&state.bindings, &args[0])?;
+            items.sort();
+            items.dedup();
+            Ok(unify(state, &args[1], &Value::list(items)))
+        }));
+
+        // sort(Key, Order, List, Sorted) sorts on the argument Key of the elements, or the elements themselves if Key is 0. Order is one of @<, @=<, @> and @>=, the ones without = remove duplicate keys.
+        impls.insert(("sort".to_string(), 4), Box::new(|args: Vec<Value>, state| {
+            let key = match state.bindings.find(&args[0]) {
+                Value::LV(_) => return Err(instantiation_error()),
+                Value::Num(n) if *n < 0 => return Err(domain_error("not_less_than_zero", Value::Num(*n))),
+                Value::Num(n) => *n as usize,
+                culprit => return Err(type_error("integer", culprit.clone()))
+            };
+            let (descending, dedup) = match state.bindings.find(&args[1]) {
+                Value::LV(_) => return Err(instantiation_error()),
+                Value::Atom(o) if o == "@<" => (false, true),
+                Value::Atom(o) if o == "@=<" => (false, false),
+                Value::Atom(o) if o == "@>" => (true, true),
+                Value::Atom(o) if o == "@>=" => (true, false),
+                culprit => return Err(domain_error("order", culprit.clone()))
+            };
+            let items = list(&state.bindings, &args[2])?;
+            let mut keyed = Vec::new();
+            for item in items.into_iter() {
+                let k = match &item {
+                    _ if key == 0 => item.clone(),
+                    Value::Ctor(_, item_args) if key <= item_args.len() => item_args[key - 1].clone(),
+                    _ => return Err(type_error("compound", item))
+                };
+                keyed.push((k, item));
+            }
+            // the sort is stable, so the elements with equal keys keep their order
+            keyed.sort_by(|(k1, _), (k2, _)| if descending { k2.cmp(k1) } else { k1.cmp(k2) });
+            if dedup {
+                keyed.dedup_by(|(k1, _), (k2, _)| k1 == k2);
+            }
+            Ok(unify(state, &args[3], &Value::list(keyed.into_iter().map(|(_, item)| item).collect::<Vec<_>>())))
+        }));
+
+        impls.insert(("keysort".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let mut pairs = Vec::new();
+            for pair in list(&state.bindings, &args[0])?.into_iter() {
+                match pair {
+                    Value::LV(_) => return Err(instantiation_error()),
+                    Value::Ctor(ref f, ref kv) if f == "-" && kv.len() == 2 => pairs.push((kv[0].clone(), pair)),
+                    _ => return Err(type_error("pair", pair))
+                }
+            }
+            pairs.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+            Ok(unify(state, &args[1], &Value::list(pairs.into_iter().map(|(_, pair)| pair).collect::<Vec<_>>())))
+        }));
+
+        let text_lists: [(&str, CharOf); 2] = [("atom_codes", (code, code_of)), ("atom_chars", (char_atom, char_of))];
+        for (name, (element, of)) in text_lists.iter() {
+            let (element, of) = (*element, *of);
+            impls.insert((name.to_string(), 2), Box::new(move |args: Vec<Value>, state| {
+                match text(&state.bindings, &args[0])? {
+                    Some(t) => Ok(unify(state, &args[1], &Value::list(t.chars().map(element).collect::<Vec<_>>()))),
+                    None => {
+                        let t = list_text(&state.bindings, &args[1], of)?;
+                        Ok(unify(state, &args[0], &Value::Atom(t)))
+                    }
+                }
+            }));
+        }
+
+        impls.insert(("char_code".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            match state.bindings.find(&args[0]) {
+                Value::LV(_) => match state.bindings.find(&args[1]) {
+                    Value::LV(_) => Err(instantiation_error()),
+                    n => {
+                        let c = code_of(n)?;
+                        Ok(unify(state, &args[0], &char_atom(c)))
+                    }
+                },
+                c => {
+                    let c = char_of(c)?;
+                    Ok(unify(state, &args[1], &code(c)))
+                }
+            }
+        }));
+
+        impls.insert(("atom_length".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let t = text(&state.bindings, &args[0])?.ok_or_else(instantiation_error)?;
+            match integer(&state.bindings, &args[1])? {
+                Some(n) if n < 0 => Err(domain_error("not_less_than_zero", Value::Num(n))),
+                _ => Ok(unify(state, &args[1], &Value::Num(t.chars().count() as i64)))
+            }
+        }));
+
+        impls.insert(("upcase_atom".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let t = text(&state.bindings, &args[0])?.ok_or_else(instantiation_error)?;
+            Ok(unify(state, &args[1], &Value::Atom(t.to_uppercase())))
+        }));
+
+        impls.insert(("atom_number".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            match state.bindings.find(&args[0]) {
+                Value::LV(_) => match state.bindings.find(&args[1]) {
+                    Value::LV(_) => Err(instantiation_error()),
+                    Value::Num(n) => {
+                        let a = Value::Atom(n.to_string());
+                        Ok(unify(state, &args[0], &a))
+                    }
+                    culprit => Err(type_error("number", culprit.clone()))
+                },
+                Value::Atom(a) => {
+                    let n = parse_number(&state.ops.borrow(), a);
+                    Ok(n.is_some_and(|n| unify(state, &args[1], &Value::Num(n))))
+                }
+                culprit => Err(type_error("atom", culprit.clone()))
+            }
+        }));
+
+        impls.insert(("number_codes".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            match state.bindings.find(&args[0]) {
+                Value::LV(_) => {
+                    let t = list_text(&state.bindings, &args[1], code_of)?;
+                    let n = parse_number(&state.ops.borrow(), &t).ok_or_else(|| syntax_error("illegal_number"))?;
+                    Ok(unify(state, &args[0], &Value::Num(n)))
+                }
+                Value::Num(n) => {
+                    let codes = Value::list(n.to_string().chars().map(code).collect::<Vec<_>>());
+                    Ok(unify(state, &args[1], &codes))
+                }
+                culprit => Err(type_error("number", culprit.clone()))
+            }
+        }));
+
+        // term_to_atom(T, A) writes T quoted if A is unbound, otherwise it reads A with the current operators
+        impls.insert(("term_to_atom".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            match text(&state.bindings, &args[1])? {
+                Some(t) => {
+                    let value = read_text(state, &t)?.value;
+                    Ok(unify(state, &args[0], &value))
+                }
+                None => {
+                    let value = state.bindings.resolve(&args[0]);
+                    if let Value::LV(_) = value {
+                        return Err(instantiation_error());
+                    }
+                    let t = Writer::new(&state.ops.borrow(), true).write(&value);
+                    Ok(unify(state, &args[1], &Value::Atom(t)))
+                }
+            }
+        }));
+
+        impls.insert(("string_chars".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            match Text::Str.of(&state.bindings, &args[0])? {
+                Some(t) => Ok(unify(state, &args[1], &Value::list(t.chars().map(char_atom).collect::<Vec<_>>()))),
+                None => {
+                    let t = list_text(&state.bindings, &args[1], char_of)?;
+                    Ok(unify(state, &args[0], &Value::Str(t)))
+                }
+            }
+        }));
+
+        // string_code(Index, String, Code) is the code of the character at the 1-based index, it fails if there is no such character
+        impls.insert(("string_code".to_string(), 3), Box::new(|args: Vec<Value>, state| {
+            let index = integer(&state.bindings, &args[0])?.ok_or_else(instantiation_error)?;
+            let t = Text::Str.of(&state.bindings, &args[1])?.ok_or_else(instantiation_error)?;
+            let c = usize::try_from(index - 1).ok().and_then(|i| t.chars().nth(i));
+            Ok(c.is_some_and(|c| unify(state, &args[2], &code(c))))
+        }));
+
+        impls.insert(("number_string".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            match Text::Str.of(&state.bindings, &args[1])? {
+                Some(t) => {
+                    let n = parse_number(&state.ops.borrow(), &t).ok_or_else(|| syntax_error("illegal_number"))?;
+                    Ok(unify(state, &args[0], &Value::Num(n)))
+                }
+                None => match state.bindings.find(&args[0]) {
+                    Value::LV(_) => Err(instantiation_error()),
+                    Value::Num(n) => {
+                        let s = Value::Str(n.to_string());
+                        Ok(unify(state, &args[1], &s))
+                    }
+                    culprit => Err(type_error("number", culprit.clone()))
+                }
+            }
+        }));
+
+        // split_string(String, SepChars, Pad, SubStrings) splits at each of the separator characters, then strips the padding characters from both ends of the parts
+        impls.insert(("split_string".to_string(), 4), Box::new(|args: Vec<Value>, state| {
+            let mut texts = Vec::new();
+            for arg in args[..3].iter() {
+                texts.push(Text::Str.of(&state.bindings, arg)?.ok_or_else(instantiation_error)?);
+            }
+            let (separators, pad) = (&texts[1], &texts[2]);
+            let parts: Vec<Value> = texts[0]
+                .split(|c| separators.contains(c))
+                .map(|part| Value::Str(part.trim_matches(|c| pad.contains(c)).to_string()))
+                .collect();
+            Ok(unify(state, &args[3], &Value::list(parts)))
+        }));
+
+        // open(Source, Mode, Stream, Options) opens the file Source for read, write or append. The only option is alias(A).
+        for arity in [3, 4].iter() {
+            impls.insert(("open".to_string(), *arity), Box::new(|args: Vec<Value>, state| {
+                let path = match state.bindings.find(&args[0]) {
+                    Value::LV(_) => return Err(instantiation_error()),
+                    Value::Atom(a) => a.clone(),
+                    culprit => return Err(domain_error("source_sink", culprit.clone()))
+                };
+                let mode = match state.bindings.find(&args[1]) {
+                    Value::LV(_) => return Err(instantiation_error()),
+                    Value::Atom(a) => Mode::from_atom(a).ok_or_else(|| domain_error("io_mode", Value::Atom(a.clone())))?,
+                    culprit => return Err(type_error("atom", culprit.clone()))
+                };
+                if !matches!(state.bindings.find(&args[2]), Value::LV(_)) {
+                    return Err(uninstantiation_error(state.bindings.resolve(&args[2])));
+                }
+                let alias = match args.get(3) {
+                    Some(options) => open_options(&state.bindings, options)?,
+                    None => None
+                };
+                let id = {
+                    let mut streams = state.streams.borrow_mut();
+                    if let Some(a) = alias.as_ref().filter(|a| streams.is_alias(a)) {
+                        return Err(permission_error("open", "source_sink", ctor("alias", vec![Value::Atom(a.clone())])));
+                    }
+                    streams.open(&path, mode, alias.as_deref())?
+                };
+                Ok(unify(state, &args[2], &streams::handle(id)))
+            }));
+        }
+
+        // open_string(Text, Stream) opens an input stream that reads given text
+        impls.insert(("open_string".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let t = Text::Str.of(&state.bindings, &args[0])?.ok_or_else(instantiation_error)?;
+            let id = state.streams.borrow_mut().open_text(t);
+            Ok(unify(state, &args[1], &streams::handle(id)))
+        }));
+
+        impls.insert(("close".to_string(), 1), Box::new(|args: Vec<Value>, state| {
+            let mut streams = state.streams.borrow_mut();
+            let id = streams.id(&state.bindings, &args[0])?;
+            streams.close(id);
+            Ok(true)
+        }));
+
+        for (name, output) in [("current_input", false), ("current_output", true)].iter() {
+            let output = *output;
+            impls.insert((name.to_string(), 1), Box::new(move |args: Vec<Value>, state| {
+                match state.bindings.find(&args[0]) {
+                    Value::LV(_) => {}
+                    Value::Ctor(f, ctor_args) if f == streams::STREAM && ctor_args.len() == 1 => {}
+                    culprit => return Err(domain_error("stream", culprit.clone()))
+                }
+                let id = {
+                    let streams = state.streams.borrow();
+                    if output { streams.output } else { streams.input }
+                };
+                Ok(unify(state, &args[0], &streams::handle(id)))
+            }));
+        }
+
+        impls.insert(("set_input".to_string(), 1), Box::new(|args: Vec<Value>, state| {
+            let mut streams = state.streams.borrow_mut();
+            streams.input(&state.bindings, &args[0])?;
+            streams.input = streams.id(&state.bindings, &args[0])?;
+            Ok(true)
+        }));
+
+        impls.insert(("set_output".to_string(), 1), Box::new(|args: Vec<Value>, state| {
+            let mut streams = state.streams.borrow_mut();
+            streams.output(&state.bindings, &args[0])?;
+            streams.output = streams.id(&state.bindings, &args[0])?;
+            Ok(true)
+        }));
+
+        for arity in [1, 2].iter() {
+            impls.insert(("get_char".to_string(), *arity), Box::new(|args: Vec<Value>, state| get(&args, state, false)));
+            impls.insert(("peek_char".to_string(), *arity), Box::new(|args: Vec<Value>, state| get(&args, state, true)));
+            impls.insert(("put_char".to_string(), *arity), Box::new(|args: Vec<Value>, state| {
+                let c = args.last().unwrap();
+                let c = match state.bindings.find(c) {
+                    Value::LV(_) => return Err(instantiation_error()),
+                    c => char_of(c)?
+                };
+                put(state, args.first().filter(|_| args.len() == 2), &c.to_string())
+            }));
+        }
+
+        impls.insert(("nl".to_string(), 0), Box::new(|_, state| put(state, None, "\n")));
+        impls.insert(("nl".to_string(), 1), Box::new(|args: Vec<Value>, state| put(state, Some(&args[0]), "\n")));
+
+        // The memory streams with_output_to/2 collects the output in
+        impls.insert(("$open_memory".to_string(), 1), Box::new(|args: Vec<Value>, state| {
+            let id = state.streams.borrow_mut().open_memory();
+            Ok(unify(state, &args[0], &streams::handle(id)))
+        }));
+
+        impls.insert(("$output_sink".to_string(), 1), Box::new(|args: Vec<Value>, state| {
+            output_sink(&state.bindings, &args[0]).map(|_| true)
+        }));
+
+        // '$close_memory'(Stream, Sink) closes a memory stream and unifies the sink with the text written to it
+        impls.insert(("$close_memory".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let (kind, value) = output_sink(&state.bindings, &args[1])?;
+            let text = {
+                let mut streams = state.streams.borrow_mut();
+                let id = streams.id(&state.bindings, &args[0])?;
+                streams.close(id).unwrap_or_default()
+            };
+            let text = match kind.as_str() {
+                "atom" => Value::Atom(text),
+                "string" => Value::Str(text),
+                "codes" => Value::list(text.chars().map(code).collect::<Vec<_>>()),
+                _ => Value::list(text.chars().map(char_atom).collect::<Vec<_>>()),
+            };
+            Ok(unify(state, &value, &text))
+        }));
+
+        // write/1,2, writeq/1,2 and write_canonical/1,2 write to given stream or to the current output
+        let writes = [
+            ("write", WriteOptions { number_vars: true, ..WriteOptions::default() }),
+            ("writeq", WriteOptions { quoted: true, number_vars: true, ..WriteOptions::default() }),
+            ("write_canonical", WriteOptions { quoted: true, ignore_ops: true, ..WriteOptions::default() }),
+        ];
+        for (name, options) in writes.iter() {
+            let options = *options;
+            impls.insert((name.to_string(), 1), Box::new(move |args: Vec<Value>, state| write_term(state, None, &args[0], &options)));
+            impls.insert((name.to_string(), 2), Box::new(move |args: Vec<Value>, state| write_term(state, Some(&args[0]), &args[1], &options)));
+        }
+
+        // write_term/2,3 are written in Prolog, since portray/1 is called for the subterms before the term is written with '$write_term'/3
+        impls.insert(("$write_term".to_string(), 3), Box::new(|args: Vec<Value>, state| {
+            let options = WriteOptions::parse(&state.bindings, &args[2])?;
+            write_term(state, Some(&args[0]), &args[1], &options)
+        }));
+
+        // '$write_options'(Options, Portray) checks the options of write_term/2,3 and tells whether portray/1 should be called
+        impls.insert(("$write_options".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let portray = WriteOptions::parse(&state.bindings, &args[0])?.portray;
+            Ok(unify(state, &args[1], &Value::Atom(portray.to_string())))
+        }));
+
+        // format/2,3 are written in Prolog, which portrays the arguments of the ~p directives before '$format'/3,4 formats the text
+        impls.insert(("$format_printed".to_string(), 3), Box::new(|args: Vec<Value>, state| {
+            let text = Text::Str.of(&state.bindings, &args[0])?.ok_or_else(instantiation_error)?;
+            let printed = format::printed(&text, &format_args(&state.bindings, &args[1])?)?;
+            Ok(unify(state, &args[2], &Value::list(printed)))
+        }));
+        impls.insert(("$format".to_string(), 4), Box::new(|args: Vec<Value>, state| format_to(state, &args[0], &args[1..])));
+
+        // read(Stream, Term) and read_term(Stream, Term, Options) read the next clause of a stream, the stream is the current input if it is left out
+        impls.insert(("read".to_string(), 1), Box::new(|args: Vec<Value>, state| read(state, None, &args[0], None)));
+        impls.insert(("read".to_string(), 2), Box::new(|args: Vec<Value>, state| read(state, Some(&args[0]), &args[1], None)));
+        impls.insert(("read_term".to_string(), 2), Box::new(|args: Vec<Value>, state| read(state, None, &args[0], Some(&args[1]))));
+        impls.insert(("read_term".to_string(), 3), Box::new(|args: Vec<Value>, state| read(state, Some(&args[0]), &args[1], Some(&args[2]))));
+
+        // term_string(T, S) writes T quoted if S is unbound, otherwise it reads S with the current operators
+        impls.insert(("term_string".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            match Text::Str.of(&state.bindings, &args[1])? {
+                Some(t) => {
+                    let value = read_text(state, &t)?.value;
+                    Ok(unify(state, &args[0], &value))
+                }
+                None => {
+                    let value = state.bindings.resolve(&args[0]);
+                    let t = Writer::new(&state.ops.borrow(), true).write(&value);
+                    Ok(unify(state, &args[1], &Value::Str(t)))
+                }
+            }
+        }));
+
+        let mut nondet: HashMap<(String, usize), NondetFn> = HashMap::new();
+
+        // atom_concat(A1, A2, A3) and string_concat(S1, S2, S3) enumerate the ways to split the last argument unless the first two are given
+        // sub_atom(Atom, Before, Length, After, Sub) and sub_string/5 enumerate the parts of the text, the positions and the lengths are in characters
+        for kind in [Text::Atom, Text::Str].iter() {
+            let kind = *kind;
+            nondet.insert((format!("{}_concat", kind.name()), 3), Box::new(move |args: Vec<Value>, state| concat(kind, &args, state)));
+            nondet.insert((format!("sub_{}", kind.name()), 5), Box::new(move |args: Vec<Value>, state| sub_text(kind, &args, state)));
+        }
+
+        nondet.insert(("clause".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            // the clauses of a predicate the module imports are those of the module that defines it
+            let (module, head) = qualified(state, &args[0])?;
+            let head = state.bindings.resolve(&head);
+            let PredSig(p, arity) = head_predicate(&head, &module)?;
+            let sig = PredSig(state.database.borrow().resolve(&module, p.name(), arity), arity);
+            if let culprit @ Value::Num(_) = state.bindings.find(&args[1]) {
+                return Err(type_error("callable", culprit.clone()));
+            }
+            let definitions = state.database.borrow().definitions(&sig)?;
+            let mut solutions = VecDeque::new();
+            for def in definitions.iter() {
+                let (h, b) = clause_terms(def, || state.fresh_lv());
+                if let Some(bindings) = state.bindings.union(&head, &h).and_then(|bindings| bindings.union(&args[1], &b)) {
+                    solutions.push_back(bindings);
+                }
+            }
+            Ok(solutions)
+        }));
+
+        // current_prolog_flag(Flag, Value) enumerates the flags when Flag is unbound
+        nondet.insert(("current_prolog_flag".to_string(), 2), Box::new(|args: Vec<Value>, state| {
+            let names: Vec<String> = match state.bindings.find(&args[0]) {
+                Value::LV(_) => Flags::NAMES.iter().map(|name| name.to_string()).collect(),
+                Value::Atom(name) => vec![name.clone()],
+                culprit => return Err(type_error("atom", culprit.clone()))
+            };
+            let flags = state.flags.borrow();
+            Ok(names.into_iter()
+               .filter_map(|name| {
+                   let value = flags.get(&name)?;
+                   state.bindings.union(&args[0], &Value::Atom(name))?.union(&args[1], &value)
+               })
+               .collect())
+        }));
+
+        impls.insert(("$bagof".to_string(), 4), Box::new(|args: Vec<Value>, state| bag_goal("bagof", &args, state)));
+        impls.insert(("$setof".to_string(), 4), Box::new(|args: Vec<Value>, state| bag_goal("setof", &args, state)));
+
+        // build the goal of aggregate_all/3, which collects the solutions then aggregates them
+        impls.insert(("$aggregate_all".to_string(), 4), Box::new(|args: Vec<Value>, state| {
+            let (kind, template) = match state.bindings.find(&args[0]) {
+                Value::LV(_) => return Err(instantiation_error()),
+                Value::Atom(a) if a == "count" => (a.clone(), Value::Atom("x".to_string())),
+                Value::Ctor(f, spec) if spec.len() == 1 && ["sum", "max", "min", "bag", "set"].contains(&f.as_str()) =>
+                    (f.clone(), spec[0].clone()),
+                culprit => return Err(domain_error("aggregate_spec", culprit.clone()))
+            };
+            let solutions = state.fresh_lv();
+            let findall = ctor("findall", vec![template, args[1].clone(), solutions.clone()]);
+            let aggregate = ctor("$aggregate", vec![Value::Atom(kind), solutions, args[2].clone()]);
+            Ok(unify(state, &args[3], &ctor(",", vec![findall, aggregate])))
+        }));
+
+        impls.insert(("$aggregate".to_string(), 3), Box::new(|args: Vec<Value>, state| {
+            let solutions = state.bindings.resolve(&args[1]);
+            let solutions = solutions.list_items().ok_or_else(|| system_error("aggregating a partial list"))?;
+            let numbers = |state: &State| solutions.iter().map(|x| eval(&state.bindings, x)).collect::<Result<Vec<i64>, _>>();
+            let result = match state.bindings.find(&args[0]) {
+                Value::Atom(kind) if kind == "count" => Value::Num(solutions.len() as i64),
+                Value::Atom(kind) if kind == "sum" => {
+                    let sum = numbers(state)?.into_iter().try_fold(0i64, |sum, n| sum.checked_add(n));
+                    Value::Num(sum.ok_or_else(|| evaluation_error("int_overflow"))?)
+                }
+                Value::Atom(kind) if kind == "max" => match numbers(state)?.into_iter().max() {
+                    Some(n) => Value::Num(n),
+                    None => return Ok(false)
+                },
+                Value::Atom(kind) if kind == "min" => match numbers(state)?.into_iter().min() {
+                    Some(n) => Value::Num(n),
+                    None => return Ok(false)
+                },
+                Value::Atom(kind) if kind == "bag" => Value::list(solutions.into_iter().cloned().collect::<Vec<_>>()),
+                Value::Atom(kind) if kind == "set" => {
+                    let mut set: Vec<Value> = solutions.into_iter().cloned().collect();
+                    set.sort();
+                    set.dedup();
+                    Value::list(set)
+                }
+                kind => return Err(system_error(&format!("unknown aggregate {}", kind)))
+            };
+            Ok(unify(state, &args[2], &result))
+        }));
+
+        let control = (1..=8).map(|n| ("call".to_string(), n))
+            .chain(["findall", "bagof", "setof", "aggregate_all"].iter().map(|name| (name.to_string(), 3)))
+            .collect();
+
+        // group the solutions of bagof/3 and setof/3 by the values of the free variables, each group is a solution
+        nondet.insert(("$bags".to_string(), 4), Box::new(|args: Vec<Value>, state| {
+            let setof = state.bindings.find(&args[0]) == &Value::Atom("setof".to_string());
+            let pairs = state.bindings.resolve(&args[2]);
+            let mut groups: Vec<(Value, Vec<Value>)> = Vec::new();
+            for pair in pairs.list_items().ok_or_else(|| system_error("grouping a partial list"))? {
+                let (witness, item) = match pair {
+                    Value::Ctor(_, pair) => (&pair[0], &pair[1]),
+                    _ => return Err(system_error("grouping a solution without its witness"))
+                };
+                match groups.iter_mut().find(|(w, _)| variant(w, witness)) {
+                    Some((_, items)) => items.push(item.clone()),
+                    None => groups.push((witness.clone(), vec![item.clone()]))
+                }
+            }
+            groups.sort_by(|(w1, _), (w2, _)| w1.cmp(w2));
+
+            let mut solutions = VecDeque::new();
+            for (witness, mut items) in groups.into_iter() {
+                if setof {
+                    items.sort();
+                    items.dedup();
+                }
+                if let Some(bindings) = state.bindings.union(&args[1], &witness).and_then(|bindings| bindings.union(&args[3], &Value::list(items))) {
+                    solutions.push_back(bindings);
+                }
+            }
+            Ok(solutions)
+        }));
+
+        BuiltIns { impls, nondet, control }
     }
 
-    pub fn exists(&self, name: &String, arity: &usize) -> bool {
-        self.impls.contains_key(&(name.clone(), *arity))
+    pub fn exists(&self, name: &str, arity: &usize) -> bool {
+        let sig = (name.to_string(), *arity);
+        self.impls.contains_key(&sig) || self.nondet.contains_key(&sig)
+    }
+
+    // The implementation of given built-in if it is nondeterministic
+    pub fn nondet(&self, sig: &(String, usize)) -> Option<&NondetFn> {
+        self.nondet.get(sig)
+    }
+
+    // Whether given built-in is implemented by the compiler and the VM rather than a function
+    pub fn is_control(&self, name: &str, arity: usize) -> bool {
+        self.control.iter().any(|(n, a)| n == name && *a == arity)
+    }
+
+    // The names and arities of all built-ins
+    pub fn signatures(&self) -> impl Iterator<Item=&(String, usize)> {
+        self.impls.keys().chain(self.nondet.keys()).chain(self.control.iter())
     }
 }
 
@@ -37,4 +1328,4 @@ impl Index<&(String, usize)> for BuiltIns {
     fn index(&self, sig: &(String, usize)) -> &BuiltInFn {
         &self.impls[sig]
     }
-}
\ No newline at end of file
+}
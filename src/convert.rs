@@ -0,0 +1,366 @@
+// Conversions between Rust values and IR terms. These make it possible to pass Rust data to cadmium programs and to read query answers back without building `Value::Ctor` trees by hand.
+//
+// The mapping follows the usual Prolog conventions:
+// - integers are numbers, strings are atoms, booleans are the atoms `true` and `false`
+// - atoms and strings both convert back to Rust strings
+// - `Vec<T>` is a proper list
+// - `Option<T>` is either `none` or `some(X)`
+// - tuples are compound terms with the functor `-`, so pairs are `K-V`
+// - `HashMap<K, V>` is a list of `K-V` pairs
+// - structs registered with `term_struct!` are compound terms with one argument per field
+
+use crate::ir::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::Hash;
+
+// The reason a term could not be converted to a Rust value
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum TermError {
+    // the term does not have the expected shape, the first component describes what was expected
+    Type(&'static str, Value),
+    // the term is a number that does not fit in the target type
+    Range(&'static str, i64),
+    // the term (or a part of it) is an unbound logic variable
+    Instantiation,
+}
+
+impl fmt::Display for TermError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TermError::Type(expected, found) => write!(f, "expected {}, found {}", expected, found),
+            TermError::Range(target, n) => write!(f, "{} does not fit in {}", n, target),
+            TermError::Instantiation => write!(f, "term is not sufficiently instantiated"),
+        }
+    }
+}
+
+impl std::error::Error for TermError {}
+
+// Types that can be converted to a term
+pub trait ToTerm {
+    fn to_term(&self) -> Value;
+}
+
+// Types that can be extracted from a term. The term should be resolved against the bindings it came from (see `Unification::resolve`) before it is converted.
+pub trait FromTerm: Sized {
+    fn from_term(term: &Value) -> Result<Self, TermError>;
+}
+
+// Check that given term is not a logic variable, otherwise report the type mismatch
+fn type_error<T>(expected: &'static str, term: &Value) -> Result<T, TermError> {
+    match term {
+        Value::LV(_) => Err(TermError::Instantiation),
+        _ => Err(TermError::Type(expected, term.clone())),
+    }
+}
+
+impl ToTerm for Value {
+    fn to_term(&self) -> Value {
+        self.clone()
+    }
+}
+
+impl FromTerm for Value {
+    fn from_term(term: &Value) -> Result<Self, TermError> {
+        Ok(term.clone())
+    }
+}
+
+impl<T: ToTerm + ?Sized> ToTerm for &T {
+    fn to_term(&self) -> Value {
+        (**self).to_term()
+    }
+}
+
+impl<T: ToTerm + ?Sized> ToTerm for Box<T> {
+    fn to_term(&self) -> Value {
+        (**self).to_term()
+    }
+}
+
+impl<T: FromTerm> FromTerm for Box<T> {
+    fn from_term(term: &Value) -> Result<Self, TermError> {
+        T::from_term(term).map(Box::new)
+    }
+}
+
+// Integers that always fit into a number term
+macro_rules! small_int_terms {
+    ($($t:ty),*) => {
+        $(
+            impl ToTerm for $t {
+                fn to_term(&self) -> Value {
+                    Value::Num(i64::from(*self))
+                }
+            }
+        )*
+    };
+}
+
+small_int_terms!(i8, i16, i32, i64, u8, u16, u32);
+
+impl ToTerm for isize {
+    fn to_term(&self) -> Value {
+        Value::Num(*self as i64)
+    }
+}
+
+// Integers are extracted with a range check
+macro_rules! int_from_terms {
+    ($($t:ty),*) => {
+        $(
+            impl FromTerm for $t {
+                fn from_term(term: &Value) -> Result<Self, TermError> {
+                    match term {
+                        Value::Num(n) => <$t>::try_from(*n).map_err(|_| TermError::Range(stringify!($t), *n)),
+                        _ => type_error("integer", term),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+int_from_terms!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl ToTerm for bool {
+    fn to_term(&self) -> Value {
+        Value::Atom(if *self { "true" } else { "false" }.to_string())
+    }
+}
+
+impl FromTerm for bool {
+    fn from_term(term: &Value) -> Result<Self, TermError> {
+        match term {
+            Value::Atom(a) if a == "true" => Ok(true),
+            Value::Atom(a) if a == "false" => Ok(false),
+            _ => type_error("boolean", term),
+        }
+    }
+}
+
+impl ToTerm for str {
+    fn to_term(&self) -> Value {
+        Value::Atom(self.to_string())
+    }
+}
+
+impl ToTerm for String {
+    fn to_term(&self) -> Value {
+        Value::Atom(self.clone())
+    }
+}
+
+impl FromTerm for String {
+    fn from_term(term: &Value) -> Result<Self, TermError> {
+        match term {
+            Value::Atom(a) | Value::Str(a) => Ok(a.clone()),
+            _ => type_error("text", term),
+        }
+    }
+}
+
+impl<T: ToTerm> ToTerm for [T] {
+    fn to_term(&self) -> Value {
+        Value::list(self.iter().map(ToTerm::to_term).collect::<Vec<_>>())
+    }
+}
+
+impl<T: ToTerm> ToTerm for Vec<T> {
+    fn to_term(&self) -> Value {
+        self.as_slice().to_term()
+    }
+}
+
+impl<T: FromTerm> FromTerm for Vec<T> {
+    fn from_term(term: &Value) -> Result<Self, TermError> {
+        match term.list_items() {
+            Some(items) => items.into_iter().map(T::from_term).collect(),
+            None => {
+                // a partial list with an unbound tail is an instantiation error rather than a type error
+                let mut tail = term;
+                while let Value::Ctor(f, args) = tail {
+                    if f != "." || args.len() != 2 {
+                        break;
+                    }
+                    tail = &args[1];
+                }
+                match tail {
+                    Value::LV(_) => Err(TermError::Instantiation),
+                    _ => Err(TermError::Type("list", term.clone())),
+                }
+            }
+        }
+    }
+}
+
+impl<T: ToTerm> ToTerm for Option<T> {
+    fn to_term(&self) -> Value {
+        match self {
+            Some(x) => Value::Ctor("some".to_string(), vec![x.to_term()]),
+            None => Value::Atom("none".to_string()),
+        }
+    }
+}
+
+impl<T: FromTerm> FromTerm for Option<T> {
+    fn from_term(term: &Value) -> Result<Self, TermError> {
+        match term {
+            Value::Atom(a) if a == "none" => Ok(None),
+            Value::Ctor(f, args) if f == "some" && args.len() == 1 => T::from_term(&args[0]).map(Some),
+            _ => type_error("option", term),
+        }
+    }
+}
+
+macro_rules! tuple_terms {
+    ($(($n:expr; $($t:ident $i:tt),+))*) => {
+        $(
+            impl<$($t: ToTerm),+> ToTerm for ($($t,)+) {
+                fn to_term(&self) -> Value {
+                    Value::Ctor("-".to_string(), vec![$(self.$i.to_term()),+])
+                }
+            }
+
+            impl<$($t: FromTerm),+> FromTerm for ($($t,)+) {
+                fn from_term(term: &Value) -> Result<Self, TermError> {
+                    match term {
+                        Value::Ctor(f, args) if f == "-" && args.len() == $n => {
+                            Ok(($($t::from_term(&args[$i])?,)+))
+                        }
+                        _ => type_error(concat!("-/", stringify!($n)), term),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+tuple_terms! {
+    (2; A 0, B 1)
+    (3; A 0, B 1, C 2)
+    (4; A 0, B 1, C 2, D 3)
+    (5; A 0, B 1, C 2, D 3, E 4)
+    (6; A 0, B 1, C 2, D 3, E 4, F 5)
+}
+
+impl<K: ToTerm, V: ToTerm, S> ToTerm for HashMap<K, V, S> {
+    fn to_term(&self) -> Value {
+        Value::list(self.iter().map(|(k, v)| (k, v).to_term()).collect::<Vec<_>>())
+    }
+}
+
+impl<K: FromTerm + Eq + Hash, V: FromTerm> FromTerm for HashMap<K, V> {
+    fn from_term(term: &Value) -> Result<Self, TermError> {
+        Vec::<(K, V)>::from_term(term).map(|pairs| pairs.into_iter().collect())
+    }
+}
+
+// Implement `ToTerm` and `FromTerm` for a struct by mapping it to a compound term with one argument per listed field, in the given order:
+//
+//     struct Point { x: i64, y: i64 }
+//     term_struct!(Point => point { x, y });
+//
+// maps `Point { x: 1, y: 2 }` to `point(1, 2)`.
+#[macro_export]
+macro_rules! term_struct {
+    ($ty:ident => $functor:ident { $($field:ident),+ $(,)* }) => {
+        impl $crate::convert::ToTerm for $ty {
+            fn to_term(&self) -> $crate::ir::Value {
+                $crate::ir::Value::Ctor(
+                    stringify!($functor).to_string(),
+                    vec![$($crate::convert::ToTerm::to_term(&self.$field)),+])
+            }
+        }
+
+        impl $crate::convert::FromTerm for $ty {
+            fn from_term(term: &$crate::ir::Value) -> Result<Self, $crate::convert::TermError> {
+                let arity = [$(stringify!($field)),+].len();
+                match term {
+                    $crate::ir::Value::Ctor(f, args) if f == stringify!($functor) && args.len() == arity => {
+                        let mut args = args.iter();
+                        Ok($ty {
+                            $($field: $crate::convert::FromTerm::from_term(args.next().unwrap())?),+
+                        })
+                    }
+                    $crate::ir::Value::LV(_) => Err($crate::convert::TermError::Instantiation),
+                    _ => Err($crate::convert::TermError::Type(stringify!($functor), term.clone())),
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unification::Unification;
+
+    #[derive(Debug,PartialEq)]
+    struct Point {
+        x: i64,
+        y: i64,
+        label: Option<String>,
+    }
+
+    term_struct!(Point => point { x, y, label });
+
+    fn round_trip<T: ToTerm + FromTerm + PartialEq + fmt::Debug>(x: T) {
+        assert_eq!(T::from_term(&x.to_term()), Ok(x));
+    }
+
+    #[test]
+    fn test_primitives() {
+        round_trip(42i64);
+        round_trip(-3i8);
+        round_trip(7u32);
+        round_trip(true);
+        round_trip("foo".to_string());
+        assert_eq!("foo".to_term(), Value::Atom("foo".to_string()));
+        assert_eq!(u8::from_term(&Value::Num(256)), Err(TermError::Range("u8", 256)));
+        assert_eq!(i64::from_term(&Value::LV(0)), Err(TermError::Instantiation));
+        assert_eq!(i64::from_term(&Value::Atom("a".to_string())), Err(TermError::Type("integer", Value::Atom("a".to_string()))));
+    }
+
+    #[test]
+    fn test_containers() {
+        round_trip(vec![1i64, 2, 3]);
+        round_trip(Vec::<i64>::new());
+        round_trip(Some(vec!["a".to_string()]));
+        round_trip(None::<i64>);
+        round_trip((1i64, "b".to_string()));
+        round_trip((1i64, 2i64, (3i64, 4i64)));
+
+        let mut map = HashMap::new();
+        map.insert("one".to_string(), 1i64);
+        map.insert("two".to_string(), 2i64);
+        round_trip(map);
+
+        assert_eq!(vec![1i64].to_term(), Value::Ctor(".".to_string(), vec![Value::Num(1), Value::Atom("[]".to_string())]));
+        let partial = Value::Ctor(".".to_string(), vec![Value::Num(1), Value::LV(3)]);
+        assert_eq!(Vec::<i64>::from_term(&partial), Err(TermError::Instantiation));
+    }
+
+    #[test]
+    fn test_struct() {
+        let p = Point { x: 1, y: 2, label: Some("origin".to_string()) };
+        assert_eq!(p.to_term(), Value::Ctor("point".to_string(), vec![
+            Value::Num(1),
+            Value::Num(2),
+            Value::Ctor("some".to_string(), vec![Value::Atom("origin".to_string())])]));
+        round_trip(p);
+        assert!(Point::from_term(&Value::Ctor("point".to_string(), vec![Value::Num(1)])).is_err());
+    }
+
+    #[test]
+    fn test_resolved_bindings() {
+        // X = point(1, Y, none), Y = 2
+        let x = Value::LV(1);
+        let y = Value::LV(2);
+        let point = Value::Ctor("point".to_string(), vec![Value::Num(1), y.clone(), Value::Atom("none".to_string())]);
+        let bindings = Unification::new().union(&x, &point).unwrap().union(&y, &Value::Num(2)).unwrap();
+        assert_eq!(Point::from_term(&bindings.resolve(&x)), Ok(Point { x: 1, y: 2, label: None }));
+    }
+}
@@ -0,0 +1,402 @@
+// The clauses of the dynamic predicates, which the program can change while it runs
+use crate::ast::*;
+use crate::ast::transform::DcgTranslation;
+use crate::ast_common::*;
+use crate::engine::lower;
+use crate::exceptions::*;
+use crate::ir::*;
+use crate::ir_gen::IRGen;
+use crate::modules::Modules;
+use crate::ops::Ops;
+use crate::parser::{self, Term, TermKind};
+use crate::unification::Unification;
+use crate::writer::Writer;
+use im_rc::Vector;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+// A clause of a dynamic predicate, with the code compiled for it
+#[derive(Debug)]
+pub struct Clause {
+    pub def: PredDef<String>,
+    pub code: Vec<Insn>,
+}
+
+impl Clause {
+    pub fn terms<F: FnMut() -> Value>(&self, fresh: F) -> (Value, Value) {
+        clause_terms(&self.def, fresh)
+    }
+}
+
+// The head and the body of a clause as values, with the variables created by given function
+pub fn clause_terms<F: FnMut() -> Value>(def: &PredDef<String>, mut fresh: F) -> (Value, Value) {
+    let mut vars = HashMap::new();
+    let args: Vec<Value> = def.params.iter().map(|e| expr_value(e, &mut vars, &mut fresh)).collect();
+    let name = def.name.name().to_string();
+    let head = if args.is_empty() { Value::Atom(name) } else { Value::Ctor(name, args) };
+    (head, goal_value(&def.body, &mut vars, &mut fresh))
+}
+
+// A term read at runtime, with its named variables in the order they first occur
+pub struct ReadTerm {
+    pub value: Value,
+    pub variable_names: Vec<(String, Value)>,
+    // the named variables that occur once
+    pub singletons: Vec<(String, Value)>,
+}
+
+// The value of a term read at runtime and the names of its variables, with the variables created by given function
+pub fn read_term<F: FnMut() -> Value>(term: Term, mut fresh: F) -> ReadTerm {
+    let e = parser::expr(term);
+    let mut vars = HashMap::new();
+    let value = expr_value(&e, &mut vars, &mut fresh);
+    let (mut names, mut counts) = (Vec::new(), HashMap::new());
+    count_variables(&e, &mut names, &mut counts);
+    let variable_names: Vec<(String, Value)> = names.into_iter().map(|x| {
+        let v = vars[&x].clone();
+        (x, v)
+    }).collect();
+    let singletons = variable_names.iter().filter(|(x, _)| counts[x] == 1).cloned().collect();
+    ReadTerm { value, variable_names, singletons }
+}
+
+// The value of a term read at runtime, with the variables created by given function
+pub fn read_value<F: FnMut() -> Value>(term: Term, fresh: F) -> Value {
+    read_term(term, fresh).value
+}
+
+// Count the occurrences of the named variables of an expression, and list them in the order they first occur
+fn count_variables(e: &Expr<String>, names: &mut Vec<String>, counts: &mut HashMap<String, usize>) {
+    match e {
+        Expr::PV(x) if x != "_" => {
+            let n = counts.entry(x.clone()).or_insert(0);
+            if *n == 0 {
+                names.push(x.clone());
+            }
+            *n += 1;
+        }
+        Expr::Ctor(_, args) => args.iter().for_each(|arg| count_variables(arg, names, counts)),
+        _ => {}
+    }
+}
+
+// The value of an expression, the variables with the same name share a value except for `_`
+fn expr_value<F: FnMut() -> Value>(e: &Expr<String>, vars: &mut HashMap<String, Value>, fresh: &mut F) -> Value {
+    match e {
+        Expr::Atom(a) => Value::Atom(a.clone()),
+        Expr::Num(n) => Value::Num(*n),
+        Expr::Str(s) => Value::Str(s.clone()),
+        Expr::PV(x) if x == "_" => fresh(),
+        Expr::PV(x) => vars.entry(x.clone()).or_insert_with(&mut *fresh).clone(),
+        Expr::Ctor(f, args) => Value::Ctor(f.clone(), args.iter().map(|arg| expr_value(arg, vars, fresh)).collect()),
+    }
+}
+
+fn ctor(f: &str, args: Vec<Value>) -> Value {
+    Value::Ctor(f.to_string(), args)
+}
+
+// The term a goal is written as
+fn goal_value<F: FnMut() -> Value>(s: &Stmt<String>, vars: &mut HashMap<String, Value>, fresh: &mut F) -> Value {
+    match s {
+        Stmt::And(s1, s2) => ctor(",", vec![goal_value(s1, vars, fresh), goal_value(s2, vars, fresh)]),
+        Stmt::Or(s1, s2) => ctor(";", vec![goal_value(s1, vars, fresh), goal_value(s2, vars, fresh)]),
+        Stmt::If(s1, s2, s3) => {
+            let condition = ctor("->", vec![goal_value(s1, vars, fresh), goal_value(s2, vars, fresh)]);
+            match **s3 {
+                Stmt::Fail => condition,
+                ref s3 => ctor(";", vec![condition, goal_value(s3, vars, fresh)])
+            }
+        }
+        Stmt::Unify(e1, e2) => ctor("=", vec![expr_value(e1, vars, fresh), expr_value(e2, vars, fresh)]),
+        Stmt::Call(p, args, _) => {
+            let args: Vec<Value> = args.iter().map(|e| expr_value(e, vars, fresh)).collect();
+            let goal = if args.is_empty() { Value::Atom(p.name().to_string()) } else { ctor(p.name(), args) };
+            match p {
+                Pred::Sys(_, _) => ctor(":", vec![Value::Atom("sys".to_string()), goal]),
+                Pred::Qualified(m, _) => ctor(":", vec![Value::Atom(m.clone()), goal]),
+                Pred::User(_) => goal
+            }
+        }
+        Stmt::Catch(s1, e, s2) => ctor("catch", vec![goal_value(s1, vars, fresh), expr_value(e, vars, fresh), goal_value(s2, vars, fresh)]),
+        Stmt::Not(s, _) => ctor("\\+", vec![goal_value(s, vars, fresh)]),
+        Stmt::Cut => Value::Atom("!".to_string()),
+        Stmt::True => Value::Atom("true".to_string()),
+        Stmt::Fail => Value::Atom("fail".to_string()),
+    }
+}
+
+// The term of a clause with its variables numbered as '$VAR'(N) in the order they first occur, and the ones that occur once as '$VAR'('_')
+fn numbered_clause(def: &PredDef<String>) -> Value {
+    fn number(value: &Value, counts: &HashMap<LV, usize>, numbers: &mut HashMap<LV, i64>) -> Value {
+        match value {
+            Value::LV(x) if counts[x] == 1 => ctor("$VAR", vec![Value::Atom("_".to_string())]),
+            Value::LV(x) => {
+                let n = numbers.len() as i64;
+                ctor("$VAR", vec![Value::Num(*numbers.entry(*x).or_insert(n))])
+            }
+            Value::Ctor(f, args) => ctor(f, args.iter().map(|arg| number(arg, counts, numbers)).collect()),
+            value => value.clone()
+        }
+    }
+    fn count(value: &Value, counts: &mut HashMap<LV, usize>) {
+        match value {
+            Value::LV(x) => *counts.entry(*x).or_insert(0) += 1,
+            Value::Ctor(_, args) => args.iter().for_each(|arg| count(arg, counts)),
+            _ => {}
+        }
+    }
+
+    let mut n = 0;
+    let (head, body) = clause_terms(def, || { n += 1; Value::LV(n) });
+    let clause = ctor(":-", vec![head, body]);
+    let mut counts = HashMap::new();
+    count(&clause, &mut counts);
+    number(&clause, &counts, &mut HashMap::new())
+}
+
+// The term for a value, the variables are named after their numbers
+fn term(value: &Value) -> Term {
+    let kind = match value {
+        Value::LV(x) => TermKind::Var(format!("_V{}", x)),
+        Value::Atom(a) => TermKind::Atom(a.clone()),
+        Value::Num(n) => TermKind::Num(*n),
+        Value::Str(s) => TermKind::Str(s.clone()),
+        Value::Ctor(f, args) => TermKind::Compound(f.clone(), args.iter().map(term).collect()),
+    };
+    Term::new(kind, Span::default())
+}
+
+// The goal that parses the list s0 with a grammar body, leaving the list s. The variables of the terms are kept, the others are created by given function.
+pub fn grammar_goal<F: FnMut() -> Value>(body: &Value, s0: &Value, s: &Value, mut fresh: F) -> Value {
+    let all = Value::Ctor(GOAL.to_string(), vec![body.clone(), s0.clone(), s.clone()]);
+    // the term of a variable is named after its number, which takes it back to the variable
+    let mut vars = all.variables().into_iter().map(|x| (format!("_V{}", x), Value::LV(x))).collect();
+    let translated = DcgTranslation::new().body(parser::expr(term(body)), parser::expr(term(s0)), parser::expr(term(s)));
+    goal_value(&translated, &mut vars, &mut fresh)
+}
+
+// Split a clause term into its head and its body
+pub fn split_clause(clause: Value) -> (Value, Value) {
+    match clause {
+        Value::Ctor(f, mut args) if f == ":-" && args.len() == 2 => {
+            let body = args.pop().unwrap();
+            (args.pop().unwrap(), body)
+        }
+        head => (head, Value::Atom("true".to_string()))
+    }
+}
+
+// Split the module qualifications off a term, which belongs to given module if it is not qualified
+pub fn strip_module(bindings: &Unification, term: &Value, module: &str) -> Result<(Atom, Value), Exception> {
+    let mut module = module.to_string();
+    let mut term = bindings.find(term);
+    while let Value::Ctor(f, args) = term {
+        if f != ":" || args.len() != 2 {
+            break;
+        }
+        module = match bindings.find(&args[0]) {
+            Value::LV(_) => return Err(instantiation_error()),
+            Value::Atom(m) => m.clone(),
+            culprit => return Err(type_error("atom", culprit.clone()))
+        };
+        term = bindings.find(&args[1]);
+    }
+    Ok((module, term.clone()))
+}
+
+// The predicate that given head belongs to, in given module
+pub fn head_predicate(head: &Value, module: &str) -> Result<PredSig, Exception> {
+    match head {
+        Value::LV(_) => Err(instantiation_error()),
+        Value::Atom(a) => Ok(PredSig(Pred::Qualified(module.to_string(), a.clone()), 0)),
+        Value::Ctor(f, args) => Ok(PredSig(Pred::Qualified(module.to_string(), f.clone()), args.len())),
+        _ => Err(type_error("callable", head.clone()))
+    }
+}
+
+// Convert the term of a clause to its definition. The clause belongs to given module unless it or its head is qualified with another one.
+pub fn clause_def(bindings: &Unification, clause: &Value, module: &str) -> Result<PredDef<String>, Exception> {
+    let (module, clause) = strip_module(bindings, clause, module)?;
+    let (head, body) = split_clause(bindings.resolve(&clause));
+    let (module, head) = strip_module(bindings, &head, &module)?;
+    let sig = head_predicate(&head, &module)?;
+    let clause = Value::Ctor(":-".to_string(), vec![head, body.clone()]);
+    let def = parser::clause(term(&clause)).map_err(|_| type_error("callable", body))?;
+    Ok(PredDef { name: sig.0, ..def })
+}
+
+// The name of the clauses compiled for the goals that are called as terms
+pub const GOAL: &str = "$call";
+
+// The clauses of a dynamic predicate. The list is persistent, so a call keeps seeing the list as it was when the call started.
+pub type Clauses = Vector<Rc<Clause>>;
+
+#[derive(Default)]
+pub struct Database {
+    predicates: im_rc::HashMap<PredSig, Clauses>,
+    // the clauses of the consulted program, which cannot be changed, kept as they were read for clause/2 and listing/1
+    static_predicates: HashMap<PredSig, Vec<PredDef<String>>>,
+    builtins: HashSet<(String, usize)>,
+    modules: Modules,
+}
+
+impl Database {
+    pub fn new(builtins: HashSet<(String, usize)>) -> Self {
+        Database { builtins, ..Default::default() }
+    }
+
+    pub fn modules(&self) -> &Modules {
+        &self.modules
+    }
+
+    pub fn modules_mut(&mut self) -> &mut Modules {
+        &mut self.modules
+    }
+
+    pub fn set_static(&mut self, program: &[PredDef<String>]) {
+        self.static_predicates.clear();
+        for def in program.iter() {
+            self.static_predicates.entry(def.sig()).or_default().push(def.clone());
+        }
+    }
+
+    fn is_builtin(&self, PredSig(p, arity): &PredSig) -> bool {
+        self.builtins.contains(&(p.name().to_string(), *arity))
+    }
+
+    // Whether given predicate has static or dynamic clauses
+    pub fn defines(&self, sig: &PredSig) -> bool {
+        self.static_predicates.contains_key(sig) || self.predicates.contains_key(sig)
+    }
+
+    // The predicate a call to name/arity in given module refers to: a built-in, or the predicate the module defines or imports. Every module but system also sees the predicates of user, and then those of system. A predicate that is not defined anywhere belongs to the module.
+    pub fn resolve(&self, module: &str, name: &str, arity: usize) -> Pred {
+        if self.builtins.contains(&(name.to_string(), arity)) {
+            return Pred::Sys(name.to_string(), arity);
+        }
+        let visible: &[&str] = if module == SYSTEM { &[SYSTEM] } else { &[module, USER, SYSTEM] };
+        for m in visible.iter() {
+            let p = Pred::Qualified(m.to_string(), name.to_string());
+            if self.defines(&PredSig(p.clone(), arity)) {
+                return p;
+            }
+            if let Some(from) = self.modules.imported(m, name, arity) {
+                return Pred::Qualified(from.to_string(), name.to_string());
+            }
+        }
+        Pred::Qualified(module.to_string(), name.to_string())
+    }
+
+    // Raise a permission error if given predicate cannot be changed. The library predicates of system cannot be redefined in another module either.
+    pub fn modifiable(&self, sig: &PredSig) -> Result<(), Exception> {
+        let PredSig(p, arity) = sig;
+        let system = PredSig(Pred::Qualified(SYSTEM.to_string(), p.name().to_string()), *arity);
+        if self.is_builtin(sig) || self.static_predicates.contains_key(sig) || self.static_predicates.contains_key(&system) {
+            Err(permission_error("modify", "static_procedure", qualified_indicator(sig)))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn is_dynamic(&self, sig: &PredSig) -> bool {
+        self.predicates.contains_key(sig)
+    }
+
+    pub fn predicates(&self) -> impl Iterator<Item=&PredSig> {
+        self.predicates.keys()
+    }
+
+    // Make given predicate dynamic, keeping its clauses if it already is
+    pub fn declare(&mut self, sig: PredSig) -> Result<(), Exception> {
+        self.modifiable(&sig)?;
+        if !self.predicates.contains_key(&sig) {
+            self.predicates.insert(sig, Vector::new());
+        }
+        Ok(())
+    }
+
+    pub fn clauses(&self, sig: &PredSig) -> Option<Clauses> {
+        self.predicates.get(sig).cloned()
+    }
+
+    // The definitions of the clauses of given static or dynamic predicate. The built-ins have no clauses to inspect.
+    pub fn definitions(&self, sig: &PredSig) -> Result<Vec<PredDef<String>>, Exception> {
+        if self.is_builtin(sig) {
+            return Err(permission_error("access", "private_procedure", qualified_indicator(sig)));
+        }
+        Ok(match (self.static_predicates.get(sig), self.predicates.get(sig)) {
+            (Some(defs), _) => defs.clone(),
+            (None, Some(clauses)) => clauses.iter().map(|c| c.def.clone()).collect(),
+            (None, None) => Vec::new()
+        })
+    }
+
+    // The clauses of the predicates with given name, and given arity if any, that given module sees, written as source text with the operators of given table
+    pub fn listing(&self, ops: &Ops, module: &str, name: &str, arity: Option<usize>) -> String {
+        let mut sigs: Vec<&PredSig> = self.static_predicates.keys().chain(self.predicates.keys())
+            .filter(|PredSig(p, n)| p.name() == name && arity.is_none_or(|arity| arity == *n) && self.resolve(module, name, *n) == *p)
+            .collect();
+        sigs.sort_by_key(|PredSig(_, n)| *n);
+        sigs.dedup();
+
+        let mut text = String::new();
+        for sig in sigs.into_iter() {
+            if self.is_dynamic(sig) {
+                text.push_str(&format!(":- dynamic {}.\n\n", sig));
+            }
+            let writer = Writer::new(ops, true).number_vars(true);
+            for def in self.definitions(sig).unwrap_or_default().iter() {
+                text.push_str(&writer.clause(&numbered_clause(def)));
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    fn compile(&self, def: PredDef<String>) -> Clause {
+        let sig = def.sig();
+        let mut ig = IRGen::without_entry();
+        ig.compile_program(lower(vec![def.clone()], self));
+        let code = ig.get_ir_program().text.remove(&sig).expect("the clause is compiled");
+        Clause { def, code }
+    }
+
+    // Compile a resolved goal term to a clause of given module that takes the variables of the goal as its arguments, and return it with the variables
+    pub fn goal_clause(&self, goal: &Value, module: &str) -> Result<(Rc<Clause>, Vec<Value>), Exception> {
+        let body = parser::goal(term(goal)).map_err(|_| type_error("callable", goal.clone()))?;
+        let vars = goal.variables();
+        let params = vars.iter().map(|x| Expr::PV(format!("_V{}", x))).collect();
+        let def = PredDef { name: Pred::Qualified(module.to_string(), GOAL.to_string()), ..PredDef::new(GOAL, params, body) };
+        Ok((Rc::new(self.compile(def)), vars.into_iter().map(Value::LV).collect()))
+    }
+
+    // Compile given clause and add it to the start or the end of its predicate, which becomes dynamic if it is not defined
+    pub fn add(&mut self, def: PredDef<String>, at_start: bool) -> Result<(), Exception> {
+        self.declare(def.sig())?;
+        let sig = def.sig();
+        let clause = Rc::new(self.compile(def));
+
+        let clauses = self.predicates.get_mut(&sig).unwrap();
+        if at_start {
+            clauses.push_front(clause);
+        } else {
+            clauses.push_back(clause);
+        }
+        Ok(())
+    }
+
+    // Remove given clause of given predicate, the calls that are running still see it
+    pub fn remove(&mut self, sig: &PredSig, clause: &Rc<Clause>) {
+        if let Some(clauses) = self.predicates.get_mut(sig) {
+            clauses.retain(|c| !Rc::ptr_eq(c, clause));
+        }
+    }
+
+    // Remove all clauses of given predicate and make it undefined
+    pub fn abolish(&mut self, sig: &PredSig) -> Result<(), Exception> {
+        self.modifiable(sig)?;
+        self.predicates.remove(sig);
+        Ok(())
+    }
+}
@@ -2,18 +2,29 @@
 use crate::ast_common::*;
 use crate::ir::*;
 use crate::unification::*;
+use crate::exceptions::*;
+use crate::flags::Flags;
+use crate::ops::Ops;
+use crate::streams::Streams;
+use crate::database::{Clause, Clauses, Database};
 use im_rc::HashMap;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
 
 
 // State local to a frame in the call stack, except for control
 // The operand stack is mutable and copied for checkpoints for now as it is mutated frequently.
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct LocalState {
     // TODO: use a HashTrieMap for persistence, or even better just a Vec
     pub locals: HashMap<usize, Value>, // the environment
     pub op_stack: Vec<Value>, // the operand stack, get rid of this?
     pub predicate: PredSig,
+    // the clause this frame runs if the predicate is dynamic, the code of static predicates is in the program
+    pub clause: Option<Rc<Clause>>,
     pub frame_depth: u32, // depth of this call stack frame, this is incremented on each call hence assigns a unique ID to each call. These are used for constructing checkpoint labels(?)
+    pub cut_barrier: usize, // the height of the checkpoint stack when this frame was called, a cut drops the checkpoints above it
 }
 
 impl LocalState {
@@ -21,11 +32,18 @@ impl LocalState {
         LocalState {
             locals: HashMap::new(),
             op_stack: vec![],
-            frame_depth: frame_depth,
-            predicate: predicate
+            frame_depth,
+            predicate,
+            clause: None,
+            cut_barrier: 0
         }
     }
 
+    // The module the predicate of this frame belongs to, which its goals are called in
+    pub fn module(&self) -> &str {
+        self.predicate.0.module().unwrap_or(USER)
+    }
+
     pub fn push_value(&mut self, v: Value) {
         self.op_stack.push(v);
     }
@@ -34,8 +52,10 @@ impl LocalState {
         self.op_stack.push(self.op_stack.last().unwrap().clone())
     }
 
-    pub fn load(&mut self, pv: usize) {
-        self.op_stack.push(self.locals.get(&pv).unwrap().clone())
+    pub fn load(&mut self, pv: usize) -> Result<(), Exception> {
+        let v = self.locals.get(&pv).ok_or_else(|| system_error("load of an uninitialized variable"))?;
+        self.op_stack.push(v.clone());
+        Ok(())
     }
 
     pub fn store(&mut self, pv: usize) {
@@ -52,8 +72,58 @@ impl LocalState {
     }
 }
 
-// The call stack, each frame consists of the PC and the local state.
-pub type CallStack = Vec<(LocalState, usize)>;
+// The call stack, each frame consists of the PC and the local state. The stack is a persistent list, so the checkpoints share its frames rather than copying them.
+#[derive(Debug,Clone,Default)]
+pub struct CallStack(Option<Rc<Frame>>);
+
+#[derive(Debug)]
+struct Frame {
+    local_state: LocalState,
+    pc: usize,
+    caller: CallStack,
+}
+
+impl CallStack {
+    pub fn push(&mut self, local_state: LocalState, pc: usize) {
+        let caller = std::mem::take(self);
+        self.0 = Some(Rc::new(Frame { local_state, pc, caller }));
+    }
+
+    // Remove the innermost frame, it is copied only if a checkpoint shares it
+    pub fn pop(&mut self) -> Option<(LocalState, usize)> {
+        let frame = self.0.take()?;
+        Some(match Rc::try_unwrap(frame) {
+            Ok(frame) => {
+                *self = frame.caller;
+                (frame.local_state, frame.pc)
+            }
+            Err(frame) => {
+                *self = frame.caller.clone();
+                (frame.local_state.clone(), frame.pc)
+            }
+        })
+    }
+
+    // The frames with their return addresses, innermost first
+    pub fn iter(&self) -> impl Iterator<Item=(&LocalState, usize)> {
+        std::iter::successors(self.0.as_deref(), |frame| frame.caller.0.as_deref())
+            .map(|frame| (&frame.local_state, frame.pc))
+    }
+}
+
+// Dropping the frames one by one rather than recursively, as deep recursion leaves long stacks
+impl Drop for CallStack {
+    fn drop(&mut self) {
+        let mut next = self.0.take();
+        while let Some(frame) = next {
+            next = match Rc::try_unwrap(frame) {
+                Ok(mut frame) => frame.caller.0.take(),
+                // the rest is shared with a checkpoint
+                Err(_) => None
+            };
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Checkpoint {
@@ -61,12 +131,36 @@ pub struct Checkpoint {
     pub local_state: LocalState,
     pub bindings: Unification, // the heap graph, as a Union-Find data structure
     pub pc: usize,
-    pub call_stack: CallStack
+    pub call_stack: CallStack,
+    pub catches: Vec<LV>, // the active catch frames
+    pub catch: Option<(LV, Value)>, // the id and the catcher if this is a catch frame rather than a choice point
+    pub collected: Option<Vec<Value>>, // the solutions found so far if this is the collection of a findall/3 call
+    pub alternatives: Option<Alternatives> // what is left to try if this is the choice point of a dynamic predicate call or a nondeterministic built-in
+}
+
+// The branches of a call that are left to try on backtracking
+#[derive(Debug)]
+pub enum Alternatives {
+    // the clauses of a dynamic predicate, and the arguments of the call
+    Clauses(PredSig, Vec<Value>, Clauses),
+    // the bindings of the solutions of a nondeterministic built-in
+    Bindings(VecDeque<Unification>),
 }
 
 // Stack of check-points
 pub type CPStack = Vec<Checkpoint>;
 
+// An exception that no catch/3 call caught
+#[derive(Debug)]
+pub struct Uncaught {
+    pub ball: Value,
+    // the predicates that were running when the exception was thrown and the pc of the instruction each was executing, innermost first
+    pub frames: Vec<(PredSig, usize)>,
+}
+
+// The result of executing an instruction: the next state if there is a branch left to explore, or an uncaught exception
+pub type Step = Result<Option<State>, Uncaught>;
+
 // State of the whole VM
 pub struct State {
     pub local_state: LocalState,
@@ -74,18 +168,41 @@ pub struct State {
     pub cp_stack: CPStack,
     pub pc: usize,
     pub call_stack: CallStack,
+    // ids of the catch frames whose goal is being executed, innermost last
+    pub catches: Vec<LV>,
+    // the flags, the dynamic predicates and the streams are global, so they are shared rather than restored by checkpoints
+    pub flags: Rc<RefCell<Flags>>,
+    pub ops: Rc<RefCell<Ops>>,
+    pub database: Rc<RefCell<Database>>,
+    pub streams: Rc<RefCell<Streams>>,
     pub gen_idx: LV, // a counter for new symbols, TODO: separate this to a global.
     pub unify_count: usize // count #successful unifications for profiling. TODO: make this global.
 }
 
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl State {
     pub fn new() -> Self {
+        Self::entry(PredSig(Pred::Qualified(USER.to_string(), "main".to_string()), 0))
+    }
+
+    // Initial state that starts executing given predicate, which should halt instead of returning
+    pub fn entry(predicate: PredSig) -> Self {
         State {
-            local_state: LocalState::new(PredSig(Pred::User("main".to_string()), 0), 0),
+            local_state: LocalState::new(predicate, 0),
             bindings: Unification::new(),
             cp_stack: vec![],
             pc: 0,
-            call_stack: vec![],
+            call_stack: CallStack::default(),
+            catches: vec![],
+            flags: Rc::new(RefCell::new(Flags::default())),
+            ops: Rc::new(RefCell::new(Ops::default())),
+            database: Rc::new(RefCell::new(Database::default())),
+            streams: Rc::new(RefCell::new(Streams::default())),
             gen_idx: 0,
             unify_count: 0
         }
@@ -106,24 +223,163 @@ impl State {
     }
 
     // perform unification of the top two stack values.
-    pub fn unify(mut self) -> Option<Self> {
+    pub fn unify(mut self) -> Step {
         if let Some((ref x, ref y)) = self.local_state.pop().and_then(|x| self.local_state.pop().map(|y| (x, y))) {
             if let Some(new_bindings) = self.bindings.union(x, y) {
                 self.bindings = new_bindings;
                 // increment # of successfull unifications
                 self.unify_count += 1;
-                Some(self)
+                Ok(Some(self))
             } else {
-                self.load_next_checkpoint()
+                Ok(self.load_next_checkpoint())
             }
         } else {
-            panic!(format!("Program error at {}:{}. Not enough values to unify!", self.local_state.predicate, self.pc))
+            let PredSig(pred, arity) = self.local_state.predicate.clone();
+            self.raise(system_error("not enough values to unify"), pred.name(), arity)
         }
     }
 
-    // The current branch of execution failed, load the next checkpoint from the checkpoint stack
+    // The current branch of execution failed, load the next checkpoint from the checkpoint stack. Catch frames are not choice points, so they are skipped.
     pub fn load_next_checkpoint(mut self) -> Option<Self> {
-        self.cp_stack.pop().map(|cp| self.load_checkpoint(cp))
+        while let Some(mut cp) = self.cp_stack.pop() {
+            if cp.catch.is_none() {
+                let alternatives = cp.alternatives.take();
+                let collected = cp.collected.take();
+                let mut s = self.load_checkpoint(cp);
+                // a findall/3 call continues with the list of the solutions of its goal once there are no more
+                if let Some(solutions) = collected {
+                    s.local_state.push_value(Value::list(solutions));
+                    return Some(s);
+                }
+                return match alternatives {
+                    Some(Alternatives::Clauses(predicate, args, clauses)) => s.try_clauses(predicate, args, clauses),
+                    Some(Alternatives::Bindings(solutions)) => s.try_bindings(solutions),
+                    None => Some(s)
+                };
+            }
+        }
+        None
+    }
+
+    fn checkpoint(&self, label: Label, pc: usize) -> Checkpoint {
+        Checkpoint {
+            label: (self.local_state.frame_depth, label),
+            local_state: self.local_state.clone(),
+            bindings: self.bindings.clone(),
+            pc,
+            call_stack: self.call_stack.clone(),
+            catches: self.catches.clone(),
+            catch: None,
+            collected: None,
+            alternatives: None
+        }
+    }
+
+    // Save the current state as a checkpoint that resumes execution at given pc
+    pub fn push_checkpoint(&mut self, label: Label, pc: usize) {
+        let cp = self.checkpoint(label, pc);
+        self.cp_stack.push(cp);
+    }
+
+    // Start collecting the solutions of a findall/3 call, the list of the solutions is pushed at given pc
+    pub fn push_collector(&mut self, pc: usize) {
+        let cp = self.checkpoint(0, pc);
+        self.cp_stack.push(Checkpoint { collected: Some(Vec::new()), ..cp });
+    }
+
+    // Add a copy of given solution to the innermost collection. Returns false if there is no collection.
+    pub fn collect(&mut self, solution: &Value) -> bool {
+        let solution = self.copy(solution);
+        match self.cp_stack.iter_mut().rev().find_map(|cp| cp.collected.as_mut()) {
+            Some(solutions) => {
+                solutions.push(solution);
+                true
+            }
+            None => false
+        }
+    }
+
+    // A copy of given value with fresh variables, which does not share anything with the bindings
+    pub fn copy(&mut self, value: &Value) -> Value {
+        fn rename(value: Value, vars: &mut std::collections::HashMap<LV, Value>, s: &mut State) -> Value {
+            match value {
+                Value::LV(x) => vars.entry(x).or_insert_with(|| s.fresh_lv()).clone(),
+                Value::Ctor(f, args) => Value::Ctor(f, args.into_iter().map(|arg| rename(arg, vars, s)).collect()),
+                value => value
+            }
+        }
+        let value = self.bindings.resolve(value);
+        rename(value, &mut std::collections::HashMap::new(), self)
+    }
+
+    // Enter the goal of a catch/3 call. The handler for the exceptions unifying with the catcher starts at given pc. The cuts in the goal drop the checkpoints down to the catch frame, which has given label.
+    pub fn push_catch(&mut self, label: Label, catcher: Value, pc: usize) {
+        let id = match self.fresh_lv() {
+            Value::LV(id) => id,
+            _ => unreachable!()
+        };
+        let cp = self.checkpoint(label, pc);
+        self.cp_stack.push(Checkpoint { catch: Some((id, catcher)), ..cp });
+        self.catches.push(id);
+    }
+
+    // Exit the goal of the innermost catch/3 call, its catch frame is dropped if the goal left no choice points
+    pub fn exit_catch(&mut self) {
+        if let Some(id) = self.catches.pop() {
+            if let Some(Checkpoint { catch: Some((top, _)), .. }) = self.cp_stack.last() {
+                if *top == id {
+                    self.cp_stack.pop();
+                }
+            }
+        }
+    }
+
+    // Unwind the checkpoint stack to the innermost active catch frame whose catcher unifies with given ball, and continue with its handler
+    pub fn throw(mut self, ball: Value) -> Step {
+        // copy the ball out of the bindings that are about to be discarded
+        let ball = self.bindings.resolve(&ball);
+        // the pc is advanced before executing an instruction, and the return address follows the call instruction
+        let frames = std::iter::once((&self.local_state, self.pc))
+            .chain(self.call_stack.iter())
+            .map(|(local_state, pc)| (local_state.predicate.clone(), pc.saturating_sub(1)))
+            .collect();
+        while let Some(cp) = self.cp_stack.pop() {
+            let handler = match &cp.catch {
+                Some((id, catcher)) if self.catches.contains(id) => cp.bindings.union(catcher, &ball),
+                _ => None
+            };
+            if let Some(bindings) = handler {
+                let mut s = self.load_checkpoint(cp);
+                s.bindings = bindings;
+                return Ok(Some(s));
+            }
+        }
+        Err(Uncaught { ball, frames })
+    }
+
+    // Raise given exception in the context of given predicate
+    pub fn raise(mut self, e: Exception, name: &str, arity: usize) -> Step {
+        let message = self.fresh_lv();
+        self.raise_with(e, name, arity, message)
+    }
+
+    // Raise given exception in the context of given predicate, with a message explaining the error
+    pub fn raise_with(self, e: Exception, name: &str, arity: usize, message: Value) -> Step {
+        let context = Value::Ctor("context".to_string(), vec![predicate_indicator(name, arity), message]);
+        self.throw(e.into_ball(context))
+    }
+
+    // Drop the checkpoints created since the checkpoint with given label in the current frame. The labelled checkpoint itself is dropped too if `inclusive` is set.
+    pub fn cut_to(&mut self, label: Label, inclusive: bool) {
+        let label = (self.local_state.frame_depth, label);
+        if let Some(i) = self.cp_stack.iter().rposition(|cp| cp.label == label) {
+            self.cp_stack.truncate(if inclusive { i } else { i + 1 });
+        }
+    }
+
+    // Drop the checkpoints created since the current frame was called, which include the choice point for the other clauses of its predicate
+    pub fn cut(&mut self) {
+        self.cp_stack.truncate(self.local_state.cut_barrier);
     }
 
     // Consume this state and given checkpoint to load the checkpoint as the state
@@ -132,17 +388,77 @@ impl State {
         self.bindings = cp.bindings;
         self.pc = cp.pc;
         self.call_stack = cp.call_stack;
+        self.catches = cp.catches;
         self
     }
 
     // Make a user predicate call, saves the local state and enters the predicate's body
-    pub fn call_user(&mut self, pred: &str, argc: usize) {
+    pub fn call_user(&mut self, predicate: PredSig) {
+        let args = self.local_state.pop_n(predicate.1);
+        let cut_barrier = self.cp_stack.len();
+        self.enter(predicate, None, args, cut_barrier);
+    }
+
+    // Call a dynamic predicate with given clauses, the clauses after the first one are tried on backtracking
+    pub fn call_dynamic(mut self, predicate: PredSig, clauses: Clauses) -> Option<State> {
+        let args = self.local_state.pop_n(predicate.1);
+        self.try_clauses(predicate, args, clauses)
+    }
+
+    // Call a clause compiled for a goal with given arguments
+    pub fn call_clause(&mut self, clause: Rc<Clause>, mut args: Vec<Value>) {
+        let predicate = PredSig(clause.def.name.clone(), args.len());
+        // the first argument goes on the top of the stack, as if the arguments were pushed right-to-left
+        args.reverse();
+        let cut_barrier = self.cp_stack.len();
+        self.enter(predicate, Some(clause), args, cut_barrier);
+    }
+
+    // Enter the next clause of a dynamic predicate call, leaving a choice point for the rest
+    fn try_clauses(mut self, predicate: PredSig, args: Vec<Value>, mut clauses: Clauses) -> Option<State> {
+        let clause = match clauses.pop_front() {
+            Some(clause) => clause,
+            None => return self.load_next_checkpoint()
+        };
+        // a cut in the clause drops the choice point for the rest of the clauses too
+        let cut_barrier = self.cp_stack.len();
+        if !clauses.is_empty() {
+            // the choice point does not have a label, so the cuts in the caller never target it
+            let cp = self.checkpoint(0, self.pc);
+            let alternatives = Alternatives::Clauses(predicate.clone(), args.clone(), clauses);
+            self.cp_stack.push(Checkpoint { alternatives: Some(alternatives), ..cp });
+        }
+        self.enter(predicate, clause.into(), args, cut_barrier);
+        Some(self)
+    }
+
+    // Continue with the bindings of the next solution of a nondeterministic built-in, leaving a choice point for the rest
+    pub fn try_bindings(mut self, mut solutions: VecDeque<Unification>) -> Option<State> {
+        let bindings = match solutions.pop_front() {
+            Some(bindings) => bindings,
+            None => return self.load_next_checkpoint()
+        };
+        if !solutions.is_empty() {
+            let cp = self.checkpoint(0, self.pc);
+            self.cp_stack.push(Checkpoint { alternatives: Some(Alternatives::Bindings(solutions)), ..cp });
+        }
+        self.bindings = bindings;
+        Some(self)
+    }
+
+    // Save the local state and enter the code of given predicate or clause, which takes given arguments on its operand stack. The cuts in the code drop the checkpoints down to given height.
+    fn enter(&mut self, predicate: PredSig, clause: Option<Rc<Clause>>, args: Vec<Value>, cut_barrier: usize) {
         // load the new local state and extract the current one
         let new_frame_depth = self.local_state.frame_depth + 1;
-        let last_frame = std::mem::replace(&mut self.local_state, LocalState::new(PredSig(Pred::User(pred.to_string()), argc), new_frame_depth));
+        let mut new_frame = LocalState::new(predicate, new_frame_depth);
+        new_frame.clause = clause;
+        new_frame.cut_barrier = cut_barrier;
+        // pass the arguments on the callee's operand stack, keeping their order
+        new_frame.op_stack = args;
+        let last_frame = std::mem::replace(&mut self.local_state, new_frame);
         // save the return address
-        self.call_stack.push((last_frame, self.pc));
+        self.call_stack.push(last_frame, self.pc);
         // move the PC to the beginning
         self.pc = 0;
     }
-}
\ No newline at end of file
+}
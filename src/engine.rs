@@ -0,0 +1,1839 @@
+// The embedding interface. An engine holds the consulted program and answers queries against it.
+
+use crate::ast::*;
+use crate::ast::check::{Diagnostic, StaticCheck};
+use crate::ast::transform::*;
+use crate::ast_common::*;
+use crate::convert::{FromTerm, TermError};
+use crate::database::Database;
+use crate::flags::Flags;
+use crate::ir::{self, Value};
+use crate::ir_gen::IRGen;
+use crate::ops::Ops;
+use crate::parser::{self, ParseError, Reader};
+use crate::vm::{Frame, VM};
+use std::cell::{Ref, RefMut};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Name of the predicate queries are compiled into. Names starting with $ cannot be written in source programs.
+const QUERY: &str = "$query";
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum EngineError {
+    Parse(Vec<ParseError>),
+    Io(String),
+    // an exception that was not caught by the program, with its ball and the stack of calls that raised it
+    Exception(Value, Vec<Frame>),
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::Parse(errors) => {
+                for (i, e) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", e)?;
+                }
+                Ok(())
+            }
+            EngineError::Io(msg) => write!(f, "cannot read {}", msg),
+            EngineError::Exception(ball, backtrace) => {
+                write!(f, "uncaught exception: {}", ball)?;
+                for frame in backtrace.iter() {
+                    write!(f, "\n    in {}", frame)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+// A problem found while loading source text, which does not stop the loading
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Warning {
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.span.is_known() {
+            write!(f, "{}: ", self.span)?;
+        }
+        write!(f, "warning: {}", self.message)
+    }
+}
+
+// The state of loading a source text, which is shared with the files it includes
+#[derive(Default)]
+struct Load {
+    // the module the clauses belong to, and the module the text declares if it is a module file
+    module: Atom,
+    declared: Option<Atom>,
+    clauses: Program<String>,
+    errors: Vec<ParseError>,
+    warnings: Vec<Warning>,
+    // the goals to run once the text is loaded, with the positions of their directives
+    initialization: Vec<(Stmt<String>, Span)>,
+    discontiguous: HashSet<PredSig>,
+    // the predicate of the last clause, and the predicates whose clauses came before it
+    last: Option<PredSig>,
+    finished: HashSet<PredSig>,
+}
+
+impl Load {
+    fn warn(&mut self, span: Span, message: String) {
+        self.warnings.push(Warning { span, message });
+    }
+
+    fn new(module: &str) -> Self {
+        Load { module: module.to_string(), ..Default::default() }
+    }
+
+    fn add(&mut self, clause: PredDef<String>) {
+        let clause = PredDef { name: clause.name.in_module(&self.module), ..clause };
+        let sig = clause.sig();
+        if self.last.as_ref() != Some(&sig) {
+            if self.finished.contains(&sig) && !self.discontiguous.contains(&sig) {
+                self.warn(clause.span.clone(), format!("clauses of {} are not together, declare it discontiguous if this is intended", sig));
+            }
+            if let Some(last) = self.last.replace(sig) {
+                self.finished.insert(last);
+            }
+        }
+        self.clauses.push(clause);
+    }
+}
+
+// The path of a file named in a directive of given file. Relative names are relative to the directory of the file, and the .pl extension may be left out.
+fn resolve(file: Option<&str>, name: &str) -> PathBuf {
+    let dir = file.and_then(|f| Path::new(f).parent()).unwrap_or_else(|| Path::new(""));
+    let path = dir.join(name);
+    if path.extension().is_none() && !path.exists() {
+        path.with_extension("pl")
+    } else {
+        path
+    }
+}
+
+// Lower a program to the form the IR generator accepts: a single definition per predicate, calls resolved to the predicates of the database they refer to and numbered variables.
+pub fn lower(program: Program<String>, database: &Database) -> Program<usize> {
+    IdempotentElim::new().transform(
+        EnumerateVariables::new().transform(
+            ResolveModules::new(database).transform(
+                UnderscoreElim::new().transform(
+                    ConsolidateDefs::new().transform(program)))))
+}
+
+pub struct Engine {
+    // clauses consulted so far, they are compiled together as more clauses are added
+    clauses: Program<String>,
+    // the files consulted so far, with the module each declares if it is a module file
+    loaded: HashMap<PathBuf, Option<Atom>>,
+    warnings: Vec<Warning>,
+    // the clauses loaded since the outermost load started, which are checked once it is done
+    unchecked: Option<Program<String>>,
+    // the goal declared by `initialization(Goal, main)`
+    main: Option<Stmt<String>>,
+    vm: VM,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The library predicates written in Prolog, which every engine loads into the system module. The modules they declare are imported into system, so every module sees them.
+const PRELUDE: [(&str, &str); 5] = [
+    ("prelude/lists.pl", include_str!("prelude/lists.pl")),
+    ("prelude/sort.pl", include_str!("prelude/sort.pl")),
+    ("prelude/io.pl", include_str!("prelude/io.pl")),
+    ("prelude/dcg.pl", include_str!("prelude/dcg.pl")),
+    ("prelude/database.pl", include_str!("prelude/database.pl")),
+];
+
+impl Engine {
+    pub fn new() -> Self {
+        let mut vm = VM::new(ir::Program::default());
+        vm.state = None;
+        let mut engine = Engine {
+            clauses: Vec::new(),
+            loaded: HashMap::new(),
+            warnings: Vec::new(),
+            unchecked: None,
+            main: None,
+            vm
+        };
+        for (file, source) in PRELUDE.iter() {
+            engine.consult_source(SYSTEM, Some(file), source, None).expect("the prelude has no syntax errors");
+        }
+        assert!(engine.warnings.is_empty(), "the prelude has warnings: {:?}", engine.warnings);
+        engine
+    }
+
+    // Add the clauses in given source text to the program
+    pub fn consult(&mut self, source: &str) -> Result<(), EngineError> {
+        self.consult_source(USER, None, source, None)
+    }
+
+    // Add the clauses in given file to the program, their positions refer to the file
+    pub fn consult_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), EngineError> {
+        self.consult_path(USER, path.as_ref(), None)
+    }
+
+    // Consult given file into given module
+    fn consult_path(&mut self, module: &str, path: &Path, imports: Option<Vec<PredSig>>) -> Result<(), EngineError> {
+        let source = fs::read_to_string(path).map_err(|e| EngineError::Io(format!("{}: {}", path.display(), e)))?;
+        self.loaded.insert(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()), None);
+        self.consult_source(module, Some(&path.display().to_string()), &source, imports)
+    }
+
+    // Add the clauses in given source text, which comes from given file if any, to given module and run its directives. If the text is a module file, the module imports its exports, or the listed ones. The clauses without syntax errors are added even if there are errors in others.
+    fn consult_source(&mut self, module: &str, file: Option<&str>, source: &str, imports: Option<Vec<PredSig>>) -> Result<(), EngineError> {
+        // the files a text loads are checked with it, once all of them are loaded
+        let outermost = self.unchecked.is_none();
+        if outermost {
+            self.unchecked = Some(Vec::new());
+        }
+        let mut load = Load::new(module);
+        self.load(file, source, &mut load);
+        if let Some(file) = file {
+            self.loaded.insert(fs::canonicalize(file).unwrap_or_else(|_| PathBuf::from(file)), load.declared.clone());
+        }
+        self.consult_clauses(load.clauses);
+        if let Some(ref declared) = load.declared {
+            self.import(module, declared, imports, &Span::default(), &mut load.warnings);
+        }
+        self.warnings.append(&mut load.warnings);
+        if outermost {
+            let clauses = self.unchecked.take().unwrap_or_default();
+            let mut findings = self.check_clauses(clauses);
+            self.warnings.append(&mut findings);
+        }
+        for (goal, span) in load.initialization.into_iter() {
+            self.initialize(goal, span);
+        }
+        if load.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(EngineError::Parse(load.errors))
+        }
+    }
+
+    // Read the items of given source text into the load, the directives take effect as they are read
+    fn load(&mut self, file: Option<&str>, source: &str, load: &mut Load) {
+        let mut reader = Reader::new(file, source);
+        // the operator table is not borrowed while a directive runs, since the directive may change it
+        loop {
+            reader.set_double_quotes(self.flags().double_quotes);
+            let result = parser::next(&mut reader, &self.vm.ops.borrow(), parser::item);
+            match result {
+                // grammar rules are added as the clauses they stand for
+                Some(Ok(Item::Clause(clause))) => DcgTranslation::new().transform(vec![clause]).into_iter().for_each(|c| load.add(c)),
+                Some(Ok(Item::Directive(directive, span))) => self.directive(file, directive, span, load),
+                Some(Err(e)) => load.errors.push(e),
+                None => break
+            }
+        }
+    }
+
+    fn directive(&mut self, file: Option<&str>, directive: Directive<String>, span: Span, load: &mut Load) {
+        match directive {
+            Directive::Dynamic(sigs) => {
+                for PredSig(p, arity) in sigs.into_iter() {
+                    let sig = PredSig(p.in_module(&load.module), arity);
+                    if self.vm.database.borrow_mut().declare(sig.clone()).is_err() {
+                        load.warn(span.clone(), format!("cannot declare {} dynamic, it is a static procedure", sig));
+                    }
+                }
+            }
+            Directive::Discontiguous(sigs) => {
+                let module = load.module.clone();
+                load.discontiguous.extend(sigs.into_iter().map(|PredSig(p, arity)| PredSig(p.in_module(&module), arity)));
+            }
+            Directive::Initialization(goal) => load.initialization.push((parser::qualify(&load.module, goal), span)),
+            Directive::Main(goal) => self.main = Some(goal),
+            Directive::Op(priority, op_type, names) => {
+                for name in names.iter() {
+                    self.vm.ops.borrow_mut().add(priority, op_type, name);
+                }
+            }
+            Directive::SetFlag(name, value) => {
+                let set = match value {
+                    Expr::Atom(ref a) => self.flags_mut().set(&name, &Value::Atom(a.clone())).is_ok(),
+                    _ => false
+                };
+                if !set {
+                    load.warn(span, format!("cannot set flag {} to {}", name, value));
+                }
+            }
+            Directive::Include(name) => {
+                let path = resolve(file, &name);
+                match fs::read_to_string(&path) {
+                    Ok(source) => self.load(Some(&path.display().to_string()), &source, load),
+                    Err(e) => load.warn(span, format!("cannot include {}: {}", path.display(), e))
+                }
+            }
+            Directive::EnsureLoaded(name) => {
+                let path = resolve(file, &name);
+                if self.loaded.contains_key(&fs::canonicalize(&path).unwrap_or_else(|_| path.clone())) {
+                    return;
+                }
+                let module = load.module.clone();
+                self.consult_module_file(&module, &path, None, span, load);
+            }
+            Directive::Module(name, exports) => {
+                let exports = exports.into_iter().map(|PredSig(p, arity)| (p.name().to_string(), arity)).collect();
+                self.vm.database.borrow_mut().modules_mut().declare(&name, exports);
+                load.module = name.clone();
+                load.declared = Some(name);
+            }
+            Directive::UseModule(source, imports) => {
+                let module = load.module.clone();
+                let name = match source {
+                    Source::Library(name) if self.vm.database.borrow().modules().exists(&name) => name,
+                    Source::Library(name) => return load.warn(span, format!("cannot find library({})", name)),
+                    Source::File(name) => {
+                        let path = resolve(file, &name);
+                        match self.loaded.get(&fs::canonicalize(&path).unwrap_or_else(|_| path.clone())) {
+                            Some(Some(declared)) => declared.clone(),
+                            // the file was loaded, but it does not declare a module to import
+                            Some(None) => return,
+                            // a module consulted from source text rather than from its file
+                            None if !path.exists() && self.vm.database.borrow().modules().exists(&name) => name,
+                            None => return self.consult_module_file(&module, &path, imports, span, load)
+                        }
+                    }
+                };
+                self.import(&module, &name, imports, &span, &mut load.warnings);
+            }
+            Directive::MetaPredicate(specs) => {
+                let mut database = self.vm.database.borrow_mut();
+                for (PredSig(p, arity), spec) in specs.into_iter() {
+                    database.modules_mut().set_meta(PredSig(p.in_module(&load.module), arity), spec);
+                }
+            }
+            Directive::Unknown(d) => load.warn(span, format!("unknown directive {}", d)),
+        }
+    }
+
+    // Consult a file that a directive of the text being loaded names, into given module
+    fn consult_module_file(&mut self, module: &str, path: &Path, imports: Option<Vec<PredSig>>, span: Span, load: &mut Load) {
+        match self.consult_path(module, path, imports) {
+            Ok(()) => {}
+            Err(EngineError::Parse(mut errors)) => load.errors.append(&mut errors),
+            Err(e) => load.warn(span, e.to_string())
+        }
+    }
+
+    // Import the predicates a module exports, or the listed ones, into another module. A predicate that is already imported from a third module keeps that import, and the clash is reported.
+    fn import(&mut self, into: &str, from: &str, imports: Option<Vec<PredSig>>, span: &Span, warnings: &mut Vec<Warning>) {
+        if into == from {
+            return;
+        }
+        let mut database = self.vm.database.borrow_mut();
+        let modules = database.modules_mut();
+        let names = match imports {
+            Some(sigs) => sigs.into_iter().map(|PredSig(p, arity)| (p.name().to_string(), arity)).collect(),
+            None => modules.exports(from).to_vec()
+        };
+        for (name, arity) in names.into_iter() {
+            if let Err(other) = modules.import(into, from, &name, arity) {
+                let message = format!("cannot import {}:{}/{} into {}, it is already imported from {}", from, name, arity, into, other);
+                warnings.push(Warning { span: span.clone(), message });
+            }
+        }
+    }
+
+    // Run an initialization goal once, a failure or an exception is reported as a warning
+    fn initialize(&mut self, goal: Stmt<String>, span: Span) {
+        let message = match self.query_stmt(goal).next() {
+            Some(Ok(_)) => return,
+            None => "initialization goal failed".to_string(),
+            Some(Err(EngineError::Exception(ball, _))) => format!("initialization goal raised {}", ball),
+            Some(Err(e)) => format!("initialization goal: {}", e)
+        };
+        self.warnings.push(Warning { span, message });
+    }
+
+    // Add given clauses to the program, the ones that are not qualified belong to user. The clauses of dynamic predicates go to the database, the rest are compiled together with the static clauses consulted before.
+    pub fn consult_clauses(&mut self, clauses: Program<String>) {
+        let clauses: Program<String> = clauses.into_iter().map(|c| PredDef { name: c.name.in_module(USER), ..c }).collect();
+        if let Some(ref mut unchecked) = self.unchecked {
+            unchecked.extend(clauses.iter().cloned());
+        }
+        let (dynamic, added): (Program<String>, Program<String>) = {
+            let database = self.vm.database.borrow();
+            clauses.into_iter().partition(|c| database.is_dynamic(&c.sig()))
+        };
+        for clause in dynamic.into_iter() {
+            self.vm.database.borrow_mut().add(clause, false).expect("dynamic predicates are modifiable");
+        }
+        self.clauses.extend(added);
+        // the calls are resolved against all the static predicates, including the ones being added
+        self.vm.database.borrow_mut().set_static(&self.clauses);
+        let mut ig = IRGen::new();
+        ig.compile_program(lower(self.clauses.clone(), &self.vm.database.borrow()));
+        self.vm.program = ig.get_ir_program();
+    }
+
+    // The findings of the static checks over given clauses that were just loaded, as warnings
+    fn check_clauses(&self, clauses: Program<String>) -> Vec<Warning> {
+        let part = lower(clauses, &self.vm.database.borrow());
+        StaticCheck::new(self.vm.builtins().signatures()).check_part(&part, &self.program()).into_iter()
+            .map(|d| Warning { span: d.location.span, message: format!("in {}: {}", d.location.predicate, d.finding) })
+            .collect()
+    }
+
+    // The static clauses, with an empty definition for each dynamic predicate so that the checks know about them
+    fn program(&self) -> Program<String> {
+        let mut program = self.clauses.clone();
+        for PredSig(name, arity) in self.vm.database.borrow().predicates() {
+            let params = vec![Expr::PV("_".to_string()); *arity];
+            program.push(PredDef { name: name.clone(), params, body: Stmt::Fail, span: Span::default() });
+        }
+        program
+    }
+
+    // The warnings from loading source text since the last call
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    // The goal a consulted file declared with `initialization(Goal, main)`, to be run once all files are loaded
+    pub fn take_main(&mut self) -> Option<Stmt<String>> {
+        self.main.take()
+    }
+
+    // Run the static checks over the program consulted so far
+    pub fn check(&self) -> Vec<Diagnostic> {
+        StaticCheck::new(self.vm.builtins().signatures()).check(&lower(self.program(), &self.vm.database.borrow()))
+    }
+
+    // The operators the source text, the queries and the terms read at runtime are read with
+    pub fn ops(&self) -> Ref<'_, Ops> {
+        self.vm.ops.borrow()
+    }
+
+    pub fn ops_mut(&mut self) -> RefMut<'_, Ops> {
+        self.vm.ops.borrow_mut()
+    }
+
+    pub fn flags(&self) -> Ref<'_, Flags> {
+        self.vm.flags.borrow()
+    }
+
+    pub fn flags_mut(&mut self) -> RefMut<'_, Flags> {
+        self.vm.flags.borrow_mut()
+    }
+
+    // Write the text the programs write to user_error, and the warnings of the unknown flag, to given writer instead of the standard error
+    pub fn set_user_error(&mut self, out: Box<dyn Write>) {
+        self.vm.streams.borrow_mut().set_user_error(out);
+    }
+
+    // Start solving given goal. The solutions are computed lazily as the query is iterated.
+    pub fn query(&mut self, goal: &str) -> Result<Query<'_>, EngineError> {
+        let goal = parser::parse_query(goal, &self.vm.ops.borrow(), self.flags().double_quotes).map_err(|e| EngineError::Parse(vec![e]))?;
+        Ok(self.query_stmt(goal))
+    }
+
+    pub fn query_stmt(&mut self, goal: Stmt<String>) -> Query<'_> {
+        let sig = PredSig(Pred::Qualified(USER.to_string(), QUERY.to_string()), 0);
+        let query = ResolveModules::new(&self.vm.database.borrow()).transform(
+            UnderscoreElim::new().transform(vec![PredDef::new(QUERY, Vec::new(), goal)]));
+        // keep the variable enumeration to report the bindings of the query variables
+        let mut enumeration = EnumerateVariables::new();
+        let lowered = IdempotentElim::new().transform(enumeration.enumerate(query));
+        let mut variables: Vec<(String, usize)> = enumeration.variables(&sig)
+            .map(|vars| vars.iter()
+                 .filter(|(name, _)| !name.starts_with('_'))
+                 .map(|(name, n)| (name.clone(), *n))
+                 .collect())
+            .unwrap_or_default();
+        variables.sort_by_key(|(_, n)| *n);
+
+        let mut ig = IRGen::with_entry(sig.clone());
+        ig.compile_program(lowered);
+        self.vm.program.extend(ig.get_ir_program());
+        self.vm.start(sig);
+
+        Query { engine: self, variables, started: false }
+    }
+}
+
+// A running query, which is an iterator over its solutions
+pub struct Query<'a> {
+    engine: &'a mut Engine,
+    // names and numbers of the variables in the query
+    variables: Vec<(String, usize)>,
+    started: bool,
+}
+
+impl<'a> Query<'a> {
+    // The names of the variables in the query, in the order of their first occurrence
+    pub fn variables(&self) -> impl Iterator<Item=&str> {
+        self.variables.iter().map(|(name, _)| name.as_str())
+    }
+
+    // Compute the next solution. An uncaught exception is reported once, then the query has no more solutions.
+    pub fn next_solution(&mut self) -> Result<Option<Solution>, EngineError> {
+        let vm = &mut self.engine.vm;
+        if self.started {
+            vm.backtrack();
+        } else {
+            self.started = true;
+            vm.run();
+        }
+
+        if let Some(ball) = vm.exception.take() {
+            return Err(EngineError::Exception(ball, std::mem::take(&mut vm.backtrace)));
+        }
+
+        let variables = &self.variables;
+        Ok(vm.state.as_ref().map(|state| Solution {
+            bindings: variables.iter().map(|(name, n)| {
+                let value = state.local_state.locals.get(n).map(|v| state.bindings.resolve(v));
+                (name.clone(), value.unwrap_or(Value::LV(-1)))
+            }).collect()
+        }))
+    }
+}
+
+impl<'a> Iterator for Query<'a> {
+    type Item = Result<Solution, EngineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_solution().transpose()
+    }
+}
+
+// The bindings of the query variables in a solution
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Solution {
+    bindings: Vec<(String, Value)>,
+}
+
+impl Solution {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.bindings.iter().find(|(x, _)| x == name).map(|(_, v)| v)
+    }
+
+    // Convert the binding of given variable. Variables that are not in the query are unbound.
+    pub fn extract<T: FromTerm>(&self, name: &str) -> Result<T, TermError> {
+        self.get(name).map_or(Err(TermError::Instantiation), T::from_term)
+    }
+
+    pub fn bindings(&self) -> &[(String, Value)] {
+        &self.bindings
+    }
+}
+
+impl fmt::Display for Solution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.bindings.is_empty() {
+            return write!(f, "true");
+        }
+
+        for (i, (x, v)) in self.bindings.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} = {}", x, v)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::{DoubleQuotes, Unknown};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn solutions(engine: &mut Engine, goal: &str) -> Vec<Solution> {
+        engine.query(goal).unwrap().collect::<Result<_, _>>().unwrap()
+    }
+
+    fn uncaught(engine: &mut Engine, goal: &str) -> Value {
+        match engine.query(goal).unwrap().next() {
+            Some(Err(EngineError::Exception(ball, _))) => ball,
+            other => panic!("expected {} to raise an exception, got {:?}", goal, other)
+        }
+    }
+
+    // The formal term of the error given goal raises
+    fn formal(engine: &mut Engine, goal: &str) -> Value {
+        let s = &solutions(engine, &format!("catch({}, error(E, _), true)", goal))[0];
+        s.get("E").cloned().unwrap()
+    }
+
+    // The formal term of the error given goal raises, and the predicate indicator of its context
+    fn formal_in_context(engine: &mut Engine, goal: &str) -> (Value, Value) {
+        let s = &solutions(engine, &format!("catch({}, error(E, context(P, _)), true)", goal))[0];
+        (s.get("E").cloned().unwrap(), s.get("P").cloned().unwrap())
+    }
+
+    // A ground term written in source syntax, the double-quoted items are strings
+    fn term(source: &str) -> Value {
+        fn convert(e: Expr<String>) -> Value {
+            match e {
+                Expr::Atom(a) => Value::Atom(a),
+                Expr::Num(n) => Value::Num(n),
+                Expr::Str(s) => Value::Str(s),
+                Expr::Ctor(f, args) => Value::Ctor(f, args.into_iter().map(convert).collect()),
+                Expr::PV(x) => panic!("unexpected variable {} in a ground term", x)
+            }
+        }
+        convert(parser::expr(parser::parse_term(source, &Ops::default(), DoubleQuotes::String).unwrap()))
+    }
+
+    // A writer that keeps the text written to it, to look at what a stream received
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Capture {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_facts_and_rules() {
+        let mut engine = Engine::new();
+        engine.consult("
+            parent(tom, bob).
+            parent(bob, ann).
+            parent(bob, pat).
+            grandparent(X, Z) :- parent(X, Y), parent(Y, Z).
+        ").unwrap();
+
+        let answers: Vec<String> = solutions(&mut engine, "grandparent(tom, X)").iter()
+            .map(|s| s.extract("X").unwrap())
+            .collect();
+        assert_eq!(answers, vec!["ann".to_string(), "pat".to_string()]);
+
+        assert!(solutions(&mut engine, "grandparent(bob, X)").is_empty());
+        assert_eq!(solutions(&mut engine, "parent(tom, bob).").len(), 1);
+    }
+
+    #[test]
+    fn test_control() {
+        let mut engine = Engine::new();
+        engine.consult("
+            color(red). color(green). color(blue).
+            first(X) :- (color(X) -> true ; X = none).
+        ").unwrap();
+
+        assert_eq!(solutions(&mut engine, "first(X)").len(), 1);
+        assert_eq!(solutions(&mut engine, "(X = a ; X = b), Y = f(X)").len(), 2);
+        assert_eq!(solutions(&mut engine, "(X = a -> fail ; true)").len(), 0);
+        assert_eq!(solutions(&mut engine, "(fail -> X = a ; X = b)")[0].get("X"), Some(&Value::Atom("b".to_string())));
+    }
+
+    #[test]
+    fn test_cut() {
+        let mut engine = Engine::new();
+        engine.consult("
+            colour(red). colour(green). colour(blue).
+            max(X, Y, X) :- X >= Y, !.
+            max(_, Y, Y).
+            first(X) :- colour(X), !.
+            branch(X) :- ( X = 1, ! ; X = 2 ).
+            branch(3).
+            then(X) :- ( true -> member(X, [1, 2]), ! ; true ).
+            then(3).
+            condition(Y) :- ( member(X, [1, 2, 3]), !, X > 1 -> Y = yes ; Y = no ).
+            called(X) :- call((member(X, [1, 2]), !)).
+            called(3).
+            caught(X) :- catch((member(X, [1, 2]), !), _, true).
+            caught(3).
+            handled(X) :- catch(throw(e), e, (member(X, [1, 2]), !)).
+            handled(3).
+            :- dynamic(d/1).
+            d(1) :- !.
+            d(2).
+        ").unwrap();
+        assert!(engine.take_warnings().is_empty());
+
+        let answers = |engine: &mut Engine, goal: &str| -> Vec<String> {
+            solutions(engine, goal).iter().map(|s| format!("{}", s.get("X").unwrap())).collect()
+        };
+        assert_eq!(answers(&mut engine, "max(3, 1, X)"), vec!["3"]);
+        assert_eq!(answers(&mut engine, "max(1, 3, X)"), vec!["3"]);
+        assert_eq!(answers(&mut engine, "first(X)"), vec!["red"]);
+        // a cut in a branch of a disjunction or in the then branch commits the clause
+        assert_eq!(answers(&mut engine, "branch(X)"), vec!["1"]);
+        assert_eq!(answers(&mut engine, "then(X)"), vec!["1"]);
+        // a cut in the condition of an if-then-else, in call/1 or in catch/3 is local to it
+        assert_eq!(answers(&mut engine, "condition(X)"), vec!["no"]);
+        assert_eq!(answers(&mut engine, "called(X)"), vec!["1", "3"]);
+        assert_eq!(answers(&mut engine, "caught(X)"), vec!["1", "3"]);
+        assert_eq!(answers(&mut engine, "handled(X)"), vec!["1", "3"]);
+        assert_eq!(answers(&mut engine, "findall(Y, (member(Y, [1, 2, 3]), !), X)"), vec!["[1]"]);
+        assert_eq!(answers(&mut engine, "member(X, [1, 2]), !"), vec!["1"]);
+        assert_eq!(solutions(&mut engine, "\\+ (!, fail)").len(), 1);
+        assert_eq!(solutions(&mut engine, "call(!)").len(), 1);
+        assert_eq!(solutions(&mut engine, "G = !, G").len(), 1);
+        // the catch frame survives a cut in its goal
+        assert_eq!(solutions(&mut engine, "catch((member(X, [1, 2]), !, throw(x)), x, true)").len(), 1);
+        // the clauses of dynamic predicates are cut too
+        assert_eq!(answers(&mut engine, "d(X)"), vec!["1"]);
+        assert_eq!(answers(&mut engine, "assertz((e(Y) :- Y = 1, !)), assertz(e(2)), findall(Y, e(Y), X)"), vec!["[1]"]);
+        assert_eq!(answers(&mut engine, "clause(d(1), X)"), vec!["!"]);
+    }
+
+    #[test]
+    fn test_compound_answers() {
+        let mut engine = Engine::new();
+        engine.consult("pair(f(X, Y), X, Y).").unwrap();
+
+        let s = &solutions(&mut engine, "pair(P, 1, g(2))")[0];
+        assert_eq!(s.get("P"), Some(&Value::Ctor("f".to_string(), vec![
+            Value::Num(1),
+            Value::Ctor("g".to_string(), vec![Value::Num(2)])])));
+        assert_eq!(s.extract::<i64>("Q"), Err(TermError::Instantiation));
+    }
+
+    #[test]
+    fn test_static_check() {
+        let mut engine = Engine::new();
+        engine.consult("p(X) :- q(X).").unwrap();
+        assert_eq!(engine.check().len(), 1);
+        engine.consult("q(a).").unwrap();
+        assert!(engine.check().is_empty());
+
+        // the clauses are checked as they are loaded, against the whole program
+        engine.take_warnings();
+        engine.consult("r :- q(a, b), s.\nt :- sys:nope(1), q(a).").unwrap();
+        let warnings: Vec<String> = engine.take_warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, vec![
+            "1:6: warning: in r/0: call to q/2, but q is only defined with arity 1".to_string(),
+            "1:15: warning: in r/0: call to undefined predicate s/0".to_string(),
+            "2:6: warning: in t/0: unknown built-in predicate sys:nope/1".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let mut engine = Engine::new();
+        assert!(engine.consult("foo(").is_err());
+        assert!(engine.query("X = ").is_err());
+    }
+
+    #[test]
+    fn test_catch_and_throw() {
+        let mut engine = Engine::new();
+        engine.consult("
+            risky(X) :- throw(oops(X)).
+            safe(X, R) :- catch(risky(X), oops(Y), R = caught(Y)).
+            rethrow(R) :- catch(risky(1), other, R = wrong).
+        ").unwrap();
+
+        let s = &solutions(&mut engine, "safe(7, R)")[0];
+        assert_eq!(s.get("R"), Some(&term("caught(7)")));
+        assert_eq!(uncaught(&mut engine, "rethrow(R)"), term("oops(1)"));
+        assert_eq!(solutions(&mut engine, "catch(true, _, fail)").len(), 1);
+        // the catcher is not active anymore once the goal has exited
+        assert_eq!(uncaught(&mut engine, "catch(X = 1, _, true), throw(late(X))"), term("late(1)"));
+        // the bindings done by the goal are undone before unifying with the catcher
+        let s = &solutions(&mut engine, "catch((X = 1, throw(X)), B, true)")[0];
+        assert_eq!(s.get("B"), Some(&Value::Num(1)));
+        assert!(matches!(s.get("X"), Some(Value::LV(_))));
+    }
+
+    #[test]
+    fn test_iso_errors() {
+        let mut engine = Engine::new();
+        let s = &solutions(&mut engine, "is(X, '+'(1, '*'(2, 3)))")[0];
+        assert_eq!(s.extract::<i64>("X"), Ok(7));
+
+        assert_eq!(formal_in_context(&mut engine, "is(X, '+'(Y, 1))"), (term("instantiation_error"), term("'/'(is, 2)")));
+        assert_eq!(formal_in_context(&mut engine, "is(X, '+'(a, 1))"), (term("type_error(evaluable, '/'(a, 0))"), term("'/'(is, 2)")));
+        assert_eq!(formal_in_context(&mut engine, "'<'(1, '//'(1, 0))"), (term("evaluation_error(zero_divisor)"), term("'/'('<', 2)")));
+        assert_eq!(formal_in_context(&mut engine, "throw(_)"), (term("instantiation_error"), term("'/'(throw, 1)")));
+
+        match uncaught(&mut engine, "is(X, foo)") {
+            Value::Ctor(f, args) => {
+                assert_eq!(f, "error");
+                assert_eq!(args[0], term("type_error(evaluable, '/'(foo, 0))"));
+            }
+            ball => panic!("unexpected ball {}", ball)
+        }
+    }
+
+    #[test]
+    fn test_unknown_procedures() {
+        let mut engine = Engine::new();
+        engine.consult("
+            parent(tom, bob).
+            likes(X) :- parnet(X, bob).
+        ").unwrap();
+
+        match uncaught(&mut engine, "likes(X)") {
+            Value::Ctor(f, args) => {
+                assert_eq!(f, "error");
+                assert_eq!(args[0], term("existence_error(procedure, '/'(parnet, 2))"));
+                assert_eq!(args[1], term("context('/'(likes, 1), 'unknown procedure parnet/2, did you mean parent/2?')"));
+            }
+            ball => panic!("unexpected ball {}", ball)
+        }
+        let s = &solutions(&mut engine, "catch(parent(X), error(_, context(_, M)), true)")[0];
+        assert_eq!(s.get("M"), Some(&term("'unknown procedure parent/1, did you mean parent/2?'")));
+        // one-character names only match themselves, and symbolic built-ins are not suggested
+        engine.consult("p(a).").unwrap();
+        let s = &solutions(&mut engine, "catch(call(p(X), extra), error(_, context(_, M)), true)")[0];
+        assert_eq!(s.get("M"), Some(&term("'unknown procedure p/2, did you mean p/1?'")));
+        let s = &solutions(&mut engine, "catch(call(q, a, b), error(_, context(_, M)), true)")[0];
+        assert_eq!(s.get("M"), Some(&term("'unknown procedure q/2'")));
+
+        engine.flags_mut().unknown = Unknown::Fail;
+        assert!(solutions(&mut engine, "likes(X)").is_empty());
+        assert_eq!(solutions(&mut engine, "set_prolog_flag(unknown, error), current_prolog_flag(unknown, V)")[0].get("V"),
+                   Some(&term("error")));
+        assert_eq!(engine.flags().unknown, Unknown::Error);
+        let flags: Vec<String> = solutions(&mut engine, "current_prolog_flag(F, _)").iter().map(|s| s.extract("F").unwrap()).collect();
+        assert_eq!(flags, vec!["double_quotes".to_string(), "unknown".to_string()]);
+        assert_eq!(solutions(&mut engine, "current_prolog_flag(F, error)")[0].get("F"), Some(&term("unknown")));
+        assert!(solutions(&mut engine, "current_prolog_flag(colour, _)").is_empty());
+        // the flags are not restored on backtracking
+        assert!(solutions(&mut engine, "set_prolog_flag(unknown, warning), fail").is_empty());
+        assert_eq!(engine.flags().unknown, Unknown::Warning);
+        // the warning is written to user_error
+        let errors = Capture::default();
+        engine.set_user_error(Box::new(errors.clone()));
+        assert!(solutions(&mut engine, "likes(X)").is_empty());
+        assert_eq!(errors.text(), "Warning: 3:25: unknown procedure parnet/2, did you mean parent/2?\n");
+
+        let s = &solutions(&mut engine, "catch(set_prolog_flag(unknown, maybe), error(E, _), true)")[0];
+        assert_eq!(s.get("E"), Some(&term("domain_error(flag_value, '+'(unknown, maybe))")));
+        let s = &solutions(&mut engine, "catch(set_prolog_flag(colour, red), error(E, _), true)")[0];
+        assert_eq!(s.get("E"), Some(&term("domain_error(prolog_flag, colour)")));
+    }
+
+    #[test]
+    fn test_error_locations() {
+        let mut engine = Engine::new();
+        engine.consult("
+            p(X) :- q(X).
+            q(X) :-
+                is(X, '+'(a, 1)).
+            r(Y) :- undefined(Y).
+        ").unwrap();
+
+        let diagnostics: Vec<String> = engine.check().iter().map(|d| d.to_string()).collect();
+        assert_eq!(diagnostics, vec!["5:21: warning: in r/1: call to undefined predicate undefined/1".to_string()]);
+
+        match engine.query("p(X)").unwrap().next() {
+            Some(Err(EngineError::Exception(_, backtrace))) => {
+                let frames: Vec<String> = backtrace.iter().map(|frame| frame.to_string()).collect();
+                assert_eq!(frames, vec!["q/1 at 4:17", "p/1 at 2:21", "$query/0 at 1:1"]);
+            }
+            other => panic!("expected an exception, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_directives() {
+        let mut engine = Engine::new();
+        engine.consult("
+            :- dynamic seen/1.
+            :- op(700, xfx, ===>).
+            :- initialization(start).
+            rule(a ===> b).
+            colour(red).
+            size(big).
+            colour(blue).
+            start :- rule(X ===> Y), print(X).
+            :- frobnicate.
+            :- initialization(fail).
+        ").unwrap();
+
+        assert!(solutions(&mut engine, "seen(X)").is_empty());
+        assert_eq!(solutions(&mut engine, "rule(X ===> b)").len(), 1);
+        assert!(engine.check().is_empty());
+
+        let warnings: Vec<String> = engine.take_warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, vec![
+            "8:13: warning: clauses of colour/1 are not together, declare it discontiguous if this is intended".to_string(),
+            "10:13: warning: unknown directive frobnicate".to_string(),
+            "11:13: warning: initialization goal failed".to_string(),
+        ]);
+        assert!(engine.take_warnings().is_empty());
+
+        engine.consult(":- discontiguous a/0. a. b. a. :- initialization(throw(oops), main).").unwrap();
+        assert!(engine.take_warnings().is_empty());
+        assert_eq!(engine.take_main(), Some(Stmt::Call(Pred::User("throw".to_string()), vec![Expr::Atom("oops".to_string())], Span::default())));
+    }
+
+    // Run the goals of a conformance table, each with the outcome it should have: true, false, or the formal term of the error it should raise
+    fn conformance(engine: &mut Engine, table: &[(&str, &str)]) {
+        for (goal, expected) in table.iter() {
+            let outcome = match solutions(engine, &format!("catch(({}), error(E, _), true)", goal)).first() {
+                None => "false".to_string(),
+                Some(s) => match s.get("E") {
+                    Some(Value::LV(_)) | None => "true".to_string(),
+                    Some(e) => format!("{:?}", e)
+                }
+            };
+            let expected = match *expected {
+                "true" | "false" => expected.to_string(),
+                formal => format!("{:?}", term(formal))
+            };
+            assert_eq!(outcome, expected, "{}", goal);
+        }
+    }
+
+    #[test]
+    fn test_term_inspection() {
+        let mut engine = Engine::new();
+        conformance(&mut engine, &[
+            ("var(X)", "true"),
+            ("X = 1, var(X)", "false"),
+            ("X = Y, var(X)", "true"),
+            ("nonvar(f(X))", "true"),
+            ("nonvar(X)", "false"),
+            ("atom(foo)", "true"),
+            ("atom([])", "true"),
+            ("atom(1)", "false"),
+            ("X = foo, atom(X)", "true"),
+            ("number(-3)", "true"),
+            ("integer(3)", "true"),
+            ("integer(X)", "false"),
+            ("atomic(foo)", "true"),
+            ("atomic(1)", "true"),
+            ("atomic(f(x))", "false"),
+            ("compound(f(x))", "true"),
+            ("compound([a])", "true"),
+            ("compound(a)", "false"),
+            ("callable(a)", "true"),
+            ("callable(f(X))", "true"),
+            ("callable(1)", "false"),
+            ("is_list([a, b])", "true"),
+            ("is_list([])", "true"),
+            ("is_list([a|T])", "false"),
+            ("T = [], is_list([a|T])", "true"),
+            ("ground(f(a, [b]))", "true"),
+            ("ground(f(a, X))", "false"),
+            ("X = b, ground(f(a, X))", "true"),
+
+            ("functor(foo(a, b, c), foo, 3)", "true"),
+            ("functor(foo(a, b, c), N, A), N = foo, A = 3", "true"),
+            ("functor(X, foo, 3), X = foo(_, _, _)", "true"),
+            ("functor(X, foo, 0), X = foo", "true"),
+            ("functor(mats(A, B), A, B), A = mats, B = 2", "true"),
+            ("functor(foo(a), foo, 2)", "false"),
+            ("functor(1, N, A), N = 1, A = 0", "true"),
+            ("functor(X, 1, 0), X = 1", "true"),
+            ("functor(X, Y, 3)", "instantiation_error"),
+            ("functor(X, foo, N)", "instantiation_error"),
+            ("functor(X, foo, a)", "type_error(integer, a)"),
+            ("functor(X, 1, 3)", "type_error(atom, 1)"),
+            ("functor(X, foo(a), 1)", "type_error(atomic, foo(a))"),
+            ("functor(X, foo, -1)", "domain_error(not_less_than_zero, -1)"),
+
+            ("arg(1, foo(a, b), a)", "true"),
+            ("arg(1, foo(a, b), X), X = a", "true"),
+            ("arg(1, foo(X, b), a), X = a", "true"),
+            ("arg(1, foo(a, b), b)", "false"),
+            ("arg(0, foo(a, b), _)", "false"),
+            ("arg(3, foo(a, b), _)", "false"),
+            ("arg(X, foo(a, b), a)", "instantiation_error"),
+            ("arg(1, X, a)", "instantiation_error"),
+            ("arg(0, atom, A)", "type_error(compound, atom)"),
+            ("arg(a, foo(a, b), _)", "type_error(integer, a)"),
+
+            ("foo(a, b) =.. L, L = [foo, a, b]", "true"),
+            ("X =.. [foo, a, b], X = foo(a, b)", "true"),
+            ("foo =.. L, L = [foo]", "true"),
+            ("1 =.. L, L = [1]", "true"),
+            ("X =.. [1], X = 1", "true"),
+            ("foo(a) =.. [foo, b]", "false"),
+            ("X =.. L", "instantiation_error"),
+            ("X =.. [foo|T]", "instantiation_error"),
+            ("X =.. [F, a]", "instantiation_error"),
+            ("X =.. [foo|bar]", "type_error(list, '.'(foo, bar))"),
+            ("X =.. []", "domain_error(non_empty_list, [])"),
+            ("X =.. [f(a), b]", "type_error(atomic, f(a))"),
+            ("X =.. [1, b]", "type_error(atom, 1)"),
+
+            ("copy_term(f(X, Y, X), f(a, b, B)), B = a, var(X)", "true"),
+            ("copy_term(f(X, Y, X), f(A, B, C)), A = 1, C = 1, var(Y)", "true"),
+            ("copy_term(f(X, Y, X), f(A, B, C)), A = 1, C = 2", "false"),
+            ("X = a, copy_term(f(X), f(Y)), Y = a", "true"),
+            ("copy_term(X, Y), X = 1, var(Y)", "true"),
+        ]);
+    }
+
+    #[test]
+    fn test_negation() {
+        let mut engine = Engine::new();
+        engine.consult("
+            person(ann).
+            person(bob).
+            married(ann, carl).
+            single(X) :- person(X), \\+ married(X, _).
+        ").unwrap();
+
+        let answers: Vec<String> = solutions(&mut engine, "single(X)").iter().map(|s| s.extract("X").unwrap()).collect();
+        assert_eq!(answers, vec!["bob".to_string()]);
+        assert!(solutions(&mut engine, "\\+ person(ann)").is_empty());
+        assert_eq!(solutions(&mut engine, "not(person(dan))").len(), 1);
+        // the bindings made by the negated goal do not leak out
+        let s = &solutions(&mut engine, "\\+ \\+ X = 1, X = 2")[0];
+        assert_eq!(s.extract::<i64>("X"), Ok(2));
+        assert_eq!(solutions(&mut engine, "G = person(dan), \\+ G").len(), 1);
+        assert_eq!(solutions(&mut engine, "call(\\+, fail)").len(), 1);
+        assert_eq!(solutions(&mut engine, "clause(single(_), (_, \\+ M))")[0].get("M").map(|m| matches!(m, Value::Ctor(f, _) if f == "married")), Some(true));
+
+        // a negation that may flounder is reported when the clause is loaded
+        assert!(engine.take_warnings().is_empty());
+        engine.consult("odd(X) :- \\+ married(X, Y), person(Y).").unwrap();
+        let warnings: Vec<String> = engine.take_warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, vec![
+            "1:11: warning: in odd/1: negated goal has variables that are unbound here but used later, it may flounder".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_call() {
+        let mut engine = Engine::new();
+        engine.consult("
+            add(X, Y, Z) :- Z is X + Y.
+            twice(G, X, Z) :- call(G, X, Y), call(G, Y, Z).
+            colour(red).
+            colour(green).
+        ").unwrap();
+
+        assert_eq!(solutions(&mut engine, "call(add(1), 2, Z)")[0].extract::<i64>("Z"), Ok(3));
+        assert_eq!(solutions(&mut engine, "twice(add(10), 1, Z)")[0].extract::<i64>("Z"), Ok(21));
+        assert_eq!(solutions(&mut engine, "call(is, X, 1 + 2)")[0].extract::<i64>("X"), Ok(3));
+        assert_eq!(solutions(&mut engine, "call(call, call, colour(X))").len(), 2);
+        assert_eq!(solutions(&mut engine, "assertz(d(1)), call(d, X)")[0].extract::<i64>("X"), Ok(1));
+
+        // goals in variables, including control constructs
+        let answers: Vec<i64> = solutions(&mut engine, "G = (X = 1 ; X = 2), G").iter().map(|s| s.extract("X").unwrap()).collect();
+        assert_eq!(answers, vec![1, 2]);
+        let s = &solutions(&mut engine, "call((X = 1, Y = X))")[0];
+        assert_eq!(s.extract::<i64>("Y"), Ok(1));
+        let s = &solutions(&mut engine, "G = (X = 1, Y = 2, Z = 3), G")[0];
+        assert_eq!((s.extract::<i64>("X"), s.extract::<i64>("Z")), (Ok(1), Ok(3)));
+        assert_eq!(solutions(&mut engine, "G = colour, call(G, C), C = green").len(), 1);
+        assert_eq!(solutions(&mut engine, "call((colour(X) -> true ; fail))").len(), 1);
+        assert_eq!(solutions(&mut engine, "call(catch(throw(x), x, true))").len(), 1);
+        assert_eq!(solutions(&mut engine, "call(',', colour(X), colour(Y))").len(), 4);
+
+        assert_eq!(formal(&mut engine, "call(_)"), term("instantiation_error"));
+        assert_eq!(formal(&mut engine, "(G = 1, G)"), term("type_error(callable, 1)"));
+        assert_eq!(formal(&mut engine, "call((fail, 1))"), term("type_error(callable, ','(fail, 1))"));
+        assert_eq!(formal(&mut engine, "call(colour, x, y)"), term("existence_error(procedure, '/'(colour, 2))"));
+    }
+
+    #[test]
+    fn test_all_solutions() {
+        let mut engine = Engine::new();
+        engine.consult("
+            age(peter, 7).
+            age(ann, 11).
+            age(pat, 8).
+            age(tom, 5).
+            age(mike, 11).
+            class(a, peter).
+            class(b, ann).
+            class(a, pat).
+            class(b, tom).
+            class(b, ann).
+        ").unwrap();
+
+        assert_eq!(solutions(&mut engine, "findall(N, age(N, _), L)")[0].get("L"),
+                   Some(&term("[peter, ann, pat, tom, mike]")));
+        assert_eq!(solutions(&mut engine, "findall(N, age(N, 20), L)")[0].get("L"), Some(&term("[]")));
+        // the solutions are copies, the variables of the template are not bound
+        let s = &solutions(&mut engine, "findall(X, (X = 1 ; X = 2), L), X = 3")[0];
+        assert_eq!(s.get("L"), Some(&term("[1, 2]")));
+        let s = &solutions(&mut engine, "findall(f(X, Y), (X = 1 ; true), [f(A, B), f(C, D)])")[0];
+        assert_eq!(s.get("A"), Some(&Value::Num(1)));
+        assert!(matches!(s.get("C"), Some(Value::LV(_))));
+        // nested collections
+        assert_eq!(solutions(&mut engine, "findall(L, (class(C, _), findall(N, class(C, N), L)), Ls)")[0].get("Ls"),
+                   Some(&term("[[peter, pat], [ann, tom, ann], [peter, pat], [ann, tom, ann], [ann, tom, ann]]")));
+
+        // bagof and setof group by the free variables and fail without solutions
+        let groups: Vec<(Value, Value)> = solutions(&mut engine, "bagof(N, class(C, N), L)").iter()
+            .map(|s| (s.get("C").cloned().unwrap(), s.get("L").cloned().unwrap()))
+            .collect();
+        assert_eq!(groups, vec![(term("a"), term("[peter, pat]")), (term("b"), term("[ann, tom, ann]"))]);
+        assert_eq!(solutions(&mut engine, "setof(N, class(b, N), L)")[0].get("L"), Some(&term("[ann, tom]")));
+        assert_eq!(solutions(&mut engine, "setof(N, C^class(C, N), L)")[0].get("L"), Some(&term("[ann, pat, peter, tom]")));
+        assert_eq!(solutions(&mut engine, "setof(A-N, age(N, A), [_, _, _, _, Oldest])")[0].get("Oldest"), Some(&term("'-'(11, mike)")));
+        assert!(solutions(&mut engine, "bagof(N, age(N, 20), L)").is_empty());
+        assert_eq!(solutions(&mut engine, "G = class(C, N), setof(C-N, G, L)")[0].get("L"),
+                   Some(&term("['-'(a, pat), '-'(a, peter), '-'(b, ann), '-'(b, tom)]")));
+
+        let aggregate = |engine: &mut Engine, goal: &str| solutions(engine, goal).pop().map(|s| s.get("R").cloned().unwrap());
+        assert_eq!(aggregate(&mut engine, "aggregate_all(count, age(_, _), R)"), Some(Value::Num(5)));
+        assert_eq!(aggregate(&mut engine, "aggregate_all(sum(A), age(_, A), R)"), Some(Value::Num(42)));
+        assert_eq!(aggregate(&mut engine, "aggregate_all(max(A), age(_, A), R)"), Some(Value::Num(11)));
+        assert_eq!(aggregate(&mut engine, "aggregate_all(min(A), age(_, A), R)"), Some(Value::Num(5)));
+        assert_eq!(aggregate(&mut engine, "aggregate_all(max(A), age(_, 20), R)"), None);
+        assert_eq!(aggregate(&mut engine, "aggregate_all(count, fail, R)"), Some(Value::Num(0)));
+        assert_eq!(aggregate(&mut engine, "aggregate_all(bag(C), class(C, _), R)"), Some(term("[a, b, a, b, b]")));
+        assert_eq!(aggregate(&mut engine, "aggregate_all(set(C), class(C, _), R)"), Some(term("[a, b]")));
+        conformance(&mut engine, &[
+            ("aggregate_all(foo, true, _)", "domain_error(aggregate_spec, foo)"),
+            ("aggregate_all(sum(X), member(X, [9223372036854775807, 1]), _)", "evaluation_error(int_overflow)"),
+        ]);
+    }
+
+    #[test]
+    fn test_dynamic_database() {
+        let mut engine = Engine::new();
+        engine.consult("
+            :- dynamic counter/1.
+            counter(0).
+            inc :- retract(counter(N)), M is N + 1, assertz(counter(M)).
+        ").unwrap();
+
+        assert_eq!(solutions(&mut engine, "inc, inc").len(), 1);
+        assert_eq!(solutions(&mut engine, "counter(X)")[0].extract::<i64>("X"), Ok(2));
+
+        // the clauses asserted by a call are not seen by the calls that are already running
+        solutions(&mut engine, "assertz(q(1)), assertz(q(2))");
+        assert_eq!(solutions(&mut engine, "(q(X), assertz(q(X)), fail ; true)").len(), 1);
+        assert_eq!(solutions(&mut engine, "q(X)").len(), 4);
+        solutions(&mut engine, "asserta(q(0))");
+        let answers: Vec<i64> = solutions(&mut engine, "q(X)").iter().map(|s| s.extract("X").unwrap()).collect();
+        assert_eq!(answers, vec![0, 1, 2, 1, 2]);
+
+        solutions(&mut engine, "retract(q(1)), !");
+        let answers: Vec<i64> = solutions(&mut engine, "q(X)").iter().map(|s| s.extract("X").unwrap()).collect();
+        assert_eq!(answers, vec![0, 2, 1, 2]);
+        solutions(&mut engine, "retractall(q(2))");
+        assert_eq!(solutions(&mut engine, "q(X)").len(), 2);
+
+        // rules keep their bodies, and retract unifies with them
+        solutions(&mut engine, "assertz((double(X, Y) :- Y is X * 2))");
+        assert_eq!(solutions(&mut engine, "double(21, Y)")[0].extract::<i64>("Y"), Ok(42));
+        assert_eq!(solutions(&mut engine, "retract((double(X, _) :- B)), X = 5, B = (_ is 5 * 2)").len(), 1);
+
+        // retract/1 removes the next matching clause on backtracking
+        solutions(&mut engine, "assertz(r(1)), assertz(r(2)), assertz(r(3))");
+        let answers: Vec<i64> = solutions(&mut engine, "retract(r(X)), X >= 2, !").iter().map(|s| s.extract("X").unwrap()).collect();
+        assert_eq!(answers, vec![2]);
+        assert_eq!(solutions(&mut engine, "r(X)").len(), 1);
+        solutions(&mut engine, "assertz(r(1)), assertz(r(2))");
+        let out = engine.query("with_output_to(string(S), (retract(r(X)), write(X), fail ; true))").unwrap().next().unwrap().unwrap();
+        assert_eq!(out.get("S"), Some(&Value::Str("312".to_string())));
+        assert!(solutions(&mut engine, "r(_)").is_empty());
+        assert!(solutions(&mut engine, "retract(r(_))").is_empty());
+        assert!(solutions(&mut engine, "double(21, Y)").is_empty());
+
+        assert!(solutions(&mut engine, "retract(undefined(_))").is_empty());
+        assert!(solutions(&mut engine, "retractall(gone(_)), gone(_)").is_empty());
+        solutions(&mut engine, "abolish(counter/1)");
+        assert_eq!(solutions(&mut engine, "catch(counter(_), error(existence_error(procedure, '/'(counter, 1)), _), true)").len(), 1);
+    }
+
+    #[test]
+    fn test_clause_and_listing() {
+        let mut engine = Engine::new();
+        engine.consult("
+            :- dynamic counter/1.
+            counter(0).
+            counter(1).
+            inc :- retract(counter(N)), M is N + 1, assertz(counter(M)).
+            sign(X, S) :- ( X < 0 -> S = negative ; X =:= 0 -> S = zero ; S = positive ).
+            safe(X) :- \\+ q(X, _), catch(t, _, true).
+        ").unwrap();
+
+        let answers: Vec<i64> = solutions(&mut engine, "clause(counter(X), true)").iter().map(|s| s.extract("X").unwrap()).collect();
+        assert_eq!(answers, vec![0, 1]);
+        let s = &solutions(&mut engine, "clause(inc, (G, _))")[0];
+        assert_eq!(s.get("G").map(|g| matches!(g, Value::Ctor(f, _) if f == "retract")), Some(true));
+        assert!(solutions(&mut engine, "clause(missing(_), _)").is_empty());
+        // the clauses are copied, so binding them does not change the predicate
+        assert_eq!(solutions(&mut engine, "clause(sign(1, S), B), S = x").len(), 1);
+        assert_eq!(solutions(&mut engine, "sign(1, S)")[0].extract::<String>("S"), Ok("positive".to_string()));
+
+        assert_eq!(formal(&mut engine, "clause(_, true)"), term("instantiation_error"));
+        assert_eq!(formal(&mut engine, "clause(4, true)"), term("type_error(callable, 4)"));
+        assert_eq!(formal(&mut engine, "clause(counter(_), 4)"), term("type_error(callable, 4)"));
+        assert_eq!(formal(&mut engine, "clause(write(_), _)"), term("permission_error(access, private_procedure, '/'(write, 1))"));
+
+        let database = engine.vm.database.borrow();
+        assert_eq!(database.listing(&Ops::default(), USER, "counter", Some(1)), ":- dynamic counter/1.\n\ncounter(0).\ncounter(1).\n\n");
+        assert_eq!(database.listing(&Ops::default(), USER, "inc", None), "\
+inc :-
+    retract(counter(A)),
+    B is A+1,
+    assertz(counter(B)).
+
+");
+        assert_eq!(database.listing(&Ops::default(), USER, "sign", None), "\
+sign(A,B) :-
+    (   A<0
+    ->  B=negative
+    ;   A=:=0
+    ->  B=zero
+    ;   B=positive
+    ).
+
+");
+        // the variables that occur once are written as _
+        assert_eq!(database.listing(&Ops::default(), USER, "safe", None), "\
+safe(A) :-
+    \\+q(A,_),
+    catch(t,_,true).
+
+");
+        assert_eq!(database.listing(&Ops::default(), USER, "sign", Some(3)), "");
+    }
+
+    #[test]
+    fn test_dynamic_database_errors() {
+        let mut engine = Engine::new();
+        engine.consult("fact(1).").unwrap();
+        assert_eq!(formal(&mut engine, "assertz(print(1))"), term("permission_error(modify, static_procedure, '/'(print, 1))"));
+        assert_eq!(formal(&mut engine, "assertz(fact(2))"), term("permission_error(modify, static_procedure, '/'(fact, 1))"));
+        assert_eq!(formal(&mut engine, "retract(fact(1))"), term("permission_error(modify, static_procedure, '/'(fact, 1))"));
+        assert_eq!(formal(&mut engine, "retract(fact(2))"), term("permission_error(modify, static_procedure, '/'(fact, 1))"));
+        assert_eq!(formal(&mut engine, "retract(_)"), term("instantiation_error"));
+        assert_eq!(formal(&mut engine, "retract((3 :- true))"), term("type_error(callable, 3)"));
+        assert_eq!(formal(&mut engine, "retract(atom_length(_, _))"), term("permission_error(modify, static_procedure, '/'(atom_length, 2))"));
+        assert_eq!(solutions(&mut engine, "catch(retract(_), error(_, context(retract/1, _)), true)").len(), 1);
+        assert_eq!(formal(&mut engine, "assertz(3)"), term("type_error(callable, 3)"));
+        assert_eq!(formal(&mut engine, "assertz(_)"), term("instantiation_error"));
+        assert_eq!(formal(&mut engine, "assertz((foo :- 4))"), term("type_error(callable, 4)"));
+        assert_eq!(formal(&mut engine, "abolish(_)"), term("instantiation_error"));
+        assert_eq!(formal(&mut engine, "abolish(foo)"), term("type_error(predicate_indicator, foo)"));
+        assert_eq!(formal(&mut engine, "abolish(foo/a)"), term("type_error(integer, a)"));
+        assert_eq!(formal(&mut engine, "abolish(1/1)"), term("type_error(atom, 1)"));
+        assert_eq!(formal(&mut engine, "abolish(foo/(-1))"), term("domain_error(not_less_than_zero, -1)"));
+        assert_eq!(formal(&mut engine, "abolish(fact/1)"), term("permission_error(modify, static_procedure, '/'(fact, 1))"));
+
+        engine.consult(":- dynamic fact/1.").unwrap();
+        let warnings: Vec<String> = engine.take_warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, vec!["1:1: warning: cannot declare fact/1 dynamic, it is a static procedure".to_string()]);
+    }
+
+    #[test]
+    fn test_include_and_ensure_loaded() {
+        let dir = std::env::temp_dir().join(format!("cadmium-load-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.pl"), ":- include(facts).\n:- ensure_loaded('rules.pl').\n:- ensure_loaded(rules).\n:- include(missing).\n").unwrap();
+        fs::write(dir.join("facts.pl"), "% included facts\nedge(a, b).\nedge(b, c).\n").unwrap();
+        fs::write(dir.join("rules.pl"), "path(X, Y) :- edge(X, Y).\npath(X, Z) :- edge(X, Y), path(Y, Z).\n").unwrap();
+
+        let mut engine = Engine::new();
+        engine.consult_file(dir.join("main.pl")).unwrap();
+        let warnings = engine.take_warnings();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(solutions(&mut engine, "path(a, X)").len(), 2);
+        // rules.pl is loaded once, so path/2 has two clauses
+        assert_eq!(solutions(&mut engine, "path(a, c)").len(), 1);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].span.line, 4);
+        assert!(warnings[0].message.starts_with("cannot include"));
+    }
+
+    #[test]
+    fn test_consult_file() {
+        let path = std::env::temp_dir().join(format!("cadmium-consult-{}.pl", std::process::id()));
+        fs::write(&path, "p(X) :-\n    q(X).\n").unwrap();
+        let mut engine = Engine::new();
+        engine.consult_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let diagnostics = engine.check();
+        assert_eq!(diagnostics[0].location.span.to_string(), format!("{}:2:5", path.display()));
+        assert!(matches!(engine.consult_file(&path), Err(EngineError::Io(_))));
+    }
+
+    #[test]
+    fn test_standard_order_and_sorting() {
+        let mut engine = Engine::new();
+        engine.consult("
+            by_length(O, X, Y) :- length_of(X, N), length_of(Y, M), compare(O, N, M).
+            length_of([], 0).
+            length_of([_|T], N) :- length_of(T, M), N is M + 1.
+        ").unwrap();
+        conformance(&mut engine, &[
+            ("f(X) == f(X)", "true"),
+            ("f(X) == f(Y)", "false"),
+            ("X = Y, f(X) == f(Y)", "true"),
+            ("a \\== b", "true"),
+            ("X \\== X", "false"),
+            ("X @< 1", "true"),
+            ("1 @< a", "true"),
+            ("a @< f(a)", "true"),
+            ("g(a) @< f(a, b)", "true"),
+            ("f(b) @> f(a)", "true"),
+            ("b @=< b", "true"),
+            ("a @>= b", "false"),
+            ("compare(O, 1, 2), O = (<)", "true"),
+            ("compare(O, b, a), O = (>)", "true"),
+            ("compare(=, f(X), f(X))", "true"),
+            ("compare(<, b, a)", "false"),
+            ("compare(1, a, b)", "type_error(atom, 1)"),
+            ("compare(less, a, b)", "domain_error(order, less)"),
+
+            ("msort([b, a, c, a], L), L = [a, a, b, c]", "true"),
+            ("sort([b, a, c, a], L), L = [a, b, c]", "true"),
+            ("sort([f(X), 1, a, X], L), L = [X, 1, a, f(X)]", "true"),
+            ("sort([], L), L = []", "true"),
+            ("sort([a|T], L)", "instantiation_error"),
+            ("sort(a, L)", "type_error(list, a)"),
+            ("sort(0, @>=, [1, 3, 2, 3], L), L = [3, 3, 2, 1]", "true"),
+            ("sort(0, @>, [1, 3, 2, 3], L), L = [3, 2, 1]", "true"),
+            ("sort(1, @<, [f(2, a), f(1, b), f(2, c)], L), L = [f(1, b), f(2, a)]", "true"),
+            ("sort(1, @=<, [f(2, a), f(1, b), f(2, c)], L), L = [f(1, b), f(2, a), f(2, c)]", "true"),
+            ("sort(2, @<, [f(a)], L)", "type_error(compound, f(a))"),
+            ("sort(a, @<, [], L)", "type_error(integer, a)"),
+            ("sort(0, <, [], L)", "domain_error(order, <)"),
+            ("keysort([b-1, a-2, b-0, a-1], L), L = [a-2, a-1, b-1, b-0]", "true"),
+            ("keysort([a-1, X], L)", "instantiation_error"),
+            ("keysort([a-1, b], L)", "type_error(pair, b)"),
+
+            ("predsort(by_length, [[a, b], [c], [], [d]], L), L = [[], [c], [a, b]]", "true"),
+            ("predsort(compare, [c, a, b, a], L), L = [a, b, c]", "true"),
+        ]);
+    }
+
+    #[test]
+    fn test_atoms() {
+        let mut engine = Engine::new();
+        engine.consult("
+            length_of([], 0).
+            length_of([_|T], N) :- length_of(T, M), N is M + 1.
+        ").unwrap();
+        conformance(&mut engine, &[
+            ("atom_codes(abc, L), L = [0'a, 0'b, 0'c]", "true"),
+            ("atom_codes(A, [0'h, 0'é]), A = 'hé'", "true"),
+            ("atom_codes(12, L), L = [0'1, 0'2]", "true"),
+            ("atom_codes(A, [0'a|_])", "instantiation_error"),
+            ("atom_codes(A, [a])", "type_error(integer, a)"),
+            ("atom_codes(A, [-1])", "representation_error(character_code)"),
+            ("atom_codes(f(x), L)", "type_error(atomic, f(x))"),
+            ("atom_chars('日本', L), L = ['日', '本']", "true"),
+            ("atom_chars(A, [a, b]), A = ab", "true"),
+            ("atom_chars(A, [ab])", "type_error(character, ab)"),
+            ("atom_chars(A, L)", "instantiation_error"),
+            ("char_code(a, C), C = 97", "true"),
+            ("char_code(C, 0x3bb), C = 'λ'", "true"),
+            ("char_code(ab, C)", "type_error(character, ab)"),
+            ("char_code(C, N)", "instantiation_error"),
+            ("atom_length('héllo', N), N = 5", "true"),
+            ("atom_length('', 0)", "true"),
+            ("atom_length(abc, 2)", "false"),
+            ("atom_length(A, N)", "instantiation_error"),
+            ("atom_length(abc, a)", "type_error(integer, a)"),
+            ("atom_length(abc, -1)", "domain_error(not_less_than_zero, -1)"),
+
+            ("atom_concat(ab, cd, X), X = abcd", "true"),
+            ("atom_concat(X, cd, abcd), X = ab", "true"),
+            ("atom_concat(ab, X, abcd), X = cd", "true"),
+            ("atom_concat(ab, X, xy)", "false"),
+            ("findall(X+Y, atom_concat(X, Y, 'äb'), L), L = [''+'äb', 'ä'+b, 'äb'+'']", "true"),
+            ("atom_concat(X, Y, Z)", "instantiation_error"),
+            ("findall(B-L-A, sub_atom(abab, B, L, A, ab), S), S = [0-2-2, 2-2-0]", "true"),
+            ("sub_atom('héllo', 1, 3, A, S), A = 1, S = 'éll'", "true"),
+            ("sub_atom(abc, B, 2, 0, S), B = 1, S = bc", "true"),
+            ("findall(S, sub_atom(abc, _, _, _, S), L), length_of(L, 10)", "true"),
+            ("findall(S, sub_atom(ab, B, L, A, S), X), X = ['', a, ab, '', b, '']", "true"),
+            ("sub_atom(X, B, L, A, S)", "instantiation_error"),
+            ("sub_atom(abc, B, L, A, f(x))", "type_error(atom, f(x))"),
+            ("upcase_atom('straße ö', U), U = 'STRASSE Ö'", "true"),
+
+            ("atom_number('42', N), N = 42", "true"),
+            ("atom_number('-7', N), N = -7", "true"),
+            ("atom_number('0x1f', N), N = 31", "true"),
+            ("atom_number(foo, N)", "false"),
+            ("atom_number(A, 12), A = '12'", "true"),
+            ("atom_number(A, N)", "instantiation_error"),
+            ("atom_number(A, foo)", "type_error(number, foo)"),
+            ("number_codes(N, [0'4, 0'2]), N = 42", "true"),
+            ("number_codes(-3, L), atom_codes(A, L), A = '-3'", "true"),
+            ("number_codes(N, [0'a])", "syntax_error(illegal_number)"),
+            ("number_codes(a, L)", "type_error(number, a)"),
+
+            ("term_to_atom(f('A b', [1, 2], -(1), (a:-b), 1 - -1), A), A = 'f(\\'A b\\',[1,2],-(1),(a:-b),1- -1)'", "true"),
+            ("term_to_atom(T, 'foo(X, Y, X)'), T = foo(A, B, C), A == C, A \\== B", "true"),
+            ("term_to_atom(T, '1 + 2 * 3'), T = +(1, *(2, 3))", "true"),
+            ("term_to_atom(T, 'foo(')", "syntax_error('expected a term')"),
+            ("term_to_atom(T, A)", "instantiation_error"),
+        ]);
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut engine = Engine::new();
+        engine.consult("
+            quoted_codes(\"ab\").
+            :- set_prolog_flag(double_quotes, string).
+            quoted_string(\"ab\").
+            :- set_prolog_flag(double_quotes, atom).
+            quoted_atom(\"ab\").
+            :- set_prolog_flag(double_quotes, chars).
+            quoted_chars(\"ab\").
+            :- set_prolog_flag(double_quotes, bytes).
+        ").unwrap();
+        let warnings: Vec<String> = engine.take_warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, vec!["9:13: warning: cannot set flag double_quotes to bytes".to_string()]);
+        assert_eq!(engine.flags().double_quotes, DoubleQuotes::Chars);
+        assert_eq!(solutions(&mut engine, "quoted_codes(X)")[0].get("X"), Some(&term("[97, 98]")));
+        assert_eq!(solutions(&mut engine, "quoted_string(X)")[0].get("X"), Some(&Value::Str("ab".to_string())));
+        assert_eq!(solutions(&mut engine, "quoted_atom(X)")[0].get("X"), Some(&term("ab")));
+        assert_eq!(solutions(&mut engine, "quoted_chars(X)")[0].get("X"), Some(&term("[a, b]")));
+
+        engine.flags_mut().double_quotes = DoubleQuotes::String;
+        conformance(&mut engine, &[
+            ("string(\"ab\")", "true"),
+            ("string(ab)", "false"),
+            ("atomic(\"ab\")", "true"),
+            ("atom(\"ab\")", "false"),
+            ("\"ab\" = \"ab\"", "true"),
+            ("\"ab\" = ab", "false"),
+            ("\"ab\" == \"ab\"", "true"),
+            ("msort([f(x), \"b\", a, 1, X, \"a\"], L), L = [X, 1, a, \"a\", \"b\", f(x)]", "true"),
+            ("compare(O, zzz, \"a\"), O = (<)", "true"),
+            ("functor(\"ab\", N, A), N == \"ab\", A = 0", "true"),
+            ("X is \"ab\" + 1", "type_error(evaluable, \"ab\")"),
+            ("term_to_atom(f(\"a\\\"b\"), A), A = 'f(\"a\\\\\"b\")'", "true"),
+            ("term_to_atom(T, 'f(\"ab\")'), T = f(S), string(S)", "true"),
+
+            ("string_concat(ab, \"cd\", S), S == \"abcd\"", "true"),
+            ("string_concat(X, \"cd\", \"abcd\"), X == \"ab\"", "true"),
+            ("findall(X-Y, string_concat(X, Y, \"ab\"), L), L = [\"\"-\"ab\", \"a\"-\"b\", \"ab\"-\"\"]", "true"),
+            ("string_concat(X, Y, Z)", "instantiation_error"),
+            ("split_string(\"a,b,,c\", \",\", \"\", L), L = [\"a\", \"b\", \"\", \"c\"]", "true"),
+            ("split_string(\"/home//jan///nice/path\", \"/\", \"\", L), L = [\"\", \"home\", \"\", \"jan\", \"\", \"\", \"nice\", \"path\"]", "true"),
+            ("split_string(\"SWI-Prolog, 7.0\", \",\", \" \", L), L = [\"SWI-Prolog\", \"7.0\"]", "true"),
+            ("split_string(\"  a word \", \"\", \" \", L), L = [\"a word\"]", "true"),
+            ("split_string([0'a, 0'-, 0'b], \"-\", \"\", L), L = [\"a\", \"b\"]", "true"),
+            ("split_string(S, \",\", \"\", L)", "instantiation_error"),
+            ("string_code(1, \"héllo\", C), C = 0'h", "true"),
+            ("string_code(2, \"héllo\", C), C = 0'é", "true"),
+            ("string_code(6, \"héllo\", C)", "false"),
+            ("string_code(0, \"abc\", C)", "false"),
+            ("string_code(I, \"abc\", C)", "instantiation_error"),
+            ("string_code(a, \"abc\", C)", "type_error(integer, a)"),
+            ("sub_string(\"hello world\", 6, 5, _, S), S == \"world\"", "true"),
+            ("findall(B, sub_string(\"abab\", B, _, _, \"ab\"), L), L = [0, 2]", "true"),
+            ("sub_string(hello, 0, 1, _, S), S == \"h\"", "true"),
+            ("number_string(N, \" 42\"), N = 42", "true"),
+            ("number_string(N, \"-7\"), N = -7", "true"),
+            ("number_string(12, S), S == \"12\"", "true"),
+            ("number_string(N, \"4a\")", "syntax_error(illegal_number)"),
+            ("number_string(N, S)", "instantiation_error"),
+            ("string_chars(S, [h, 'é']), S == \"hé\"", "true"),
+            ("string_chars(\"hé\", L), L = [h, 'é']", "true"),
+            ("string_chars(S, [a|_])", "instantiation_error"),
+            ("atom_length(\"héllo\", N), N = 5", "true"),
+            ("atom_codes(A, \"ab\")", "type_error(list, \"ab\")"),
+        ]);
+    }
+
+    #[test]
+    fn test_streams() {
+        let mut engine = Engine::new();
+        engine.consult("
+            greet(Name) :- print(hello), put_char(' '), print(Name), nl.
+            read_all(S, Cs) :- get_char(S, C), ( C == end_of_file -> Cs = [] ; Cs = [C|Rest], read_all(S, Rest) ).
+        ").unwrap();
+        engine.flags_mut().double_quotes = DoubleQuotes::String;
+        conformance(&mut engine, &[
+            ("with_output_to(string(S), greet(world)), S == \"hello world\\n\"", "true"),
+            ("with_output_to(atom(A), (put_char(a), put_char(b))), A == ab", "true"),
+            ("with_output_to(codes(Cs), put_char(a)), Cs = [0'a]", "true"),
+            ("with_output_to(chars(Cs), nl), Cs = ['\\n']", "true"),
+            ("with_output_to(atom(A), fail)", "false"),
+            ("with_output_to(atom(A), put_char(1))", "type_error(character, 1)"),
+            ("catch(with_output_to(atom(A), (put_char(a), throw(oops))), oops, true), current_output(S), S == '$stream'(1)", "true"),
+            ("with_output_to(atom(x), put_char(x))", "true"),
+            ("with_output_to(S, true)", "instantiation_error"),
+            ("with_output_to(file(f), true)", "domain_error(output_sink, file(f))"),
+            ("with_output_to(atom(A), with_output_to(atom(B), put_char(b))), A == '', B == b", "true"),
+
+            ("open_string(\"hé\", S), get_char(S, C1), peek_char(S, C2), get_char(S, C3), get_char(S, C4), [C1, C2, C3, C4] = [h, 'é', 'é', end_of_file]", "true"),
+            ("open_string(\"abc\", S), read_all(S, Cs), close(S), Cs = [a, b, c]", "true"),
+            ("open_string(\"abc\", S), set_input(S), get_char(C), peek_char(D), C = a, D = b, close(S), current_input(I), I == '$stream'(0)", "true"),
+            ("open_string(\"abc\", S), get_char(S, b)", "false"),
+            ("open_string(\"abc\", S), get_char(S, 1)", "type_error(in_character, 1)"),
+            ("open_string(\"abc\", S), catch(put_char(S, a), error(permission_error(output, stream, S), _), true)", "true"),
+            ("put_char(user_input, a)", "permission_error(output, stream, user_input)"),
+            ("get_char(user_output, C)", "permission_error(input, stream, user_output)"),
+            ("put_char(C)", "instantiation_error"),
+            ("put_char(ab)", "type_error(character, ab)"),
+            ("nl(S)", "instantiation_error"),
+            ("nl(foo)", "existence_error(stream, foo)"),
+            ("nl(f(x))", "domain_error(stream_or_alias, f(x))"),
+            ("nl('$stream'(99))", "existence_error(stream, '$stream'(99))"),
+            ("current_output(S), S == '$stream'(1)", "true"),
+            ("current_output(foo)", "domain_error(stream, foo)"),
+            ("close(user_output), current_output(S), S == '$stream'(1)", "true"),
+            ("set_output(user_input)", "permission_error(output, stream, user_input)"),
+
+            ("open(F, read, S)", "instantiation_error"),
+            ("open(f(x), read, S)", "domain_error(source_sink, f(x))"),
+            ("open(f, M, S)", "instantiation_error"),
+            ("open(f, 1, S)", "type_error(atom, 1)"),
+            ("open(f, readwrite, S)", "domain_error(io_mode, readwrite)"),
+            ("open(f, read, s)", "uninstantiation_error(s)"),
+            ("open(f, read, S, [type(text)])", "domain_error(stream_option, type(text))"),
+            ("open(f, read, S, [alias(user_output)])", "permission_error(open, source_sink, alias(user_output))"),
+            ("open(f, read, S, [alias(a)|_])", "instantiation_error"),
+            ("open('/nonexistent/file', read, S)", "existence_error(source_sink, '/nonexistent/file')"),
+        ]);
+
+        let path = std::env::temp_dir().join(format!("cadmium-streams-{}.txt", std::process::id()));
+        let file = crate::writer::quote(&path.display().to_string(), '\'');
+        conformance(&mut engine, &[
+            (&format!("open({}, write, S, [alias(out)]), put_char(out, a), nl(out), close(out)", file), "true"),
+            (&format!("open({}, append, S), put_char(S, b), close(S)", file), "true"),
+            (&format!("open({}, read, S), read_all(S, Cs), close(S), Cs = [a, '\\n', b]", file), "true"),
+            ("put_char(out, a)", "existence_error(stream, out)"),
+        ]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_terms() {
+        let mut engine = Engine::new();
+        engine.consult("
+            read_all(S, Ts) :- read(S, T), ( T == end_of_file -> Ts = [] ; Ts = [T|Rest], read_all(S, Rest) ).
+        ").unwrap();
+        engine.flags_mut().double_quotes = DoubleQuotes::String;
+        conformance(&mut engine, &[
+            ("open_string(\"foo(X, Y, X). bar.\", S), read(S, T), T = foo(A, B, C), A == C, A \\== B", "true"),
+            ("open_string(\"a. 'b. c'. 0'. . \\\"d. e\\\". f /* g. */ . % h.\\n [i]. =.. . \", S), read_all(S, Ts), Ts = [a, 'b. c', 46, \"d. e\", f, [i], =..]", "true"),
+            ("open_string(\"a :- b, c.\\n:- op(700, xfx, ===>).\", S), read_all(S, Ts), Ts = [(a :- b, c), (:- op(700, xfx, ===>))]", "true"),
+            ("open_string(\"  % nothing\\n\", S), read(S, T), read(S, U), T == end_of_file, U == end_of_file", "true"),
+            ("open_string(\"f(X, _Y, Z, _, X).\", S), read_term(S, T, [variable_names(Vs), singletons(Ss), variables(Ws)]), T = f(A, B, C, D, _), Vs = ['X' = A1, '_Y' = B1, 'Z' = C1], A1 == A, B1 == B, C1 == C, Ss = ['_Y' = B2, 'Z' = C2], B2 == B, C2 == C, Ws = [A2, B3, C3, D1], A2 == A, B3 == B, C3 == C, D1 == D", "true"),
+            ("open_string(\"t.\", S), read_term(S, T, [variable_names(Vs)]), Vs == []", "true"),
+            ("open_string(\"f(a\", S), read(S, T)", "syntax_error('expected an operator, `,` or `)`')"),
+            ("open_string(\"f(a b). ok.\", S), catch(read(S, _), error(syntax_error(_), _), true), read(S, T), T == ok", "true"),
+            ("open_string(\"a.\", S), read_term(S, T, [foo])", "domain_error(read_option, foo)"),
+            ("open_string(\"a.\", S), read_term(S, T, [V])", "instantiation_error"),
+            ("open_string(\"a.\", S), read_term(S, T, foo)", "type_error(list, foo)"),
+            ("read(user_output, T)", "permission_error(input, stream, user_output)"),
+            ("read(nosuch, T)", "existence_error(stream, nosuch)"),
+            ("open_string(\"x(\\\"s\\\").\", S), set_input(S), read(T), close(S), T = x(Str), string(Str)", "true"),
+            ("open_string(\"p(X) :- q(X).\", S), set_input(S), read_term(T, []), close(S), T = (p(A) :- q(B)), A == B", "true"),
+
+            ("term_string(T, \"foo(X, bar, X)\"), T = foo(A, bar, B), A == B", "true"),
+            ("term_string(T, \"1 + 2 * 3.\"), T == 1+2*3", "true"),
+            ("term_string(f(X, 'A b', \"s\", [1, 2]), S), sub_string(S, 0, 2, _, \"f(\"), sub_string(S, _, _, 0, \",'A b',\\\"s\\\",[1,2])\")", "true"),
+            ("term_string(a-(-1), S), S == \"a- -1\"", "true"),
+            ("term_string(T, \"foo(\")", "syntax_error('expected a term')"),
+            ("term_string(T, \"a. b.\")", "syntax_error('expected the end of the input')"),
+        ]);
+    }
+
+    #[test]
+    fn test_write_terms() {
+        let mut engine = Engine::new();
+        engine.consult("
+            portray(secret(_)) :- write('<hidden>').
+        ").unwrap();
+        engine.flags_mut().double_quotes = DoubleQuotes::String;
+        conformance(&mut engine, &[
+            ("with_output_to(string(S), write('A b'-[x, \"s\"])), S == \"A b-[x,s]\"", "true"),
+            ("with_output_to(string(S), writeq('A b'-[x, \"s\"])), S == \"'A b'-[x,\\\"s\\\"]\"", "true"),
+            ("with_output_to(string(S), write_canonical(['$VAR'(1), 1 + 2])), S == \"['$VAR'(1),+(1,2)]\"", "true"),
+            ("with_output_to(string(S), (write(f('$VAR'(1), '$VAR'(28))), writeq(' '), print('$VAR'(0)))), S == \"f(B,C1)' 'A\"", "true"),
+            ("with_output_to(string(S), write_term([1, 2, 3], [max_depth(2)])), S == \"[1|...]\"", "true"),
+            ("with_output_to(string(S), write_term(a + b, [ignore_ops(true), quoted(true)])), S == \"+(a,b)\"", "true"),
+            ("with_output_to(string(S), write_term('$VAR'(3), [numbervars(false)])), S == \"$VAR(3)\"", "true"),
+            ("with_output_to(string(S), print(f(secret(1), [secret(x), y]))), S == \"f(<hidden>,[<hidden>,y])\"", "true"),
+            ("with_output_to(string(S), write_term(secret(1), [portray(false)])), S == \"secret(1)\"", "true"),
+            ("with_output_to(string(S), print(- secret(1))), S == \"- <hidden>\"", "true"),
+            ("with_output_to(string(S), print(\"text\")), S == \"\\\"text\\\"\"", "true"),
+            ("open_string(\"\", In), catch(write(In, a), error(permission_error(output, stream, In), _), true)", "true"),
+            ("write(nosuch, a)", "existence_error(stream, nosuch)"),
+            ("write_term(a, [quoted(maybe)])", "domain_error(write_option, quoted(maybe))"),
+            ("write_term(a, [max_depth(-1)])", "domain_error(write_option, max_depth(-1))"),
+            ("write_term(a, [bold(true)])", "domain_error(write_option, bold(true))"),
+            ("write_term(a, [quoted(_)])", "instantiation_error"),
+            ("write_term(a, [_])", "instantiation_error"),
+            ("write_term(a, foo)", "type_error(list, foo)"),
+        ]);
+        let answers: Vec<String> = solutions(&mut engine, "X = f('A', [1, 2], \"s\", 1 - -1, - (1), '$VAR'(2))").iter().map(|s| s.to_string()).collect();
+        assert_eq!(answers, vec!["X = f('A',[1,2],\"s\",1- -1,-(1),C)".to_string()]);
+    }
+
+    #[test]
+    fn test_format() {
+        let mut engine = Engine::new();
+        engine.consult("
+            portray(secret(_)) :- write('<hidden>').
+            table :- format(\"~w~t~8|~w~n\", [name, value]), format(\"~a~t~8|~d~n\", [x, 42]).
+        ").unwrap();
+        engine.flags_mut().double_quotes = DoubleQuotes::String;
+        conformance(&mut engine, &[
+            ("format(atom(A), \"~w and ~q\", [f('A'), 'B c']), A == 'f(A) and \\'B c\\''", "true"),
+            ("format(string(S), '~a', abc), S == \"abc\"", "true"),
+            ("format(codes(Cs), \"~d\", [7]), Cs = [0'7]", "true"),
+            ("format(chars(Cs), [0'~, 0'w], [x]), Cs = [x]", "true"),
+            ("format(atom(A), \"~p\", [[secret(1), 2]]), A == '[<hidden>,2]'", "true"),
+            ("format(atom(A), \"~w ~p ~w\", [secret(1), secret(2), secret(3)]), A == 'secret(1) <hidden> secret(3)'", "true"),
+            ("format(atom(A), \"~s~2d~D\", [\"ab\", 314, 1000]), A == 'ab3.141,000'", "true"),
+            ("format(atom(A), \"~e|~3f|~g\", [1, 2, 3]), A == '1.000000e+00|2.000|3'", "true"),
+            ("with_output_to(string(S), table), S == \"name    value\\nx       42\\n\"", "true"),
+            ("with_output_to(string(S), (write(abc), format(\"~t~w~6|\", [x]))), S == \"abc  x\"", "true"),
+            ("open_string(\"\", In), catch(format(In, \"x\", []), error(permission_error(output, stream, In), _), true)", "true"),
+            ("format(atom(A), \"~w ~w\", [a])", "format('not enough arguments')"),
+            ("format(atom(A), \"~w\", [a, b])", "format('too many arguments')"),
+            ("format(atom(A), \"~d\", [a])", "type_error(integer, a)"),
+            ("format(atom(A), \"~y\", [])", "format('unknown directive ~y')"),
+            ("format(atom(A), F, [])", "instantiation_error"),
+            ("format(atom(A), \"~w\", [a|_])", "instantiation_error"),
+            ("format(nosuch, \"x\", [])", "existence_error(stream, nosuch)"),
+            ("format(file(f), \"x\", [])", "domain_error(output_sink, file(f))"),
+            // the errors are raised in the context of the predicate that was called
+            ("catch(format(\"~d\", [a]), error(_, C), true), C = context(format/2, _)", "true"),
+            ("catch(format(atom(_), \"~d\", [a]), error(_, C), true), C = context(format/3, _)", "true"),
+            ("current_output(S), catch(format(S, \"~w\", []), error(_, C), true), C = context(format/3, _)", "true"),
+            ("catch(format(file(f), \"x\", []), error(_, C), true), C = context(format/3, _)", "true"),
+            ("'$format'(user_output, \"~p\", [a], [])", "system_error('the arguments of ~p are not portrayed')"),
+        ]);
+    }
+
+    #[test]
+    fn test_lists() {
+        let mut engine = Engine::new();
+        engine.consult("
+            double(X, Y) :- Y is 2 * X.
+            add3(X, Y, Z, S) :- S is X + Y + Z.
+            sum4(A, B, C, D, S) :- S is A + B + C + D.
+            sum5(A, B, C, D, E, S) :- S is A + B + C + D + E.
+            positive(X) :- X > 0.
+            plus(X, Y, Z) :- Z is X + Y.
+            dot(X, Y, S0, S) :- S is S0 + X * Y.
+            dot3(X, Y, Z, S0, S) :- S is S0 + X * Y * Z.
+        ").unwrap();
+        conformance(&mut engine, &[
+            ("append([a, b], [c], L), L == [a, b, c]", "true"),
+            ("findall(X-Y, append(X, Y, [1, 2]), L), L == [[]-[1, 2], [1]-[2], [1, 2]-[]]", "true"),
+            ("append(X, [c], [a, b, c]), X == [a, b]", "true"),
+            ("append([[a], [], [b, c]], L), L == [a, b, c]", "true"),
+            ("findall(X, member(X, [a, b, c]), L), L == [a, b, c]", "true"),
+            ("member(d, [a, b, c])", "false"),
+            ("findall(X, memberchk(X, [a, b]), L), L == [a]", "true"),
+            ("memberchk(f(X), [a, f(1), f(2)]), X == 1", "true"),
+            ("length([a, b, c], N), N == 3", "true"),
+            ("length(L, 2), L = [A, B], var(A), var(B), A \\== B", "true"),
+            ("length([a|T], 3), length(T, 2)", "true"),
+            ("length([a, b], 3)", "false"),
+            ("length(L, -1)", "domain_error(not_less_than_zero, -1)"),
+            ("length(L, a)", "type_error(integer, a)"),
+            ("catch(length(L, a), error(_, C), true), C = context(length/2, _)", "true"),
+            ("reverse([1, 2, 3], L), L == [3, 2, 1]", "true"),
+            ("reverse([], L), L == []", "true"),
+            ("nth0(1, [a, b, c], X), X == b", "true"),
+            ("nth1(1, [a, b, c], X), X == a", "true"),
+            ("nth0(3, [a, b, c], X)", "false"),
+            ("nth1(0, [a, b, c], X)", "false"),
+            ("findall(I-X, nth0(I, [a, b], X), L), L == [0-a, 1-b]", "true"),
+            ("findall(I, nth1(I, [a, b, a], a), L), L == [1, 3]", "true"),
+            ("nth0(x, [a], X)", "type_error(integer, x)"),
+            ("catch(nth0(x, [a], X), error(_, C), true), C = context(nth0/3, _)", "true"),
+            ("catch(nth1(x, [a], X), error(_, C), true), C = context(nth1/3, _)", "true"),
+            ("last([a, b, c], X), X == c", "true"),
+            ("last([], X)", "false"),
+            ("maplist(positive, [1, 2])", "true"),
+            ("maplist(positive, [1, -2])", "false"),
+            ("maplist(double, [1, 2, 3], L), L == [2, 4, 6]", "true"),
+            ("maplist(plus, [1, 2], [10, 20], L), L == [11, 22]", "true"),
+            ("maplist(add3, [1], [2], [3], L), L == [6]", "true"),
+            ("maplist(sum4, [1], [2], [3], [4], L), L == [10]", "true"),
+            ("maplist(sum5, [1, 0], [2, 0], [3, 0], [4, 0], [5, 0], L), L == [15, 0]", "true"),
+            ("maplist(=(x), [A, B]), A == x, B == x", "true"),
+            ("foldl(plus, [1, 2, 3], 0, S), S == 6", "true"),
+            ("foldl(dot, [1, 2], [3, 4], 0, S), S == 11", "true"),
+            ("foldl(dot3, [1, 2], [3, 4], [5, 6], 0, S), S == 63", "true"),
+            ("include(positive, [1, -1, 2, 0], L), L == [1, 2]", "true"),
+            ("exclude(positive, [1, -1, 2, 0], L), L == [-1, 0]", "true"),
+            ("partition(positive, [1, -1, 2, 0], I, X), I == [1, 2], X == [-1, 0]", "true"),
+            ("sum_list([1, 2, 3], S), S == 6", "true"),
+            ("sum_list([], S), S == 0", "true"),
+            ("sum_list([1, a], S)", "type_error(evaluable, '/'(a, 0))"),
+            ("max_list([3, 1, 4, 1, 5], M), M == 5", "true"),
+            ("min_list([3, 1, 4, 1, 5], M), M == 1", "true"),
+            ("max_list([], M)", "false"),
+            ("list_to_set([b, a, b, c, a, X, Y, X], S), S = [b, a, c, X1, Y1], X1 == X, Y1 == Y", "true"),
+        ]);
+
+        // a program can define its own version of a library predicate
+        engine.consult("last(_, mine).").unwrap();
+        assert_eq!(solutions(&mut engine, "last([a, b], X)").len(), 1);
+        assert_eq!(solutions(&mut engine, "last([a, b], X)")[0].get("X"), Some(&term("mine")));
+        assert_eq!(solutions(&mut engine, "member(b, [a, b])").len(), 1);
+    }
+
+    #[test]
+    fn test_modules() {
+        let mut engine = Engine::new();
+        engine.consult("
+            :- module(shapes, [area/2]).
+            area(square(S), A) :- sq(S, A).
+            area(rect(W, H), A) :- A is W * H.
+            sq(X, Y) :- Y is X * X.
+        ").unwrap();
+        engine.consult("
+            :- module(counter, [next/1]).
+            :- dynamic count/1.
+            count(0).
+            next(N) :- retract(count(N0)), N is N0 + 1, assertz(count(N)).
+        ").unwrap();
+        engine.consult("
+            :- module(m, [apply_to/3]).
+            :- meta_predicate apply_to(2, ?, ?).
+            apply_to(G, X, Y) :- call(G, X, Y).
+            double(X, Y) :- Y is 10 * X.
+        ").unwrap();
+        engine.consult("double(X, Y) :- Y is 2 * X.").unwrap();
+        assert!(engine.take_warnings().is_empty());
+        conformance(&mut engine, &[
+            ("area(square(3), A), A == 9", "true"),
+            ("sq(3, A)", "existence_error(procedure, '/'(sq, 2))"),
+            ("shapes:sq(3, A), A == 9", "true"),
+            ("G = sq(2, A), shapes:G, A == 4", "true"),
+            ("M = shapes, M:sq(2, A), A == 4", "true"),
+            ("call(shapes:sq, 2, A), A == 4", "true"),
+            ("shapes:nope", "existence_error(procedure, ':'(shapes, '/'(nope, 0)))"),
+            ("call(M:true)", "instantiation_error"),
+            ("call(1:true)", "type_error(atom, 1)"),
+            ("findall(S, clause(area(S, _), _), L), L = [square(_), rect(_, _)]", "true"),
+            ("next(X), next(Y), X == 1, Y == 2", "true"),
+            ("count(_)", "existence_error(procedure, '/'(count, 1))"),
+            ("counter:count(N), N == 2", "true"),
+            ("clause(counter:count(N), true), N == 2", "true"),
+            // the closure is called in the module of the caller
+            ("apply_to(double, 3, Y), Y == 6", "true"),
+            ("m:apply_to(double, 3, Y), Y == 30", "true"),
+            ("call(apply_to(double), 3, Y), Y == 6", "true"),
+            ("G = apply_to(double, 3, Y), m:G, Y == 30", "true"),
+            ("maplist(apply_to(double), [1, 2], L), L == [2, 4]", "true"),
+        ]);
+
+        // a predicate cannot be imported from two modules
+        engine.consult(":- module(a, [p/1]). p(a).").unwrap();
+        engine.consult(":- module(b, [p/1]). p(b).").unwrap();
+        let warnings: Vec<String> = engine.take_warnings().iter().map(|w| w.message.clone()).collect();
+        assert_eq!(warnings, vec!["cannot import b:p/1 into user, it is already imported from a".to_string()]);
+        assert_eq!(solutions(&mut engine, "p(X)")[0].get("X"), Some(&term("a")));
+        assert_eq!(solutions(&mut engine, "b:p(X)")[0].get("X"), Some(&term("b")));
+    }
+
+    #[test]
+    fn test_use_module() {
+        let dir = std::env::temp_dir().join(format!("cadmium-modules-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("geometry.pl"), ":- module(geometry, [perimeter/2, diagonal/2]).\nperimeter(square(S), P) :- P is 4 * S.\ndiagonal(a, c).\n").unwrap();
+        fs::write(dir.join("main.pl"), "\
+:- use_module(geometry, [perimeter/2]).
+:- use_module('geometry.pl', [perimeter/2]).
+:- use_module(library(lists)).
+:- use_module(library(nope)).
+run(P) :- perimeter(square(2), P).
+").unwrap();
+
+        let mut engine = Engine::new();
+        engine.consult_file(dir.join("main.pl")).unwrap();
+        let warnings = engine.take_warnings();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].span.line, 4);
+        assert_eq!(warnings[0].message, "cannot find library(nope)");
+        conformance(&mut engine, &[
+            ("run(P), P == 8", "true"),
+            // only the listed predicates are imported
+            ("diagonal(a, X)", "existence_error(procedure, '/'(diagonal, 2))"),
+            ("geometry:diagonal(a, X), X == c", "true"),
+        ]);
+    }
+
+    #[test]
+    fn test_dcg() {
+        let mut engine = Engine::new();
+        engine.consult("
+            greeting --> [hello], name.
+            name --> [world].
+            name --> [prolog].
+
+            expr(V) --> term(T), ( \"+\" -> expr(R), { V is T + R } ; { V = T } ).
+            term(N) --> digits(Ds), { number_codes(N, Ds) }.
+            digits([D|Ds]) --> digit(D), ( digits(Ds) -> [] ; { Ds = [] } ).
+            digit(D) --> [D], { D >= 0'0, D =< 0'9 }.
+
+            peek(X), [X] --> [X].
+            twice(G) --> call(G), call(G).
+            letter(C) --> [C], { C \\== 0'b }.
+            not_a --> \\+ \"a\", [_].
+            any --> [].
+            any --> [_], any.
+
+            word([C]) --> [C].
+            word([C|Cs]) --> [C], word(Cs).
+            first_word(W) --> word(W), [' '], !.
+            sign(-1) --> \"-\", !.
+            sign(1) --> [].
+
+            % the variables of the rule are kept apart from the ones of the translation
+            same(_S1) --> [_S1], greeting.
+        ").unwrap();
+        assert!(engine.take_warnings().is_empty());
+        conformance(&mut engine, &[
+            ("phrase(greeting, [hello, world])", "true"),
+            ("phrase(greeting, [hello, you])", "false"),
+            ("findall(X, phrase(greeting, [hello, X]), L), L == [world, prolog]", "true"),
+            ("phrase(greeting, L, R), L == [hello, world|R]", "true"),
+            ("phrase(expr(V), \"12+3+40\"), V == 55", "true"),
+            ("phrase(expr(V), \"12+\")", "false"),
+            ("phrase(peek(X), [a, b], R), X == a, R == [a, b]", "true"),
+            ("phrase(twice(letter(C)), \"aa\"), C == 0'a", "true"),
+            ("phrase(twice(letter(_)), \"ab\")", "false"),
+            ("phrase(not_a, \"b\")", "true"),
+            ("phrase(not_a, \"a\")", "false"),
+            // a body is translated when it is called
+            ("phrase(([hello], name), [hello, prolog])", "true"),
+            ("phrase(({X = 1}, []), [])", "true"),
+            ("findall(R, phrase(any, [a, b], R), L), L == [[a, b], [b], []]", "true"),
+            // a cut commits to the rule and to the choices made in its body so far
+            ("findall(W-R, phrase(first_word(W), [a, b, ' ', c], R), L), L == [[a, b]-[c]]", "true"),
+            ("findall(W-R, phrase(first_word(W), [a, ' ', b, ' '], R), L), L == [[a]-[b, ' ']]", "true"),
+            ("phrase(first_word(_), [a, ' ', b, ' '])", "false"),
+            ("findall(S, phrase(sign(S), \"-\", _), L), L == [-1]", "true"),
+            ("findall(S, phrase(sign(S), \"+\", _), L), L == [1]", "true"),
+            ("findall(R, phrase(([a], !, [b] ; [a, c]), [a, c], R), L), L == []", "true"),
+            ("phrase(same(X), [x, hello, world]), X == x", "true"),
+            ("phrase(G, [a])", "instantiation_error"),
+            ("phrase(1, [a])", "type_error(callable, 1)"),
+            ("phrase(any, a)", "type_error(list, a)"),
+            ("phrase(any, [a], [b|c])", "type_error(list, '.'(b, c))"),
+        ]);
+    }
+}
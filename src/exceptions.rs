@@ -0,0 +1,91 @@
+// Exceptions raised while running a program, and constructors for the ISO error terms.
+
+use crate::ast_common::{PredSig, USER};
+use crate::ir::Value;
+
+// Exceptional outcome of a built-in predicate
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Exception {
+    // a ball thrown by the program, it is propagated as is
+    Throw(Value),
+    // an ISO error with given formal term, the VM wraps it in error(Formal, Context)
+    Error(Value),
+}
+
+impl Exception {
+    // The ball to unwind the stack with, given the context of the error
+    pub fn into_ball(self, context: Value) -> Value {
+        match self {
+            Exception::Throw(ball) => ball,
+            Exception::Error(formal) => Value::Ctor("error".to_string(), vec![formal, context]),
+        }
+    }
+}
+
+fn atom(a: &str) -> Value {
+    Value::Atom(a.to_string())
+}
+
+fn formal(name: &str, args: Vec<Value>) -> Exception {
+    Exception::Error(Value::Ctor(name.to_string(), args))
+}
+
+// Name/Arity
+pub fn predicate_indicator(name: &str, arity: usize) -> Value {
+    Value::Ctor("/".to_string(), vec![atom(name), Value::Num(arity as i64)])
+}
+
+// Name/Arity for the predicates of user, Module:Name/Arity for the others
+pub fn qualified_indicator(PredSig(p, arity): &PredSig) -> Value {
+    let indicator = predicate_indicator(p.name(), *arity);
+    match p.module() {
+        Some(m) if m != USER => Value::Ctor(":".to_string(), vec![atom(m), indicator]),
+        _ => indicator
+    }
+}
+
+pub fn instantiation_error() -> Exception {
+    Exception::Error(atom("instantiation_error"))
+}
+
+pub fn uninstantiation_error(culprit: Value) -> Exception {
+    formal("uninstantiation_error", vec![culprit])
+}
+
+pub fn type_error(kind: &str, culprit: Value) -> Exception {
+    formal("type_error", vec![atom(kind), culprit])
+}
+
+pub fn domain_error(domain: &str, culprit: Value) -> Exception {
+    formal("domain_error", vec![atom(domain), culprit])
+}
+
+pub fn existence_error(kind: &str, culprit: Value) -> Exception {
+    formal("existence_error", vec![atom(kind), culprit])
+}
+
+pub fn permission_error(action: &str, kind: &str, culprit: Value) -> Exception {
+    formal("permission_error", vec![atom(action), atom(kind), culprit])
+}
+
+pub fn representation_error(what: &str) -> Exception {
+    formal("representation_error", vec![atom(what)])
+}
+
+pub fn syntax_error(what: &str) -> Exception {
+    formal("syntax_error", vec![atom(what)])
+}
+
+// An error in the format text or the arguments of format/2,3
+pub fn format_error(message: &str) -> Exception {
+    formal("format", vec![atom(message)])
+}
+
+pub fn evaluation_error(what: &str) -> Exception {
+    formal("evaluation_error", vec![atom(what)])
+}
+
+// Errors that indicate a bug in the compiler or the VM rather than in the program
+pub fn system_error(msg: &str) -> Exception {
+    formal("system_error", vec![atom(msg)])
+}
@@ -0,0 +1,442 @@
+// C interface for embedding cadmium in non-Rust hosts. The declarations in include/cadmium.h are generated from this file by the build script, run `CADMIUM_UPDATE_HEADER=1 cargo build` to update them.
+//
+// Engines, queries and terms are exposed as opaque handles. Every function returns a status code or a null pointer on failure, and the message describing the last error of an engine is available through `cadmium_engine_error`.
+
+use crate::engine::{Engine, Query, Solution};
+use crate::ir::Value;
+use std::cell::{Cell, RefCell};
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/// Status codes returned by the C interface.
+#[repr(C)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum CadmiumStatus {
+    /// The call succeeded. For `cadmium_query_next`, a solution was found.
+    Ok = 0,
+    /// The query has no (more) solutions.
+    NoSolution = 1,
+    /// The source text or the goal could not be parsed.
+    ParseError = 2,
+    /// A null pointer or a string that is not valid UTF-8 was passed.
+    InvalidArgument = 3,
+    /// Another query on the same engine is still open.
+    Busy = 4,
+    /// The variable or argument that was asked for does not exist.
+    NotFound = 5,
+    /// The term is not of the kind the call expects.
+    TypeError = 6,
+    /// The query raised an exception that it did not catch. The message of `cadmium_engine_error` describes the exception.
+    Exception = 7,
+    /// The engine failed with an internal error, which `cadmium_engine_error` describes. The engine should not be used anymore, except to free it.
+    InternalError = 8,
+}
+
+/// The kinds of terms.
+#[repr(C)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum CadmiumTermKind {
+    Variable = 0,
+    Atom = 1,
+    Integer = 2,
+    Compound = 3,
+    String = 4,
+    /// No term was given.
+    Invalid = -1,
+}
+
+/// An engine holding a consulted program.
+pub struct CadmiumEngine {
+    // the engine has its own allocation, so an open query can hold the only mutable borrow of it while the error and the query flag below are updated through shared references
+    engine: *mut Engine,
+    last_error: RefCell<Option<CString>>,
+    query_open: Cell<bool>,
+}
+
+/// An open query and its current solution.
+pub struct CadmiumQuery {
+    engine: *const CadmiumEngine,
+    // the query borrows the engine, which cannot be freed while the query is open
+    query: Query<'static>,
+    variables: Vec<CString>,
+    solution: Option<Solution>,
+}
+
+/// A term read back from a solution.
+pub struct CadmiumTerm {
+    value: Value,
+    // the name of an atom, the functor of a compound term or the text of a string
+    name: Option<CString>,
+}
+
+impl CadmiumEngine {
+    fn fail(&self, status: CadmiumStatus, msg: String) -> CadmiumStatus {
+        // interior NULs cannot be represented in C strings, so they are dropped
+        *self.last_error.borrow_mut() = CString::new(msg.replace('\0', "")).ok();
+        status
+    }
+
+    // The engine, unless a query holds it. The borrow is not tied to this handle, the engine lives until the handle is freed.
+    unsafe fn engine_mut<'a>(&self) -> Result<&'a mut Engine, CadmiumStatus> {
+        if self.query_open.get() {
+            Err(self.fail(CadmiumStatus::Busy, "a query is open on this engine".to_string()))
+        } else {
+            Ok(&mut *self.engine)
+        }
+    }
+}
+
+impl Drop for CadmiumEngine {
+    fn drop(&mut self) {
+        // SAFETY: the engine was allocated by `cadmium_engine_new` and no query borrows it anymore
+        unsafe { drop(Box::from_raw(self.engine)) }
+    }
+}
+
+impl CadmiumTerm {
+    fn new(value: Value) -> *mut CadmiumTerm {
+        let name = match &value {
+            Value::Atom(a) | Value::Ctor(a, _) | Value::Str(a) => CString::new(a.replace('\0', "")).ok(),
+            _ => None,
+        };
+        Box::into_raw(Box::new(CadmiumTerm { value, name }))
+    }
+}
+
+// The text of the payload of a panic
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(payload) => payload.downcast_ref::<&str>().map_or_else(|| "unknown panic".to_string(), |msg| msg.to_string()),
+    }
+}
+
+// Run the body of an entry point, a panic must not unwind into the caller. It gives `fallback` instead, and is described as the last error of given engine, if any.
+fn guard<T>(engine: Option<&CadmiumEngine>, fallback: T, body: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(result) => result,
+        Err(payload) => {
+            if let Some(engine) = engine {
+                engine.fail(CadmiumStatus::InternalError, format!("internal error: {}", panic_message(payload)));
+            }
+            fallback
+        }
+    }
+}
+
+// The engine of a query, if there is a query
+unsafe fn query_engine<'a>(query: *const CadmiumQuery) -> Option<&'a CadmiumEngine> {
+    query.as_ref().and_then(|q| q.engine.as_ref())
+}
+
+// Borrow a C string argument as UTF-8
+unsafe fn str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        None
+    } else {
+        CStr::from_ptr(s).to_str().ok()
+    }
+}
+
+/// Create a new engine with an empty program. Free it with `cadmium_engine_free`.
+#[no_mangle]
+pub extern "C" fn cadmium_engine_new() -> *mut CadmiumEngine {
+    guard(None, ptr::null_mut(), || {
+        let engine = Box::into_raw(Box::new(Engine::new()));
+        Box::into_raw(Box::new(CadmiumEngine { engine, last_error: RefCell::new(None), query_open: Cell::new(false) }))
+    })
+}
+
+/// Destroy an engine. Returns `CADMIUM_STATUS_BUSY` and leaves the engine alive if a query is still open on it.
+///
+/// # Safety
+/// `engine` must be null or a pointer returned by `cadmium_engine_new` that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_engine_free(engine: *mut CadmiumEngine) -> CadmiumStatus {
+    match engine.as_ref() {
+        Some(e) if e.query_open.get() => e.fail(CadmiumStatus::Busy, "a query is open on this engine".to_string()),
+        // the engine is gone once it is dropped, so a panic cannot be recorded on it
+        Some(_) => guard(None, CadmiumStatus::InternalError, || {
+            drop(Box::from_raw(engine));
+            CadmiumStatus::Ok
+        }),
+        None => CadmiumStatus::InvalidArgument,
+    }
+}
+
+/// The message describing the last error on given engine, or null if there was none. The string is owned by the engine and valid until the next call on it.
+///
+/// # Safety
+/// `engine` must be null or a live engine.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_engine_error(engine: *const CadmiumEngine) -> *const c_char {
+    // the string stays where it is until the error is replaced, which outlives the borrow
+    match engine.as_ref().and_then(|e| e.last_error.borrow().as_ref().map(|msg| msg.as_ptr())) {
+        Some(msg) => msg,
+        None => ptr::null(),
+    }
+}
+
+/// Add the clauses in given source text to the program of an engine. This is refused with `CADMIUM_STATUS_BUSY` while a query is open on the engine.
+///
+/// # Safety
+/// `engine` must be null or a live engine, `source` must be null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_consult_string(engine: *mut CadmiumEngine, source: *const c_char) -> CadmiumStatus {
+    let engine = match engine.as_ref() {
+        Some(engine) => engine,
+        None => return CadmiumStatus::InvalidArgument,
+    };
+    let source = match str_arg(source) {
+        Some(source) => source,
+        None => return engine.fail(CadmiumStatus::InvalidArgument, "source is not a valid UTF-8 string".to_string()),
+    };
+
+    let inner = match engine.engine_mut() {
+        Ok(inner) => inner,
+        Err(status) => return status,
+    };
+    guard(Some(engine), CadmiumStatus::InternalError, || match inner.consult(source) {
+        Ok(()) => CadmiumStatus::Ok,
+        Err(e) => engine.fail(CadmiumStatus::ParseError, e.to_string()),
+    })
+}
+
+/// Open a query for given goal and store it in `*query`. Only one query can be open on an engine at a time. Close it with `cadmium_query_close`.
+///
+/// # Safety
+/// `engine` must be null or a live engine, `goal` must be null or a NUL-terminated string, and `query` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_query_open(engine: *mut CadmiumEngine, goal: *const c_char, query: *mut *mut CadmiumQuery) -> CadmiumStatus {
+    let engine_ptr = engine;
+    let engine = match engine.as_ref() {
+        Some(engine) => engine,
+        None => return CadmiumStatus::InvalidArgument,
+    };
+    if query.is_null() {
+        return engine.fail(CadmiumStatus::InvalidArgument, "no place to store the query".to_string());
+    }
+    let goal = match str_arg(goal) {
+        Some(goal) => goal,
+        None => return engine.fail(CadmiumStatus::InvalidArgument, "goal is not a valid UTF-8 string".to_string()),
+    };
+    // the engine lives at a fixed address until it is freed, which is refused while the query is open
+    let inner: &'static mut Engine = match engine.engine_mut() {
+        Ok(inner) => inner,
+        Err(status) => return status,
+    };
+    guard(Some(engine), CadmiumStatus::InternalError, move || match inner.query(goal) {
+        Ok(q) => {
+            let variables = q.variables().map(|x| CString::new(x).unwrap()).collect();
+            engine.query_open.set(true);
+            *query = Box::into_raw(Box::new(CadmiumQuery { engine: engine_ptr, query: q, variables, solution: None }));
+            CadmiumStatus::Ok
+        }
+        Err(e) => engine.fail(CadmiumStatus::ParseError, e.to_string()),
+    })
+}
+
+/// Find the next solution of a query. Returns `CADMIUM_STATUS_OK` if there is one, `CADMIUM_STATUS_NO_SOLUTION` once the solutions are exhausted and `CADMIUM_STATUS_EXCEPTION` if the query raised an uncaught exception.
+///
+/// # Safety
+/// `query` must be null or a live query.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_query_next(query: *mut CadmiumQuery) -> CadmiumStatus {
+    guard(query_engine(query), CadmiumStatus::InternalError, || match query.as_mut() {
+        Some(q) => {
+            q.solution = None;
+            match q.query.next_solution() {
+                Ok(Some(solution)) => {
+                    q.solution = Some(solution);
+                    CadmiumStatus::Ok
+                }
+                Ok(None) => CadmiumStatus::NoSolution,
+                Err(e) => (*q.engine).fail(CadmiumStatus::Exception, e.to_string()),
+            }
+        }
+        None => CadmiumStatus::InvalidArgument,
+    })
+}
+
+/// The number of named variables in a query.
+///
+/// # Safety
+/// `query` must be null or a live query.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_query_variable_count(query: *const CadmiumQuery) -> usize {
+    guard(query_engine(query), 0, || query.as_ref().map_or(0, |q| q.variables.len()))
+}
+
+/// The name of the variable at given index of a query, or null if the index is out of range. The string is owned by the query.
+///
+/// # Safety
+/// `query` must be null or a live query.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_query_variable_name(query: *const CadmiumQuery, index: usize) -> *const c_char {
+    guard(query_engine(query), ptr::null(), || match query.as_ref().and_then(|q| q.variables.get(index)) {
+        Some(name) => name.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Store the binding of given variable in the current solution in `*term`. Free the term with `cadmium_term_free`.
+///
+/// # Safety
+/// `query` must be null or a live query, `name` must be null or a NUL-terminated string, and `term` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_query_binding(query: *const CadmiumQuery, name: *const c_char, term: *mut *mut CadmiumTerm) -> CadmiumStatus {
+    let (q, name) = match (query.as_ref(), str_arg(name)) {
+        (Some(q), Some(name)) if !term.is_null() => (q, name),
+        _ => return CadmiumStatus::InvalidArgument,
+    };
+
+    guard(query_engine(query), CadmiumStatus::InternalError, || match q.solution.as_ref().and_then(|s| s.get(name)) {
+        Some(value) => {
+            *term = CadmiumTerm::new(value.clone());
+            CadmiumStatus::Ok
+        }
+        None => (*q.engine).fail(CadmiumStatus::NotFound, format!("no binding for {} in the current solution", name)),
+    })
+}
+
+/// Close a query and release the engine for the next one.
+///
+/// # Safety
+/// `query` must be null or a live query, its engine must still be alive.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_query_close(query: *mut CadmiumQuery) {
+    if let Some(engine) = query_engine(query) {
+        guard(Some(engine), (), || drop(Box::from_raw(query)));
+        engine.query_open.set(false);
+    }
+}
+
+/// The kind of a term, `CADMIUM_TERM_KIND_INVALID` if `term` is null.
+///
+/// # Safety
+/// `term` must be null or a live term.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_term_kind(term: *const CadmiumTerm) -> CadmiumTermKind {
+    guard(None, CadmiumTermKind::Invalid, || match term.as_ref().map(|t| &t.value) {
+        Some(Value::LV(_)) => CadmiumTermKind::Variable,
+        Some(Value::Atom(_)) => CadmiumTermKind::Atom,
+        Some(Value::Num(_)) => CadmiumTermKind::Integer,
+        Some(Value::Ctor(_, _)) => CadmiumTermKind::Compound,
+        Some(Value::Str(_)) => CadmiumTermKind::String,
+        None => CadmiumTermKind::Invalid,
+    })
+}
+
+/// Store the value of an integer term in `*value`.
+///
+/// # Safety
+/// `term` must be null or a live term, and `value` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_term_integer(term: *const CadmiumTerm, value: *mut i64) -> CadmiumStatus {
+    guard(None, CadmiumStatus::InternalError, || match (term.as_ref(), value.is_null()) {
+        (Some(CadmiumTerm { value: Value::Num(n), .. }), false) => {
+            *value = *n;
+            CadmiumStatus::Ok
+        }
+        (Some(_), false) => CadmiumStatus::TypeError,
+        _ => CadmiumStatus::InvalidArgument,
+    })
+}
+
+/// The name of an atom, the functor name of a compound term or the text of a string, null for other terms. The string is owned by the term.
+///
+/// # Safety
+/// `term` must be null or a live term.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_term_name(term: *const CadmiumTerm) -> *const c_char {
+    guard(None, ptr::null(), || match term.as_ref().and_then(|t| t.name.as_ref()) {
+        Some(name) => name.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// The number of arguments of a compound term, 0 for other terms.
+///
+/// # Safety
+/// `term` must be null or a live term.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_term_arity(term: *const CadmiumTerm) -> usize {
+    guard(None, 0, || match term.as_ref() {
+        Some(CadmiumTerm { value: Value::Ctor(_, args), .. }) => args.len(),
+        _ => 0,
+    })
+}
+
+/// Store the argument at given zero-based index of a compound term in `*arg`. Free the argument with `cadmium_term_free`.
+///
+/// # Safety
+/// `term` must be null or a live term, and `arg` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_term_arg(term: *const CadmiumTerm, index: usize, arg: *mut *mut CadmiumTerm) -> CadmiumStatus {
+    guard(None, CadmiumStatus::InternalError, || match (term.as_ref(), arg.is_null()) {
+        (Some(CadmiumTerm { value: Value::Ctor(_, args), .. }), false) => match args.get(index) {
+            Some(value) => {
+                *arg = CadmiumTerm::new(value.clone());
+                CadmiumStatus::Ok
+            }
+            None => CadmiumStatus::NotFound,
+        },
+        (Some(_), false) => CadmiumStatus::TypeError,
+        _ => CadmiumStatus::InvalidArgument,
+    })
+}
+
+/// Render a term as text. Free the string with `cadmium_string_free`.
+///
+/// # Safety
+/// `term` must be null or a live term.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_term_to_string(term: *const CadmiumTerm) -> *mut c_char {
+    guard(None, ptr::null_mut(), || match term.as_ref() {
+        Some(t) => CString::new(t.value.to_string().replace('\0', "")).unwrap().into_raw(),
+        None => ptr::null_mut(),
+    })
+}
+
+/// Free a term.
+///
+/// # Safety
+/// `term` must be null or a live term.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_term_free(term: *mut CadmiumTerm) {
+    if !term.is_null() {
+        guard(None, (), || drop(Box::from_raw(term)));
+    }
+}
+
+/// Free a string returned by the C interface.
+///
+/// # Safety
+/// `s` must be null or a string returned by `cadmium_term_to_string`.
+#[no_mangle]
+pub unsafe extern "C" fn cadmium_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        guard(None, (), || drop(CString::from_raw(s)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panics_are_caught() {
+        let engine = cadmium_engine_new();
+        unsafe {
+            let status = guard(engine.as_ref(), CadmiumStatus::Ok, || panic!("broken invariant"));
+            assert_eq!(status, CadmiumStatus::Ok);
+            let status = guard(engine.as_ref(), CadmiumStatus::InternalError, || panic!("{} frames", 3));
+            assert_eq!(status, CadmiumStatus::InternalError);
+            assert_eq!(CStr::from_ptr(cadmium_engine_error(engine)).to_str(), Ok("internal error: 3 frames"));
+            assert_eq!(cadmium_term_kind(ptr::null()), CadmiumTermKind::Invalid);
+            assert_eq!(cadmium_engine_free(engine), CadmiumStatus::Ok);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+// Prolog flags, which configure the behaviour of the VM and can be changed by the program
+use crate::exceptions::*;
+use crate::ir::Value;
+
+// What to do when an undefined procedure is called
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Unknown {
+    // raise an existence_error
+    Error,
+    // fail silently
+    Fail,
+    // print a warning then fail
+    Warning,
+}
+
+// What a double-quoted item in the source text reads as
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub enum DoubleQuotes {
+    // a list of character codes
+    #[default]
+    Codes,
+    // a list of one-character atoms
+    Chars,
+    Atom,
+    String,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Flags {
+    pub unknown: Unknown,
+    pub double_quotes: DoubleQuotes,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Flags { unknown: Unknown::Error, double_quotes: DoubleQuotes::Codes }
+    }
+}
+
+fn atom(a: &str) -> Value {
+    Value::Atom(a.to_string())
+}
+
+impl Flags {
+    // The names of all flags
+    pub const NAMES: [&'static str; 2] = ["double_quotes", "unknown"];
+
+    // The value of given flag, if it exists
+    pub fn get(&self, name: &str) -> Option<Value> {
+        match name {
+            "unknown" => Some(atom(match self.unknown {
+                Unknown::Error => "error",
+                Unknown::Fail => "fail",
+                Unknown::Warning => "warning",
+            })),
+            "double_quotes" => Some(atom(match self.double_quotes {
+                DoubleQuotes::Codes => "codes",
+                DoubleQuotes::Chars => "chars",
+                DoubleQuotes::Atom => "atom",
+                DoubleQuotes::String => "string",
+            })),
+            _ => None
+        }
+    }
+
+    // Set given flag, the errors are the ones set_prolog_flag/2 raises
+    pub fn set(&mut self, name: &str, value: &Value) -> Result<(), Exception> {
+        let invalid = || domain_error("flag_value", Value::Ctor("+".to_string(), vec![atom(name), value.clone()]));
+        match (name, value) {
+            (_, Value::LV(_)) => Err(instantiation_error()),
+            ("unknown", Value::Atom(v)) => {
+                self.unknown = match v.as_str() {
+                    "error" => Unknown::Error,
+                    "fail" => Unknown::Fail,
+                    "warning" => Unknown::Warning,
+                    _ => return Err(invalid())
+                };
+                Ok(())
+            }
+            ("unknown", _) => Err(invalid()),
+            ("double_quotes", Value::Atom(v)) => {
+                self.double_quotes = match v.as_str() {
+                    "codes" => DoubleQuotes::Codes,
+                    "chars" => DoubleQuotes::Chars,
+                    "atom" => DoubleQuotes::Atom,
+                    "string" => DoubleQuotes::String,
+                    _ => return Err(invalid())
+                };
+                Ok(())
+            }
+            ("double_quotes", _) => Err(invalid()),
+            _ => Err(domain_error("prolog_flag", atom(name)))
+        }
+    }
+}
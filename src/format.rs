@@ -0,0 +1,334 @@
+// The directives of format/2,3, which write their arguments into a format text
+use crate::exceptions::*;
+use crate::ir::Value;
+use crate::ops::Ops;
+use crate::writer::Writer;
+use std::convert::TryFrom;
+
+// The numeric argument of a directive, which is written in the format text or taken from the arguments with `*`
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Count {
+    Given(usize),
+    Next,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+enum Piece {
+    Text(String),
+    // ~Nc, with the fill character of ~`ct
+    Directive(Option<Count>, Option<char>, char),
+}
+
+// Split a format text into the text written as is and the directives
+fn pieces(text: &str) -> Result<Vec<Piece>, Exception> {
+    let mut pieces = Vec::new();
+    let mut plain = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            plain.push(c);
+            continue;
+        }
+        let mut count = None;
+        let mut fill = None;
+        match chars.peek() {
+            Some('*') => {
+                chars.next();
+                count = Some(Count::Next);
+            }
+            Some('`') => {
+                chars.next();
+                fill = Some(chars.next().ok_or_else(|| format_error("truncated format specification"))?);
+            }
+            Some(d) if d.is_ascii_digit() => {
+                let mut n = 0usize;
+                while let Some(d) = chars.peek().and_then(|d| d.to_digit(10)) {
+                    chars.next();
+                    n = n.saturating_mul(10).saturating_add(d as usize);
+                }
+                count = Some(Count::Given(n));
+            }
+            _ => {}
+        }
+        let directive = chars.next().ok_or_else(|| format_error("truncated format specification"))?;
+        if directive == '~' {
+            plain.push('~');
+            continue;
+        }
+        if !plain.is_empty() {
+            pieces.push(Piece::Text(std::mem::take(&mut plain)));
+        }
+        pieces.push(Piece::Directive(count, fill, directive));
+    }
+    if !plain.is_empty() {
+        pieces.push(Piece::Text(plain));
+    }
+    Ok(pieces)
+}
+
+// Whether a directive writes an argument
+fn takes_argument(directive: char) -> bool {
+    "wpqadDsefgci".contains(directive)
+}
+
+// The arguments the ~p directives of a format text write, in order. They are portrayed before the text is formatted.
+pub fn printed(text: &str, args: &[Value]) -> Result<Vec<Value>, Exception> {
+    let mut args = args.iter();
+    let mut printed = Vec::new();
+    for piece in pieces(text)? {
+        if let Piece::Directive(count, _, directive) = piece {
+            if count == Some(Count::Next) {
+                args.next();
+            }
+            if takes_argument(directive) {
+                match args.next() {
+                    Some(arg) if directive == 'p' => printed.push(arg.clone()),
+                    Some(_) => {}
+                    None => break
+                }
+            }
+        }
+    }
+    Ok(printed)
+}
+
+// The text formatted so far, with the fill points of the column that is not complete yet
+struct Output {
+    text: String,
+    // the column the text starts at
+    start: usize,
+    // the column of the last column stop
+    stop: usize,
+    // the positions of the ~t fill points since the last column stop, with their fill characters
+    fills: Vec<(usize, char)>,
+}
+
+impl Output {
+    fn column(&self) -> usize {
+        match self.text.rfind('\n') {
+            Some(i) => self.text[i + 1..].chars().count(),
+            None => self.start + self.text.chars().count()
+        }
+    }
+
+    // End the column at given column, the text since the last stop is padded at its fill points, or on the right if it has none
+    fn column_stop(&mut self, column: usize) {
+        let current = self.column();
+        if current < column {
+            if self.fills.is_empty() {
+                self.fills.push((self.text.len(), ' '));
+            }
+            let pad = column - current;
+            let n = self.fills.len();
+            // the fill points on the right get the padding that does not divide evenly
+            for (i, (position, fill)) in self.fills.iter().enumerate().rev() {
+                let width = pad / n + if i >= n - pad % n { 1 } else { 0 };
+                self.text.insert_str(*position, &fill.to_string().repeat(width));
+            }
+        }
+        self.stop = column.max(current);
+        self.fills.clear();
+    }
+
+    fn newline(&mut self) {
+        self.text.push('\n');
+        self.stop = 0;
+        self.fills.clear();
+    }
+}
+
+// Insert a decimal point given number of digits from the right, and group the digits of the integer part by three if `group` is set
+fn decimal(n: i64, point: usize, group: bool) -> String {
+    let digits = n.unsigned_abs().to_string();
+    let digits = if digits.len() <= point { format!("{}{}", "0".repeat(point + 1 - digits.len()), digits) } else { digits };
+    let (integer, fraction) = digits.split_at(digits.len() - point);
+    let mut out = if n < 0 { "-".to_string() } else { String::new() };
+    for (i, c) in integer.chars().enumerate() {
+        if group && i > 0 && (integer.len() - i) % 3 == 0 {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    if point > 0 {
+        out.push('.');
+        out.push_str(fraction);
+    }
+    out
+}
+
+// A number in C's %e notation, with given number of digits after the point
+fn exponential(x: f64, digits: usize) -> String {
+    let text = format!("{:.*e}", digits, x);
+    let (mantissa, exponent) = text.split_once('e').expect("the exponent is written");
+    let exponent: i32 = exponent.parse().expect("the exponent is a number");
+    format!("{}e{}{:02}", mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+}
+
+// A number in C's %g notation, which is %e or %f depending on the exponent, without trailing zeros
+fn general(x: f64, digits: usize) -> String {
+    let precision = digits.max(1);
+    let exponent = if x == 0.0 { 0 } else { x.abs().log10().floor() as i64 };
+    let strip = |text: String| if text.contains('.') { text.trim_end_matches('0').trim_end_matches('.').to_string() } else { text };
+    if exponent < -4 || exponent >= precision as i64 {
+        let text = exponential(x, precision - 1);
+        let (mantissa, exponent) = text.split_once('e').unwrap();
+        format!("{}e{}", strip(mantissa.to_string()), exponent)
+    } else {
+        strip(format!("{:.*}", (precision as i64 - 1 - exponent) as usize, x))
+    }
+}
+
+fn integer(value: &Value) -> Result<i64, Exception> {
+    match value {
+        Value::Num(n) => Ok(*n),
+        Value::LV(_) => Err(instantiation_error()),
+        culprit => Err(type_error("integer", culprit.clone()))
+    }
+}
+
+// The text of the argument of ~s, which is a string or a list of codes or characters
+fn string_text(value: &Value) -> Result<String, Exception> {
+    if let Value::Str(s) = value {
+        return Ok(s.clone());
+    }
+    let items = value.list_items().ok_or_else(|| match value {
+        Value::LV(_) => instantiation_error(),
+        culprit => type_error("text", culprit.clone())
+    })?;
+    items.into_iter().map(|item| match item {
+        Value::Num(n) => u32::try_from(*n).ok().and_then(std::char::from_u32).ok_or_else(|| representation_error("character_code")),
+        Value::Atom(a) if a.chars().count() == 1 => Ok(a.chars().next().unwrap()),
+        Value::LV(_) => Err(instantiation_error()),
+        culprit => Err(type_error("text", culprit.clone()))
+    }).collect()
+}
+
+// Format the arguments, which should be resolved, as given format text says. The text is written at given column of the output,
+// and `printed` are the arguments of the ~p directives after portray/1 was called for them.
+pub fn format(ops: &Ops, text: &str, args: &[Value], printed: &[Value], column: usize) -> Result<String, Exception> {
+    let mut out = Output { text: String::new(), start: column, stop: column, fills: Vec::new() };
+    let mut args = args.iter();
+    let mut printed = printed.iter();
+    let mut next = || args.next().ok_or_else(|| format_error("not enough arguments"));
+    for piece in pieces(text)? {
+        let (count, fill, directive) = match piece {
+            Piece::Text(t) => {
+                out.text.push_str(&t);
+                continue;
+            }
+            Piece::Directive(count, fill, directive) => (count, fill, directive)
+        };
+        let count = match count {
+            Some(Count::Given(n)) => Some(n),
+            Some(Count::Next) => match next()? {
+                Value::Num(n) if *n >= 0 => Some(*n as usize),
+                culprit => return Err(format_error(&format!("~* expects a non-negative integer argument, found {}", culprit)))
+            },
+            None => None
+        };
+        match directive {
+            'w' => out.text.push_str(&Writer::new(ops, false).number_vars(true).write(next()?)),
+            'q' => out.text.push_str(&Writer::new(ops, true).number_vars(true).write(next()?)),
+            'p' => {
+                next()?;
+                let arg = printed.next().ok_or_else(|| system_error("the arguments of ~p are not portrayed"))?;
+                out.text.push_str(&Writer::new(ops, true).number_vars(true).portray(true).write(arg));
+            }
+            'a' => match next()? {
+                Value::Atom(a) | Value::Str(a) => out.text.push_str(a),
+                Value::Num(n) => out.text.push_str(&n.to_string()),
+                Value::LV(_) => return Err(instantiation_error()),
+                culprit => return Err(type_error("atomic", culprit.clone()))
+            },
+            'd' | 'D' => {
+                let n = integer(next()?)?;
+                out.text.push_str(&decimal(n, count.unwrap_or(0), directive == 'D'));
+            }
+            's' => out.text.push_str(&string_text(next()?)?),
+            'e' | 'f' | 'g' => {
+                // numbers are integers, they are written as floating point numbers
+                let x = integer(next()?)? as f64;
+                let digits = count.unwrap_or(6);
+                out.text.push_str(&match directive {
+                    'e' => exponential(x, digits),
+                    'f' => format!("{:.*}", digits, x),
+                    _ => general(x, digits)
+                });
+            }
+            'c' => {
+                let n = integer(next()?)?;
+                let c = u32::try_from(n).ok().and_then(std::char::from_u32).ok_or_else(|| representation_error("character_code"))?;
+                out.text.push_str(&c.to_string().repeat(count.unwrap_or(1)));
+            }
+            'i' => {
+                next()?;
+            }
+            'n' => (0..count.unwrap_or(1)).for_each(|_| out.newline()),
+            't' => out.fills.push((out.text.len(), fill.unwrap_or(' '))),
+            '|' => {
+                let column = count.unwrap_or_else(|| out.column());
+                out.column_stop(column);
+            }
+            '+' => {
+                let column = out.stop + count.unwrap_or(8);
+                out.column_stop(column);
+            }
+            _ => return Err(format_error(&format!("unknown directive ~{}", directive)))
+        }
+    }
+    if args.next().is_some() {
+        return Err(format_error("too many arguments"));
+    }
+    Ok(out.text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atom(a: &str) -> Value {
+        Value::Atom(a.to_string())
+    }
+
+    fn run(text: &str, args: &[Value]) -> Result<String, Exception> {
+        format(&Ops::default(), text, args, &[], 0)
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(run("~d ~2d ~D ~2D ~0d", &[Value::Num(42), Value::Num(1234), Value::Num(1234567), Value::Num(-1234567), Value::Num(5)]).unwrap(),
+                   "42 12.34 1,234,567 -12,345.67 5");
+        assert_eq!(run("~3d", &[Value::Num(-5)]).unwrap(), "-0.005");
+        assert_eq!(run("~e ~2e ~f ~1f ~g ~g", &[Value::Num(1234), Value::Num(-5), Value::Num(3), Value::Num(7), Value::Num(100), Value::Num(12345678)]).unwrap(),
+                   "1.234000e+03 -5.00e+00 3.000000 7.0 100 1.23457e+07");
+        assert_eq!(run("~d", &[atom("a")]), Err(type_error("integer", atom("a"))));
+    }
+
+    #[test]
+    fn test_columns() {
+        assert_eq!(run("~w~t~10|~w", &[atom("name"), atom("value")]).unwrap(), "name      value");
+        assert_eq!(run("~t~w~10|", &[atom("right")]).unwrap(), "     right");
+        assert_eq!(run("~t~w~t~11|", &[atom("mid")]).unwrap(), "    mid    ");
+        assert_eq!(run("~`-t~30|", &[]).unwrap(), "-".repeat(30));
+        assert_eq!(run("~w~t~5+~w~t~5+|", &[atom("a"), atom("b")]).unwrap(), "a    b    |");
+        assert_eq!(run("~w~4|~w", &[atom("toolong"), atom("x")]).unwrap(), "toolongx");
+        assert_eq!(run("a~nb~t~3|c", &[]).unwrap(), "a\nb  c");
+        assert_eq!(format(&Ops::default(), "~t~w~6|", &[atom("x")], &[], 2).unwrap(), "   x");
+    }
+
+    #[test]
+    fn test_directives() {
+        let args = [atom("A b"), atom("A b"), Value::Str("s".to_string()), Value::list(vec![Value::Num(104), Value::Num(105)]), Value::Num(0x263a), Value::Num(1)];
+        assert_eq!(run("~w ~q ~a ~s ~c~~~i~3n", &args).unwrap(), "A b 'A b' s hi ☺~\n\n\n");
+        assert_eq!(run("~*c", &[Value::Num(3), Value::Num(120)]).unwrap(), "xxx");
+        assert_eq!(run("no directives", &[]).unwrap(), "no directives");
+        assert_eq!(printed("~w ~p ~*c ~p", &[atom("a"), atom("b"), Value::Num(1), Value::Num(2), atom("c")]).unwrap(), vec![atom("b"), atom("c")]);
+
+        assert_eq!(run("~w ~w", &[atom("a")]), Err(format_error("not enough arguments")));
+        assert_eq!(run("~w", &[atom("a"), atom("b")]), Err(format_error("too many arguments")));
+        assert_eq!(run("~z", &[]), Err(format_error("unknown directive ~z")));
+        assert_eq!(run("abc~", &[]), Err(format_error("truncated format specification")));
+        // ~p writes the arguments portrayed by the caller
+        assert_eq!(run("~p", &[atom("a")]), Err(system_error("the arguments of ~p are not portrayed")));
+    }
+}
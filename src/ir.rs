@@ -1,7 +1,10 @@
 // Intermediate representation that the virtual machine uses
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
 use crate::ast_common::*;
+use crate::ops::Ops;
+use crate::writer::Writer;
 
 // Representation for logic variables, subject to change
 pub type LV = i64;
@@ -23,8 +26,14 @@ pub enum Insn {
     MkCheckpoint(Label, isize),
     Jump(isize),
     Call(PredSig),
+    CallGoal(usize), // pop a goal term and given number of extra arguments, then call the goal with the arguments added
+    MkCollect(isize), // start collecting the solutions of a findall/3 call, the list of the solutions is pushed at given offset once there are no more
+    Collect, // pop a solution, add a copy of it to the innermost collection, then backtrack for the next one
     Det(Label),
     DetUntil(Label),
+    Cut, // drop the checkpoints created since the current frame was entered, including the other clauses of its predicate
+    MkCatch(Label, isize), // pop the catcher and enter the goal of a catch/3 call with a catch frame of given label, the handler is at given offset
+    ExitCatch, // exit the goal of the innermost catch/3 call
     Fail,
     Ret,
     Halt
@@ -37,6 +46,10 @@ impl Insn {
             *target_of_self = target;
         } else if let Insn::Jump(ref mut target_of_self) = self {
             *target_of_self = target;
+        } else if let Insn::MkCatch(_, ref mut target_of_self) = self {
+            *target_of_self = target;
+        } else if let Insn::MkCollect(ref mut target_of_self) = self {
+            *target_of_self = target;
         } else {
             panic!("Tried to set the target of a non-jump, non-checkpoint instruction")
         }
@@ -49,27 +62,104 @@ pub enum Value {
     Atom(Atom),
     LV(LV),
     Num(i64),
+    // an SWI-Prolog string, which is text that is not an atom
+    Str(String),
     Ctor(Atom, Vec<Value>)
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        // values are shown the way writeq/1 writes them with the standard operators
+        formatter.write_str(&Writer::new(&Ops::default(), true).number_vars(true).write(self))
+    }
+}
+
+// The standard order of terms: variables, then numbers, then atoms, then strings, then compound terms by arity, name and arguments. The values should be resolved first.
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
         use Value::*;
 
-        match self {
-            Atom(a) => formatter.write_str(&a),
-            LV(x) => formatter.write_fmt(format_args!("_LV{}", x)),
-            Num(n) => formatter.write_fmt(format_args!("{}", n)),
-            Ctor(f, args) => {
-                for arg in args.iter() {
-                    arg.fmt(formatter)?;
+        fn rank(value: &Value) -> u8 {
+            match value {
+                LV(_) => 0,
+                Num(_) => 1,
+                Atom(_) => 2,
+                Str(_) => 3,
+                Ctor(_, _) => 4
+            }
+        }
+
+        match (self, other) {
+            (LV(x), LV(y)) => x.cmp(y),
+            (Num(m), Num(n)) => m.cmp(n),
+            (Atom(a), Atom(b)) | (Str(a), Str(b)) => a.cmp(b),
+            (Ctor(f, xs), Ctor(g, ys)) => xs.len().cmp(&ys.len()).then_with(|| f.cmp(g)).then_with(|| xs.cmp(ys)),
+            _ => rank(self).cmp(&rank(other))
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Value {
+    // The unbound variables in this value, in the order of their first occurrence. The value should be resolved first.
+    pub fn variables(&self) -> Vec<LV> {
+        fn collect(value: &Value, vars: &mut Vec<LV>) {
+            match value {
+                Value::LV(x) if !vars.contains(x) => vars.push(*x),
+                Value::Ctor(_, args) => args.iter().for_each(|arg| collect(arg, vars)),
+                _ => {}
+            }
+        }
+        let mut vars = Vec::new();
+        collect(self, &mut vars);
+        vars
+    }
+
+    // Build a proper list of given elements using the traditional '.'/2 and '[]' representation.
+    pub fn list<I>(items: I) -> Value where I: IntoIterator<Item=Value>, I::IntoIter: DoubleEndedIterator {
+        items.into_iter().rev().fold(Value::Atom("[]".to_string()), |tail, head| {
+            Value::Ctor(".".to_string(), vec![head, tail])
+        })
+    }
+
+    // The elements of this value if it is a proper list. Bound logic variables are not followed, so the value should be resolved first.
+    pub fn list_items(&self) -> Option<Vec<&Value>> {
+        let mut items = Vec::new();
+        let mut current = self;
+
+        loop {
+            match current {
+                Value::Atom(a) if a == "[]" => return Some(items),
+                Value::Ctor(f, args) if f == "." && args.len() == 2 => {
+                    items.push(&args[0]);
+                    current = &args[1];
                 }
-                formatter.write_str(&f)
+                _ => return None
             }
         }
     }
 }
 
+#[derive(Default)]
 pub struct Program {
     pub text: HashMap<PredSig, Vec<Insn>>, // code of each user predicate
+    pub spans: HashMap<PredSig, Vec<Span>>, // source position of each instruction, indexed like the code
+}
+
+impl Program {
+    // The source position of the instruction at given pc of given predicate
+    pub fn span(&self, predicate: &PredSig, pc: usize) -> Option<&Span> {
+        self.spans.get(predicate).and_then(|spans| spans.get(pc)).filter(|span| span.is_known())
+    }
+
+    // Add the predicates of given program, replacing the existing definitions
+    pub fn extend(&mut self, other: Program) {
+        self.text.extend(other.text);
+        self.spans.extend(other.spans);
+    }
 }
\ No newline at end of file
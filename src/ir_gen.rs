@@ -7,6 +7,9 @@ use crate::ir;
 use crate::ast_common::*;
 use std::collections::{HashMap, HashSet};
 
+// The all-solutions built-ins that are compiled to a call of the goal a built-in of the same name with a `$` prefix builds
+const GOAL_BUILDERS: [&str; 3] = ["bagof", "setof", "aggregate_all"];
+
 // The state of the IR-generating compiler. This structure carries information about the scope, the generated variable counter, etc.
 pub struct IRGen {
     // program text of the generated IR so far
@@ -14,11 +17,44 @@ pub struct IRGen {
     // counter for generated labels
     label_counter: Label,
     current_ir_code: Vec<Insn>,
+    // source positions of the instructions in the current code, and of the predicates compiled so far
+    current_spans: Vec<Span>,
+    spans: HashMap<PredSig, Vec<Span>>,
+    // the position of the goal being compiled
+    current_span: Span,
+    // the entry point of the program, which halts the VM instead of returning
+    entry: Option<PredSig>,
+    // the checkpoint a cut drops the later checkpoints down to if the cut is local to a construct, a cut commits to the clause otherwise
+    cut_scope: Option<Label>,
+}
+
+impl Default for IRGen {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IRGen {
     pub fn new() -> Self {
-        IRGen { ir_code: HashMap::new(), label_counter: 0, current_ir_code: Vec::new() }
+        Self::with_entry(PredSig(Pred::Qualified(USER.to_string(), "main".to_string()), 0))
+    }
+
+    pub fn with_entry(entry: PredSig) -> Self {
+        IRGen { entry: Some(entry), ..Self::without_entry() }
+    }
+
+    // A compiler for code that is called from other code, so every predicate returns
+    pub fn without_entry() -> Self {
+        IRGen {
+            ir_code: HashMap::new(),
+            label_counter: 0,
+            current_ir_code: Vec::new(),
+            current_spans: Vec::new(),
+            spans: HashMap::new(),
+            current_span: Span::default(),
+            entry: None,
+            cut_scope: None
+        }
     }
 
     pub fn compile_program(&mut self, program: ast::Program<usize>) {
@@ -31,20 +67,24 @@ impl IRGen {
         let sig = pred_def.sig();
         
         match pred_def.name {
-            p@Pred::Sys(_, _) => panic!(format!("Trying to define the system predicate {} in user code!", p)),
-            Pred::User(name) => {
+            p@Pred::Sys(_, _) => panic!("Trying to define the system predicate {} in user code!", p),
+            p@Pred::User(_) => panic!("Trying to compile the predicate {} before resolving its module!", p),
+            Pred::Qualified(_, name) => {
                 // we want each predicate to have only one definition by this point. Also, we don't allow re-definitions of system predicates. TODO: make these static checks
-                assert!(!self.ir_code.contains_key(&sig), format!("Trying to redefine the predicate {} in user code!", name));
+                assert!(!self.ir_code.contains_key(&sig), "Trying to redefine the predicate {} in user code!", name);
                 // assert that we are not in the middle of compiling another predicate
                 assert!(self.current_ir_code.is_empty(), "trying to compile a predicate while being in the middle of compiling another one");
-                let arity = pred_def.params.len();
+                // the code that is not a part of a goal belongs to the head of the predicate
+                self.current_span = pred_def.span.clone();
                 // generate the IR that will unify the parameters with the arguments on stack
                 self.compile_params(pred_def.params);
                 // compile given statement
                 self.compile_stmt(pred_def.body);
-                // insert a halt instruction if we are working on main
-                if name == "main" && arity  == 0 {
-                    self.current_ir_code.push(Insn::Halt);
+                // insert a halt instruction if we are working on the entry point, return to the caller otherwise
+                if self.entry.as_ref() == Some(&sig) {
+                    self.emit(Insn::Halt);
+                } else {
+                    self.emit(Insn::Ret);
                 }
                 // Insert initialization code for all locals
                 // TODO: do this after all optimizations and using a DFA to lower some unifications to Store instructions when one side is free and the other side is ground.
@@ -56,18 +96,21 @@ impl IRGen {
                     }
                 }
 
-                let mut ir_code = Vec::with_capacity(used_locals.len() * 1 + self.current_ir_code.len());
+                let mut ir_code = Vec::with_capacity(2 * used_locals.len() + self.current_ir_code.len());
 
                 // generate the initialization code
                 for n in used_locals.into_iter() {
                     ir_code.push(Insn::Fresh);
                     ir_code.push(Insn::Store(n));
                 }
+                let mut spans = vec![pred_def.span; ir_code.len()];
 
                 // move the body we were working on to the initialization code
                 ir_code.append(&mut self.current_ir_code);
+                spans.append(&mut self.current_spans);
 
                 // insert the code for this predicate
+                self.spans.insert(sig.clone(), spans);
                 self.ir_code.insert(sig, ir_code);
             }
         }
@@ -78,7 +121,7 @@ impl IRGen {
         for param in params.into_iter() {
             // push the expression to the op stack, then unify it with the argument that was on top of the stack already.
             self.compile_expr(param);
-            self.current_ir_code.push(Insn::Unify);
+            self.emit(Insn::Unify);
         }
     }
 
@@ -87,9 +130,10 @@ impl IRGen {
         use Expr::*;
 
         match expr {
-            Atom(a) => self.current_ir_code.push(Insn::PushValue(Value::Atom(a))),
-            PV(x) => self.current_ir_code.push(Insn::Load(x)),
-            Num(n) => self.current_ir_code.push(Insn::PushValue(Value::Num(n))),
+            Atom(a) => self.emit(Insn::PushValue(Value::Atom(a))),
+            PV(x) => self.emit(Insn::Load(x)),
+            Num(n) => self.emit(Insn::PushValue(Value::Num(n))),
+            Str(s) => self.emit(Insn::PushValue(Value::Str(s))),
             Ctor(f, args) => {
                 let n_args = args.len();
                 // push the args to the stack
@@ -97,14 +141,28 @@ impl IRGen {
                     self.compile_expr(arg);
                 }
                 // construct the functor
-                self.current_ir_code.push(Insn::Construct(f, n_args));
+                self.emit(Insn::Construct(f, n_args));
             }
         }
     }
 
-    pub fn create_checkpoint(&mut self) {
+    // add given instruction to the end of the current code, at the position of the current goal
+    fn emit(&mut self, insn: Insn) {
+        self.current_ir_code.push(insn);
+        self.current_spans.push(self.current_span.clone());
+    }
+
+    // create a checkpoint with a fresh label and return the label, the target is set later on
+    pub fn create_checkpoint(&mut self) -> Label {
         self.label_counter += 1;
-        self.current_ir_code.push(Insn::MkCheckpoint(self.label_counter, 0));
+        self.emit(Insn::MkCheckpoint(self.label_counter, 0));
+        self.label_counter
+    }
+
+    // set the target of the jump or checkpoint instruction at given pc to the end of the current code. The offsets are relative to the instruction following the jump.
+    fn patch_target(&mut self, pc: usize) {
+        let offset = (self.current_ir_code.len() - pc - 1) as isize;
+        self.current_ir_code[pc].set_target(offset);
     }
 
     // compile given statement and add it to the end of the current IR body
@@ -128,51 +186,149 @@ impl IRGen {
                 self.create_checkpoint();
                 // compile s1
                 self.compile_stmt(*s1);
-                // set checkpoint target
-                let cp_target_offset = (self.current_ir_code.len() - cp_pc) as isize;
-                self.current_ir_code[cp_pc].set_target(cp_target_offset);
                 // create the jump instruction
                 let jump_pc = self.current_ir_code.len();
-                self.current_ir_code.push(Insn::Jump(0));
+                self.emit(Insn::Jump(0));
+                // set checkpoint target to s2
+                self.patch_target(cp_pc);
                 // compile s2
                 self.compile_stmt(*s2);
                 // set jump target
-                let jump_target_offset = (self.current_ir_code.len() - jump_pc) as isize;
-                self.current_ir_code[jump_pc].set_target(jump_target_offset);
+                self.patch_target(jump_pc);
+            }
+            If(s1, s2, s3) => {
+                // we are compiling s1 -> s2 ; s3 into
+                // MkCheckpoint fresh_label, |[[s1]]| + |[[s2]]| + 2
+                // [[s1]]
+                // Det fresh_label
+                // [[s2]]
+                // Jump |[[s3]]|
+                // [[s3]]
+                // a cut in the condition is local to the condition, so it keeps the checkpoint of the else branch
+
+                let cp_pc = self.current_ir_code.len();
+                let label = self.create_checkpoint();
+                self.compile_scoped(*s1, label);
+                // commit to the first solution of the condition
+                self.emit(Insn::Det(label));
+                self.compile_stmt(*s2);
+                let jump_pc = self.current_ir_code.len();
+                self.emit(Insn::Jump(0));
+                self.patch_target(cp_pc);
+                self.compile_stmt(*s3);
+                self.patch_target(jump_pc);
+            }
+            // \+ G is (G -> fail ; true), the checkpoint of the if-then-else undoes the bindings of G
+            Not(s, _) => self.compile_stmt(If(s, Box::new(Fail), Box::new(True))),
+            Catch(s1, e, s2) => {
+                // we are compiling catch(s1, e, s2) into
+                // [[e]]
+                // MkCatch fresh_label, |[[s1]]| + 2
+                // [[s1]]
+                // ExitCatch
+                // Jump |[[s2]]|
+                // [[s2]]
+                // the goal and the handler are called as by call/1, so a cut in them is local to them
+
+                self.compile_expr(e);
+                let catch_pc = self.current_ir_code.len();
+                self.label_counter += 1;
+                let label = self.label_counter;
+                self.emit(Insn::MkCatch(label, 0));
+                self.compile_scoped(*s1, label);
+                self.emit(Insn::ExitCatch);
+                let jump_pc = self.current_ir_code.len();
+                self.emit(Insn::Jump(0));
+                self.patch_target(catch_pc);
+                if s2.has_cut() {
+                    // the catch frame is gone once the handler runs, so the handler is compiled as (s2 ; fail) and its cuts stop at that checkpoint
+                    let cp_pc = self.current_ir_code.len();
+                    let label = self.create_checkpoint();
+                    self.compile_scoped(*s2, label);
+                    let handler_jump_pc = self.current_ir_code.len();
+                    self.emit(Insn::Jump(0));
+                    self.patch_target(cp_pc);
+                    self.emit(Insn::Fail);
+                    self.patch_target(handler_jump_pc);
+                } else {
+                    self.compile_stmt(*s2);
+                }
+                self.patch_target(jump_pc);
             }
-            If(s1, s2, s3) => panic!("not implemented yet!"),
             Unify(e1, e2) => {
                 self.compile_expr(e1);
                 self.compile_expr(e2);
-                self.current_ir_code.push(Insn::Unify);
+                self.emit(Insn::Unify);
             }
-            Call(p, args) => {
+            Call(p, args, span) => {
                 let arity = args.len();
-                // push the arguments, right-to-left
-                for expr in args.into_iter().rev() {
-                    self.compile_expr(expr);
+                let outer_span = std::mem::replace(&mut self.current_span, span);
+                match p {
+                    Pred::Sys(ref name, _) if name == "findall" && arity == 3 => self.compile_findall(args),
+                    // these built-ins build the goal to call from their arguments, in a fresh variable that is passed as the last argument
+                    Pred::Sys(ref name, _) if GOAL_BUILDERS.contains(&name.as_str()) && arity == 3 => {
+                        self.emit(Insn::Fresh);
+                        self.emit(Insn::Dup);
+                        for expr in args.into_iter().rev() {
+                            self.compile_expr(expr);
+                        }
+                        self.emit(Insn::Call(PredSig(Pred::Sys(format!("${}", name), 4), 4)));
+                        self.emit(Insn::CallGoal(0));
+                    }
+                    p => {
+                        // push the arguments, right-to-left
+                        for expr in args.into_iter().rev() {
+                            self.compile_expr(expr);
+                        }
+                        // call the predicate, call/N calls the goal it is given at runtime
+                        match p {
+                            Pred::Sys(ref name, _) if name == "call" && arity > 0 => self.emit(Insn::CallGoal(arity - 1)),
+                            p => self.emit(Insn::Call(PredSig(p, arity)))
+                        }
+                    }
                 }
-                // call the predicate
-                self.current_ir_code.push(Insn::Call(PredSig(p, arity)));
+                self.current_span = outer_span;
             }
-            Fail => self.current_ir_code.push(Insn::Fail),
+            Cut => match self.cut_scope {
+                Some(label) => self.emit(Insn::DetUntil(label)),
+                None => self.emit(Insn::Cut)
+            },
+            Fail => self.emit(Insn::Fail),
             True => {}
         }
     }
 
+    // compile given statement with its cuts local to it, they drop only the checkpoints created after the one with given label
+    fn compile_scoped(&mut self, stmt: Stmt<usize>, label: Label) {
+        let outer_scope = self.cut_scope.replace(label);
+        self.compile_stmt(stmt);
+        self.cut_scope = outer_scope;
+    }
+
+    // compile findall(Template, Goal, List): the goal is called in a collection, each of its solutions adds a copy of the template and backtracks, and the collection pushes the list once the goal fails
+    fn compile_findall(&mut self, mut args: Vec<Expr<usize>>) {
+        let list = args.pop().unwrap();
+        let goal = args.pop().unwrap();
+        let template = args.pop().unwrap();
+        let collect_pc = self.current_ir_code.len();
+        self.emit(Insn::MkCollect(0));
+        self.compile_expr(goal);
+        self.emit(Insn::CallGoal(0));
+        self.compile_expr(template);
+        self.emit(Insn::Collect);
+        self.patch_target(collect_pc);
+        self.compile_expr(list);
+        self.emit(Insn::Unify);
+    }
+
     // allow the user to inspect the generated code
     pub fn get_ir_ref(&self) -> &HashMap<PredSig, Vec<Insn>> {
         &self.ir_code
     }
 
     // extract the generated IR program and consume Self.
-    pub fn get_ir_program(mut self) -> ir::Program {
+    pub fn get_ir_program(self) -> ir::Program {
         assert!(self.current_ir_code.is_empty(), "Tried to extract the program in middle of compiling a predicate");
-        // Add halt at the end of main
-        if let Some(main) = self.ir_code.get_mut(&PredSig(Pred::User("main".to_string()), 0)) {
-            main.push(Insn::Halt);
-        }
-
-        ir::Program { text: self.ir_code }
+        ir::Program { text: self.ir_code, spans: self.spans }
     }
 }
\ No newline at end of file
@@ -0,0 +1,22 @@
+extern crate im_rc;
+
+pub mod ast_common;
+pub mod ast;
+pub mod ir;
+pub mod parser;
+pub mod vm;
+pub mod domains;
+pub mod unification;
+pub mod ir_gen;
+pub mod builtins;
+pub mod exceptions;
+pub mod flags;
+pub mod ops;
+pub mod writer;
+pub mod format;
+pub mod streams;
+pub mod convert;
+pub mod modules;
+pub mod database;
+pub mod engine;
+pub mod ffi;
@@ -1,93 +1,87 @@
-extern crate nom;
-extern crate im_rc;
 extern crate rustyline;
-
-pub mod ast_common;
-pub mod ast;
-pub mod ir;
-pub mod parser;
-pub mod vm;
-pub mod domains;
-pub mod unification;
-pub mod ir_gen;
-pub mod builtins;
+extern crate cadmium;
 
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use nom::types::CompleteStr;
 
-use std::collections::HashMap;
-use ast::*;
-use ast::transform::*;
-use vm::VM;
-use ir_gen::IRGen;
+use cadmium::parser;
+use cadmium::ast::*;
+use cadmium::engine::Engine;
 
-// Compile given top-level statement to IR
-fn compile_stmt(s: Stmt<String>) -> ir::Program {
-    let input_ast = vec![PredDef::new("main", Vec::new(), s)];
-    // The AST after eliminating multiple clauses, underscores, etc. Also, the variables are renamed into numbers.
-    let lowered_ast = {
-        IdempotentElim::new().transform(
-            EnumerateVariables::new().transform(
-                UnderscoreElim::new().transform(
-                    ConsolidateDefs::new().transform(input_ast))))
-    };
-    let mut ig = IRGen::new();
-    ig.compile_program(lowered_ast);
-    ig.get_ir_program()
+// Run each top-level statement as a query and print its solutions
+fn run_all(engine: &mut Engine, stmts: Vec<Stmt<String>>) {
+    for s in stmts.into_iter() {
+        let mut found = false;
+        for solution in engine.query_stmt(s) {
+            match solution {
+                Ok(solution) => println!("{}", solution),
+                Err(e) => println!("{}", e)
+            }
+            found = true;
+        }
+        if !found {
+            println!("false");
+        }
+    }
 }
 
 fn main() {
+    // consult the files given on the command line
+    let mut engine = Engine::new();
+    for path in std::env::args().skip(1) {
+        if let Err(e) = engine.consult_file(&path) {
+            eprintln!("{}", e);
+        }
+        for warning in engine.take_warnings() {
+            eprintln!("{}", warning);
+        }
+    }
+
+    // a program with a main goal is run rather than queried
+    if let Some(goal) = engine.take_main() {
+        let status = match engine.query_stmt(goal).next() {
+            Some(Ok(_)) => 0,
+            Some(Err(e)) => {
+                eprintln!("{}", e);
+                1
+            }
+            None => {
+                eprintln!("goal (main) failed");
+                1
+            }
+        };
+        std::process::exit(status);
+    }
+
     let mut rl = Editor::<()>::new();
 
     if rl.load_history(".cadmium.hist").is_err() {
         println!("Creating history file.");
     }
 
-    // Set up the compiler and the vm
-    let mut vm = VM::new(ir::Program { text: HashMap::new() });
-    let mut run_all = |stmts: Vec<Stmt<String>>| {
-        for s in stmts.into_iter() {
-            println!("running: {:?}", &s);
-            let ir_code = compile_stmt(s);
-            println!("IR code: {:?}", ir_code.text);
-            vm = VM::new(ir_code);
-            vm.run();
-            if let Some(state) = &vm.state {
-                for (x, v) in state.local_state.locals.iter() {
-                    println!("{} = {}", x, state.bindings.find(v));
-                }
-                println!("bindings: {:?}", state.bindings);
-            }
-        }
-    };
-
-    // unused parts of the previous line
+    // the lines of the statements that are not complete yet
     let mut previous = "".to_string();
 
     // the repl
     loop {
-        let readline = rl.readline(">> ");
+        let prompt = if previous.is_empty() { ">> " } else { "|  " };
+        let readline = rl.readline(prompt);
         match readline {
-            Ok(mut line) => {
-                if &previous != "" {
-                    previous.push_str(&line);
-                } else {
-                    std::mem::swap(&mut previous, &mut line);
+            Ok(line) => {
+                previous.push_str(&line);
+                previous.push('\n');
+
+                // wait for the full stop that ends the last statement
+                if !previous.trim_end().ends_with('.') {
+                    continue;
                 }
 
-                match parser::top_level(CompleteStr(&previous)) {
-                    Ok((CompleteStr(rest), stmts)) => {
-                        println!("parsed: {:?}", stmts);
-                        run_all(stmts);
-                        previous = rest.trim().to_string();
-                    }
-                    result => {
-                        println!("Parse error: {:?}", result);
-                        previous.clear();
-                        continue;
-                    }
+                let (stmts, errors) = parser::parse_goals(&previous, &engine.ops(), engine.flags().double_quotes);
+                for e in errors.iter() {
+                    println!("{}", e);
                 }
+                run_all(&mut engine, stmts);
+                previous.clear();
             }
             Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
                 break;
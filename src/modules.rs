@@ -0,0 +1,60 @@
+// Modules, which give each library or part of a program its own namespace of predicates. A module sees the predicates it defines and the ones it imports, the predicates of other modules are called by qualifying the goal with their module.
+use crate::ast_common::{Atom, PredSig};
+use std::collections::HashMap;
+
+#[derive(Default)]
+struct Module {
+    // the predicates the module exports, by name and arity
+    exports: Vec<(Atom, usize)>,
+    // the module each imported predicate is defined in, by name and arity
+    imports: HashMap<(Atom, usize), Atom>,
+}
+
+#[derive(Default)]
+pub struct Modules {
+    modules: HashMap<Atom, Module>,
+    // whether each argument of a meta-predicate is a goal or a closure, which is called in the module of the caller
+    meta: HashMap<PredSig, Vec<bool>>,
+}
+
+impl Modules {
+    // Declare a module with given exports, which replace the ones of an earlier declaration
+    pub fn declare(&mut self, name: &str, exports: Vec<(Atom, usize)>) {
+        self.modules.entry(name.to_string()).or_default().exports = exports;
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.modules.contains_key(name)
+    }
+
+    // The predicates given module exports, none if there is no such module
+    pub fn exports(&self, name: &str) -> &[(Atom, usize)] {
+        self.modules.get(name).map_or(&[], |m| &m.exports)
+    }
+
+    // Import a predicate of a module into another. If it is already imported from a third module, it keeps that import and the error is the module.
+    pub fn import(&mut self, into: &str, from: &str, name: &str, arity: usize) -> Result<(), Atom> {
+        let imports = &mut self.modules.entry(into.to_string()).or_default().imports;
+        match imports.get(&(name.to_string(), arity)) {
+            Some(other) if other != from => Err(other.clone()),
+            _ => {
+                imports.insert((name.to_string(), arity), from.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    // The module given module imports a predicate from, if it imports it
+    pub fn imported(&self, module: &str, name: &str, arity: usize) -> Option<&str> {
+        self.modules.get(module)?.imports.get(&(name.to_string(), arity)).map(|m| m.as_str())
+    }
+
+    pub fn set_meta(&mut self, sig: PredSig, args: Vec<bool>) {
+        self.meta.insert(sig, args);
+    }
+
+    // For each argument of given meta-predicate, whether it is called in the module of the caller
+    pub fn meta(&self, sig: &PredSig) -> Option<&[bool]> {
+        self.meta.get(sig).map(|args| args.as_slice())
+    }
+}
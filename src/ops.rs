@@ -0,0 +1,167 @@
+// Operator table shared by the reader and the writer of terms
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+// The types of operators, which determine their fixity and associativity
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum OpType {
+    Xfx,
+    Xfy,
+    Yfx,
+    Fy,
+    Fx,
+    Xf,
+    Yf,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Fixity {
+    Prefix,
+    Infix,
+    Postfix,
+}
+
+impl OpType {
+    pub fn fixity(self) -> Fixity {
+        use OpType::*;
+        match self {
+            Xfx | Xfy | Yfx => Fixity::Infix,
+            Fy | Fx => Fixity::Prefix,
+            Xf | Yf => Fixity::Postfix,
+        }
+    }
+
+    // The maximum priority of the left argument of an operator with given priority
+    pub fn left_max(self, priority: u32) -> u32 {
+        use OpType::*;
+        match self {
+            Yfx | Yf => priority,
+            _ => priority - 1,
+        }
+    }
+
+    // The maximum priority of the right argument of an operator with given priority
+    pub fn right_max(self, priority: u32) -> u32 {
+        use OpType::*;
+        match self {
+            Xfy | Fy => priority,
+            _ => priority - 1,
+        }
+    }
+}
+
+impl FromStr for OpType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        use OpType::*;
+        Ok(match s {
+            "xfx" => Xfx,
+            "xfy" => Xfy,
+            "yfx" => Yfx,
+            "fy" => Fy,
+            "fx" => Fx,
+            "xf" => Xf,
+            "yf" => Yf,
+            _ => return Err(())
+        })
+    }
+}
+
+impl fmt::Display for OpType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use OpType::*;
+        let name = match self {
+            Xfx => "xfx",
+            Xfy => "xfy",
+            Yfx => "yfx",
+            Fy => "fy",
+            Fx => "fx",
+            Xf => "xf",
+            Yf => "yf",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// The operator definitions, an atom can be a prefix operator and an infix or a postfix operator at the same time
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Ops {
+    prefix: HashMap<String, (u32, OpType)>,
+    infix: HashMap<String, (u32, OpType)>,
+    postfix: HashMap<String, (u32, OpType)>,
+}
+
+// The standard operators, and the common extensions for declarations
+const DEFAULT_OPS: &[(u32, OpType, &[&str])] = &[
+    (1200, OpType::Xfx, &[":-", "-->"]),
+    (1200, OpType::Fx, &[":-", "?-"]),
+    (1150, OpType::Fx, &["dynamic", "discontiguous", "initialization", "meta_predicate", "multifile", "module_transparent"]),
+    (1100, OpType::Xfy, &[";", "|"]),
+    (1050, OpType::Xfy, &["->", "*->"]),
+    (1000, OpType::Xfy, &[","]),
+    (900, OpType::Fy, &["\\+"]),
+    (700, OpType::Xfx, &["=", "\\=", "==", "\\==", "@<", "@>", "@=<", "@>=", "=..", "is", "=:=", "=\\=", "<", ">", "=<", ">="]),
+    (200, OpType::Xfy, &[":"]),
+    (500, OpType::Yfx, &["+", "-", "/\\", "\\/", "xor"]),
+    (400, OpType::Yfx, &["*", "/", "//", "rem", "mod", "div", "<<", ">>"]),
+    (200, OpType::Xfx, &["**"]),
+    (200, OpType::Xfy, &["^"]),
+    (200, OpType::Fy, &["-", "+", "\\"]),
+];
+
+impl Default for Ops {
+    fn default() -> Self {
+        let mut ops = Ops { prefix: HashMap::new(), infix: HashMap::new(), postfix: HashMap::new() };
+        for (priority, op_type, names) in DEFAULT_OPS.iter() {
+            for name in names.iter() {
+                ops.add(*priority, *op_type, name);
+            }
+        }
+        ops
+    }
+}
+
+impl Ops {
+    // Define an operator, priority 0 removes the definition
+    pub fn add(&mut self, priority: u32, op_type: OpType, name: &str) {
+        let table = match op_type.fixity() {
+            Fixity::Prefix => &mut self.prefix,
+            Fixity::Infix => &mut self.infix,
+            Fixity::Postfix => &mut self.postfix,
+        };
+        if priority == 0 {
+            table.remove(name);
+        } else {
+            table.insert(name.to_string(), (priority, op_type));
+        }
+    }
+
+    pub fn prefix(&self, name: &str) -> Option<(u32, OpType)> {
+        self.prefix.get(name).cloned()
+    }
+
+    pub fn infix(&self, name: &str) -> Option<(u32, OpType)> {
+        self.infix.get(name).cloned()
+    }
+
+    pub fn postfix(&self, name: &str) -> Option<(u32, OpType)> {
+        self.postfix.get(name).cloned()
+    }
+
+    pub fn is_op(&self, name: &str) -> bool {
+        self.prefix.contains_key(name) || self.infix.contains_key(name) || self.postfix.contains_key(name)
+    }
+
+    // All definitions as (priority, type, name), sorted by name
+    pub fn definitions(&self) -> Vec<(u32, OpType, &str)> {
+        let mut all: Vec<(u32, OpType, &str)> = self.prefix.iter()
+            .chain(self.infix.iter())
+            .chain(self.postfix.iter())
+            .map(|(name, (priority, op_type))| (*priority, *op_type, name.as_str()))
+            .collect();
+        all.sort_by(|a, b| (a.2, a.0).cmp(&(b.2, b.0)));
+        all
+    }
+}
@@ -1,197 +1,501 @@
-// Parser for the high-level AST
+// Parser for the high-level AST. The lexer splits the source into tokens, the reader builds terms out of them according to the operator table, and the terms are converted to clauses and goals here.
+pub mod lexer;
+pub mod reader;
 
-use ::nom::*;
-use ::nom::types::CompleteStr;
 use crate::ast_common::*;
 use crate::ast::*;
+use crate::flags::DoubleQuotes;
+use crate::ops::{Ops, OpType};
+use std::fmt;
+pub use self::reader::{Reader, Term, TermKind};
+
+// A syntax error, with the line it is on and either what the parser expected to see or a description of the problem
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ParseError {
+    pub span: Span,
+    pub expected: Vec<String>,
+    pub message: Option<String>,
+    pub line: String,
+}
 
-// Use CompleteStr to communicate with nom that we have the complete inputs.
+impl ParseError {
+    // What is wrong, without the position
+    pub fn description(&self) -> String {
+        let mut description = self.message.clone().unwrap_or_default();
+        for (i, what) in self.expected.iter().enumerate() {
+            let separator = match i {
+                0 if description.is_empty() => "expected ",
+                0 => ": expected ",
+                _ if i + 1 == self.expected.len() => " or ",
+                _ => ", "
+            };
+            description.push_str(separator);
+            description.push_str(what);
+        }
+        description
+    }
+}
 
-named!(
-    alnum_or_underscore<CompleteStr, CompleteStr>,
-    take_while1!(|c: char| {
-        c.is_alphanumeric() || c == '_'
-    })
-);
-
-named!(
-    pub var<CompleteStr, String>,
-    map_opt!(alnum_or_underscore, |s: CompleteStr| {
-        if s.chars().next().unwrap().is_uppercase() {
-            Some(s.to_string())
-        } else if s.starts_with("_") && !s.starts_with("__") {
-            Some(s.to_string())
-        } else {
-            None
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: syntax error", self.span)?;
+        let description = self.description();
+        if !description.is_empty() {
+            write!(f, ": {}", description)?;
+        }
+        // put a caret under the offending character
+        let indent: String = self.line.chars().take(self.span.col - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        write!(f, "\n    {}\n    {}^", self.line, indent)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Convert a term to an expression
+pub fn expr(term: Term) -> Expr<String> {
+    match term.kind {
+        TermKind::Var(x) => Expr::PV(x),
+        TermKind::Atom(a) => Expr::Atom(a),
+        TermKind::Num(n) => Expr::Num(n),
+        TermKind::Str(s) => Expr::Str(s),
+        TermKind::Compound(f, args) => Expr::Ctor(f, args.into_iter().map(expr).collect()),
+    }
+}
+
+// A term that cannot be converted to what it should stand for, with the reason
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Invalid {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Invalid {
+    fn new(span: Span, message: String) -> Self {
+        Invalid { span, message }
+    }
+
+    // The syntax error for this term in the text of given reader
+    pub fn error(self, reader: &Reader) -> ParseError {
+        reader.error_at(self.span, &self.message)
+    }
+}
+
+// Whether a goal with given name and arity is a control construct, which is compiled to a statement rather than a call
+pub fn is_control(name: &str, arity: usize) -> bool {
+    matches!((name, arity), ("true", 0) | ("!", 0) | ("fail", 0) | ("false", 0) | (",", 2) | (";", 2) | ("->", 2) | ("=", 2) | ("catch", 3) | (":", 2) | ("^", 2) | ("\\+", 1) | ("not", 1))
+}
+
+// Convert a term to the goal it stands for. Control constructs become statements, the other callable terms become calls.
+pub fn goal(term: Term) -> Result<Stmt<String>, Invalid> {
+    let span = term.span;
+    let (name, mut args) = match term.kind {
+        // a variable goal is called with its value at runtime
+        TermKind::Var(x) => return Ok(Stmt::Call(Pred::User("call".to_string()), vec![Expr::PV(x)], span)),
+        TermKind::Num(n) => return Err(Invalid::new(span, format!("{} is not callable", n))),
+        TermKind::Str(s) => return Err(Invalid::new(span, format!("the string \"{}\" is not callable", s))),
+        TermKind::Atom(a) => (a, Vec::new()),
+        TermKind::Compound(f, args) => (f, args),
+    };
+
+    let stmt = match (name.as_str(), args.len()) {
+        ("true", 0) => Stmt::True,
+        ("!", 0) => Stmt::Cut,
+        ("fail", 0) | ("false", 0) => Stmt::Fail,
+        (",", 2) => {
+            let s2 = goal(args.pop().unwrap())?;
+            let s1 = goal(args.pop().unwrap())?;
+            Stmt::And(Box::new(s1), Box::new(s2))
+        }
+        (";", 2) => {
+            let s2 = goal(args.pop().unwrap())?;
+            let left = args.pop().unwrap();
+            match left.kind {
+                TermKind::Compound(f, mut ct) if f == "->" && ct.len() == 2 => {
+                    let t = goal(ct.pop().unwrap())?;
+                    let c = goal(ct.pop().unwrap())?;
+                    Stmt::If(Box::new(c), Box::new(t), Box::new(s2))
+                }
+                kind => Stmt::Or(Box::new(goal(Term::new(kind, left.span))?), Box::new(s2))
+            }
+        }
+        ("->", 2) => {
+            let t = goal(args.pop().unwrap())?;
+            let c = goal(args.pop().unwrap())?;
+            Stmt::If(Box::new(c), Box::new(t), Box::new(Stmt::Fail))
+        }
+        ("=", 2) => {
+            let e2 = expr(args.pop().unwrap());
+            let e1 = expr(args.pop().unwrap());
+            Stmt::Unify(e1, e2)
+        }
+        ("\\+", 1) | ("not", 1) => Stmt::Not(Box::new(goal(args.pop().unwrap())?), span),
+        ("catch", 3) => {
+            let recovery = goal(args.pop().unwrap())?;
+            let catcher = expr(args.pop().unwrap());
+            let g = goal(args.pop().unwrap())?;
+            Stmt::Catch(Box::new(g), catcher, Box::new(recovery))
         }
+        // V^Goal calls the goal, the variables before ^ only matter to bagof/3 and setof/3
+        ("^", 2) => goal(args.pop().unwrap())?,
+        // sys:Goal calls a built-in directly
+        (":", 2) if args[0].kind == TermKind::Atom("sys".to_string()) => {
+            let g = args.pop().unwrap();
+            match g.kind {
+                TermKind::Atom(p) => Stmt::Call(Pred::Sys(p, 0), Vec::new(), span),
+                TermKind::Compound(p, args) => Stmt::Call(Pred::Sys(p, args.len()), args.into_iter().map(expr).collect(), span),
+                _ => return Err(Invalid::new(g.span, "expected a built-in predicate after sys:".to_string()))
+            }
+        }
+        // Module:Goal calls the goal in the module, which is only known at runtime if the module or the goal is a variable
+        (":", 2) => {
+            let g = args.pop().unwrap();
+            match args.pop().unwrap() {
+                Term { kind: TermKind::Atom(m), .. } if !matches!(g.kind, TermKind::Var(_)) => qualify(&m, goal(g)?),
+                m => Stmt::Call(Pred::User("call".to_string()), vec![Expr::Ctor(":".to_string(), vec![expr(m), expr(g)])], span)
+            }
+        }
+        _ => Stmt::Call(Pred::User(name), args.into_iter().map(expr).collect(), span)
+    };
+    Ok(stmt)
+}
+
+// Qualify the calls of a goal with the module they are called in
+pub fn qualify(module: &str, mut stmt: Stmt<String>) -> Stmt<String> {
+    stmt.traverse_mut(&mut |s| {
+        if let Stmt::Call(p, args, _) = s {
+            match p {
+                // the goal call/N calls is called in the module too
+                Pred::User(name) if name == "call" && !args.is_empty() => {
+                    let g = args.remove(0);
+                    args.insert(0, Expr::Ctor(":".to_string(), vec![Expr::Atom(module.to_string()), g]));
+                }
+                Pred::User(name) => *p = Pred::Qualified(module.to_string(), std::mem::take(name)),
+                _ => {}
+            }
+        }
+    });
+    stmt
+}
+
+// Convert a term read at the top level of a source file to a clause or a directive
+pub fn item(reader: &Reader, term: Term) -> Result<Item<String>, ParseError> {
+    match term.kind {
+        TermKind::Compound(f, mut args) if f == ":-" && args.len() == 1 =>
+            Ok(Item::Directive(directive(reader, args.pop().unwrap())?, term.span)),
+        kind => clause(Term::new(kind, term.span)).map(Item::Clause).map_err(|e| e.error(reader))
+    }
+}
+
+// Convert a term to a clause, or to a grammar rule that is yet to be translated to its clause
+pub fn clause(term: Term) -> Result<PredDef<String>, Invalid> {
+    let (head, body) = match term.kind {
+        TermKind::Compound(f, mut args) if f == ":-" && args.len() == 2 => {
+            let body = args.pop().unwrap();
+            (args.pop().unwrap(), Some(body))
+        }
+        TermKind::Compound(f, mut args) if f == GRAMMAR_RULE && args.len() == 2 => {
+            let body = args.pop().unwrap();
+            return grammar_rule(args.pop().unwrap(), body);
+        }
+        kind => (Term::new(kind, term.span), None)
+    };
+
+    let span = head.span.clone();
+    let (name, params) = match head.kind {
+        TermKind::Atom(a) => (a, Vec::new()),
+        TermKind::Compound(f, args) => (f, args),
+        kind => return Err(invalid_head("clause", Term::new(kind, span)))
+    };
+    let body = match body {
+        Some(body) => goal(body)?,
+        None => Stmt::True
+    };
+
+    Ok(PredDef {
+        name: Pred::User(name),
+        params: params.into_iter().map(expr).collect(),
+        body,
+        span
     })
-);
-
-fn unescape_atom(s: CompleteStr) -> Option<String> {
-    let mut iter = s.chars();
-    let mut buffer = String::with_capacity(s.len());
-
-    while let Some(c) = iter.next() {
-        if c == '\\' {
-            if let Some(escaped) = iter.next() {
-                let c = match escaped {
-                    '\\' => '\\',
-                    'n' => '\n',
-                    't' => '\t',
-                    'r' => '\r',
-                    '\'' => '\'',
-                    _ => return None // no known escape sequence, fail
-                };
-
-                buffer.push(c);
-            } else {
-                // There is a dangling backslash at the end, fail
-                return None;
+}
+
+fn invalid_head(what: &str, head: Term) -> Invalid {
+    let message = match head.kind {
+        TermKind::Num(n) => format!("the head of a {} cannot be the number {}", what, n),
+        TermKind::Str(s) => format!("the head of a {} cannot be the string \"{}\"", what, s),
+        _ => format!("the head of a {} cannot be a variable", what)
+    };
+    Invalid::new(head.span, message)
+}
+
+// Convert a grammar rule, `Head --> Body` or `Head, Pushback --> Body`, which is translated to a clause by transform::DcgTranslation
+fn grammar_rule(head: Term, body: Term) -> Result<PredDef<String>, Invalid> {
+    let span = head.span.clone();
+    let non_terminal = match head.kind {
+        TermKind::Compound(ref f, ref args) if f == "," && args.len() == 2 => {
+            if !is_list(&args[1]) {
+                return Err(Invalid::new(args[1].span.clone(), "the pushback of a grammar rule must be a list".to_string()));
             }
-        } else {
-            buffer.push(c);
+            &args[0]
         }
+        _ => &head
+    };
+    if !matches!(non_terminal.kind, TermKind::Atom(_) | TermKind::Compound(_, _)) {
+        return Err(invalid_head("grammar rule", non_terminal.clone()));
     }
+    Ok(PredDef {
+        name: Pred::User(GRAMMAR_RULE.to_string()),
+        params: vec![expr(head), expr(body)],
+        body: Stmt::True,
+        span
+    })
+}
 
-    Some(buffer)
+// Whether a term is a proper list, or a string, which stands for a list in a grammar rule
+fn is_list(term: &Term) -> bool {
+    match term.kind {
+        TermKind::Atom(ref a) => a == "[]",
+        TermKind::Str(_) => true,
+        TermKind::Compound(ref f, ref args) if f == "." && args.len() == 2 => is_list(&args[1]),
+        _ => false
+    }
 }
 
-// A quoted atom is of the form 'contents' where contents is an escaped string.
-named!(quoted_atom<CompleteStr, String>,
-    map_opt!(delimited!(tag!("'"), escaped!(none_of!("'\\"), '\\', one_of!("n'\\")), tag!("'")), unescape_atom)
-);
-
-named!(
-    pub atom<CompleteStr, String>,
-    alt!(quoted_atom | map_opt!(alnum_or_underscore, |s: CompleteStr| {
-        if s.chars().next().unwrap().is_lowercase() {
-            Some(s.to_string())
-        } else {
-            None
+// The term for an expression, with given position for all of its subterms
+pub fn expr_term(e: Expr<String>, span: &Span) -> Term {
+    let kind = match e {
+        Expr::Atom(a) => TermKind::Atom(a),
+        Expr::PV(x) => TermKind::Var(x),
+        Expr::Num(n) => TermKind::Num(n),
+        Expr::Str(s) => TermKind::Str(s),
+        Expr::Ctor(f, args) => TermKind::Compound(f, args.into_iter().map(|arg| expr_term(arg, span)).collect()),
+    };
+    Term::new(kind, span.clone())
+}
+
+// Convert the goal of a `:-` directive
+pub fn directive(reader: &Reader, term: Term) -> Result<Directive<String>, ParseError> {
+    let span = term.span.clone();
+    let (name, mut args) = match term.kind {
+        TermKind::Compound(f, args) => (f, args),
+        kind => return Ok(Directive::Unknown(expr(Term::new(kind, span))))
+    };
+
+    Ok(match (name.as_str(), args.len()) {
+        ("dynamic", 1) => Directive::Dynamic(indicators(reader, args.pop().unwrap())?),
+        ("discontiguous", 1) => Directive::Discontiguous(indicators(reader, args.pop().unwrap())?),
+        ("initialization", 1) => Directive::Initialization(goal(args.pop().unwrap()).map_err(|e| e.error(reader))?),
+        ("initialization", 2) => {
+            let when = args.pop().unwrap();
+            let g = goal(args.pop().unwrap()).map_err(|e| e.error(reader))?;
+            match when.kind {
+                TermKind::Atom(ref w) if w == "main" => Directive::Main(g),
+                TermKind::Atom(ref w) if w == "after_load" => Directive::Initialization(g),
+                _ => return Err(reader.error_at(when.span, "expected `main` or `after_load` as the time of initialization"))
+            }
         }
-    }))
-);
-
-named!(unum<CompleteStr, usize>, map_res!(digit1::<CompleteStr>, |s:CompleteStr| {
-           usize::from_str_radix(&*s, 10)
-        }));
-
-named!(num<CompleteStr, i64>, map_res!(digit1::<CompleteStr>, |s:CompleteStr| {
-           i64::from_str_radix(&*s, 10)
-        }));
-
-named!(
-    pub pred<CompleteStr, Pred>,
-    alt!(
-        do_parse!(
-            tag!("sys:") >>
-            p: atom >>
-            tag!("/") >>
-            arity: unum >>
-            (Pred::Sys(p, arity)))
-      | map_opt!(atom, |a| {
-            if &a != "sys" {
-                Some(Pred::User(a))
-            } else {
-                None
+        ("op", 3) => {
+            let names = args.pop().unwrap();
+            let op_type = args.pop().unwrap();
+            let priority = args.pop().unwrap();
+            let priority = match priority.kind {
+                TermKind::Num(p) if (0..=1200).contains(&p) => p as u32,
+                _ => return Err(reader.error_at(priority.span, "expected an operator priority between 0 and 1200"))
+            };
+            let op_type = match op_type.kind {
+                TermKind::Atom(ref t) => t.parse::<OpType>().ok(),
+                _ => None
+            }.ok_or_else(|| reader.error_at(op_type.span.clone(), "expected an operator type such as xfx, xfy, yfx, fy, fx, xf or yf"))?;
+            let mut atoms = Vec::new();
+            for name in sequence(names) {
+                match name.kind {
+                    TermKind::Atom(ref a) if a == "," || a == "|" =>
+                        return Err(reader.error_at(name.span, &format!("the operator `{}` cannot be modified", a))),
+                    TermKind::Atom(a) => atoms.push(a),
+                    _ => return Err(reader.error_at(name.span, "expected an operator name"))
+                }
             }
-        })
-    )
-);
-
-named!(
-    ctor<CompleteStr, Expr<String>>,
-    ws!(do_parse!(
-        p: atom >>
-        args: delimited!(tag!("("), separated_list_complete!(ws!(tag!(",")), expr), tag!(")")) >>
-        (Expr::Ctor(p, args))
-    ))
-);
-
-named!(
-    pub expr<CompleteStr, Expr<String>>,
-    alt!(
-        map!(var, |v| { Expr::PV::<String>(v) })
-      | ctor
-      | map!(atom, |a| { Expr::Atom(a.to_string()) })
-      | map!(num, Expr::Num)
-      | ws!(delimited!(tag!("("), expr, tag!(")")))
-    )
-);
-
-// A simple operator parser with hardcoded precedence
-named!(conjunct<CompleteStr, Stmt<String>>,
-    ws!(alt!(
-        do_parse!(
-            e1: expr >>
-            tag!("=") >>
-            e2: expr >>
-            (Stmt::Unify(e1, e2))
-        )
-      | do_parse!(
-            p: pred >>
-            args: delimited!(tag!("("), separated_list!(tag!(","), expr), tag!(")")) >>
-            (Stmt::Call(p, args))
-      )
-      | delimited!(tag!("("), stmt, tag!(")"))
-    ))
-);
-
-named!(
-    disjunct<CompleteStr, Stmt<String>>,
-    map!(separated_nonempty_list!(tag!(","), conjunct), |conjuncts: Vec<Stmt<String>>| {
-        conjuncts.into_iter().fold(Stmt::True, |a, b| Stmt::And(Box::new(a), Box::new(b)))
+            Directive::Op(priority, op_type, atoms)
+        }
+        ("set_prolog_flag", 2) => {
+            let value = expr(args.pop().unwrap());
+            let flag = args.pop().unwrap();
+            match flag.kind {
+                TermKind::Atom(name) => Directive::SetFlag(name, value),
+                _ => return Err(reader.error_at(flag.span, "expected a flag name"))
+            }
+        }
+        ("ensure_loaded", 1) => Directive::EnsureLoaded(file_name(reader, args.pop().unwrap())?),
+        ("include", 1) => Directive::Include(file_name(reader, args.pop().unwrap())?),
+        ("module", 2) => {
+            let exports = indicator_list(reader, args.pop().unwrap())?;
+            let name = args.pop().unwrap();
+            match name.kind {
+                TermKind::Atom(name) => Directive::Module(name, exports),
+                _ => return Err(reader.error_at(name.span, "expected a module name"))
+            }
+        }
+        ("use_module", 1) => Directive::UseModule(source(reader, args.pop().unwrap())?, None),
+        ("use_module", 2) => {
+            let imports = indicator_list(reader, args.pop().unwrap())?;
+            Directive::UseModule(source(reader, args.pop().unwrap())?, Some(imports))
+        }
+        ("meta_predicate", 1) => Directive::MetaPredicate(sequence(args.pop().unwrap()).into_iter().map(|head| meta_spec(reader, head)).collect::<Result<_, _>>()?),
+        _ => Directive::Unknown(expr(Term::new(TermKind::Compound(name, args), span)))
     })
-);
-
-named!(
-    pub stmt<CompleteStr, Stmt<String>>,
-    alt!(
-        do_parse!(
-            s1: disjunct >>
-            ws!(tag!("->")) >>
-            s2: disjunct >>
-            ws!(tag!(";")) >>
-            s3: disjunct >>
-            (Stmt::If(Box::new(s1), Box::new(s2), Box::new(s3)))
-        )
-      | do_parse!(
-            s1: disjunct >>
-            ws!(tag!(";")) >>
-            s2: disjunct >>
-            (Stmt::Or(Box::new(s1), Box::new(s2)))
-        )
-      | disjunct
-    )
-);
-
-// A top-level statement for the repl, which is a statement terminated with a "."
-named!(
-    pub top_level<CompleteStr, Vec<Stmt<String>>>,
-    many1!(terminated!(stmt, tag!(".")))
-);
-
-named!(
-    pub pred_def<CompleteStr, PredDef<String>>,
-    ws!(do_parse!(
-        name: atom >>
-        params: delimited!(tag!("("), separated_list!(tag!(","), expr), tag!(")")) >>
-        body: map!(opt!(preceded!(tag!(":-"), stmt)),
-            |b| { b.unwrap_or(Stmt::True) }
-        ) >>
-        tag!(".") >>
-        (PredDef {
-            name: Pred::User(name),
-            params: params,
-            body: body
-        })
-    ))
-);
-
-named!(
-    pub program<CompleteStr, Program<String>>,
-    exact!(many1!(pred_def))
-);
+}
+
+// The elements of a list or a comma-separated sequence
+fn sequence(term: Term) -> Vec<Term> {
+    let mut elements = Vec::new();
+    let mut rest = term;
+    loop {
+        match rest.kind {
+            TermKind::Compound(f, mut args) if (f == "," || f == ".") && args.len() == 2 => {
+                rest = args.pop().unwrap();
+                elements.push(args.pop().unwrap());
+            }
+            TermKind::Atom(ref a) if a == "[]" && !elements.is_empty() => return elements,
+            kind => {
+                elements.push(Term::new(kind, rest.span));
+                return elements;
+            }
+        }
+    }
+}
+
+// Predicate indicators, which are terms of the form Name/Arity or Module:Name/Arity
+fn indicators(reader: &Reader, term: Term) -> Result<Vec<PredSig>, ParseError> {
+    sequence(term).into_iter().map(|pi| match indicator(&pi) {
+        Some(sig) => Ok(sig),
+        None => Err(reader.error_at(pi.span, "expected a predicate indicator such as foo/2"))
+    }).collect()
+}
+
+fn indicator(pi: &Term) -> Option<PredSig> {
+    let args = match pi.kind {
+        TermKind::Compound(ref f, ref args) if f == "/" && args.len() == 2 => args,
+        _ => return None
+    };
+    let arity = match args[1].kind {
+        TermKind::Num(arity) if arity >= 0 => arity as usize,
+        _ => return None
+    };
+    match args[0].kind {
+        TermKind::Atom(ref name) => Some(PredSig(Pred::User(name.clone()), arity)),
+        // Module:Name/Arity reads as (Module:Name)/Arity
+        TermKind::Compound(ref f, ref qualified) if f == ":" && qualified.len() == 2 => match (&qualified[0].kind, &qualified[1].kind) {
+            (TermKind::Atom(module), TermKind::Atom(name)) => Some(PredSig(Pred::Qualified(module.clone(), name.clone()), arity)),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+// A list of predicate indicators, which may be empty
+fn indicator_list(reader: &Reader, term: Term) -> Result<Vec<PredSig>, ParseError> {
+    match term.kind {
+        TermKind::Atom(ref a) if a == "[]" => Ok(Vec::new()),
+        _ => indicators(reader, term)
+    }
+}
+
+// A meta-predicate declaration such as maplist(2, ?, ?), where an integer, `:`, `^` or `//` marks an argument that is called in the module of the caller
+fn meta_spec(reader: &Reader, head: Term) -> Result<(PredSig, Vec<bool>), ParseError> {
+    let (name, args) = match head.kind {
+        TermKind::Compound(f, args) => (f, args),
+        _ => return Err(reader.error_at(head.span, "expected a meta-predicate declaration such as maplist(1, ?)"))
+    };
+    let spec = args.into_iter().map(|arg| match arg.kind {
+        TermKind::Num(n) if (0..=9).contains(&n) => Ok(true),
+        TermKind::Atom(ref a) if [":", "^", "//"].contains(&a.as_str()) => Ok(true),
+        TermKind::Atom(ref a) if ["?", "+", "-", "*"].contains(&a.as_str()) => Ok(false),
+        _ => Err(reader.error_at(arg.span, "expected a meta-argument specifier such as 0, :, ? or +"))
+    }).collect::<Result<Vec<bool>, _>>()?;
+    Ok((PredSig(Pred::User(name), spec.len()), spec))
+}
+
+// The module a use_module directive names: a file name, or library(Name) for a module of the prelude
+fn source(reader: &Reader, term: Term) -> Result<Source, ParseError> {
+    match term.kind {
+        TermKind::Atom(a) => Ok(Source::File(a)),
+        TermKind::Compound(ref f, ref args) if f == "library" && args.len() == 1 => match args[0].kind {
+            TermKind::Atom(ref name) => Ok(Source::Library(name.clone())),
+            _ => Err(reader.error_at(term.span, "expected a file name or library(Name)"))
+        },
+        _ => Err(reader.error_at(term.span, "expected a file name or library(Name)"))
+    }
+}
+
+fn file_name(reader: &Reader, term: Term) -> Result<String, ParseError> {
+    match term.kind {
+        TermKind::Atom(a) => Ok(a),
+        _ => Err(reader.error_at(term.span, "expected a file name"))
+    }
+}
+
+// Read the next term and convert it with given function, None at the end of the input. After a syntax error, the reader skips to the next full stop so that the next call reports the errors in the later items too.
+pub fn next<T, F>(reader: &mut Reader, ops: &Ops, convert: F) -> Option<Result<T, ParseError>> where F: Fn(&Reader, Term) -> Result<T, ParseError> {
+    match reader.read_clause(ops) {
+        Ok(Some(term)) => Some(convert(reader, term)),
+        Ok(None) => None,
+        Err(e) => {
+            reader.recover();
+            Some(Err(e))
+        }
+    }
+}
+
+// Parse the items of a program, `file` is the name of the file the text comes from, if any. The operator directives take effect on the items after them.
+pub fn parse_program(file: Option<&str>, text: &str, ops: &mut Ops) -> (Vec<Item<String>>, Vec<ParseError>) {
+    let mut reader = Reader::new(file, text);
+    let mut items = Vec::new();
+    let mut errors = Vec::new();
+    while let Some(result) = next(&mut reader, ops, item) {
+        match result {
+            Ok(item) => {
+                if let Item::Directive(Directive::Op(priority, op_type, ref names), _) = item {
+                    for name in names.iter() {
+                        ops.add(priority, op_type, name);
+                    }
+                }
+                items.push(item);
+            }
+            Err(e) => errors.push(e)
+        }
+    }
+    (items, errors)
+}
+
+// Parse a sequence of goals, each terminated by a full stop
+pub fn parse_goals(text: &str, ops: &Ops, double_quotes: DoubleQuotes) -> (Vec<Stmt<String>>, Vec<ParseError>) {
+    let mut reader = Reader::new(None, text);
+    reader.set_double_quotes(double_quotes);
+    let mut goals = Vec::new();
+    let mut errors = Vec::new();
+    while let Some(result) = next(&mut reader, ops, |reader, term| goal(term).map_err(|e| e.error(reader))) {
+        match result {
+            Ok(g) => goals.push(g),
+            Err(e) => errors.push(e)
+        }
+    }
+    (goals, errors)
+}
+
+// Parse a query, which is a goal with an optional terminating full stop
+pub fn parse_query(text: &str, ops: &Ops, double_quotes: DoubleQuotes) -> Result<Stmt<String>, ParseError> {
+    let mut reader = Reader::new(None, text);
+    reader.set_double_quotes(double_quotes);
+    let term = reader.read_all(ops)?;
+    goal(term).map_err(|e| e.error(&reader))
+}
+
+// Parse a single term with an optional terminating full stop
+pub fn parse_term(text: &str, ops: &Ops, double_quotes: DoubleQuotes) -> Result<Term, ParseError> {
+    let mut reader = Reader::new(None, text);
+    reader.set_double_quotes(double_quotes);
+    reader.read_all(ops)
+}
 
 // Unit tests
 #[cfg(test)]
@@ -205,7 +509,7 @@ mod tests {
         ("a ", "a"),
         ("ab__C_dAA ", "ab__C_dAA"),
         ("'\\''", "'"),
-        ("'\n'", "\n"),
+        ("''''", "'"),
         ("'\\n'", "\n"),
         ("';'", ";"),
         ("':-'", ":-"),
@@ -214,78 +518,77 @@ mod tests {
 
     static VALID_VARS: [&str; 6] = ["A", "AbCdAA", "Ab__C_dAA", "_", "_X", "X"];
 
+    fn parse_expr(text: &str) -> Result<Expr<String>, ParseError> {
+        parse_term(text, &Ops::default(), DoubleQuotes::Codes).map(expr)
+    }
+
+    fn program(text: &str) -> Program<String> {
+        let (items, errors) = parse_program(None, text, &mut Ops::default());
+        assert!(errors.is_empty(), "unexpected errors {:?}", errors);
+        items.into_iter().filter_map(Item::into_clause).collect()
+    }
+
+    fn call(name: &str, args: Vec<Expr<String>>) -> Stmt<String> {
+        Stmt::Call(Pred::User(name.to_string()), args, Span::default())
+    }
+
+    fn atom(a: &str) -> Expr<String> {
+        Atom(a.to_string())
+    }
+
     // Tests
 
     #[test]
     fn test_var() {
-
         for x in VALID_VARS.iter() {
-            let mut input = x.to_string();
-            input.push(' ');
-            
-            assert_eq!(var(CompleteStr(&input)), Ok((CompleteStr(" "), x.to_string())));
+            assert_eq!(parse_expr(&format!("{} ", x)), Ok(PV(x.to_string())));
         }
 
-        assert_eq!(var(CompleteStr("A b")), Ok((CompleteStr(" b"), "A".to_string())));
-
-        if let Err(nom::Err::Error(_)) = var(CompleteStr("__ ")) {
-        } else {
-            assert!(false, "variables starting with two underscores should be rejected")
-        }
+        assert!(parse_expr("A b").is_err());
+        assert!(parse_expr("__ ").is_err(), "variables starting with two underscores should be rejected");
     }
 
     #[test]
     fn test_atom() {
-        for (input, atom) in VALID_ATOMS.iter() {
-            let remainder = CompleteStr(if input.ends_with(" ") {
-                " "
-            } else {
-                ""
-            });
-            assert_eq!(expr(CompleteStr(input)), Ok((remainder, Expr::Atom(atom.to_string()))));
+        for (input, a) in VALID_ATOMS.iter() {
+            assert_eq!(parse_expr(input), Ok(atom(a)));
         }
 
-        assert_eq!(atom(CompleteStr("a b")), Ok((CompleteStr(" b"), "a".to_string())));
-
-        if let Err(nom::Err::Error(_)) = atom(CompleteStr("_ ")) {
-        } else {
-            assert!(false, "variables starting with two underscores should be rejected")
-        }
+        assert!(parse_expr("a b").is_err());
+        assert!(parse_expr("'a").is_err());
     }
 
     #[test]
     fn test_pred() {
-        let empty = CompleteStr("");
-        assert_eq!(pred(CompleteStr("foo")), Ok((empty, Pred::User("foo".to_string()))));
-        assert_eq!(pred(CompleteStr("sys:foo/2")), Ok((empty, Pred::Sys("foo".to_string(), 2))));
-
-        let invalid_arity = CompleteStr("sys:foo/-1");
-        if let Err(nom::Err::Error(_)) = pred(invalid_arity) {
-        } else {
-            assert!(false, format!("system predicates with negative arity should be rejected, but got {:?}", pred(invalid_arity)))
-        }
+        let goal = parse_query("foo", &Ops::default(), DoubleQuotes::Codes).unwrap();
+        assert_eq!(goal, call("foo", vec![]));
+        let goal = parse_query("sys:foo(a, B)", &Ops::default(), DoubleQuotes::Codes).unwrap();
+        assert_eq!(goal, Stmt::Call(Pred::Sys("foo".to_string(), 2), vec![atom("a"), PV("B".to_string())], Span::default()));
+        assert!(parse_query("sys:1", &Ops::default(), DoubleQuotes::Codes).is_err());
+        // the calls of a goal qualified with a module are called in the module, including the goal of call/N
+        let goal = parse_query("m:(foo, call(G, 1))", &Ops::default(), DoubleQuotes::Codes).unwrap();
+        let qualified = Ctor(":".to_string(), vec![atom("m"), PV("G".to_string())]);
+        assert_eq!(goal, Stmt::And(
+            Box::new(Stmt::Call(Pred::Qualified("m".to_string(), "foo".to_string()), vec![], Span::default())),
+            Box::new(call("call", vec![qualified.clone(), Num(1)]))));
+        // the module of a variable goal is only known at runtime
+        let goal = parse_query("m:G", &Ops::default(), DoubleQuotes::Codes).unwrap();
+        assert_eq!(goal, call("call", vec![qualified]));
     }
 
     #[test]
     fn test_expr_atomic() {
-        for (input, atom) in VALID_ATOMS.iter() {
-            let remainder = CompleteStr(if input.ends_with(" ") {
-                "  "
-            } else {
-                " "
-            });
-
-            assert_eq!(expr(CompleteStr(input)), Ok((remainder, Expr::Atom(atom.to_string()))));
+        for (input, a) in VALID_ATOMS.iter() {
+            assert_eq!(parse_expr(input), Ok(atom(a)));
         }
 
         for x in VALID_VARS.iter() {
-            let mut input = x.to_string();
-            input.push(' '); // put a delimiter whitespace at the end
-
-            assert_eq!(expr(CompleteStr(&input)), Ok((CompleteStr(" "), Expr::PV(x.to_string()))));
+            assert_eq!(parse_expr(x), Ok(PV(x.to_string())));
         }
 
-        // TODO: test numbers
+        assert_eq!(parse_expr("42"), Ok(Num(42)));
+        assert_eq!(parse_expr("-7"), Ok(Num(-7)));
+        assert_eq!(parse_expr("0'a"), Ok(Num(97)));
     }
 
     #[test]
@@ -294,23 +597,179 @@ mod tests {
             ("foo()",
              Ctor::<String>("foo".to_string(), vec![])),
             ("foo(bar)",
-             Ctor::<String>("foo".to_string(), vec![Atom("bar".to_string())])),
+             Ctor::<String>("foo".to_string(), vec![atom("bar")])),
             ("foo(Baz)",
              Ctor::<String>("foo".to_string(), vec![PV("Baz".to_string())])),
             ("foo(_)",
              Ctor::<String>("foo".to_string(), vec![PV("_".to_string())])),
             ("foo(bar,Baz)",
-             Ctor::<String>("foo".to_string(), vec![Atom("bar".to_string()), PV("Baz".to_string())])),
+             Ctor::<String>("foo".to_string(), vec![atom("bar"), PV("Baz".to_string())])),
             ("foo(bar, Baz)",
-             Ctor::<String>("foo".to_string(), vec![Atom("bar".to_string()), PV("Baz".to_string())])),
+             Ctor::<String>("foo".to_string(), vec![atom("bar"), PV("Baz".to_string())])),
             ("foo(bar, baz(quux))",
-             Ctor::<String>("foo".to_string(), vec![Atom("bar".to_string()), PV("Baz".to_string())])),
+             Ctor::<String>("foo".to_string(), vec![atom("bar"), Ctor("baz".to_string(), vec![atom("quux")])])),
+            ("1 + f(X) * 2",
+             Ctor::<String>("+".to_string(), vec![Num(1), Ctor("*".to_string(), vec![
+                 Ctor("f".to_string(), vec![PV("X".to_string())]), Num(2)])])),
         ];
 
         for (input, functor) in valid_functors.into_iter() {
-            let remainder = CompleteStr("");
-            assert_eq!(ctor(CompleteStr(input)),  Ok((remainder, functor.clone())));
-            assert_eq!(expr(CompleteStr(input)),  Ok((remainder, functor)));
+            assert_eq!(parse_expr(input), Ok(functor));
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_goals() {
+        let goal = parse_query("(a, b -> X = 1 ; c), \\+ d", &Ops::default(), DoubleQuotes::Codes).unwrap();
+        assert_eq!(goal, Stmt::And(
+            Box::new(Stmt::If(
+                Box::new(Stmt::And(Box::new(call("a", vec![])), Box::new(call("b", vec![])))),
+                Box::new(Stmt::Unify(PV("X".to_string()), Num(1))),
+                Box::new(call("c", vec![])))),
+            Box::new(Stmt::Not(Box::new(call("d", vec![])), Span::default()))));
+
+        let goal = parse_query("catch(G, E, true)", &Ops::default(), DoubleQuotes::Codes).unwrap();
+        assert_eq!(goal, Stmt::Catch(
+            Box::new(call("call", vec![PV("G".to_string())])),
+            PV("E".to_string()),
+            Box::new(Stmt::True)));
+
+        assert!(parse_query("a, 1", &Ops::default(), DoubleQuotes::Codes).is_err());
+    }
+
+    #[test]
+    fn test_comments() {
+        let clauses = program("
+            % facts
+            p(a). /* more
+            facts */ p(b).
+            q(X) :- % a rule
+                p(X), /* inline */ r(X).
+            r(_). % the end");
+        let names: Vec<String> = clauses.iter().map(|c| c.sig().to_string()).collect();
+        assert_eq!(names, vec!["p/1", "p/1", "q/1", "r/1"]);
+        assert_eq!(clauses[2].body, Stmt::And(Box::new(call("p", vec![PV("X".to_string())])), Box::new(call("r", vec![PV("X".to_string())]))));
+        assert_eq!((clauses[1].span.line, clauses[1].span.col), (4, 22));
+    }
+
+    #[test]
+    fn test_layout_before_parenthesis() {
+        assert_eq!(program("p :- f(a).")[0].body, call("f", vec![atom("a")]));
+        assert_eq!(program("p :- - (a).")[0].body, call("-", vec![atom("a")]));
+        assert_eq!(program("r.")[0].sig().to_string(), "r/0");
+
+        let (_, errors) = parse_program(None, "p :- f (a).", &mut Ops::default());
+        assert_eq!(errors[0].span.col, 8);
+        assert_eq!(errors[0].expected, vec!["an operator", "`.`"]);
+    }
+
+    #[test]
+    fn test_directives() {
+        let text = "
+            :- dynamic counter/1, seen/2.
+            :- discontiguous([p/1]).
+            :- initialization(main, main).
+            :- op(700, xfx, ===>).
+            rule(a ===> b).
+            :- op(0, xfx, ===>).
+            :- include(common).
+            :- module(shapes, [area/2, m:p/0]).
+            :- use_module(library(lists)).
+            :- use_module(geometry, []).
+            :- meta_predicate maplist(2, ?, ?), with_output_to(+, 0).
+            :- foo(bar).
+        ";
+        let (items, errors) = parse_program(None, text, &mut Ops::default());
+        assert!(errors.is_empty(), "unexpected errors {:?}", errors);
+        let directives: Vec<Directive<String>> = items.into_iter().filter_map(|item| match item {
+            Item::Directive(d, _) => Some(d),
+            Item::Clause(c) => {
+                assert_eq!(c.params, vec![Ctor("===>".to_string(), vec![atom("a"), atom("b")])]);
+                None
+            }
+        }).collect();
+        let sig = |name: &str, arity| PredSig(Pred::User(name.to_string()), arity);
+        assert_eq!(directives, vec![
+            Directive::Dynamic(vec![sig("counter", 1), sig("seen", 2)]),
+            Directive::Discontiguous(vec![sig("p", 1)]),
+            Directive::Main(call("main", vec![])),
+            Directive::Op(700, OpType::Xfx, vec!["===>".to_string()]),
+            Directive::Op(0, OpType::Xfx, vec!["===>".to_string()]),
+            Directive::Include("common".to_string()),
+            Directive::Module("shapes".to_string(), vec![sig("area", 2), PredSig(Pred::Qualified("m".to_string(), "p".to_string()), 0)]),
+            Directive::UseModule(Source::Library("lists".to_string()), None),
+            Directive::UseModule(Source::File("geometry".to_string()), Some(vec![])),
+            Directive::MetaPredicate(vec![(sig("maplist", 3), vec![true, false, false]), (sig("with_output_to", 2), vec![false, true])]),
+            Directive::Unknown(Ctor("foo".to_string(), vec![atom("bar")])),
+        ]);
+
+        // the operator is not defined after it is removed
+        let (_, errors) = parse_program(None, ":- op(700, xfx, ===>). :- op(0, xfx, ===>). r(a ===> b).", &mut Ops::default());
+        assert_eq!(errors.len(), 1);
+
+        let (_, errors) = parse_program(None, "\
+            :- dynamic foo. :- op(700, xfz, foo). :- op(1000, xfy, ','). \
+            :- module(1, []). :- use_module(library(1)). :- meta_predicate foo(x).", &mut Ops::default());
+        let messages: Vec<Option<String>> = errors.into_iter().map(|e| e.message).collect();
+        assert_eq!(messages, vec![
+            Some("expected a predicate indicator such as foo/2".to_string()),
+            Some("expected an operator type such as xfx, xfy, yfx, fy, fx, xf or yf".to_string()),
+            Some("the operator `,` cannot be modified".to_string()),
+            Some("expected a module name".to_string()),
+            Some("expected a file name or library(Name)".to_string()),
+            Some("expected a meta-argument specifier such as 0, :, ? or +".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_grammar_rules() {
+        let (items, errors) = parse_program(None, "greeting, [!] --> [hello], name.", &mut Ops::default());
+        assert!(errors.is_empty(), "unexpected errors {:?}", errors);
+        let head = Ctor(",".to_string(), vec![atom("greeting"), Ctor(".".to_string(), vec![atom("!"), atom("[]")])]);
+        let body = Ctor(",".to_string(), vec![Ctor(".".to_string(), vec![atom("hello"), atom("[]")]), atom("name")]);
+        assert_eq!(items, vec![Item::Clause(PredDef::new(GRAMMAR_RULE, vec![head, body], Stmt::True))]);
+
+        let (_, errors) = parse_program(None, "X --> [a]. 1 --> [a]. p, [a|_] --> [b].", &mut Ops::default());
+        let messages: Vec<Option<String>> = errors.into_iter().map(|e| e.message).collect();
+        assert_eq!(messages, vec![
+            Some("the head of a grammar rule cannot be a variable".to_string()),
+            Some("the head of a grammar rule cannot be the number 1".to_string()),
+            Some("the pushback of a grammar rule must be a list".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let (items, errors) = parse_program(Some("test.pl"), "p(a).\nq(a b).\nr(c) :- s(.\n'x. y'(1.\nt(d).\n2 :- p.\n", &mut Ops::default());
+        let names: Vec<String> = items.iter().map(|item| match item {
+            Item::Clause(c) => c.sig().to_string(),
+            Item::Directive(d, _) => format!("{:?}", d)
+        }).collect();
+        assert_eq!(names, vec!["p/1", "t/1"]);
+
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(messages, vec![
+            "test.pl:2:5: syntax error: expected an operator, `,` or `)`\n    q(a b).\n        ^".to_string(),
+            "test.pl:3:11: syntax error: expected a term\n    r(c) :- s(.\n              ^".to_string(),
+            "test.pl:4:9: syntax error: expected an operator, `,` or `)`\n    'x. y'(1.\n            ^".to_string(),
+            "test.pl:6:1: syntax error: the head of a clause cannot be the number 2\n    2 :- p.\n    ^".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_error_expectations() {
+        let error = parse_query("X = ", &Ops::default(), DoubleQuotes::Codes).unwrap_err();
+        assert_eq!((error.span.line, error.span.col), (1, 5));
+        assert_eq!(error.expected, vec!["a term"]);
+
+        let error = parse_query("foo bar", &Ops::default(), DoubleQuotes::Codes).unwrap_err();
+        assert_eq!(error.span.col, 5);
+        assert_eq!(error.expected, vec!["an operator", "`.`"]);
+
+        let error = parse_query("foo. bar", &Ops::default(), DoubleQuotes::Codes).unwrap_err();
+        assert_eq!(error.expected, vec!["the end of the input"]);
+
+        let error = parse_query("1.5", &Ops::default(), DoubleQuotes::Codes).unwrap_err();
+        assert_eq!(error.message.as_deref(), Some("floating point numbers are not supported"));
+    }
+}
@@ -0,0 +1,394 @@
+// Tokenizer for Prolog text. It takes care of the layout and the comments, and records where each token starts.
+use crate::ast_common::Span;
+use super::ParseError;
+use std::rc::Rc;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum TokenKind {
+    // an atom: a letter-digit sequence, a graphic sequence, a quoted atom, `!` or `;`
+    Name(String),
+    Var(String),
+    Int(i64),
+    // a double-quoted string
+    Str(String),
+    // a back-quoted string
+    BackQuoted(String),
+    // one of ( ) [ ] { } , |
+    Punct(&'static str),
+    // the full stop ending a clause
+    End,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+    // whether there is layout or a comment right before the token, this distinguishes `f(` from `f (`
+    pub layout_before: bool,
+}
+
+const GRAPHIC: &str = "#$&*+-./:<=>?@^~\\";
+
+pub fn is_graphic(c: char) -> bool {
+    GRAPHIC.contains(c)
+}
+
+fn is_alnum(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+pub struct Lexer<'a> {
+    text: &'a str,
+    file: Option<Rc<str>>,
+    // byte offset of the next character, and its position
+    pos: usize,
+    line: usize,
+    col: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(file: Option<&str>, text: &'a str) -> Self {
+        Lexer { text, file: file.map(Rc::from), pos: 0, line: 1, col: 1 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<char> {
+        self.rest().chars().nth(n)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    pub fn span(&self) -> Span {
+        Span::new(self.file.clone(), self.line, self.col)
+    }
+
+    // The text of given line, to show the context of an error
+    pub fn line_text(&self, line: usize) -> String {
+        self.text.lines().nth(line - 1).unwrap_or("").to_string()
+    }
+
+    pub fn error(&self, span: Span, message: &str) -> ParseError {
+        let line = self.line_text(span.line);
+        ParseError { span, expected: Vec::new(), message: Some(message.to_string()), line }
+    }
+
+    // Skip the layout and the comments, return whether there were any
+    fn skip_layout(&mut self) -> Result<bool, ParseError> {
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => { self.bump(); }
+                Some('%') => {
+                    while let Some(c) = self.bump() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                }
+                Some('/') if self.peek_at(1) == Some('*') => {
+                    let span = self.span();
+                    self.bump();
+                    self.bump();
+                    loop {
+                        match self.bump() {
+                            Some('*') if self.peek() == Some('/') => {
+                                self.bump();
+                                break;
+                            }
+                            Some(_) => {}
+                            None => return Err(self.error(span, "unterminated block comment"))
+                        }
+                    }
+                }
+                _ => return Ok(self.pos > start)
+            }
+        }
+    }
+
+    // Skip past the next full stop after an error, so that reading can resume with the next clause
+    pub fn skip_to_full_stop(&mut self) {
+        while let Some(c) = self.bump() {
+            match c {
+                '\'' | '"' | '`' => {
+                    // skip the quoted item as a whole, it may contain full stops
+                    while let Some(d) = self.bump() {
+                        if d == '\\' {
+                            self.bump();
+                        } else if d == c || d == '\n' {
+                            break;
+                        }
+                    }
+                }
+                '%' => {
+                    while let Some(d) = self.bump() {
+                        if d == '\n' {
+                            break;
+                        }
+                    }
+                }
+                '.' if self.peek().is_none_or(|d| d.is_whitespace() || d == '%') => return,
+                _ => {}
+            }
+        }
+    }
+
+    // The next token, or None at the end of the input
+    pub fn next_token(&mut self) -> Result<Option<Token>, ParseError> {
+        let layout_before = self.skip_layout()?;
+        let span = self.span();
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Ok(None)
+        };
+
+        let kind = match c {
+            '0'..='9' => self.number(&span)?,
+            '_' => {
+                let name = self.take_while(is_alnum);
+                // such names are reserved for the variables the compiler generates
+                if name.starts_with("__") {
+                    return Err(self.error(span, "variable names cannot start with two underscores"));
+                }
+                TokenKind::Var(name)
+            }
+            c if c.is_uppercase() => TokenKind::Var(self.take_while(is_alnum)),
+            c if c.is_alphabetic() => TokenKind::Name(self.take_while(is_alnum)),
+            '\'' => TokenKind::Name(self.quoted('\'')?),
+            '"' => TokenKind::Str(self.quoted('"')?),
+            '`' => TokenKind::BackQuoted(self.quoted('`')?),
+            '(' | ')' | '[' | ']' | '{' | '}' | ',' | '|' => {
+                self.bump();
+                TokenKind::Punct(match c {
+                    '(' => "(",
+                    ')' => ")",
+                    '[' => "[",
+                    ']' => "]",
+                    '{' => "{",
+                    '}' => "}",
+                    ',' => ",",
+                    _ => "|",
+                })
+            }
+            '!' | ';' => {
+                self.bump();
+                TokenKind::Name(c.to_string())
+            }
+            '.' if self.peek_at(1).is_none_or(|d| d.is_whitespace() || d == '%') => {
+                self.bump();
+                TokenKind::End
+            }
+            c if is_graphic(c) => TokenKind::Name(self.take_while(is_graphic)),
+            _ => {
+                self.bump();
+                return Err(self.error(span, &format!("unexpected character `{}`", c)));
+            }
+        };
+
+        Ok(Some(Token { kind, span, layout_before }))
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, f: F) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(&f) {
+            self.bump();
+        }
+        self.text[start..self.pos].to_string()
+    }
+
+    fn number(&mut self, span: &Span) -> Result<TokenKind, ParseError> {
+        let overflow = |lexer: &Self| lexer.error(span.clone(), "integer is too large");
+        if self.peek() == Some('0') {
+            let radix = match self.peek_at(1) {
+                Some('x') => 16,
+                Some('o') => 8,
+                Some('b') => 2,
+                Some('\'') => 0,
+                _ => 10,
+            };
+            if radix == 0 {
+                self.bump();
+                self.bump();
+                return self.char_code(span);
+            }
+            if radix != 10 && self.peek_at(2).is_some_and(|c| c.is_digit(radix)) {
+                self.bump();
+                self.bump();
+                let digits = self.take_while(|c| c.is_digit(radix));
+                return i64::from_str_radix(&digits, radix).map(TokenKind::Int).map_err(|_| overflow(self));
+            }
+        }
+
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        if self.peek() == Some('.') && self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) {
+            return Err(self.error(span.clone(), "floating point numbers are not supported"));
+        }
+        digits.parse::<i64>().map(TokenKind::Int).map_err(|_| overflow(self))
+    }
+
+    // The code of the character after 0'
+    fn char_code(&mut self, span: &Span) -> Result<TokenKind, ParseError> {
+        match self.bump() {
+            Some('\\') => match self.escape(span)? {
+                Some(c) => Ok(TokenKind::Int(c as i64)),
+                None => Err(self.error(span.clone(), "invalid character code"))
+            },
+            // a quote is written as 0'' or 0'''
+            Some('\'') => {
+                if self.peek() == Some('\'') {
+                    self.bump();
+                }
+                Ok(TokenKind::Int('\'' as i64))
+            }
+            Some(c) => Ok(TokenKind::Int(c as i64)),
+            None => Err(self.error(span.clone(), "missing character after 0'"))
+        }
+    }
+
+    // Read an escape sequence after a backslash. A backslash followed by a newline is a continuation, which stands for no character.
+    fn escape(&mut self, span: &Span) -> Result<Option<char>, ParseError> {
+        let c = match self.bump() {
+            Some(c) => c,
+            None => return Err(self.error(span.clone(), "unterminated escape sequence"))
+        };
+        Ok(Some(match c {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            'a' => '\x07',
+            'b' => '\x08',
+            'f' => '\x0c',
+            'v' => '\x0b',
+            'e' => '\x1b',
+            's' => ' ',
+            '0'..='7' | 'x' => {
+                let (radix, mut digits) = if c == 'x' { (16, String::new()) } else { (8, c.to_string()) };
+                digits.push_str(&self.take_while(|d| d.is_digit(radix)));
+                if self.bump() != Some('\\') {
+                    return Err(self.error(span.clone(), "missing closing \\ in a character code escape"));
+                }
+                u32::from_str_radix(&digits, radix).ok().and_then(std::char::from_u32)
+                    .ok_or_else(|| self.error(span.clone(), "invalid character code"))?
+            }
+            '\n' => return Ok(None),
+            '\\' | '\'' | '"' | '`' => c,
+            _ => return Err(self.error(span.clone(), &format!("unknown escape sequence \\{}", c)))
+        }))
+    }
+
+    // Read a quoted item, doubling the quote stands for the quote itself
+    fn quoted(&mut self, quote: char) -> Result<String, ParseError> {
+        let span = self.span();
+        self.bump();
+        let mut buffer = String::new();
+        loop {
+            match self.bump() {
+                Some(c) if c == quote => {
+                    if self.peek() == Some(quote) {
+                        self.bump();
+                        buffer.push(quote);
+                    } else {
+                        return Ok(buffer);
+                    }
+                }
+                Some('\\') => {
+                    if let Some(c) = self.escape(&span)? {
+                        buffer.push(c);
+                    }
+                }
+                Some(c) => buffer.push(c),
+                None => return Err(self.error(span, &format!("unterminated quoted item, missing {}", quote)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TokenKind::*;
+
+    fn kinds(text: &str) -> Vec<TokenKind> {
+        let mut lexer = Lexer::new(None, text);
+        let mut tokens = Vec::new();
+        while let Some(token) = lexer.next_token().unwrap() {
+            tokens.push(token.kind);
+        }
+        tokens
+    }
+
+    fn name(s: &str) -> TokenKind {
+        Name(s.to_string())
+    }
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(kinds("foo(X, _y) :- bar."), vec![
+            name("foo"), Punct("("), Var("X".to_string()), Punct(","), Var("_y".to_string()), Punct(")"),
+            name(":-"), name("bar"), End]);
+        assert_eq!(kinds("X =.. [a|T], !; 'hello world'"), vec![
+            Var("X".to_string()), name("=.."), Punct("["), name("a"), Punct("|"), Var("T".to_string()), Punct("]"),
+            Punct(","), name("!"), name(";"), name("hello world")]);
+        assert_eq!(kinds("\"ab\" `c` {}"), vec![Str("ab".to_string()), BackQuoted("c".to_string()), Punct("{"), Punct("}")]);
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(kinds("42 0x1F 0o17 0b101 0'a 0' 0''' 0'\\n"), vec![
+            Int(42), Int(31), Int(15), Int(5), Int(97), Int(32), Int(39), Int(10)]);
+        assert!(Lexer::new(None, "1.5").next_token().is_err());
+        assert!(Lexer::new(None, "99999999999999999999").next_token().is_err());
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert_eq!(kinds("'\\n\\t\\\\' 'it''s' '\\x41\\\\101\\' 'a\\\nb'"), vec![
+            name("\n\t\\"), name("it's"), name("AA"), name("ab")]);
+        assert!(Lexer::new(None, "'\\q'").next_token().is_err());
+        assert!(Lexer::new(None, "'abc").next_token().is_err());
+    }
+
+    #[test]
+    fn test_comments_and_layout() {
+        let text = "% a comment\nfoo /* block\ncomment */ (a).\nf(x).% trailing";
+        let mut lexer = Lexer::new(None, text);
+        let mut tokens = Vec::new();
+        while let Some(token) = lexer.next_token().unwrap() {
+            tokens.push(token);
+        }
+        let kinds: Vec<TokenKind> = tokens.iter().map(|t| t.kind.clone()).collect();
+        assert_eq!(kinds, vec![name("foo"), Punct("("), name("a"), Punct(")"), End, name("f"), Punct("("), name("x"), Punct(")"), End]);
+        // there is a comment between foo and (, but not between f and (
+        assert!(tokens[1].layout_before);
+        assert!(!tokens[6].layout_before);
+        assert_eq!((tokens[0].span.line, tokens[0].span.col), (2, 1));
+        assert_eq!((tokens[1].span.line, tokens[1].span.col), (3, 12));
+
+        assert!(Lexer::new(None, "/* unterminated").next_token().is_err());
+    }
+
+    #[test]
+    fn test_full_stops() {
+        assert_eq!(kinds("a. b.c .(d)"), vec![name("a"), End, name("b"), name("."), name("c"), name("."), Punct("("), name("d"), Punct(")")]);
+
+        let mut lexer = Lexer::new(None, "'a. b' c. d.");
+        lexer.skip_to_full_stop();
+        assert_eq!(lexer.next_token().unwrap().map(|t| t.kind), Some(name("d")));
+    }
+}
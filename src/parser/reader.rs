@@ -0,0 +1,461 @@
+// Operator precedence reader, which builds terms out of the tokens of the lexer according to an operator table
+use crate::ast_common::{Atom, Span};
+use crate::flags::DoubleQuotes;
+use crate::ops::Ops;
+use super::ParseError;
+use super::lexer::{Lexer, Token, TokenKind};
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum TermKind {
+    Var(String),
+    Atom(Atom),
+    Num(i64),
+    Str(String),
+    Compound(Atom, Vec<Term>),
+}
+
+// A term as it is written in the source, with the position it starts at. The span of an operator term is the span of its leftmost operand.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Term {
+    pub kind: TermKind,
+    pub span: Span,
+}
+
+impl Term {
+    pub fn new(kind: TermKind, span: Span) -> Self {
+        Term { kind, span }
+    }
+
+    fn atom(name: &str, span: Span) -> Self {
+        Term::new(TermKind::Atom(name.to_string()), span)
+    }
+
+    // The name and the arguments of a callable term, atoms have no arguments
+    pub fn functor(&self) -> Option<(&str, &[Term])> {
+        match self.kind {
+            TermKind::Atom(ref name) => Some((name, &[])),
+            TermKind::Compound(ref name, ref args) => Some((name, args)),
+            _ => None
+        }
+    }
+
+    // The list of the codes of given text, which is how a back-quoted string reads
+    fn codes(text: &str, span: Span) -> Self {
+        Term::list(text.chars().map(|c| TermKind::Num(c as i64)), span)
+    }
+
+    fn list<I: DoubleEndedIterator<Item=TermKind>>(elements: I, span: Span) -> Self {
+        elements.rev().fold(Term::atom("[]", span.clone()), |tail, head| {
+            let head = Term::new(head, span.clone());
+            Term::new(TermKind::Compound(".".to_string(), vec![head, tail]), span.clone())
+        })
+    }
+
+    // What a double-quoted string reads as
+    fn double_quoted(text: String, double_quotes: DoubleQuotes, span: Span) -> Self {
+        match double_quotes {
+            DoubleQuotes::Codes => Term::codes(&text, span),
+            DoubleQuotes::Chars => Term::list(text.chars().map(|c| TermKind::Atom(c.to_string())), span),
+            DoubleQuotes::Atom => Term::new(TermKind::Atom(text), span),
+            DoubleQuotes::String => Term::new(TermKind::Str(text), span),
+        }
+    }
+}
+
+// Reads the terms of a text one after the other. The operator table is passed to each read, since directives may change it between the terms.
+pub struct Reader<'a> {
+    lexer: Lexer<'a>,
+    // the token after the ones consumed so far, if it was looked at already
+    peeked: Option<Token>,
+    // what the reader would have accepted instead of the next token
+    expected: Vec<String>,
+    double_quotes: DoubleQuotes,
+}
+
+// Tokens that cannot start a term, a prefix operator in front of them is an atom
+fn ends_term(token: Option<&Token>) -> bool {
+    match token.map(|t| &t.kind) {
+        None | Some(TokenKind::End) => true,
+        Some(TokenKind::Punct(p)) => [")", "]", "}", ",", "|"].contains(p),
+        _ => false
+    }
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(file: Option<&str>, text: &'a str) -> Self {
+        Reader { lexer: Lexer::new(file, text), peeked: None, expected: Vec::new(), double_quotes: DoubleQuotes::default() }
+    }
+
+    // Read the double-quoted strings of the next terms as given kind of term
+    pub fn set_double_quotes(&mut self, double_quotes: DoubleQuotes) {
+        self.double_quotes = double_quotes;
+    }
+
+    fn peek(&mut self) -> Result<Option<&Token>, ParseError> {
+        if self.peeked.is_none() {
+            self.peeked = self.lexer.next_token()?;
+        }
+        Ok(self.peeked.as_ref())
+    }
+
+    // The kind of the next token, and whether there is layout before it
+    fn peek_kind(&mut self) -> Result<Option<(TokenKind, bool)>, ParseError> {
+        Ok(self.peek()?.map(|t| (t.kind.clone(), t.layout_before)))
+    }
+
+    fn advance(&mut self) -> Result<Option<Token>, ParseError> {
+        self.peek()?;
+        self.expected.clear();
+        Ok(self.peeked.take())
+    }
+
+    // Record that given item would have been accepted at the next token
+    fn expecting(&mut self, what: &str) {
+        if !self.expected.iter().any(|e| e == what) {
+            self.expected.push(what.to_string());
+        }
+    }
+
+    // Consume the next token if it is given punctuation
+    fn accept(&mut self, punct: &'static str) -> Result<bool, ParseError> {
+        if let Some(Token { kind: TokenKind::Punct(p), .. }) = self.peek()? {
+            if *p == punct {
+                self.advance()?;
+                return Ok(true);
+            }
+        }
+        self.expecting(&format!("`{}`", punct));
+        Ok(false)
+    }
+
+    fn expect(&mut self, punct: &'static str) -> Result<(), ParseError> {
+        if self.accept(punct)? {
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    // The error at the next token, listing what was expected there
+    fn error(&mut self) -> ParseError {
+        let span = match self.peek() {
+            Ok(Some(token)) => token.span.clone(),
+            Ok(None) => self.lexer.span(),
+            Err(e) => return e
+        };
+        let line = self.lexer.line_text(span.line);
+        ParseError { span, expected: self.expected.clone(), message: None, line }
+    }
+
+    // An error with given message at given position
+    pub fn error_at(&self, span: Span, message: &str) -> ParseError {
+        self.lexer.error(span, message)
+    }
+
+    // Read the next clause, which is a term terminated by a full stop. Returns None at the end of the input.
+    pub fn read_clause(&mut self, ops: &Ops) -> Result<Option<Term>, ParseError> {
+        self.expected.clear();
+        if self.peek()?.is_none() {
+            return Ok(None);
+        }
+        let term = self.read(ops, 1200)?.0;
+        if let Some(TokenKind::End) = self.peek_kind()?.map(|(kind, _)| kind) {
+            self.advance()?;
+            Ok(Some(term))
+        } else {
+            self.expecting("`.`");
+            Err(self.error())
+        }
+    }
+
+    // Read a term that makes up the rest of the input, the full stop after it is optional
+    pub fn read_all(&mut self, ops: &Ops) -> Result<Term, ParseError> {
+        self.expected.clear();
+        let term = self.read(ops, 1200)?.0;
+        match self.peek_kind()? {
+            None => return Ok(term),
+            Some((TokenKind::End, _)) => { self.advance()?; }
+            Some(_) => {
+                self.expecting("`.`");
+                return Err(self.error());
+            }
+        }
+        if self.peek()?.is_some() {
+            self.expecting("the end of the input");
+            return Err(self.error());
+        }
+        Ok(term)
+    }
+
+    // Skip the rest of the clause after an error, so that reading can resume with the next one
+    pub fn recover(&mut self) {
+        match self.peeked.take() {
+            Some(Token { kind: TokenKind::End, .. }) => {}
+            _ => self.lexer.skip_to_full_stop()
+        }
+        self.expected.clear();
+    }
+
+    // Read a term with a priority of at most given one, returns the term and its priority
+    fn read(&mut self, ops: &Ops, max: u32) -> Result<(Term, u32), ParseError> {
+        let (mut left, mut priority) = self.read_primary(ops, max)?;
+        loop {
+            let name = match self.peek_kind()? {
+                Some((TokenKind::Name(name), _)) => name,
+                Some((TokenKind::Punct(p), _)) if p == "," || p == "|" => p.to_string(),
+                _ => {
+                    self.expecting("an operator");
+                    break;
+                }
+            };
+
+            if let Some((p, op_type)) = ops.infix(&name) {
+                if p <= max && priority <= op_type.left_max(p) {
+                    self.advance()?;
+                    let (right, _) = self.read(ops, op_type.right_max(p))?;
+                    // `|` between goals is another way of writing a disjunction
+                    let name = if name == "|" { ";".to_string() } else { name };
+                    let span = left.span.clone();
+                    left = Term::new(TermKind::Compound(name, vec![left, right]), span);
+                    priority = p;
+                    continue;
+                }
+            }
+            if let Some((p, op_type)) = ops.postfix(&name) {
+                if p <= max && priority <= op_type.left_max(p) {
+                    self.advance()?;
+                    let span = left.span.clone();
+                    left = Term::new(TermKind::Compound(name, vec![left]), span);
+                    priority = p;
+                    continue;
+                }
+            }
+            self.expecting("an operator");
+            break;
+        }
+        Ok((left, priority))
+    }
+
+    // Read a term that does not start with an operand of an infix or postfix operator
+    fn read_primary(&mut self, ops: &Ops, max: u32) -> Result<(Term, u32), ParseError> {
+        let token = match self.peek()? {
+            Some(token) if token.kind != TokenKind::End => token.clone(),
+            _ => {
+                self.expecting("a term");
+                return Err(self.error());
+            }
+        };
+        let span = token.span.clone();
+
+        let term = match token.kind {
+            TokenKind::Int(n) => {
+                self.advance()?;
+                Term::new(TermKind::Num(n), span)
+            }
+            TokenKind::Var(x) => {
+                self.advance()?;
+                Term::new(TermKind::Var(x), span)
+            }
+            TokenKind::Str(s) => {
+                self.advance()?;
+                Term::double_quoted(s, self.double_quotes, span)
+            }
+            TokenKind::BackQuoted(s) => {
+                self.advance()?;
+                Term::codes(&s, span)
+            }
+            TokenKind::Punct("(") => {
+                self.advance()?;
+                let (term, _) = self.read(ops, 1200)?;
+                self.expect(")")?;
+                term
+            }
+            TokenKind::Punct("[") => {
+                self.advance()?;
+                if self.accept("]")? {
+                    return self.read_name(ops, "[]", span, max);
+                }
+                self.read_list(ops, span)?
+            }
+            TokenKind::Punct("{") => {
+                self.advance()?;
+                if self.accept("}")? {
+                    return self.read_name(ops, "{}", span, max);
+                }
+                let (term, _) = self.read(ops, 1200)?;
+                self.expect("}")?;
+                Term::new(TermKind::Compound("{}".to_string(), vec![term]), span)
+            }
+            TokenKind::Name(name) => {
+                self.advance()?;
+                return self.read_name(ops, &name, span, max);
+            }
+            _ => {
+                self.expecting("a term");
+                return Err(self.error());
+            }
+        };
+        Ok((term, 0))
+    }
+
+    // Read the rest of a term that starts with given name, which is already consumed
+    fn read_name(&mut self, ops: &Ops, name: &str, span: Span, max: u32) -> Result<(Term, u32), ParseError> {
+        let next = self.peek_kind()?;
+
+        // functional notation requires the parenthesis to follow the name immediately
+        if let Some((TokenKind::Punct("("), false)) = next {
+            self.advance()?;
+            let mut args = Vec::new();
+            // `foo()` is a compound term without arguments
+            if let Some((TokenKind::Punct(")"), _)) = self.peek_kind()? {
+                self.advance()?;
+            } else {
+                loop {
+                    args.push(self.read(ops, 999)?.0);
+                    if !self.accept(",")? {
+                        break;
+                    }
+                }
+                self.expect(")")?;
+            }
+            return Ok((Term::new(TermKind::Compound(name.to_string(), args), span), 0));
+        }
+
+        // a minus sign directly in front of a number is a part of it
+        if name == "-" {
+            if let Some((TokenKind::Int(n), false)) = next {
+                self.advance()?;
+                return Ok((Term::new(TermKind::Num(-n), span), 0));
+            }
+        }
+
+        if let Some((p, op_type)) = ops.prefix(name) {
+            let operand_follows = !ends_term(self.peek()?) && match next {
+                // a name that can only be an infix operator makes this one an atom, as in `- = X`
+                Some((TokenKind::Name(ref next), _)) => ops.prefix(next).is_some() || ops.infix(next).is_none(),
+                _ => true
+            };
+            if operand_follows {
+                if p > max {
+                    return Err(self.error_at(span, &format!("operator priority clash, `{}` has priority {} where at most {} is allowed", name, p, max)));
+                }
+                let (arg, _) = self.read(ops, op_type.right_max(p))?;
+                return Ok((Term::new(TermKind::Compound(name.to_string(), vec![arg]), span), p));
+            }
+        }
+
+        Ok((Term::atom(name, span), 0))
+    }
+
+    // Read the elements and the tail of a list after the opening bracket
+    fn read_list(&mut self, ops: &Ops, span: Span) -> Result<Term, ParseError> {
+        let mut elements = Vec::new();
+        loop {
+            elements.push(self.read(ops, 999)?.0);
+            if !self.accept(",")? {
+                break;
+            }
+        }
+        let tail = if self.accept("|")? {
+            self.read(ops, 999)?.0
+        } else {
+            Term::atom("[]", span.clone())
+        };
+        self.expect("]")?;
+        Ok(elements.into_iter().rev().fold(tail, |tail, head| {
+            let span = head.span.clone();
+            Term::new(TermKind::Compound(".".to_string(), vec![head, tail]), span)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Write a term in canonical form, to compare the structure of the terms read
+    fn canonical(term: &Term) -> String {
+        match term.kind {
+            TermKind::Var(ref x) => x.clone(),
+            TermKind::Atom(ref a) => a.clone(),
+            TermKind::Num(n) => n.to_string(),
+            TermKind::Str(ref s) => format!("\"{}\"", s),
+            TermKind::Compound(ref f, ref args) => {
+                let args: Vec<String> = args.iter().map(canonical).collect();
+                format!("{}({})", f, args.join(","))
+            }
+        }
+    }
+
+    fn read(text: &str) -> String {
+        let ops = Ops::default();
+        canonical(&Reader::new(None, text).read_all(&ops).unwrap())
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(read("a :- b, c ; d -> e"), ":-(a,;(,(b,c),->(d,e)))");
+        assert_eq!(read("X is 1 + 2 * 3 - 4"), "is(X,-(+(1,*(2,3)),4))");
+        assert_eq!(read("2 ** 3 + 2 ^ 3 ^ 4"), "+(**(2,3),^(2,^(3,4)))");
+        assert_eq!(read("\\+ a = b"), "\\+(=(a,b))");
+        assert_eq!(read("- 1 + -2 - (-(3))"), "-(+(-(1),-2),-(3))");
+        assert_eq!(read("a | b"), ";(a,b)");
+        assert_eq!(read("f(a, (b, c)) :- sys:g"), ":-(f(a,,(b,c)),:(sys,g))");
+    }
+
+    #[test]
+    fn test_operators_as_atoms() {
+        assert_eq!(read("X = -"), "=(X,-)");
+        assert_eq!(read("f(-, +, a)"), "f(-,+,a)");
+        assert_eq!(read("- = X"), "=(-,X)");
+        assert_eq!(read("[-]"), ".(-,[])");
+        assert_eq!(read("- - a"), "-(-(a))");
+    }
+
+    #[test]
+    fn test_functional_notation() {
+        assert_eq!(read("foo(a)"), "foo(a)");
+        assert_eq!(read("- (1, 2)"), "-(,(1,2))");
+        assert_eq!(read("-(1, 2)"), "-(1,2)");
+
+        let ops = Ops::default();
+        let error = Reader::new(None, "foo (a)").read_all(&ops).unwrap_err();
+        assert_eq!(error.span.col, 5);
+        assert_eq!(error.expected, vec!["an operator", "`.`"]);
+    }
+
+    #[test]
+    fn test_lists_and_strings() {
+        assert_eq!(read("[a, B | T]"), ".(a,.(B,T))");
+        assert_eq!(read("[[]]"), ".([],[])");
+        assert_eq!(read("\"ab\""), ".(97,.(98,[]))");
+        assert_eq!(read("`ab`"), ".(97,.(98,[]))");
+        assert_eq!(read("{a, b}"), "{}(,(a,b))");
+
+        let ops = Ops::default();
+        let mut reader = Reader::new(None, "\"ab\". \"ab\". \"ab\". `ab`.");
+        let mut strings = Vec::new();
+        for double_quotes in [DoubleQuotes::Chars, DoubleQuotes::Atom, DoubleQuotes::String, DoubleQuotes::String].iter() {
+            reader.set_double_quotes(*double_quotes);
+            strings.push(canonical(&reader.read_clause(&ops).unwrap().unwrap()));
+        }
+        assert_eq!(strings, vec![".(a,.(b,[]))", "ab", "\"ab\"", ".(97,.(98,[]))"]);
+    }
+
+    #[test]
+    fn test_clauses() {
+        let ops = Ops::default();
+        let mut reader = Reader::new(None, "a. b :- c.\n% done\n");
+        assert_eq!(reader.read_clause(&ops).unwrap().map(|t| canonical(&t)), Some("a".to_string()));
+        assert_eq!(reader.read_clause(&ops).unwrap().map(|t| canonical(&t)), Some(":-(b,c)".to_string()));
+        assert_eq!(reader.read_clause(&ops).unwrap(), None);
+    }
+
+    #[test]
+    fn test_priority_clash() {
+        let ops = Ops::default();
+        assert!(Reader::new(None, "a :- b :- c").read_all(&ops).is_err());
+        assert!(Reader::new(None, "f(a :- b)").read_all(&ops).is_err());
+        assert_eq!(read("f((a :- b))"), "f(:-(a,b))");
+    }
+}
@@ -0,0 +1,11 @@
+% Changing the clauses of dynamic predicates.
+
+:- meta_predicate retract(:).
+
+% retract(:Clause) removes the first clause that unifies with Clause, and the
+% next one on backtracking. As for clause/2, the clauses are those the
+% predicate had when retract/1 was called.
+retract(Clause) :-
+    '$retract'(Clause, Head, Body),
+    clause(Head, Body),
+    '$erase'(Head, Body).
@@ -0,0 +1,13 @@
+% Parsing with grammar rules. A rule `Head --> Body` is translated to a clause
+% of Head with two more arguments: the list to parse and the rest of it.
+
+:- meta_predicate phrase(2, ?), phrase(2, ?, ?).
+
+% phrase(:Body, ?List) holds when the grammar body Body covers all of List.
+phrase(G, L) :-
+    phrase(G, L, []).
+
+% phrase(:Body, ?List, ?Rest) holds when Body covers List up to Rest.
+phrase(G, L, R) :-
+    '$phrase'(G, L, R, Goal),
+    call(Goal).
@@ -0,0 +1,102 @@
+% Input and output built-ins written in Prolog.
+
+% with_output_to(+Sink, :Goal) runs Goal once with the current output
+% collected in memory, then unifies Sink with the text written. Sink is
+% atom(A), string(S), codes(Cs) or chars(Cs). The current output is restored
+% whether Goal succeeds, fails or raises an exception.
+:- meta_predicate with_output_to(+, 0).
+
+with_output_to(Sink, Goal) :-
+    '$output_sink'(Sink),
+    current_output(Old),
+    '$open_memory'(S),
+    set_output(S),
+    (   catch(Goal, E, true)
+    ->  set_output(Old),
+        (   nonvar(E)
+        ->  close(S),
+            throw(E)
+        ;   '$close_memory'(S, Sink)
+        )
+    ;   set_output(Old),
+        close(S),
+        fail
+    ).
+
+% portray(+Term) is the hook print/1 calls to write a term in a custom way. It
+% has no clauses unless the program adds some.
+:- dynamic user:portray/1.
+
+print(Term) :-
+    write_term(Term, [portray(true), numbervars(true), quoted(true)]).
+
+print(S, Term) :-
+    write_term(S, Term, [portray(true), numbervars(true), quoted(true)]).
+
+write_term(Term, Options) :-
+    current_output(S),
+    write_term(S, Term, Options).
+
+% With the portray(true) option, the subterms portray/1 succeeds for are
+% replaced by '$portrayed'(Text), with the text it wrote, before the term is
+% written.
+write_term(S, Term, Options) :-
+    '$write_options'(Options, Portray),
+    (   Portray == true
+    ->  '$portray'(Term, Portrayed),
+        '$write_term'(S, Portrayed, Options)
+    ;   '$write_term'(S, Term, Options)
+    ).
+
+'$portray'(T, P) :-
+    (   var(T)
+    ->  P = T
+    ;   with_output_to(string(S), user:portray(T))
+    ->  P = '$portrayed'(S)
+    ;   T = [_|_]
+    ->  '$portray_list'(T, P)
+    ;   compound(T)
+    ->  T =.. [F|Args],
+        '$portray_args'(Args, PArgs),
+        P =.. [F|PArgs]
+    ;   P = T
+    ).
+
+% The elements of a list are portrayed one by one, rather than its tails
+'$portray_list'(L, P) :-
+    (   L == []
+    ->  P = []
+    ;   nonvar(L),
+        L = [X|Xs]
+    ->  '$portray'(X, PX),
+        P = [PX|PXs],
+        '$portray_list'(Xs, PXs)
+    ;   '$portray'(L, P)
+    ).
+
+'$portray_args'([], []).
+'$portray_args'([X|Xs], [P|Ps]) :-
+    '$portray'(X, P),
+    '$portray_args'(Xs, Ps).
+
+format(Format, Args) :-
+    current_output(S),
+    '$format_printed'(Format, Args, Printed),
+    '$portray_args'(Printed, Portrayed),
+    '$format'(S, Format, Args, Portrayed).
+
+% format(+Output, +Format, +Args) writes to a stream, or to a sink of
+% with_output_to/2 such as atom(A). The text for a sink is collected here
+% rather than by with_output_to/2, so that the errors are raised in the
+% context of format/3.
+format(Output, Format, Args) :-
+    '$format_printed'(Format, Args, Printed),
+    '$portray_args'(Printed, Portrayed),
+    (   compound(Output),
+        \+ Output = '$stream'(_)
+    ->  '$output_sink'(Output),
+        '$open_memory'(S),
+        catch('$format'(S, Format, Args, Portrayed), E, (close(S), throw(E))),
+        '$close_memory'(S, Output)
+    ;   '$format'(Output, Format, Args, Portrayed)
+    ).
@@ -0,0 +1,256 @@
+% List manipulation predicates.
+
+:- module(lists, [
+    append/3, append/2, member/2, memberchk/2, length/2, reverse/2,
+    nth0/3, nth1/3, last/2,
+    maplist/2, maplist/3, maplist/4, maplist/5, maplist/6, maplist/7,
+    foldl/4, foldl/5, foldl/6, include/3, exclude/3, partition/4,
+    sum_list/2, max_list/2, min_list/2, list_to_set/2
+]).
+
+:- meta_predicate
+    maplist(1, ?), maplist(2, ?, ?), maplist(3, ?, ?, ?),
+    maplist(4, ?, ?, ?, ?), maplist(5, ?, ?, ?, ?, ?),
+    maplist(6, ?, ?, ?, ?, ?, ?),
+    foldl(3, +, +, -), foldl(4, +, +, +, -), foldl(5, +, +, +, +, -),
+    include(1, +, -), exclude(1, +, -), partition(1, +, -, -).
+
+% append(?L1, ?L2, ?L3) holds when L3 is L1 followed by L2.
+append([], L, L).
+append([X|Xs], L, [X|Ys]) :-
+    append(Xs, L, Ys).
+
+% append(+Lists, ?List) concatenates a list of lists.
+append([], []).
+append([L|Ls], As) :-
+    append(L, Ws, As),
+    append(Ls, Ws).
+
+member(X, [X|_]).
+member(X, [_|Xs]) :-
+    member(X, Xs).
+
+% memberchk(?X, +List) is member/2 without the choice points.
+memberchk(X, L) :-
+    member(X, L) -> true.
+
+% length(?List, ?N) enumerates longer and longer lists when both are unbound.
+length(L, N) :-
+    (   integer(N)
+    ->  (   N >= 0
+        ->  '$length_bound'(L, N)
+        ;   throw(error(domain_error(not_less_than_zero, N), context(length/2, _)))
+        )
+    ;   var(N)
+    ->  '$length'(L, 0, N)
+    ;   throw(error(type_error(integer, N), context(length/2, _)))
+    ).
+
+'$length'([], N, N).
+'$length'([_|Xs], N0, N) :-
+    N1 is N0 + 1,
+    '$length'(Xs, N1, N).
+
+'$length_bound'(L, N) :-
+    (   N =:= 0
+    ->  L = []
+    ;   L = [_|Xs],
+        N1 is N - 1,
+        '$length_bound'(Xs, N1)
+    ).
+
+reverse(L, R) :-
+    '$reverse'(L, [], R).
+
+'$reverse'([], R, R).
+'$reverse'([X|Xs], Acc, R) :-
+    '$reverse'(Xs, [X|Acc], R).
+
+% nth0(?Index, ?List, ?Elem) counts from 0, and enumerates the elements with
+% their index when the index is unbound.
+nth0(I, L, E) :-
+    (   integer(I)
+    ->  I >= 0,
+        '$nth'(I, L, E)
+    ;   var(I)
+    ->  '$nth_enum'(L, E, 0, I)
+    ;   throw(error(type_error(integer, I), context(nth0/3, _)))
+    ).
+
+% nth1(?Index, ?List, ?Elem) counts from 1.
+nth1(I, L, E) :-
+    (   integer(I)
+    ->  I0 is I - 1,
+        nth0(I0, L, E)
+    ;   var(I)
+    ->  nth0(I0, L, E),
+        I is I0 + 1
+    ;   throw(error(type_error(integer, I), context(nth1/3, _)))
+    ).
+
+'$nth'(I, [X|Xs], E) :-
+    (   I =:= 0
+    ->  E = X
+    ;   I1 is I - 1,
+        '$nth'(I1, Xs, E)
+    ).
+
+'$nth_enum'([X|_], X, I, I).
+'$nth_enum'([_|Xs], E, I0, I) :-
+    I1 is I0 + 1,
+    '$nth_enum'(Xs, E, I1, I).
+
+last([X|Xs], Last) :-
+    '$last'(Xs, X, Last).
+
+'$last'([], Last, Last).
+'$last'([X|Xs], _, Last) :-
+    '$last'(Xs, X, Last).
+
+% maplist(:Goal, ?L1, ..., ?Ln) calls Goal on the elements at the same
+% position in each list.
+maplist(_, []).
+maplist(G, [X|Xs]) :-
+    call(G, X),
+    maplist(G, Xs).
+
+maplist(_, [], []).
+maplist(G, [X|Xs], [Y|Ys]) :-
+    call(G, X, Y),
+    maplist(G, Xs, Ys).
+
+maplist(_, [], [], []).
+maplist(G, [X|Xs], [Y|Ys], [Z|Zs]) :-
+    call(G, X, Y, Z),
+    maplist(G, Xs, Ys, Zs).
+
+maplist(_, [], [], [], []).
+maplist(G, [X|Xs], [Y|Ys], [Z|Zs], [U|Us]) :-
+    call(G, X, Y, Z, U),
+    maplist(G, Xs, Ys, Zs, Us).
+
+maplist(_, [], [], [], [], []).
+maplist(G, [X|Xs], [Y|Ys], [Z|Zs], [U|Us], [V|Vs]) :-
+    call(G, X, Y, Z, U, V),
+    maplist(G, Xs, Ys, Zs, Us, Vs).
+
+maplist(_, [], [], [], [], [], []).
+maplist(G, [X|Xs], [Y|Ys], [Z|Zs], [U|Us], [V|Vs], [W|Ws]) :-
+    call(G, X, Y, Z, U, V, W),
+    maplist(G, Xs, Ys, Zs, Us, Vs, Ws).
+
+% foldl(:Goal, ?L1, ..., ?Ln, +V0, -V) calls Goal on the elements at the same
+% position in each list, with the value so far and the next value.
+foldl(G, Xs, V0, V) :-
+    '$foldl'(Xs, G, V0, V).
+
+foldl(G, Xs, Ys, V0, V) :-
+    '$foldl'(Xs, Ys, G, V0, V).
+
+foldl(G, Xs, Ys, Zs, V0, V) :-
+    '$foldl'(Xs, Ys, Zs, G, V0, V).
+
+'$foldl'([], _, V, V).
+'$foldl'([X|Xs], G, V0, V) :-
+    call(G, X, V0, V1),
+    '$foldl'(Xs, G, V1, V).
+
+'$foldl'([], [], _, V, V).
+'$foldl'([X|Xs], [Y|Ys], G, V0, V) :-
+    call(G, X, Y, V0, V1),
+    '$foldl'(Xs, Ys, G, V1, V).
+
+'$foldl'([], [], [], _, V, V).
+'$foldl'([X|Xs], [Y|Ys], [Z|Zs], G, V0, V) :-
+    call(G, X, Y, Z, V0, V1),
+    '$foldl'(Xs, Ys, Zs, G, V1, V).
+
+% include(:Pred, +List, -Included) keeps the elements Pred succeeds for.
+include(_, [], []).
+include(P, [X|Xs], Included) :-
+    (   call(P, X)
+    ->  Included = [X|Included1]
+    ;   Included = Included1
+    ),
+    include(P, Xs, Included1).
+
+% exclude(:Pred, +List, -Excluded) keeps the elements Pred fails for.
+exclude(_, [], []).
+exclude(P, [X|Xs], Excluded) :-
+    (   call(P, X)
+    ->  Excluded = Excluded1
+    ;   Excluded = [X|Excluded1]
+    ),
+    exclude(P, Xs, Excluded1).
+
+% partition(:Pred, +List, -Included, -Excluded) splits a list in the elements
+% Pred succeeds for and the rest.
+partition(_, [], [], []).
+partition(P, [X|Xs], Included, Excluded) :-
+    (   call(P, X)
+    ->  Included = [X|Included1],
+        Excluded = Excluded1
+    ;   Included = Included1,
+        Excluded = [X|Excluded1]
+    ),
+    partition(P, Xs, Included1, Excluded1).
+
+sum_list(Xs, Sum) :-
+    '$sum_list'(Xs, 0, Sum).
+
+'$sum_list'([], Sum, Sum).
+'$sum_list'([X|Xs], Sum0, Sum) :-
+    Sum1 is Sum0 + X,
+    '$sum_list'(Xs, Sum1, Sum).
+
+% max_list(+Xs, -Max) and min_list(+Xs, -Min) fail for the empty list.
+max_list([X|Xs], Max) :-
+    '$max_list'(Xs, X, Max).
+
+'$max_list'([], Max, Max).
+'$max_list'([X|Xs], Max0, Max) :-
+    (   X > Max0
+    ->  '$max_list'(Xs, X, Max)
+    ;   '$max_list'(Xs, Max0, Max)
+    ).
+
+min_list([X|Xs], Min) :-
+    '$min_list'(Xs, X, Min).
+
+'$min_list'([], Min, Min).
+'$min_list'([X|Xs], Min0, Min) :-
+    (   X < Min0
+    ->  '$min_list'(Xs, X, Min)
+    ;   '$min_list'(Xs, Min0, Min)
+    ).
+
+% list_to_set(+List, -Set) removes the elements equal to an earlier one,
+% keeping the first of them. It sorts the elements with their positions, so
+% that it takes n log n steps.
+list_to_set(List, Set) :-
+    '$number_list'(List, 1, Numbered),
+    msort(Numbered, Sorted),
+    '$first_of_equal'(Sorted, Firsts),
+    sort(2, @=<, Firsts, Positioned),
+    '$pairs_keys'(Positioned, Set).
+
+'$number_list'([], _, []).
+'$number_list'([X|Xs], N, [X-N|Ps]) :-
+    N1 is N + 1,
+    '$number_list'(Xs, N1, Ps).
+
+'$first_of_equal'([], []).
+'$first_of_equal'([X-N|Ps], [X-N|Firsts]) :-
+    '$skip_equal'(Ps, X, Rest),
+    '$first_of_equal'(Rest, Firsts).
+
+'$skip_equal'([], _, []).
+'$skip_equal'([Y-N|Ps], X, Rest) :-
+    (   Y == X
+    ->  '$skip_equal'(Ps, X, Rest)
+    ;   Rest = [Y-N|Ps]
+    ).
+
+'$pairs_keys'([], []).
+'$pairs_keys'([K-_|Ps], [K|Ks]) :-
+    '$pairs_keys'(Ps, Ks).
@@ -0,0 +1,38 @@
+% Sorting with a user-supplied ordering predicate.
+
+% predsort(+Pred, +List, -Sorted) sorts List with call(Pred, Order, X, Y), which
+% gives <, > or =. The elements that compare = to an earlier one are removed.
+:- meta_predicate predsort(3, +, -).
+
+predsort(P, L, Sorted) :-
+    (   L = []
+    ->  Sorted = []
+    ;   L = [_]
+    ->  Sorted = L
+    ;   '$predsort_split'(L, L1, L2),
+        predsort(P, L1, S1),
+        predsort(P, L2, S2),
+        '$predsort_merge'(P, S1, S2, Sorted)
+    ).
+
+'$predsort_split'([], [], []).
+'$predsort_split'([X|Xs], [X|Ys], Zs) :-
+    '$predsort_split'(Xs, Zs, Ys).
+
+'$predsort_merge'(P, Xs, Ys, Zs) :-
+    (   Xs = []
+    ->  Zs = Ys
+    ;   Ys = []
+    ->  Zs = Xs
+    ;   Xs = [X|Xs1],
+        Ys = [Y|Ys1],
+        call(P, O, X, Y),
+        '$predsort_merge'(O, P, X, Xs1, Y, Ys1, Zs)
+    ).
+
+'$predsort_merge'(<, P, X, Xs, Y, Ys, [X|Zs]) :-
+    '$predsort_merge'(P, Xs, [Y|Ys], Zs).
+'$predsort_merge'(>, P, X, Xs, Y, Ys, [Y|Zs]) :-
+    '$predsort_merge'(P, [X|Xs], Ys, Zs).
+'$predsort_merge'(=, P, X, Xs, _, Ys, [X|Zs]) :-
+    '$predsort_merge'(P, Xs, Ys, Zs).
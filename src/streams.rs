@@ -0,0 +1,345 @@
+// Streams for the input and the output of programs: the standard streams, files and in-memory text
+use crate::exceptions::*;
+use crate::ir::Value;
+use crate::parser::lexer::is_graphic;
+use crate::unification::Unification;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+
+// The functor of stream handles, a stream is '$stream'(Id)
+pub const STREAM: &str = "$stream";
+
+// The ids of the standard streams, these are internal to the crate and stay out of the C header
+pub(crate) const USER_INPUT: i64 = 0;
+pub(crate) const USER_OUTPUT: i64 = 1;
+pub(crate) const USER_ERROR: i64 = 2;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Mode {
+    Read,
+    Write,
+    Append,
+}
+
+impl Mode {
+    pub fn from_atom(mode: &str) -> Option<Mode> {
+        match mode {
+            "read" => Some(Mode::Read),
+            "write" => Some(Mode::Write),
+            "append" => Some(Mode::Append),
+            _ => None
+        }
+    }
+}
+
+enum Channel {
+    Input(Box<dyn BufRead>),
+    Output(Box<dyn Write>),
+    // an output stream that collects what is written to it
+    Memory(String),
+}
+
+pub struct Stream {
+    channel: Channel,
+    alias: Option<String>,
+    // the character read by peeking, it is the next one to get
+    peeked: Option<Option<char>>,
+    // the column the next character written goes to
+    column: usize,
+}
+
+impl Stream {
+    fn new(channel: Channel, alias: Option<&str>) -> Self {
+        Stream { channel, alias: alias.map(str::to_string), peeked: None, column: 0 }
+    }
+
+    pub fn is_input(&self) -> bool {
+        matches!(self.channel, Channel::Input(_))
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn put(&mut self, text: &str) -> Result<(), Exception> {
+        self.column = match text.rfind('\n') {
+            Some(i) => text[i + 1..].chars().count(),
+            None => self.column + text.chars().count()
+        };
+        match self.channel {
+            Channel::Output(ref mut out) => out.write_all(text.as_bytes()).and_then(|_| {
+                // the terminal should show the output as soon as a line is complete
+                if text.contains('\n') { out.flush() } else { Ok(()) }
+            }).map_err(|e| system_error(&e.to_string())),
+            Channel::Memory(ref mut buffer) => {
+                buffer.push_str(text);
+                Ok(())
+            }
+            Channel::Input(_) => unreachable!("writing to an input stream")
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Channel::Output(ref mut out) = self.channel {
+            // there is nobody to report a failure to flush to
+            let _ = out.flush();
+        }
+    }
+
+    // The next character without consuming it, None at the end of the stream
+    pub fn peek(&mut self) -> Result<Option<char>, Exception> {
+        if let Some(c) = self.peeked {
+            return Ok(c);
+        }
+        let c = self.read()?;
+        self.peeked = Some(c);
+        Ok(c)
+    }
+
+    // Consume the next character, None at the end of the stream
+    pub fn get(&mut self) -> Result<Option<char>, Exception> {
+        match self.peeked.take() {
+            Some(c) => Ok(c),
+            None => self.read()
+        }
+    }
+
+    // The text of the next clause of an input stream, up to the full stop that ends it. The text is read as far as the lexer would,
+    // so that quoted items, character codes and comments may contain full stops. None if there is only layout before the end of the stream.
+    pub fn clause_text(&mut self) -> Result<Option<String>, Exception> {
+        let mut text = String::new();
+        let mut blank = true;
+        // the previous character if it may be part of the same token as the next one
+        let mut previous: Option<char> = None;
+        while let Some(c) = self.get()? {
+            text.push(c);
+            match c {
+                '%' => {
+                    while let Some(d) = self.get()? {
+                        text.push(d);
+                        if d == '\n' {
+                            break;
+                        }
+                    }
+                    previous = None;
+                    continue;
+                }
+                '/' if self.peek()? == Some('*') && !previous.is_some_and(is_graphic) => {
+                    text.push(self.get()?.unwrap());
+                    let mut star = false;
+                    while let Some(d) = self.get()? {
+                        text.push(d);
+                        if star && d == '/' {
+                            break;
+                        }
+                        star = d == '*';
+                    }
+                    previous = None;
+                    continue;
+                }
+                '\'' | '"' | '`' => {
+                    while let Some(d) = self.get()? {
+                        text.push(d);
+                        if d == '\\' {
+                            text.extend(self.get()?);
+                        } else if d == c {
+                            // a doubled quote stands for the quote
+                            if self.peek()? != Some(c) {
+                                break;
+                            }
+                            text.push(self.get()?.unwrap());
+                        }
+                    }
+                    blank = false;
+                    previous = None;
+                    continue;
+                }
+                // 0'c is the code of c, which may be a quote or a full stop
+                '0' if self.peek()? == Some('\'') && !previous.is_some_and(|p| p.is_alphanumeric() || p == '_') => {
+                    text.push(self.get()?.unwrap());
+                    match self.get()? {
+                        Some(d) => {
+                            text.push(d);
+                            if d == '\\' || (d == '\'' && self.peek()? == Some('\'')) {
+                                text.extend(self.get()?);
+                            }
+                        }
+                        None => break
+                    }
+                    blank = false;
+                    previous = None;
+                    continue;
+                }
+                '.' if !previous.is_some_and(is_graphic) && self.peek()?.is_none_or(|d| d.is_whitespace() || d == '%') => {
+                    return Ok(Some(text));
+                }
+                c if c.is_whitespace() => {}
+                _ => blank = false
+            }
+            previous = Some(c);
+        }
+        Ok(if blank { None } else { Some(text) })
+    }
+
+    // Decode the next UTF-8 character of an input stream
+    fn read(&mut self) -> Result<Option<char>, Exception> {
+        let input = match self.channel {
+            Channel::Input(ref mut input) => input,
+            _ => unreachable!("reading from an output stream")
+        };
+        let io_error = |e: io::Error| system_error(&e.to_string());
+        let first = match input.fill_buf().map_err(io_error)?.first() {
+            Some(b) => *b,
+            None => return Ok(None)
+        };
+        let len = match first {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 0
+        };
+        let mut bytes = vec![0; len.max(1)];
+        input.read_exact(&mut bytes).map_err(|_| representation_error("character"))?;
+        std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()).map(Some).ok_or_else(|| representation_error("character"))
+    }
+}
+
+// The open streams, and the current input and output
+pub struct Streams {
+    streams: HashMap<i64, Stream>,
+    next_id: i64,
+    pub input: i64,
+    pub output: i64,
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        let mut streams = HashMap::new();
+        streams.insert(USER_INPUT, Stream::new(Channel::Input(Box::new(BufReader::new(io::stdin()))), Some("user_input")));
+        streams.insert(USER_OUTPUT, Stream::new(Channel::Output(Box::new(io::stdout())), Some("user_output")));
+        streams.insert(USER_ERROR, Stream::new(Channel::Output(Box::new(io::stderr())), Some("user_error")));
+        Streams { streams, next_id: 3, input: USER_INPUT, output: USER_OUTPUT }
+    }
+}
+
+// The term for the stream with given id
+pub fn handle(id: i64) -> Value {
+    Value::Ctor(STREAM.to_string(), vec![Value::Num(id)])
+}
+
+impl Streams {
+    fn add(&mut self, channel: Channel, alias: Option<&str>) -> i64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.streams.insert(id, Stream::new(channel, alias));
+        id
+    }
+
+    // Open given file, the errors are the ones open/4 raises
+    pub fn open(&mut self, path: &str, mode: Mode, alias: Option<&str>) -> Result<i64, Exception> {
+        let file = match mode {
+            Mode::Read => File::open(path),
+            Mode::Write => File::create(path),
+            Mode::Append => OpenOptions::new().append(true).create(true).open(path),
+        };
+        let file = file.map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => existence_error("source_sink", Value::Atom(path.to_string())),
+            _ => permission_error("open", "source_sink", Value::Atom(path.to_string()))
+        })?;
+        let channel = match mode {
+            Mode::Read => Channel::Input(Box::new(BufReader::new(file))),
+            _ => Channel::Output(Box::new(io::BufWriter::new(file))),
+        };
+        Ok(self.add(channel, alias))
+    }
+
+    // An input stream that reads given text
+    pub fn open_text(&mut self, text: String) -> i64 {
+        self.add(Channel::Input(Box::new(Cursor::new(text.into_bytes()))), None)
+    }
+
+    // An output stream that collects the text written to it
+    pub fn open_memory(&mut self) -> i64 {
+        self.add(Channel::Memory(String::new()), None)
+    }
+
+    // Close given stream and return the text collected if it is a memory stream. The standard streams stay open.
+    pub fn close(&mut self, id: i64) -> Option<String> {
+        if id <= USER_ERROR {
+            return None;
+        }
+        if self.input == id {
+            self.input = USER_INPUT;
+        }
+        if self.output == id {
+            self.output = USER_OUTPUT;
+        }
+        match self.streams.remove(&id) {
+            Some(Stream { channel: Channel::Memory(text), .. }) => Some(text),
+            Some(mut stream) => {
+                stream.flush();
+                None
+            }
+            None => None
+        }
+    }
+
+    pub fn is_alias(&self, alias: &str) -> bool {
+        self.streams.values().any(|s| s.alias.as_deref() == Some(alias))
+    }
+
+    // The id of the stream given term stands for, which is a stream handle or an alias
+    pub fn id(&self, bindings: &Unification, term: &Value) -> Result<i64, Exception> {
+        let id = match bindings.find(term) {
+            Value::LV(_) => return Err(instantiation_error()),
+            Value::Ctor(f, args) if f == STREAM && args.len() == 1 => match args[0] {
+                Value::Num(id) => Some(id).filter(|id| self.streams.contains_key(id)),
+                _ => return Err(domain_error("stream_or_alias", term.clone()))
+            },
+            Value::Atom(alias) => self.streams.iter().find(|(_, s)| s.alias.as_deref() == Some(alias.as_str())).map(|(id, _)| *id),
+            culprit => return Err(domain_error("stream_or_alias", culprit.clone()))
+        };
+        id.ok_or_else(|| existence_error("stream", bindings.resolve(term)))
+    }
+
+    // The stream given term stands for, which should be an input stream
+    pub fn input(&mut self, bindings: &Unification, term: &Value) -> Result<&mut Stream, Exception> {
+        let id = self.id(bindings, term)?;
+        let stream = self.streams.get_mut(&id).unwrap();
+        if stream.is_input() {
+            Ok(stream)
+        } else {
+            Err(permission_error("input", "stream", bindings.resolve(term)))
+        }
+    }
+
+    // The stream given term stands for, which should be an output stream
+    pub fn output(&mut self, bindings: &Unification, term: &Value) -> Result<&mut Stream, Exception> {
+        let id = self.id(bindings, term)?;
+        let stream = self.streams.get_mut(&id).unwrap();
+        if stream.is_input() {
+            Err(permission_error("output", "stream", bindings.resolve(term)))
+        } else {
+            Ok(stream)
+        }
+    }
+
+    // Write what is written to user_error to given writer instead of the standard error
+    pub fn set_user_error(&mut self, out: Box<dyn Write>) {
+        self.streams.insert(USER_ERROR, Stream::new(Channel::Output(out), Some("user_error")));
+    }
+
+    pub fn user_error(&mut self) -> &mut Stream {
+        self.streams.get_mut(&USER_ERROR).expect("user_error is open")
+    }
+
+    pub fn current_input(&mut self) -> &mut Stream {
+        self.streams.get_mut(&self.input).expect("the current input is open")
+    }
+
+    pub fn current_output(&mut self) -> &mut Stream {
+        self.streams.get_mut(&self.output).expect("the current output is open")
+    }
+}
@@ -11,6 +11,12 @@ pub struct Unification {
 }
 
 // TODO: implement path compression
+impl Default for Unification {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Unification {
     pub fn new() -> Self {
         Unification {
@@ -26,6 +32,14 @@ impl Unification {
         }
     }
 
+    // Substitute the bindings of all bound logic variables inside given value, leaving only the free ones.
+    pub fn resolve(&self, x: &Value) -> Value {
+        match self.find(x) {
+            Value::Ctor(f, args) => Value::Ctor(f.clone(), args.iter().map(|arg| self.resolve(arg)).collect()),
+            v => v.clone(),
+        }
+    }
+
     // Unify given values, this clones the values into the union-find if they are not present.
     pub fn union(&self, x: &Value, y: &Value) -> Option<Self> {
         match (self.find(x), self.find(y)) {
@@ -40,7 +54,7 @@ impl Unification {
             (Value::Ctor(f, f_args), Value::Ctor(g, g_args))
                 if f == g && f_args.len() == g_args.len() =>
             {
-                (1..f_args.len()).fold(Some(self.clone()), {
+                (0..f_args.len()).fold(Some(self.clone()), {
                     |maybe_uf, i| maybe_uf.and_then(|uf| uf.union(&f_args[i], &g_args[i]))
                 })
             }
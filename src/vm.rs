@@ -1,41 +1,129 @@
-use crate::ast_common::PredSig;
+use crate::ast_common::{PredSig, Span, SYSTEM, USER};
 use crate::ir::*;
 use crate::domains::*;
 use crate::ast_common::Pred;
 use crate::builtins::*;
+use crate::exceptions::*;
+use crate::flags::*;
+use crate::ops::Ops;
+use crate::streams::Streams;
+use crate::database::{strip_module, Database};
+use crate::parser;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
 
 pub struct VM {
     // we are using a linked list for now. if this becomes a bottleneck, we can switch to VecDeque but I suspect that will be beneficial considering that State is already a large data structure.
     pub state: Option<State>,
     pub program: Program,
     builtins: BuiltIns,
+    // print each step for debugging
+    pub trace: bool,
+    // the ball of the exception that terminated the execution, if any, and where it was thrown
+    pub exception: Option<Value>,
+    pub backtrace: Vec<Frame>,
+    // the flags, the operators, the dynamic predicates and the streams shared by all the states this VM runs
+    pub flags: Rc<RefCell<Flags>>,
+    pub ops: Rc<RefCell<Ops>>,
+    pub database: Rc<RefCell<Database>>,
+    pub streams: Rc<RefCell<Streams>>,
+}
+
+// A frame of the stack of predicate calls, with the position of the goal the predicate was executing
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Frame {
+    pub predicate: PredSig,
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(ref span) => write!(f, "{} at {}", self.predicate, span),
+            None => write!(f, "{}", self.predicate)
+        }
+    }
+}
+
+// The predicate the errors of given built-in are raised in the context of. The built-ins whose names start with $ are helpers of the predicates of the prelude, so their errors belong to the prelude predicate calling them.
+fn error_context(s: &State, name: &str, arity: usize) -> PredSig {
+    let caller = &s.local_state.predicate;
+    if name.starts_with('$') && caller.0.module() == Some(SYSTEM) && !caller.0.name().starts_with('$') {
+        caller.clone()
+    } else {
+        PredSig(Pred::Sys(name.to_string(), arity), arity)
+    }
+}
+
+// The edit distance between two strings, counting insertions, deletions, substitutions and transpositions of adjacent characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    // d[i][j] is the distance between the first i characters of a and the first j characters of b
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1).min(d[i][j - 1] + 1).min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
 }
 
 impl VM {
     pub fn new(program: Program) -> Self {
+        let mut state = State::new();
+        let builtins = BuiltIns::new();
+        state.database = Rc::new(RefCell::new(Database::new(builtins.signatures().cloned().collect())));
         VM {
-            state: Self::singleton(State::new()),
-            program: program,
-            builtins: BuiltIns::new(),
+            flags: state.flags.clone(),
+            ops: state.ops.clone(),
+            database: state.database.clone(),
+            streams: state.streams.clone(),
+            state: Some(state),
+            program,
+            builtins,
+            trace: false,
+            exception: None,
+            backtrace: Vec::new(),
         }
     }
 
-    fn singleton(s: State) -> Option<State> {
-        Some(s)
+    fn singleton(s: State) -> Step {
+        Ok(Some(s))
     }
 
-    fn modify_then_pack<F: FnOnce(&mut State)>(mut s: State, f: F) -> Option<State> {
+    fn modify_then_pack<F: FnOnce(&mut State)>(mut s: State, f: F) -> Step {
         f(&mut s);
         Self::singleton(s)
     }
 
     // process this state, potentially producing multiple states
-    fn next(&self, mut s: State) -> Option<State> {
-        use Insn::*;
-
+    fn next(&self, mut s: State) -> Step {
         // advance the PC, we may do it on only the non-jump cases later on as an optimization perhaps but loading the checkpoint will dominate this probably anyway
         s.pc += 1;
-        match &self.program.text[&s.local_state.predicate][s.pc - 1] {
+        // the frames of dynamic predicates and called goals run the code of their clause
+        let clause = s.local_state.clause.clone();
+        let code = match clause {
+            Some(ref clause) => &clause.code,
+            None => &self.program.text[&s.local_state.predicate]
+        };
+        let insn = &code[s.pc - 1];
+        self.execute(s, insn)
+    }
+
+    // execute given instruction, the PC is already advanced past it
+    fn execute(&self, mut s: State, insn: &Insn) -> Step {
+        use Insn::*;
+
+        match insn {
             PushValue(v) => Self::modify_then_pack(s, |s| s.local_state.push_value(v.clone())),
             Pop => Self::modify_then_pack(s, |s: &mut State| {s.local_state.op_stack.pop();}),
             Dup => Self::modify_then_pack(s, |s| s.local_state.dup()),
@@ -43,58 +131,259 @@ impl VM {
                 let lv = s.fresh_lv();
                 s.local_state.push_value(lv)
             }),
-            Load(x) => Self::modify_then_pack(s, |s| s.local_state.load(x.clone())),
-            Store(x) => Self::modify_then_pack(s, |s| s.local_state.store(x.clone())),
+            Load(x) => match s.local_state.load(*x) {
+                Ok(()) => Self::singleton(s),
+                Err(e) => {
+                    let PredSig(pred, arity) = s.local_state.predicate.clone();
+                    s.raise(e, pred.name(), arity)
+                }
+            },
+            Store(x) => Self::modify_then_pack(s, |s| s.local_state.store(*x)),
             Construct(f, n_args) => Self::modify_then_pack(s, |s| {
-                let args = s.local_state.pop_n(n_args.clone());
+                let args = s.local_state.pop_n(*n_args);
                 s.local_state.push_value(Value::Ctor(f.clone(), args));
             }),
             Unify => s.unify(),
-            MkCheckpoint(label, offset) => panic!("not implemented"),
+            MkCheckpoint(label, offset) => Self::modify_then_pack(s, |s| {
+                let target = (*offset as usize).wrapping_add(s.pc);
+                s.push_checkpoint(*label, target)
+            }),
             Jump(offset) => {
                 s.pc = (*offset as usize).wrapping_add(s.pc); // addition in 2's complement with no penalty
                 Self::singleton(s)
             },
-            Call(PredSig(Pred::User(pred), arity)) => {
-                // TODO: error checking when loading the predicate
-                s.call_user(pred, *arity);
-                Self::singleton(s)
-            },
             Call(PredSig(Pred::Sys(pred, arity), _)) => {
-                if self.builtins.exists(pred, arity) {
-                    let args = s.local_state.pop_n(*arity);
-                    if self.builtins[&(pred.clone(), *arity)](args, &mut s) {
-                        Self::singleton(s)
-                    } else {
-                        s.load_next_checkpoint()
+                let mut args = s.local_state.pop_n(*arity);
+                if let Some(builtin) = self.builtins.nondet(&(pred.clone(), *arity)) {
+                    args.reverse();
+                    match builtin(args, &mut s) {
+                        Ok(solutions) => Ok(s.try_bindings(solutions)),
+                        Err(e) => {
+                            let PredSig(p, n) = error_context(&s, pred, *arity);
+                            s.raise(e, p.name(), n)
+                        }
                     }
+                } else if self.builtins.exists(pred, arity) {
+                    // the arguments are pushed right-to-left
+                    args.reverse();
+                    match self.builtins[&(pred.clone(), *arity)](args, &mut s) {
+                        Ok(true) => Self::singleton(s),
+                        Ok(false) => Ok(s.load_next_checkpoint()),
+                        Err(e) => {
+                            let PredSig(p, n) = error_context(&s, pred, *arity);
+                            s.raise(e, p.name(), n)
+                        }
+                    }
+                } else {
+                    let culprit = predicate_indicator(pred, *arity);
+                    s.raise(existence_error("procedure", culprit), pred, *arity)
+                }
+            }
+            Call(sig) => {
+                if self.program.text.contains_key(sig) {
+                    s.call_user(sig.clone());
+                    return Self::singleton(s);
+                }
+                let clauses = s.database.borrow().clauses(sig);
+                if let Some(clauses) = clauses {
+                    return Ok(s.call_dynamic(sig.clone(), clauses));
+                }
+                // the module may see a predicate of another module that was defined after the call was compiled
+                let PredSig(p, arity) = sig;
+                let resolved = s.database.borrow().resolve(p.module().unwrap_or(USER), p.name(), *arity);
+                let resolved = PredSig(resolved, *arity);
+                if resolved.0 != *p && s.database.borrow().defines(&resolved) {
+                    self.execute(s, &Call(resolved))
                 } else {
-                    panic!("The built-in predicate {} does not exist", Pred::Sys(pred.clone(), *arity))
+                    self.unknown_procedure(s, sig)
                 }
             }
-            Det(label) => panic!("not implemented"),
-            DetUntil(label) => panic!("not implemented"),
-            Fail => s.load_next_checkpoint(),
-            Ret => s.ret(),
+            CallGoal(n) => {
+                let mut args = s.local_state.pop_n(n + 1);
+                // the arguments are pushed right-to-left, so the goal is the last one popped
+                args.reverse();
+                let extra = args.split_off(1);
+                // the goal is called in the module it is qualified with, or else in the module of the caller
+                let (module, goal) = match strip_module(&s.bindings, &args[0], s.local_state.module()) {
+                    Ok(qualified) => qualified,
+                    Err(e) => return s.raise(e, "call", n + 1)
+                };
+                let (f, mut goal_args) = match goal {
+                    Value::LV(_) => return s.raise(instantiation_error(), "call", n + 1),
+                    Value::Atom(f) => (f, Vec::new()),
+                    Value::Ctor(f, goal_args) => (f, goal_args),
+                    culprit => return s.raise(type_error("callable", culprit), "call", n + 1)
+                };
+                goal_args.extend(extra);
+                self.call_goal(s, &module, f, goal_args)
+            }
+            Det(label) => Self::modify_then_pack(s, |s| s.cut_to(*label, true)),
+            DetUntil(label) => Self::modify_then_pack(s, |s| s.cut_to(*label, false)),
+            Cut => Self::modify_then_pack(s, |s| s.cut()),
+            MkCatch(label, offset) => match s.local_state.pop() {
+                Some(catcher) => Self::modify_then_pack(s, |s| {
+                    let target = (*offset as usize).wrapping_add(s.pc);
+                    s.push_catch(*label, catcher, target)
+                }),
+                None => s.raise(system_error("catch/3 without a catcher"), "catch", 3)
+            },
+            ExitCatch => Self::modify_then_pack(s, |s| s.exit_catch()),
+            MkCollect(offset) => Self::modify_then_pack(s, |s| {
+                let target = (*offset as usize).wrapping_add(s.pc);
+                s.push_collector(target)
+            }),
+            Collect => match s.local_state.pop() {
+                Some(solution) if s.collect(&solution) => Ok(s.load_next_checkpoint()),
+                _ => s.raise(system_error("a solution to collect outside findall/3"), "findall", 3)
+            },
+            Fail => Ok(s.load_next_checkpoint()),
+            Ret => Ok(s.ret()),
             Halt => Self::singleton(s), // halt and catch fire
         }
     }
 
+    // Call the goal with given functor and arguments in given module. Control constructs are compiled first, the other goals are called like a compiled call in the module would.
+    fn call_goal(&self, mut s: State, module: &str, f: String, mut args: Vec<Value>) -> Step {
+        let arity = args.len();
+        let control = parser::is_control(&f, arity) || (f != "call" && self.builtins.is_control(&f, arity));
+        // the built-ins that depend on the module they are called in see the module of the caller, so a call in another module runs in a clause of that module
+        let elsewhere = f != "call" && module != s.local_state.module() && self.builtins.exists(&f, &arity);
+        if control || elsewhere {
+            let goal = s.bindings.resolve(&Value::Ctor(f, args));
+            let compiled = s.database.borrow().goal_clause(&goal, module);
+            match compiled {
+                Ok((clause, vars)) => Self::modify_then_pack(s, |s| s.call_clause(clause, vars)),
+                Err(e) => s.raise(e, "call", 1)
+            }
+        } else {
+            let insn = if f == "call" && arity > 0 {
+                if module != s.local_state.module() {
+                    args[0] = Value::Ctor(":".to_string(), vec![Value::Atom(module.to_string()), args[0].clone()]);
+                }
+                Insn::CallGoal(arity - 1)
+            } else {
+                let database = s.database.borrow();
+                let p = database.resolve(module, &f, arity);
+                // the goals given to a meta-predicate of another module are called in this module
+                if let Some(meta) = p.module().filter(|m| *m != module).and_then(|_| database.modules().meta(&PredSig(p.clone(), arity))) {
+                    for (arg, _) in args.iter_mut().zip(meta.iter()).filter(|(_, is_meta)| **is_meta) {
+                        if !matches!(s.bindings.find(arg), Value::Ctor(f, qualified) if f == ":" && qualified.len() == 2) {
+                            *arg = Value::Ctor(":".to_string(), vec![Value::Atom(module.to_string()), arg.clone()]);
+                        }
+                    }
+                }
+                Insn::Call(PredSig(p, arity))
+            };
+            for arg in args.into_iter().rev() {
+                s.local_state.push_value(arg);
+            }
+            self.execute(s, &insn)
+        }
+    }
+
+    // Handle a call to an undefined user predicate as the unknown flag says
+    fn unknown_procedure(&self, mut s: State, sig: &PredSig) -> Step {
+        let PredSig(p, arity) = sig;
+        s.local_state.pop_n(*arity);
+        let unknown = s.flags.borrow().unknown;
+        let mut message = format!("unknown procedure {}", sig);
+        let similar = self.similar_procedures(p.name(), *arity);
+        if !similar.is_empty() {
+            message.push_str(&format!(", did you mean {}?", similar.join(" or ")));
+        }
+
+        match unknown {
+            Unknown::Error => {
+                let PredSig(caller, caller_arity) = s.local_state.predicate.clone();
+                let e = existence_error("procedure", qualified_indicator(sig));
+                s.raise_with(e, caller.name(), caller_arity, Value::Atom(message))
+            }
+            Unknown::Warning => {
+                let warning = match self.program.span(&s.local_state.predicate, s.pc - 1) {
+                    Some(span) => format!("Warning: {}: {}\n", span, message),
+                    None => format!("Warning: {}\n", message)
+                };
+                // the procedure fails whether the warning could be written or not
+                let _ = s.streams.borrow_mut().user_error().put(&warning);
+                Ok(s.load_next_checkpoint())
+            }
+            Unknown::Fail => Ok(s.load_next_checkpoint())
+        }
+    }
+
+    // Defined procedures that the program may have meant to call instead of given one: the same name with another arity, or a similar name with the same arity
+    fn similar_procedures(&self, name: &str, arity: usize) -> Vec<String> {
+        let database = self.database.borrow();
+        let user = self.program.text.keys().chain(database.predicates()).filter_map(|PredSig(p, n)| match p {
+            Pred::Qualified(_, p) if !p.starts_with('$') => Some((p.as_str(), *n)),
+            _ => None
+        });
+        let sys = self.builtins.signatures().map(|(p, n)| (p.as_str(), *n));
+        // symbolic names such as </2 are no useful suggestion for an alphanumeric name
+        let alphanumeric = |p: &str| p.chars().all(|c| c.is_alphanumeric() || c == '_');
+        let symbolic = !alphanumeric(name);
+        let mut similar: Vec<(usize, String)> = user.chain(sys)
+            .filter(|(p, _)| symbolic || alphanumeric(p))
+            .filter_map(|(p, n)| {
+                let distance = edit_distance(name, p);
+                if (p == name && n != arity) || (n == arity && distance > 0 && distance <= 1 + name.len() / 8 && distance < name.len()) {
+                    Some((distance, format!("{}/{}", p, n)))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        similar.sort();
+        similar.dedup();
+        similar.into_iter().map(|(_, pi)| pi).collect()
+    }
+
     // make a small step
     pub fn step(&mut self) {
         if let Some(state) = self.state.take() {
-            println!("state before: {:?}", state.local_state);
-            println!("processing {:?}", self.program.text[&state.local_state.predicate][state.pc]);
+            if self.trace {
+                println!("state before: {:?}", state.local_state);
+                println!("processing {:?}", self.control());
+            }
 
-            self.state = self.next(state);
+            self.state = match self.next(state) {
+                Ok(state) => state,
+                Err(Uncaught { ball, frames }) => {
+                    self.exception = Some(ball);
+                    self.backtrace = frames.into_iter().map(|(predicate, pc)| {
+                        let span = self.program.span(&predicate, pc).cloned();
+                        Frame { predicate, span }
+                    }).collect();
+                    None
+                }
+            };
 
-            println!("state after: {:?}", self.state.as_ref().map(|s| &s.local_state));
+            if self.trace {
+                println!("state after: {:?}", self.state.as_ref().map(|s| &s.local_state));
+            }
         }
     }
 
+    // Start executing given predicate from scratch
+    pub fn start(&mut self, predicate: PredSig) {
+        let mut state = State::entry(predicate);
+        state.flags = self.flags.clone();
+        state.ops = self.ops.clone();
+        state.database = self.database.clone();
+        state.streams = self.streams.clone();
+        self.state = Some(state);
+        self.exception = None;
+        self.backtrace.clear();
+    }
+
+    pub fn builtins(&self) -> &BuiltIns {
+        &self.builtins
+    }
+
     pub fn control(&self) -> Option<&Insn> {
-        self.state.as_ref().map(|s| {
-            &self.program.text[&s.local_state.predicate][s.pc]
+        self.state.as_ref().map(|s| match s.local_state.clause {
+            Some(ref clause) => &clause.code[s.pc],
+            None => &self.program.text[&s.local_state.predicate][s.pc]
         })
     }
 
@@ -103,4 +392,10 @@ impl VM {
             self.step();
         }
     }
+
+    // Discard the current solution and run until the next one, if any
+    pub fn backtrack(&mut self) {
+        self.state = self.state.take().and_then(|s| s.load_next_checkpoint());
+        self.run();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,391 @@
+// Writer of terms as text, which uses the operator table to write the terms the way the reader reads them back
+use crate::ir::Value;
+use crate::ops::{OpType, Ops};
+
+const GRAPHIC: &str = "#$&*+-./:<=>?@^~\\";
+
+fn is_graphic(c: char) -> bool {
+    GRAPHIC.contains(c)
+}
+
+fn is_alnum(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Whether given atom reads back as itself without quotes
+fn is_plain(atom: &str) -> bool {
+    let mut chars = atom.chars();
+    match chars.next() {
+        None => false,
+        Some(c) if c.is_alphabetic() && !c.is_uppercase() => chars.all(is_alnum),
+        // a lone `.` is a full stop, and `/*` starts a comment
+        Some(c) if is_graphic(c) => atom != "." && !atom.starts_with("/*") && atom.chars().all(is_graphic),
+        _ => ["[]", "{}", "!", ";"].contains(&atom)
+    }
+}
+
+// Given text in given quotes, with the escape sequences the reader understands
+pub fn quote(text: &str, quote: char) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push(quote);
+    for c in text.chars() {
+        match c {
+            c if c == quote => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\x{:x}\\", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push(quote);
+    quoted
+}
+
+// The functor of the text portray/1 wrote for a term, '$portrayed'(Text) is written as the text
+pub const PORTRAYED: &str = "$portrayed";
+
+fn is_true(goal: &Value) -> bool {
+    matches!(goal, Value::Atom(a) if a == "true")
+}
+
+// The name of the variable '$VAR'(N) stands for, which is a letter followed by a number from the second round of the alphabet on
+fn variable_name(n: i64) -> String {
+    let letter = (b'A' + (n % 26) as u8) as char;
+    match n / 26 {
+        0 => letter.to_string(),
+        round => format!("{}{}", letter, round)
+    }
+}
+
+// The settings of a writer, which are the options of write_term/2. Quoted atoms and variable names read back as the term that was written.
+pub struct Writer<'a> {
+    ops: &'a Ops,
+    quoted: bool,
+    // write the operators in canonical form
+    ignore_ops: bool,
+    // write '$VAR'(N) as a variable name
+    number_vars: bool,
+    // write '$portrayed'(Text) as the text
+    portray: bool,
+    // write the terms nested deeper than this as ..., 0 for no limit
+    max_depth: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(ops: &'a Ops, quoted: bool) -> Self {
+        Writer { ops, quoted, ignore_ops: false, number_vars: false, portray: false, max_depth: 0 }
+    }
+
+    pub fn ignore_ops(self, ignore_ops: bool) -> Self {
+        Writer { ignore_ops, ..self }
+    }
+
+    pub fn number_vars(self, number_vars: bool) -> Self {
+        Writer { number_vars, ..self }
+    }
+
+    pub fn portray(self, portray: bool) -> Self {
+        Writer { portray, ..self }
+    }
+
+    pub fn max_depth(self, max_depth: usize) -> Self {
+        Writer { max_depth, ..self }
+    }
+
+    // The text of given value, which should be resolved first
+    pub fn write(&self, value: &Value) -> String {
+        let mut out = String::new();
+        self.term(value, 1200, 1, &mut out);
+        out
+    }
+
+    // The text of given clause as a listing shows it, with each goal of the body on a line of its own and full stop and newline at the end
+    pub fn clause(&self, clause: &Value) -> String {
+        let mut out = String::new();
+        match clause {
+            Value::Ctor(f, args) if f == ":-" && args.len() == 2 && !is_true(&args[1]) => {
+                self.term(&args[0], 1199, 1, &mut out);
+                out.push_str(" :-\n");
+                self.body(&args[1], 4, false, &mut out);
+            }
+            Value::Ctor(f, args) if f == ":-" && args.len() == 2 => self.term(&args[0], 1199, 1, &mut out),
+            head => self.term(head, 1199, 1, &mut out)
+        }
+        out.push_str(".\n");
+        out
+    }
+
+    // Write a body indented by given number of spaces, the first line is not indented if it continues a line
+    fn body(&self, goal: &Value, indent: usize, continued: bool, out: &mut String) {
+        let pad = if continued { 0 } else { indent };
+        match goal {
+            Value::Ctor(f, args) if f == "," && args.len() == 2 => {
+                self.body(&args[0], indent, continued, out);
+                out.push_str(",\n");
+                self.body(&args[1], indent, false, out);
+            }
+            Value::Ctor(f, args) if (f == ";" || f == "->") && args.len() == 2 => {
+                out.push_str(&format!("{:pad$}(   ", "", pad = pad));
+                self.branches(goal, indent, out);
+                out.push_str(&format!("\n{:indent$})", "", indent = indent));
+            }
+            goal => {
+                out.push_str(&format!("{:pad$}", "", pad = pad));
+                self.term(goal, 999, 1, out);
+            }
+        }
+    }
+
+    // Write the branches of a disjunction or an if-then-else that starts at given indentation
+    fn branches(&self, goal: &Value, indent: usize, out: &mut String) {
+        match goal {
+            Value::Ctor(f, args) if f == ";" && args.len() == 2 => {
+                self.branches(&args[0], indent, out);
+                out.push_str(&format!("\n{:indent$};   ", "", indent = indent));
+                self.branches(&args[1], indent, out);
+            }
+            Value::Ctor(f, args) if f == "->" && args.len() == 2 => {
+                self.body(&args[0], indent + 4, true, out);
+                out.push_str(&format!("\n{:indent$}->  ", "", indent = indent));
+                self.body(&args[1], indent + 4, true, out);
+            }
+            goal => self.body(goal, indent + 4, true, out)
+        }
+    }
+
+    fn too_deep(&self, depth: usize) -> bool {
+        self.max_depth > 0 && depth > self.max_depth
+    }
+
+    fn atom(&self, atom: &str, out: &mut String) {
+        if self.quoted && !is_plain(atom) {
+            out.push_str(&quote(atom, '\''));
+        } else {
+            out.push_str(atom);
+        }
+    }
+
+    // Write given value as an operand whose priority is at most `max`, at given depth from the term written
+    fn term(&self, value: &Value, max: u32, depth: usize, out: &mut String) {
+        if self.too_deep(depth) {
+            out.push_str("...");
+            return;
+        }
+        match value {
+            Value::LV(x) => out.push_str(&format!("_G{}", x)),
+            Value::Num(n) => out.push_str(&n.to_string()),
+            Value::Str(s) if self.quoted => out.push_str(&quote(s, '"')),
+            Value::Str(s) => out.push_str(s),
+            Value::Atom(a) => self.atom(a, out),
+            Value::Ctor(f, args) if f == "." && args.len() == 2 => self.list(value, depth, out),
+            Value::Ctor(f, args) if f == "{}" && args.len() == 1 && !self.ignore_ops => {
+                out.push('{');
+                self.term(&args[0], 1200, depth + 1, out);
+                out.push('}');
+            }
+            Value::Ctor(f, args) => match args.as_slice() {
+                [Value::Num(n)] if f == "$VAR" && self.number_vars && *n >= 0 => out.push_str(&variable_name(*n)),
+                [Value::Atom(a)] if f == "$VAR" && self.number_vars && a == "_" => out.push('_'),
+                [Value::Str(text)] if f == PORTRAYED && self.portray => out.push_str(text),
+                _ => match self.operator(f, args) {
+                    Some(op) => self.operation(f, args, op, max, depth, out),
+                    None => {
+                        self.atom(f, out);
+                        out.push('(');
+                        for (i, arg) in args.iter().enumerate() {
+                            if i > 0 {
+                                out.push(',');
+                            }
+                            self.term(arg, 999, depth + 1, out);
+                        }
+                        out.push(')');
+                    }
+                }
+            }
+        }
+    }
+
+    // The definition of the operator a compound term is written with, if any
+    fn operator(&self, f: &str, args: &[Value]) -> Option<(u32, OpType)> {
+        if self.ignore_ops {
+            return None;
+        }
+        match args {
+            [_, _] => self.ops.infix(f),
+            // - 1 would read as the number -1
+            [Value::Num(_)] if f == "-" || f == "+" => None,
+            [_] => self.ops.prefix(f).or_else(|| self.ops.postfix(f)),
+            _ => None
+        }
+    }
+
+    fn operation(&self, f: &str, args: &[Value], (priority, op_type): (u32, OpType), max: u32, depth: usize, out: &mut String) {
+        let open = priority > max;
+        if open {
+            out.push('(');
+        }
+        let mut op = String::new();
+        self.atom(f, &mut op);
+        // alphanumeric operators are separated from their operands, symbolic ones only where they would run together
+        let alpha = op.chars().next().is_some_and(is_alnum);
+        match args {
+            [left, right] => {
+                let left = self.operand(left, op_type.left_max(priority), depth + 1);
+                let right = self.operand(right, op_type.right_max(priority), depth + 1);
+                out.push_str(&left);
+                if f == "," {
+                    out.push(',');
+                } else {
+                    if alpha || left.ends_with(is_graphic) {
+                        out.push(' ');
+                    }
+                    out.push_str(&op);
+                    if alpha || right.starts_with(is_graphic) {
+                        out.push(' ');
+                    }
+                }
+                out.push_str(&right);
+            }
+            [arg] if self.ops.prefix(f).is_some() => {
+                let arg = self.operand(arg, op_type.right_max(priority), depth + 1);
+                out.push_str(&op);
+                // f (X) would read as the compound f(X)
+                if alpha || arg.starts_with(|c| is_graphic(c) || c == '(') {
+                    out.push(' ');
+                }
+                out.push_str(&arg);
+            }
+            [arg] => {
+                let arg = self.operand(arg, op_type.left_max(priority), depth + 1);
+                out.push_str(&arg);
+                if alpha || arg.ends_with(is_graphic) {
+                    out.push(' ');
+                }
+                out.push_str(&op);
+            }
+            _ => unreachable!("operators have one or two operands")
+        }
+        if open {
+            out.push(')');
+        }
+    }
+
+    fn operand(&self, value: &Value, max: u32, depth: usize) -> String {
+        let mut out = String::new();
+        match value {
+            // an operator as an operand of another operator is in parentheses, so that it is not read as an operator
+            Value::Atom(a) if self.is_operator(a) && !self.too_deep(depth) => {
+                out.push('(');
+                self.atom(a, &mut out);
+                out.push(')');
+            }
+            _ => self.term(value, max, depth, &mut out)
+        }
+        out
+    }
+
+    fn is_operator(&self, atom: &str) -> bool {
+        self.ops.prefix(atom).is_some() || self.ops.infix(atom).is_some() || self.ops.postfix(atom).is_some()
+    }
+
+    // Write a list in brackets, each element is one level deeper than the one before it
+    fn list(&self, value: &Value, depth: usize, out: &mut String) {
+        out.push('[');
+        let mut current = value;
+        let mut depth = depth;
+        let mut first = true;
+        loop {
+            match current {
+                Value::Ctor(f, args) if f == "." && args.len() == 2 => {
+                    if !first {
+                        if self.max_depth > 0 && depth >= self.max_depth {
+                            out.push_str("|...");
+                            break;
+                        }
+                        out.push(',');
+                    }
+                    self.term(&args[0], 999, depth, out);
+                    current = &args[1];
+                    depth += 1;
+                    first = false;
+                }
+                Value::Atom(a) if a == "[]" => break,
+                tail => {
+                    out.push('|');
+                    self.term(tail, 999, depth, out);
+                    break;
+                }
+            }
+        }
+        out.push(']');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::read_value;
+    use crate::flags::DoubleQuotes;
+    use crate::parser;
+
+    fn read(text: &str) -> Value {
+        let mut n = 0;
+        read_value(parser::parse_term(text, &Ops::default(), DoubleQuotes::Codes).unwrap(), || { n += 1; Value::LV(n) })
+    }
+
+    // Read given text and write it back
+    fn rewrite(text: &str, quoted: bool) -> String {
+        Writer::new(&Ops::default(), quoted).write(&read(text))
+    }
+
+    #[test]
+    fn test_operators() {
+        for text in ["a:-b,c;d->e", "1+2*3", "(1+2)*3", "1-(2-3)", "1-2-3", "2**(3**4)", "-(1)", "-a", "- -(1)", "1- -1", "- (-)",
+                     "\\+a", "f((a,b))", "f(:-)", "a=(\\+b)", "a= -b", "{a,b}", "- (a,b)", "f(;,'|')", "1 rem 2 mod 3"] {
+            assert_eq!(rewrite(text, true), text, "{}", text);
+        }
+        assert_eq!(rewrite("f(- 1)", true), "f(-(1))");
+        // an operator atom is bracketed as an operand whatever its priority
+        assert_eq!(rewrite("-(-, a)", true), "(-)-a");
+        assert_eq!(rewrite("(:-) :- a", true), "(:-):-a");
+        assert_eq!(rewrite("a = (mod)", true), "a=(mod)");
+        assert_eq!(rewrite("[-, f(+)]", true), "[-,f(+)]");
+        assert_eq!(rewrite("X is Y mod 2", true), "_G1 is _G2 mod 2");
+        assert_eq!(rewrite("[a,b|T]", true), "[a,b|_G1]");
+    }
+
+    #[test]
+    fn test_quoting() {
+        assert_eq!(rewrite("['hello world','Abc',[],'[]',{},'.',é,'Été',+,'\\n','it''s',\"ab\"]", true),
+                   "['hello world','Abc',[],[],{},'.',é,'Été',+,'\\n','it\\'s',[97,98]]");
+        assert_eq!(rewrite("f('A b', 'c')", false), "f(A b,c)");
+    }
+
+    #[test]
+    fn test_options() {
+        let ops = Ops::default();
+        let canonical = Writer::new(&ops, true).ignore_ops(true);
+        assert_eq!(canonical.write(&read("a:-b,c")), ":-(a,','(b,c))");
+        assert_eq!(canonical.write(&read("[- 1,{x}]")), "[-(1),{}(x)]");
+
+        let names = Writer::new(&ops, true).number_vars(true);
+        assert_eq!(names.write(&read("f('$VAR'(0),'$VAR'(25),'$VAR'(27),'$VAR'(-1),'$VAR'(x))")), "f(A,Z,B1,'$VAR'(-1),'$VAR'(x))");
+        assert_eq!(rewrite("'$VAR'(1)", true), "'$VAR'(1)");
+        assert_eq!(names.write(&read("f('$VAR'('_'),'$VAR'(0))")), "f(_,A)");
+
+        let shallow = Writer::new(&ops, true).max_depth(3);
+        assert_eq!(shallow.write(&read("[1,2,3,4,5,6]")), "[1,2|...]");
+        assert_eq!(shallow.write(&read("f(g(h(i)),j)")), "f(g(h(...)),j)");
+        assert_eq!(shallow.write(&read("1+2+3+4")), "... + ... +3+4");
+        assert_eq!(Writer::new(&ops, true).max_depth(1).write(&read("f(a)")), "f(...)");
+
+        let portrayed = Value::Ctor(PORTRAYED.to_string(), vec![Value::Str("<x>".to_string())]);
+        let term = Value::Ctor("-".to_string(), vec![Value::Atom("a".to_string()), portrayed]);
+        assert_eq!(Writer::new(&ops, true).portray(true).write(&term), "a- <x>");
+        assert_eq!(Writer::new(&ops, true).write(&term), "a-'$portrayed'(\"<x>\")");
+    }
+}
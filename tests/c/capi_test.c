@@ -0,0 +1,122 @@
+/* Exercise the C interface: consult a program, run queries and read the solutions back. */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "cadmium.h"
+
+static int failures = 0;
+
+#define CHECK(cond)                                                         \
+    do {                                                                    \
+        if (!(cond)) {                                                      \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #cond); \
+            failures++;                                                     \
+        }                                                                   \
+    } while (0)
+
+static const char *PROGRAM =
+    "parent(tom, bob).\n"
+    "parent(bob, ann).\n"
+    "parent(bob, pat).\n"
+    "grandparent(X, Z) :- parent(X, Y), parent(Y, Z).\n"
+    "point(p(1, two)).\n";
+
+static void test_solutions(CadmiumEngine *engine) {
+    CadmiumQuery *query = NULL;
+    CHECK(cadmium_query_open(engine, "grandparent(tom, X)", &query) == CADMIUM_STATUS_OK);
+    CHECK(cadmium_query_variable_count(query) == 1);
+    CHECK(strcmp(cadmium_query_variable_name(query, 0), "X") == 0);
+    CHECK(cadmium_query_variable_name(query, 1) == NULL);
+
+    const char *expected[] = {"ann", "pat"};
+    size_t n = 0;
+    while (cadmium_query_next(query) == CADMIUM_STATUS_OK) {
+        CadmiumTerm *x = NULL;
+        CHECK(cadmium_query_binding(query, "X", &x) == CADMIUM_STATUS_OK);
+        CHECK(cadmium_term_kind(x) == CADMIUM_TERM_KIND_ATOM);
+        CHECK(n < 2 && strcmp(cadmium_term_name(x), expected[n]) == 0);
+        cadmium_term_free(x);
+        n++;
+    }
+    CHECK(n == 2);
+    /* exhausted queries stay exhausted */
+    CHECK(cadmium_query_next(query) == CADMIUM_STATUS_NO_SOLUTION);
+    cadmium_query_close(query);
+}
+
+static void test_compound_terms(CadmiumEngine *engine) {
+    CadmiumQuery *query = NULL;
+    CHECK(cadmium_query_open(engine, "point(P)", &query) == CADMIUM_STATUS_OK);
+    CHECK(cadmium_query_next(query) == CADMIUM_STATUS_OK);
+
+    CadmiumTerm *p = NULL, *arg = NULL;
+    CHECK(cadmium_query_binding(query, "P", &p) == CADMIUM_STATUS_OK);
+    CHECK(cadmium_query_binding(query, "Q", &arg) == CADMIUM_STATUS_NOT_FOUND);
+    CHECK(cadmium_term_kind(p) == CADMIUM_TERM_KIND_COMPOUND);
+    CHECK(strcmp(cadmium_term_name(p), "p") == 0);
+    CHECK(cadmium_term_arity(p) == 2);
+
+    int64_t value = 0;
+    CHECK(cadmium_term_arg(p, 0, &arg) == CADMIUM_STATUS_OK);
+    CHECK(cadmium_term_kind(arg) == CADMIUM_TERM_KIND_INTEGER);
+    CHECK(cadmium_term_integer(arg, &value) == CADMIUM_STATUS_OK && value == 1);
+    cadmium_term_free(arg);
+
+    CHECK(cadmium_term_arg(p, 1, &arg) == CADMIUM_STATUS_OK);
+    CHECK(cadmium_term_integer(arg, &value) == CADMIUM_STATUS_TYPE_ERROR);
+    cadmium_term_free(arg);
+    CHECK(cadmium_term_arg(p, 2, &arg) == CADMIUM_STATUS_NOT_FOUND);
+
+    char *text = cadmium_term_to_string(p);
+    CHECK(text != NULL);
+    cadmium_string_free(text);
+    cadmium_term_free(p);
+    cadmium_query_close(query);
+}
+
+static void test_errors(CadmiumEngine *engine) {
+    CadmiumQuery *query = NULL, *other = NULL;
+    CHECK(cadmium_consult_string(engine, "foo(") == CADMIUM_STATUS_PARSE_ERROR);
+    CHECK(cadmium_engine_error(engine) != NULL);
+    CHECK(cadmium_query_open(engine, "X = ", &query) == CADMIUM_STATUS_PARSE_ERROR);
+    CHECK(cadmium_consult_string(NULL, "foo.") == CADMIUM_STATUS_INVALID_ARGUMENT);
+    CHECK(cadmium_term_kind(NULL) == CADMIUM_TERM_KIND_INVALID);
+    CHECK(cadmium_term_arity(NULL) == 0);
+
+    CHECK(cadmium_query_open(engine, "parent(X, Y)", &query) == CADMIUM_STATUS_OK);
+    CHECK(cadmium_query_open(engine, "parent(X, Y)", &other) == CADMIUM_STATUS_BUSY);
+    /* the engine is neither changed nor freed under an open query */
+    CHECK(cadmium_consult_string(engine, "foo.") == CADMIUM_STATUS_BUSY);
+    CHECK(cadmium_engine_free(engine) == CADMIUM_STATUS_BUSY);
+    CHECK(cadmium_query_next(query) == CADMIUM_STATUS_OK);
+    cadmium_query_close(query);
+    CHECK(cadmium_query_open(engine, "parent(X, Y)", &other) == CADMIUM_STATUS_OK);
+    cadmium_query_close(other);
+
+    CHECK(cadmium_query_open(engine, "throw(oops)", &query) == CADMIUM_STATUS_OK);
+    CHECK(cadmium_query_next(query) == CADMIUM_STATUS_EXCEPTION);
+    CHECK(strstr(cadmium_engine_error(engine), "oops") != NULL);
+    CHECK(cadmium_query_next(query) == CADMIUM_STATUS_NO_SOLUTION);
+    cadmium_query_close(query);
+}
+
+int main(void) {
+    CadmiumEngine *engine = cadmium_engine_new();
+    if (cadmium_consult_string(engine, PROGRAM) != CADMIUM_STATUS_OK) {
+        fprintf(stderr, "consult failed: %s\n", cadmium_engine_error(engine));
+        return 1;
+    }
+
+    test_solutions(engine);
+    test_compound_terms(engine);
+    test_errors(engine);
+
+    CHECK(cadmium_engine_free(engine) == CADMIUM_STATUS_OK);
+    if (failures > 0) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    printf("all checks passed\n");
+    return 0;
+}
@@ -0,0 +1,47 @@
+// Build the C test program against the shared library and run it.
+#![cfg(target_os = "linux")]
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+// The build script generates the header from the current source, the C programs use the committed one
+#[test]
+fn header_is_up_to_date() {
+    let committed = fs::read_to_string(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("include/cadmium.h")).unwrap();
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/cadmium.h"));
+    assert!(committed == generated, "include/cadmium.h is out of date, run `CADMIUM_UPDATE_HEADER=1 cargo build` to update it");
+}
+
+#[test]
+fn c_test_program() {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    // the test binary lives in target/<profile>/deps, next to the shared library's directory
+    let lib_dir = env::current_exe().unwrap().parent().unwrap().parent().unwrap().to_path_buf();
+    // cargo only builds the cdylib when the library itself is built, which is not the case when running this test alone
+    let mut build = Command::new(env!("CARGO"));
+    build.args(["build", "--lib", "--target-dir"]).arg(lib_dir.parent().unwrap());
+    if lib_dir.ends_with("release") {
+        build.arg("--release");
+    }
+    assert!(build.status().expect("failed to run cargo").success(), "building the cdylib failed");
+    assert!(lib_dir.join("libcadmium.so").exists(), "the cdylib was not built in {}", lib_dir.display());
+
+    let exe = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("capi_test");
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let status = Command::new(cc)
+        .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-o"])
+        .arg(&exe)
+        .arg(manifest_dir.join("tests/c/capi_test.c"))
+        .arg("-I").arg(manifest_dir.join("include"))
+        .arg("-L").arg(&lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-lcadmium")
+        .status()
+        .expect("failed to run the C compiler");
+    assert!(status.success(), "compiling the C test program failed");
+
+    let output = Command::new(&exe).output().expect("failed to run the C test program");
+    assert!(output.status.success(), "C test program failed:\n{}", String::from_utf8_lossy(&output.stderr));
+}